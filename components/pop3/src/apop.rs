@@ -0,0 +1,145 @@
+//! APOP digest auth described in
+//! [RFC 1939](https://tools.ietf.org/html/rfc1939#page-15).
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use md5::{Digest, Md5};
+
+use crate::MAX_LINE_LENGTH;
+
+/// Sequence to keep timestamps unique even if the clock doesn't move.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Build a greeting of `text` with a fresh timestamp for APOP.
+///
+/// Returns the greeting text to be sent as `Response::GREET`, and the
+/// timestamp like `<process-id.clock@hostname>` which should be kept by
+/// the connection to verify the later APOP command. `hostname` must pass
+/// `validate_hostname`, or the timestamp is not a valid msg-id. `text`
+/// must not contain control characters, and the greeting line must fit in
+/// `MAX_LINE_LENGTH`.
+pub fn make_apop_greeting(text: &str, hostname: &str) -> Result<(String, String)> {
+    validate_hostname(hostname)?;
+    if text.bytes().any(|b| b < 0x20 || b == 0x7f) {
+        return Err(anyhow::anyhow!("invalid greeting {:?}", text));
+    }
+
+    let clock = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_nanos())
+        .unwrap_or_default();
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    let timestamp = format!("<{}.{}.{}@{}>", process::id(), clock, seq, hostname);
+    let greet = format!("{} {}", text, timestamp);
+
+    // Sent as `+OK greet CRLF`.
+    if greet.len() + 6 > MAX_LINE_LENGTH {
+        return Err(anyhow::anyhow!(
+            "greeting is longer than {} octets",
+            MAX_LINE_LENGTH
+        ));
+    }
+
+    Ok((greet, timestamp))
+}
+
+/// Check whether `hostname` is a valid DNS name to be put in the greeting
+/// timestamp: dot separated labels of ASCII letters, digits and hyphens.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let valid = !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    if !valid {
+        return Err(anyhow::anyhow!("invalid hostname {:?}", hostname));
+    }
+
+    Ok(())
+}
+
+/// Compute the lowercase hex encoded MD5 digest of timestamp and secret.
+pub fn apop_digest(timestamp: &str, secret: &str) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(timestamp.as_bytes());
+    hasher.update(secret.as_bytes());
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Verify the digest sent by client with user's secret.
+pub fn apop_verify(timestamp: &str, secret: &str, digest: &str) -> bool {
+    apop_digest(timestamp, secret).eq_ignore_ascii_case(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn greeting() -> Result<()> {
+        let (greet, ts) = make_apop_greeting("POP3 server ready", "postman.example.com")?;
+        assert!(greet.starts_with("POP3 server ready <"));
+        assert!(greet.ends_with(&ts));
+        assert!(ts.starts_with(&format!("<{}.", process::id())));
+        assert!(ts.ends_with("@postman.example.com>"));
+
+        let (_, other) = make_apop_greeting("POP3 server ready", "postman.example.com")?;
+        assert_ne!(ts, other);
+
+        assert!(make_apop_greeting("ready\r\n+OK", "postman.example.com").is_err());
+        assert_eq!(
+            make_apop_greeting(&"x".repeat(500), "postman.example.com")
+                .unwrap_err()
+                .to_string(),
+            "greeting is longer than 512 octets"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn hostname() {
+        for v in &["localhost", "pop.example.com", "mail-1.example.com"] {
+            assert!(validate_hostname(v).is_ok(), "{}", v);
+        }
+        for v in &["", "my host", "<postman>", "a..b", "-a.com", "a@b"] {
+            assert!(validate_hostname(v).is_err(), "{}", v);
+        }
+
+        assert_eq!(
+            make_apop_greeting("ready", "my host")
+                .unwrap_err()
+                .to_string(),
+            r#"invalid hostname "my host""#
+        );
+    }
+
+    #[test]
+    fn digest() {
+        let ts = "<1896.697170952@dbc.mtview.ca.us>";
+        assert_eq!(
+            apop_digest(ts, "tanstaaf"),
+            "c4c9334bac560ecc979e58001b3e22fb"
+        );
+        assert!(apop_verify(
+            ts,
+            "tanstaaf",
+            "C4C9334BAC560ECC979E58001B3E22FB"
+        ));
+        assert!(!apop_verify(
+            ts,
+            "wrong",
+            "c4c9334bac560ecc979e58001b3e22fb"
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+//! Base64 used by AUTH and SASL mechanisms, which is the standard alphabet
+//! with padding described in
+//! [RFC 4648](https://tools.ietf.org/html/rfc4648#section-4).
+use crate::ProtoError;
+
+/// Encode `v` into base64 with padding.
+pub fn encode(v: impl AsRef<[u8]>) -> String {
+    base64::encode(v)
+}
+
+/// Decode base64 with padding, an empty string is decoded as empty.
+///
+/// Whitespace is not allowed, trim lines before decoding.
+pub fn decode(v: &str) -> Result<Vec<u8>, ProtoError> {
+    // The base64 crate accepts missing padding, which is not allowed by
+    // RFC 5034.
+    if !v.len().is_multiple_of(4) {
+        return Err(ProtoError::InvalidBase64(v.to_string()));
+    }
+
+    base64::decode(v).map_err(|_| ProtoError::InvalidBase64(v.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kerberos() {
+        // Example of AUTH KERBEROS_V4 in RFC 1734.
+        let challenge = decode("AmFYig==").expect("decode");
+        assert_eq!(challenge, [0x02, 0x61, 0x58, 0x8a]);
+        assert_eq!(encode(&challenge), "AmFYig==");
+
+        let v = concat!(
+            "BAcAQU5EUkVXLkNNVS5FRFUAOCAsho84kLN3/IJmrMG+25a4DT",
+            "+nZImJjnTNHJUtxAA+o0KPKfHEcAFs9a3CL5Oebe/ydHJUwYFd",
+            "WwuQ1MWiy6IesKvjL5rL9WjXUb9MwT9bpObYLGOKi1Qh",
+        );
+        assert_eq!(decode(v).expect("decode").len(), 108);
+        assert_eq!(encode(decode(v).expect("decode")), v);
+        assert_eq!(decode("or//EoAADZI=").expect("decode").len(), 8);
+
+        assert_eq!(decode("").expect("decode"), Vec::<u8>::new());
+        for v in &["AmFYig", "AmFY ig==", "AmFYig=", "*"] {
+            assert_eq!(
+                decode(v).unwrap_err(),
+                ProtoError::InvalidBase64(v.to_string())
+            );
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::Result;
+
+use crate::{ProtoError, Response, Session, State};
+
+/// Capabilities is the typed form of the CAPA response described in
+/// [RFC 2449](https://tools.ietf.org/html/rfc2449).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `TOP`: server supports the TOP command.
+    pub top: bool,
+    /// `USER`: server supports the USER and PASS commands.
+    pub user: bool,
+    /// `SASL`: mechanisms supported by the AUTH command.
+    pub sasl: Vec<String>,
+    /// `RESP-CODES`: server will return extended response codes.
+    pub resp_codes: bool,
+    /// `LOGIN-DELAY`: minimum seconds between logins.
+    pub login_delay: Option<u32>,
+    /// `PIPELINING`: server accepts pipelined commands.
+    pub pipelining: bool,
+    /// `EXPIRE`: how long server will retain messages.
+    pub expire: Option<Expire>,
+    /// `UIDL`: server supports the UIDL command.
+    pub uidl: bool,
+    /// `IMPLEMENTATION`: server implementation information.
+    pub implementation: Option<String>,
+    /// `STLS`: server supports the STLS command described in RFC 2595.
+    pub stls: bool,
+    /// Capabilities which are not known, kept as is.
+    pub others: Vec<String>,
+}
+
+/// Expire is the argument of `EXPIRE` capability.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Expire {
+    /// Messages will never be deleted by server.
+    Never,
+    /// Messages will be retained for at least such days, `0` means messages
+    /// will be deleted once retrieved.
+    Days(u32),
+}
+
+impl Display for Expire {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expire::Never => write!(f, "NEVER"),
+            Expire::Days(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Build capability lines in canonical order.
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if self.top {
+            lines.push("TOP".to_string());
+        }
+        if self.user {
+            lines.push("USER".to_string());
+        }
+        if !self.sasl.is_empty() {
+            lines.push(format!("SASL {}", self.sasl.join(" ")));
+        }
+        if self.resp_codes {
+            lines.push("RESP-CODES".to_string());
+        }
+        if let Some(v) = self.login_delay {
+            lines.push(format!("LOGIN-DELAY {}", v));
+        }
+        if self.pipelining {
+            lines.push("PIPELINING".to_string());
+        }
+        if let Some(v) = self.expire {
+            lines.push(format!("EXPIRE {}", v));
+        }
+        if self.uidl {
+            lines.push("UIDL".to_string());
+        }
+        if let Some(v) = &self.implementation {
+            lines.push(format!("IMPLEMENTATION {}", v));
+        }
+        if self.stls {
+            lines.push("STLS".to_string());
+        }
+        lines.extend(self.others.iter().cloned());
+
+        lines
+    }
+
+    /// Capabilities advertised in `session`.
+    ///
+    /// SASL and STLS only make sense before authenticated, and STLS is
+    /// dropped once TLS is active as well. USER is advertised only if
+    /// plaintext auth is enabled by `user`.
+    pub fn advertised(&self, session: &Session) -> Capabilities {
+        let authorization = session.state() == State::AUTHORIZATION;
+
+        Capabilities {
+            sasl: if authorization {
+                self.sasl.clone()
+            } else {
+                Vec::new()
+            },
+            stls: self.stls && authorization && !session.is_tls_active(),
+            ..self.clone()
+        }
+    }
+
+    /// Serve CAPA in `session`, lines are in the canonical order of
+    /// `to_lines`.
+    pub fn handle_capa(&self, session: &Session) -> Response {
+        self.advertised(session).to_response()
+    }
+
+    /// Build the CAPA response.
+    pub fn to_response(&self) -> Response {
+        Response::CAPA(self.to_lines())
+    }
+
+    /// Parse capabilities from a CAPA response.
+    ///
+    /// Unknown capabilities and known capabilities with malformed arguments
+    /// will be kept in `others`.
+    pub fn parse(resp: &Response) -> Result<Capabilities> {
+        Capabilities::parse_with(resp, false)
+    }
+
+    /// Parse capabilities from a CAPA response, known capabilities with
+    /// malformed arguments are errors, like `LOGIN-DELAY abc` or an
+    /// `EXPIRE` suffix other than `USER`.
+    ///
+    /// Unknown capabilities are still kept in `others`.
+    pub fn parse_strict(resp: &Response) -> Result<Capabilities> {
+        Capabilities::parse_with(resp, true)
+    }
+
+    fn parse_with(resp: &Response, strict: bool) -> Result<Capabilities> {
+        let lines = match resp {
+            Response::CAPA(v) => v,
+            v => return Err(anyhow::anyhow!("invalid response for CAPA: {:?}", v)),
+        };
+
+        let mut caps = Capabilities::default();
+        for line in lines {
+            let mut vs = line.split_whitespace();
+            let name = vs.next().unwrap_or_default().to_ascii_uppercase();
+            let args: Vec<&str> = vs.collect();
+            // Only `USER` is defined as the suffix of LOGIN-DELAY and EXPIRE.
+            let suffix_ok = match args.as_slice() {
+                [_, v] => v.eq_ignore_ascii_case("USER"),
+                _ => true,
+            };
+
+            let known = match (name.as_str(), args.as_slice()) {
+                ("TOP", []) => {
+                    caps.top = true;
+                    true
+                }
+                ("USER", []) => {
+                    caps.user = true;
+                    true
+                }
+                ("SASL", v) => {
+                    caps.sasl = v.iter().map(|v| v.to_string()).collect();
+                    true
+                }
+                ("RESP-CODES", []) => {
+                    caps.resp_codes = true;
+                    true
+                }
+                // LOGIN-DELAY could have a `USER` suffix which means the value
+                // may vary by user, we only care about the value here.
+                ("LOGIN-DELAY", [v]) | ("LOGIN-DELAY", [v, _]) => match v.parse() {
+                    Ok(v) => {
+                        caps.login_delay = Some(v);
+                        true
+                    }
+                    Err(_) => false,
+                },
+                ("PIPELINING", []) => {
+                    caps.pipelining = true;
+                    true
+                }
+                ("EXPIRE", [v]) | ("EXPIRE", [v, _]) => match parse_expire(v) {
+                    Some(v) => {
+                        caps.expire = Some(v);
+                        true
+                    }
+                    None => false,
+                },
+                ("UIDL", []) => {
+                    caps.uidl = true;
+                    true
+                }
+                ("IMPLEMENTATION", v) if !v.is_empty() => {
+                    caps.implementation = Some(v.join(" "));
+                    true
+                }
+                ("STLS", []) => {
+                    caps.stls = true;
+                    true
+                }
+                _ => false,
+            };
+            if strict && (!known || !suffix_ok) && KNOWN.contains(&name.as_str()) {
+                return Err(ProtoError::InvalidCapability(line.to_string()).into());
+            }
+            if !known {
+                caps.others.push(line.to_string());
+            }
+        }
+
+        Ok(caps)
+    }
+}
+
+/// Names of capabilities parsed by `Capabilities::parse`.
+const KNOWN: &[&str] = &[
+    "TOP",
+    "USER",
+    "SASL",
+    "RESP-CODES",
+    "LOGIN-DELAY",
+    "PIPELINING",
+    "EXPIRE",
+    "UIDL",
+    "IMPLEMENTATION",
+    "STLS",
+];
+
+fn parse_expire(v: &str) -> Option<Expire> {
+    if v.eq_ignore_ascii_case("NEVER") {
+        return Some(Expire::Never);
+    }
+
+    v.parse().ok().map(Expire::Days)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AuthResponse, Request};
+
+    #[test]
+    fn round_trip() {
+        let caps = Capabilities {
+            top: true,
+            user: true,
+            sasl: vec!["CRAM-MD5".to_string(), "PLAIN".to_string()],
+            resp_codes: true,
+            login_delay: Some(900),
+            pipelining: true,
+            expire: Some(Expire::Never),
+            uidl: true,
+            implementation: Some("Shlemazle-Plotz-v302".to_string()),
+            stls: false,
+            others: vec!["XYZZY foo".to_string()],
+        };
+
+        let resp = caps.to_response();
+        assert_eq!(
+            resp,
+            Response::CAPA(
+                vec![
+                    "TOP",
+                    "USER",
+                    "SASL CRAM-MD5 PLAIN",
+                    "RESP-CODES",
+                    "LOGIN-DELAY 900",
+                    "PIPELINING",
+                    "EXPIRE NEVER",
+                    "UIDL",
+                    "IMPLEMENTATION Shlemazle-Plotz-v302",
+                    "XYZZY foo",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect()
+            )
+        );
+
+        assert_eq!(Capabilities::parse(&resp).expect("parse"), caps);
+    }
+
+    #[test]
+    fn handle_capa() {
+        let caps = Capabilities {
+            top: true,
+            user: true,
+            sasl: vec!["PLAIN".to_string()],
+            uidl: true,
+            stls: true,
+            ..Default::default()
+        };
+        let lines = |v: &[&str]| Response::CAPA(v.iter().map(|v| v.to_string()).collect());
+
+        let mut session = Session::new();
+        assert_eq!(
+            caps.handle_capa(&session),
+            lines(&["TOP", "USER", "SASL PLAIN", "UIDL", "STLS"])
+        );
+
+        session.apply(&Request::STLS).expect("apply STLS");
+        session.apply_response(&Response::STLS("Begin TLS negotiation".to_string()));
+        assert_eq!(
+            caps.handle_capa(&session),
+            lines(&["TOP", "USER", "SASL PLAIN", "UIDL"])
+        );
+
+        session
+            .apply(&Request::AUTH(Some("PLAIN".to_string())))
+            .expect("apply AUTH");
+        session.apply_response(&Response::AUTH(AuthResponse::Success(String::new())));
+        assert_eq!(caps.handle_capa(&session), lines(&["TOP", "USER", "UIDL"]));
+
+        // Plaintext auth is disabled.
+        let caps = Capabilities {
+            user: false,
+            ..caps
+        };
+        assert_eq!(
+            caps.handle_capa(&Session::new()),
+            lines(&["TOP", "SASL PLAIN", "UIDL", "STLS"])
+        );
+    }
+
+    #[test]
+    fn advertised() {
+        let caps = Capabilities {
+            stls: true,
+            ..Default::default()
+        };
+
+        let mut session = Session::new();
+        assert!(caps.advertised(&session).stls);
+        session.set_tls_active();
+        assert_eq!(caps.advertised(&session), Capabilities::default());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let resp = Response::CAPA(vec!["LOGIN-DELAY abc".to_string(), "EXPIRE 0".to_string()]);
+
+        let caps = Capabilities::parse(&resp).expect("parse");
+        assert_eq!(caps.login_delay, None);
+        assert_eq!(caps.expire, Some(Expire::Days(0)));
+        assert_eq!(caps.others, vec!["LOGIN-DELAY abc".to_string()]);
+    }
+
+    #[test]
+    fn parse_strict() {
+        let resp = Response::CAPA(vec![
+            "LOGIN-DELAY 900 USER".to_string(),
+            "EXPIRE NEVER".to_string(),
+            "XYZZY foo".to_string(),
+        ]);
+        let caps = Capabilities::parse_strict(&resp).expect("parse");
+        assert_eq!(caps.login_delay, Some(900));
+        assert_eq!(caps.expire, Some(Expire::Never));
+        assert_eq!(caps.others, vec!["XYZZY foo".to_string()]);
+
+        let cases = vec![
+            "LOGIN-DELAY abc",
+            "EXPIRE 1 DAYS",
+            "TOP 1",
+            "IMPLEMENTATION",
+        ];
+        for line in cases {
+            let resp = Response::CAPA(vec![line.to_string()]);
+            let err = Capabilities::parse_strict(&resp).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ProtoError>(),
+                Some(&ProtoError::InvalidCapability(line.to_string())),
+                "{}",
+                line
+            );
+            // Kept as is by the lenient parse.
+            assert!(Capabilities::parse(&resp).is_ok());
+        }
+    }
+}
@@ -0,0 +1,1729 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::debug;
+use md5::{Digest, Md5};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time;
+
+use crate::proto::is_multiline;
+use crate::{
+    apop_digest, message_top, parse_scan_listing, parse_unique_id_listing, sasl, AuthType,
+    Capabilities, ClientError, Command, ErrResponse, ListResponse, MessageMeta, ProtoError,
+    Request, Response, ResponseRef, TimeoutError, UidlResponse,
+};
+
+/// Max requests pipelined before reading their replies, so that neither
+/// side blocks on writing while the other is not reading.
+const PIPELINE_BATCH: usize = 64;
+
+/// Max time to wait for a line of CAPA or AUTH list if `Timeouts::read` is
+/// not set, so that a list never terminated doesn't hang forever.
+const TERMINATOR_WAIT: Duration = Duration::from_secs(30);
+
+/// RetrievalPolicy decides what to do with messages after retrieved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetrievalPolicy {
+    /// Leave all messages on server.
+    LeaveOnServer,
+    /// Delete messages after retrieved, or only those first seen more than
+    /// `days` days ago if set.
+    DeleteAfterRetrieve { days: Option<u32> },
+}
+
+/// RetrFallback is the result of `Client::retr_with_fallback`.
+#[derive(Debug)]
+pub enum RetrFallback {
+    /// The message is retrieved as a whole.
+    Complete(Vec<u8>),
+    /// RETR was interrupted in the middle even after retried.
+    Partial {
+        /// Bytes of the body read by the last attempt.
+        partial: Vec<u8>,
+        /// Error which interrupted the last attempt.
+        error: anyhow::Error,
+        /// Headers of the message fetched by `TOP <id> 0`, `None` if they
+        /// can't be fetched either.
+        headers: Option<Vec<u8>>,
+    },
+}
+
+/// Quirks are commands a server doesn't support or gets wrong, which the
+/// client works around instead of sending them as is. All commands are
+/// assumed to be supported by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Quirks {
+    /// UIDL is not supported, unique-ids are synthesized from the MD5 of
+    /// message contents, which retrieves every message once per session.
+    /// Messages with the same content share the same unique-id.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub no_uidl: bool,
+    /// Pipelining is broken even if advertised in CAPA.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub no_pipelining: bool,
+    /// TOP is not supported, it's served by retrieving the whole message
+    /// and cutting the top lines.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub no_top: bool,
+}
+
+/// SeenStore keeps when messages of a maildrop have been seen at the first
+/// time, keyed by their unique ids.
+pub trait SeenStore {
+    /// Record uid as seen now, the first seen time should be kept if it has
+    /// been seen before.
+    fn mark_seen(&self, uid: &str) -> Result<()>;
+    /// Returns the unix timestamp when uid was seen at the first time.
+    fn first_seen(&self, uid: &str) -> Result<Option<u64>>;
+}
+
+/// Timeouts of network operations of `Client`, operations are not limited
+/// if not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Max time to establish the connection.
+    pub connect: Option<Duration>,
+    /// Max time to wait for a line of response.
+    pub read: Option<Duration>,
+    /// Max time to send a request.
+    pub write: Option<Duration>,
+}
+
+/// Client is a POP3 client which talks with a POP3 server.
+///
+/// Once a network operation failed or timed out, the connection is broken
+/// since the rest of a response may be left unread, all later requests
+/// will fail.
+///
+/// Call `close` to end the session by QUIT. Dropping a client only closes
+/// the connection without QUIT since `Drop` can't be async, so deletions
+/// will never be committed by server.
+#[derive(Debug)]
+pub struct Client<S = TcpStream> {
+    stream: BufReader<S>,
+    greeting: String,
+    /// Max bytes of a buffered response, unlimited if not set.
+    max_response_bytes: Option<usize>,
+    timeouts: Timeouts,
+    quirks: Quirks,
+    /// Unique-ids synthesized for `Quirks::no_uidl`, message numbers and
+    /// contents never change in a session.
+    synthetic_uids: BTreeMap<usize, String>,
+    /// Capabilities replied by the last CAPA, `None` if never queried.
+    capabilities: Option<Box<Capabilities>>,
+    broken: bool,
+}
+
+impl Client<TcpStream> {
+    /// Connect to a POP3 server and read the greeting.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client<TcpStream>> {
+        Client::connect_with_timeouts(addr, Timeouts::default()).await
+    }
+
+    /// Connect to a POP3 server and read the greeting, all network
+    /// operations are limited by `timeouts`.
+    ///
+    /// `TCP_NODELAY` is set as commands and responses are small, use `new`
+    /// with a connected stream to change socket options.
+    pub async fn connect_with_timeouts<A: ToSocketAddrs>(
+        addr: A,
+        timeouts: Timeouts,
+    ) -> Result<Client<TcpStream>> {
+        let stream = with_timeout(timeouts.connect, "connect", async move {
+            TcpStream::connect(addr).await.map_err(ClientError::Io)
+        })
+        .await?;
+        stream.set_nodelay(true)?;
+
+        Client::new_with_timeouts(stream, timeouts).await
+    }
+}
+
+impl<S> Client<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Create a client on an established stream and read the greeting.
+    pub async fn new(stream: S) -> Result<Client<S>> {
+        Client::new_with_timeouts(stream, Timeouts::default()).await
+    }
+
+    /// Create a client on an established stream and read the greeting,
+    /// reads and writes are limited by `timeouts`.
+    pub async fn new_with_timeouts(stream: S, timeouts: Timeouts) -> Result<Client<S>> {
+        let mut client = Client {
+            stream: BufReader::new(stream),
+            greeting: String::new(),
+            max_response_bytes: None,
+            timeouts,
+            quirks: Quirks::default(),
+            synthetic_uids: BTreeMap::new(),
+            capabilities: None,
+            broken: false,
+        };
+
+        let line = client.read_line().await?;
+        match Response::parse_greeting(&line)? {
+            Response::GREET(v) => client.greeting = v.trim().to_string(),
+            Response::ERR(v) => return Err(ClientError::ConnectionRefused(v).into()),
+            v => unreachable!("invalid greeting: {:?}", v),
+        }
+
+        Ok(client)
+    }
+
+    /// Greeting text sent by server, without the leading `+OK`.
+    pub fn greeting(&self) -> &str {
+        &self.greeting
+    }
+
+    /// Limit the bytes of a response read by `send`, reading will be
+    /// aborted with an error once the limit is exceeded.
+    ///
+    /// Use `retr_to` to retrieve messages larger than the limit.
+    pub fn set_max_response_bytes(&mut self, max: Option<usize>) {
+        self.max_response_bytes = max;
+    }
+
+    /// Work around commands the server doesn't support, see `Quirks`.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Whether requests could be pipelined, which is true only if server
+    /// advertised `PIPELINING` in the last `capabilities` and
+    /// `Quirks::no_pipelining` is not set.
+    ///
+    /// It's `false` if `capabilities` has never been called, as sending
+    /// pipelined requests to a server which doesn't support it may break
+    /// the session.
+    pub fn supports_pipelining(&self) -> bool {
+        !self.quirks.no_pipelining && matches!(&self.capabilities, Some(v) if v.pipelining)
+    }
+
+    /// Whether the connection is broken by a failed network operation, a
+    /// broken client should be dropped instead of reused.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Timestamp like `<1896.697170952@dbc.mtview.ca.us>` in the greeting,
+    /// which is required by APOP.
+    pub fn apop_timestamp(&self) -> Option<&str> {
+        let start = self.greeting.find('<')?;
+        let end = start + self.greeting[start..].find('>')?;
+
+        Some(&self.greeting[start..=end])
+    }
+
+    /// Send a request and read the whole response.
+    ///
+    /// Requests are translated by `Quirks` if the server doesn't support
+    /// them.
+    pub async fn send(&mut self, req: &Request) -> Result<Response> {
+        match *req {
+            Request::UIDL(id) if self.quirks.no_uidl => self.synthesize_uidl(id).await,
+            Request::TOP { id, lines } if self.quirks.no_top => {
+                self.synthesize_top(id, lines).await
+            }
+            _ => self.send_raw(req).await,
+        }
+    }
+
+    /// Send a request as is and read the whole response.
+    async fn send_raw(&mut self, req: &Request) -> Result<Response> {
+        let mut content = String::new();
+        let resp = self.send_ref(req, &mut content).await?.to_owned()?;
+
+        debug!("S: {:?}", resp);
+        Ok(resp)
+    }
+
+    /// Serve UIDL by unique-ids synthesized from the MD5 of messages, only
+    /// messages not seen in this session are retrieved.
+    async fn synthesize_uidl(&mut self, id: Option<usize>) -> Result<Response> {
+        let ids = match self.send_raw(&Request::LIST(id)).await? {
+            Response::LIST(ListResponse::All(v)) => v.into_iter().map(|(id, _)| id).collect(),
+            Response::LIST(ListResponse::Single(id, _)) => vec![id],
+            Response::ERR(v) => return Ok(Response::ERR(v)),
+            v => return Err(anyhow::anyhow!("unexpected response for LIST: {:?}", v)),
+        };
+
+        let mut uids = BTreeMap::new();
+        for id in ids {
+            let uid = match self.synthetic_uids.get(&id) {
+                Some(v) => v.clone(),
+                None => match self.retr_bytes(id).await? {
+                    Ok(v) => {
+                        let uid = format!("{:x}", Md5::digest(&v));
+                        self.synthetic_uids.insert(id, uid.clone());
+                        uid
+                    }
+                    // Deleted by another session, skip it like LIST did.
+                    Err(_) => continue,
+                },
+            };
+            uids.insert(id, uid);
+        }
+
+        Ok(match id {
+            None => Response::UIDL(UidlResponse::All(uids)),
+            Some(id) => match uids.remove(&id) {
+                Some(uid) => Response::UIDL(UidlResponse::Single(id, uid)),
+                None => Response::ERR(format!("no such message {}", id)),
+            },
+        })
+    }
+
+    /// Serve TOP by retrieving the whole message and cutting the top lines.
+    async fn synthesize_top(&mut self, id: usize, lines: usize) -> Result<Response> {
+        match self.retr_bytes(id).await? {
+            Ok(v) => {
+                let top = message_top(&v, lines);
+                Ok(Response::TOP(String::from_utf8_lossy(&top).into_owned()))
+            }
+            Err(v) => Ok(Response::ERR(v)),
+        }
+    }
+
+    /// Retrieve message `id` as bytes, which may not be valid UTF-8 like an
+    /// 8-bit MIME body. A `-ERR` response is returned as `Err` with its
+    /// text.
+    async fn retr_bytes(&mut self, id: usize) -> Result<std::result::Result<Vec<u8>, String>> {
+        let mut v = Vec::new();
+        match self.retr_to(id, &mut v).await {
+            Ok(_) => Ok(Ok(v)),
+            Err(err) => match err.downcast::<ErrResponse>() {
+                Ok(err) => Ok(Err(err.text)),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    /// Send a request and read the whole response into `buf` without
+    /// decoding, which is useful to forward responses as is.
+    pub async fn send_ref<'a>(
+        &mut self,
+        req: &'a Request,
+        buf: &'a mut String,
+    ) -> Result<ResponseRef<'a>> {
+        let v = req.to_bytes()?;
+        debug!("C: {:?}", req);
+        self.write_all(&v).await?;
+
+        let list = match req {
+            Request::CAPA | Request::AUTH(None) => Some(req.command()),
+            _ => None,
+        };
+        self.read_response_into(buf, is_multiline(req), list)
+            .await?;
+        ResponseRef::parse(buf, req)
+    }
+
+    /// Send a command line not known by this crate like `XTND XLST`, and
+    /// read the raw response into `buf`.
+    ///
+    /// `line` should not contain the CRLF, a positive response is read
+    /// until the terminating `.` if `multiline`.
+    pub async fn send_line(&mut self, line: &str, multiline: bool, buf: &mut String) -> Result<()> {
+        if line.contains(['\r', '\n']) {
+            return Err(anyhow::anyhow!("invalid command line: {:?}", line));
+        }
+        debug!("C: {:?}", line);
+        self.write_all(format!("{}\r\n", line).as_bytes()).await?;
+
+        self.read_response_into(buf, multiline, None).await
+    }
+
+    /// Retrieve message `id` and write its content into `w` line by line,
+    /// so that a huge message is never buffered as a whole.
+    ///
+    /// Returns the bytes written, `max_response_bytes` only limits the
+    /// length of a single line here.
+    pub async fn retr_to<W>(&mut self, id: usize, w: &mut W) -> Result<usize>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.retr_to_with_progress(id, w, |_, _| {}).await
+    }
+
+    /// Same as `retr_to`, and call `on_progress(bytes, lines)` with the
+    /// total bytes and lines written so far after every line of the body,
+    /// so that a caller could report the progress of a large message.
+    pub async fn retr_to_with_progress<W, F>(
+        &mut self,
+        id: usize,
+        w: &mut W,
+        mut on_progress: F,
+    ) -> Result<usize>
+    where
+        W: AsyncWrite + Unpin,
+        F: FnMut(usize, usize),
+    {
+        let req = Request::RETR(id);
+        debug!("C: {:?}", req);
+        self.write_all(&req.to_bytes()?).await?;
+
+        let mut line = String::new();
+        self.read_line_into(&mut line).await?;
+        if let Response::ERR(v) = Response::from_str(&line, &Request::NOOP)? {
+            return Err(ErrResponse::new("RETR", v).into());
+        }
+
+        let mut line = Vec::new();
+        let mut written = 0;
+        let mut lines = 0;
+        loop {
+            line.clear();
+            self.read_bytes_into(&mut line).await?;
+            if line == b".\r\n" {
+                break;
+            }
+
+            // Remove the byte-stuffed termination octet.
+            let v = if line.starts_with(b"..") {
+                &line[1..]
+            } else {
+                &line[..]
+            };
+            w.write_all(v).await?;
+            written += v.len();
+            lines += 1;
+            on_progress(written, lines);
+        }
+        w.flush().await?;
+
+        Ok(written)
+    }
+
+    /// Retrieve message `id`, and retry once if RETR is interrupted by a
+    /// network error, like a read timeout in the middle of the body.
+    ///
+    /// A broken session can't be reused, so `reconnect` is called to open
+    /// a new logged in session which replaces this one. If the retry is
+    /// interrupted as well, headers of the message are fetched by TOP in
+    /// another session, so that caller could decide whether to retrieve
+    /// it again. POP3 has no way to resume from a byte offset.
+    ///
+    /// `-ERR` responses are returned as errors without retry, and so are
+    /// errors of the reconnection for retry.
+    pub async fn retr_with_fallback<F, Fut>(
+        &mut self,
+        id: usize,
+        mut reconnect: F,
+    ) -> Result<RetrFallback>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Client<S>>>,
+    {
+        let mut partial = Vec::new();
+        let mut error = None;
+        for _ in 0..2 {
+            if error.is_some() {
+                *self = reconnect().await?;
+                partial.clear();
+            }
+
+            match self.retr_to(id, &mut partial).await {
+                Ok(_) => return Ok(RetrFallback::Complete(partial)),
+                Err(err) if !self.is_broken() => return Err(err),
+                Err(err) => error = Some(err),
+            }
+        }
+
+        let headers = match reconnect().await {
+            Ok(client) => {
+                *self = client;
+                match self.send(&Request::TOP { id, lines: 0 }).await {
+                    Ok(Response::TOP(v)) => Some(v.into_bytes()),
+                    _ => None,
+                }
+            }
+            Err(_) => None,
+        };
+
+        Ok(RetrFallback::Partial {
+            partial,
+            error: error.expect("RETR must have failed"),
+            headers,
+        })
+    }
+
+    /// Send LIST and call `f` with every scan listing as it's read, so that
+    /// the listing of a huge maildrop is never buffered as a whole.
+    ///
+    /// Returns the number of messages listed.
+    pub async fn list_each<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(MessageMeta),
+    {
+        let mut n = 0;
+        self.multiline_each(&Request::LIST(None), |line| {
+            f(parse_scan_listing(line, false)?);
+            n += 1;
+            Ok(())
+        })
+        .await?;
+
+        Ok(n)
+    }
+
+    /// Send UIDL and call `f` with every message number and unique-id as
+    /// it's read, like `list_each`.
+    pub async fn uidl_each<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(usize, String),
+    {
+        if self.quirks.no_uidl {
+            return match self.synthesize_uidl(None).await? {
+                Response::UIDL(UidlResponse::All(v)) => {
+                    let n = v.len();
+                    v.into_iter().for_each(|(id, uid)| f(id, uid));
+                    Ok(n)
+                }
+                Response::ERR(v) => Err(ErrResponse::new("LIST", v).into()),
+                v => Err(anyhow::anyhow!("unexpected response for UIDL: {:?}", v)),
+            };
+        }
+
+        let mut n = 0;
+        self.multiline_each(&Request::UIDL(None), |line| {
+            let (id, uid) = parse_unique_id_listing(line)?;
+            f(id, uid);
+            n += 1;
+            Ok(())
+        })
+        .await?;
+
+        Ok(n)
+    }
+
+    /// Send a request whose positive response is multi-line, and call `f`
+    /// with every dot-unstuffed line without line ending.
+    async fn multiline_each<F>(&mut self, req: &Request, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        debug!("C: {:?}", req);
+        self.write_all(&req.to_bytes()?).await?;
+
+        let mut line = String::new();
+        self.read_line_into(&mut line).await?;
+        if let Response::ERR(v) = Response::from_str(&line, &Request::NOOP)? {
+            return Err(ErrResponse::new(req.command().to_string(), v).into());
+        }
+
+        loop {
+            line.clear();
+            self.read_line_into(&mut line).await?;
+            let v = line
+                .strip_suffix("\r\n")
+                .or_else(|| line.strip_suffix('\n'))
+                .unwrap_or(&line);
+            if v == "." {
+                return Ok(());
+            }
+
+            f(v.strip_prefix('.').unwrap_or(v))?;
+        }
+    }
+
+    /// Mark messages `ids` as deleted, returns the result of every id.
+    ///
+    /// DELEs are pipelined if `supports_pipelining`, or sent one by one
+    /// otherwise. A `-ERR` reply only fails its own id, while a network
+    /// error fails the whole call.
+    pub async fn dele_range(
+        &mut self,
+        ids: impl IntoIterator<Item = usize>,
+    ) -> Result<BTreeMap<usize, std::result::Result<(), ErrResponse>>> {
+        let ids: Vec<usize> = ids.into_iter().collect();
+        let batch = if self.supports_pipelining() {
+            PIPELINE_BATCH
+        } else {
+            1
+        };
+
+        let mut results = BTreeMap::new();
+        for ids in ids.chunks(batch) {
+            let mut v = Vec::new();
+            for id in ids {
+                let req = Request::DELE(*id);
+                debug!("C: {:?}", req);
+                v.extend(req.to_bytes()?);
+            }
+            self.write_all(&v).await?;
+
+            for id in ids {
+                let line = self.read_line().await?;
+                let res = match Response::from_str(&line, &Request::DELE(*id))? {
+                    Response::ERR(v) => Err(ErrResponse::new(format!("DELE {}", id), v)),
+                    _ => Ok(()),
+                };
+                results.insert(*id, res);
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Send QUIT and read the reply, server commits deletions and closes
+    /// the connection.
+    pub async fn close(mut self) -> Result<()> {
+        match self.send(&Request::QUIT).await? {
+            Response::ERR(v) => Err(ErrResponse::new("QUIT", v).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Query capabilities by CAPA.
+    ///
+    /// Returns empty capabilities if server replies `-ERR` since CAPA is
+    /// not implemented by every server, caller could fall back to probing.
+    ///
+    /// The result is kept for `supports_pipelining`, call it again after
+    /// login as server may advertise different capabilities.
+    pub async fn capabilities(&mut self) -> Result<Capabilities> {
+        let caps = match self.send(&Request::CAPA).await? {
+            Response::ERR(_) => Capabilities::default(),
+            v => Capabilities::parse(&v)?,
+        };
+        self.capabilities = Some(Box::new(caps.clone()));
+
+        Ok(caps)
+    }
+
+    /// Query count and total size of messages by STAT.
+    pub async fn stat(&mut self) -> Result<(usize, usize)> {
+        match self.send(&Request::STAT).await? {
+            Response::STAT { count, size } => Ok((count, size)),
+            Response::ERR(v) => Err(ErrResponse::new("STAT", v).into()),
+            v => Err(anyhow::anyhow!("unexpected response for STAT: {:?}", v)),
+        }
+    }
+
+    /// Send NOOP and measure the round-trip until its `+OK` is read.
+    ///
+    /// It's cheap enough to probe pooled connections frequently, the
+    /// duration never includes connection setup.
+    pub async fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        match self.send(&Request::NOOP).await? {
+            Response::NOOP => Ok(start.elapsed()),
+            Response::ERR(v) => Err(ErrResponse::new("NOOP", v).into()),
+            v => Err(anyhow::anyhow!("unexpected response for NOOP: {:?}", v)),
+        }
+    }
+
+    /// Send a line of SASL response after server returns a challenge.
+    ///
+    /// `v` should have been encoded by base64 already, `*` cancels the
+    /// authentication exchange.
+    pub async fn auth_continue(&mut self, v: &str) -> Result<Response> {
+        self.write_all(format!("{}\r\n", v).as_bytes()).await?;
+
+        let content = self.read_line().await?;
+        Response::from_str(&content, &Request::AUTH(Some(String::new())))
+    }
+
+    /// Authenticate by an arbitrary SASL `mechanism`.
+    ///
+    /// `step` is called with every challenge decoded from base64, and
+    /// returns the raw response to send. Returns `ErrResponse` if server
+    /// rejects the exchange, or an error after cancelling it by `*` if a
+    /// challenge isn't valid base64.
+    pub async fn sasl_exchange(
+        &mut self,
+        mechanism: &str,
+        mut step: impl FnMut(&[u8]) -> Vec<u8>,
+    ) -> Result<()> {
+        sasl::exchange(self, mechanism, |v| Ok(step(v))).await
+    }
+
+    /// Login with APOP by the timestamp in greeting.
+    pub async fn apop(&mut self, username: &str, secret: &str) -> Result<()> {
+        let timestamp = self
+            .apop_timestamp()
+            .ok_or_else(|| anyhow::anyhow!("APOP is not supported: no timestamp in greeting"))?;
+        let req = Request::APOP {
+            username: username.to_string(),
+            digest: apop_digest(timestamp, secret),
+        };
+
+        match self.send(&req).await? {
+            Response::ERR(v) => Err(ErrResponse::new("APOP", v).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Login with an OAuth 2.0 access token by the XOAUTH2 mechanism.
+    pub async fn auth_xoauth2(&mut self, user: &str, access_token: &str) -> Result<()> {
+        sasl::auth_xoauth2(self, user, access_token).await
+    }
+
+    /// Login with given auth type.
+    pub async fn login(
+        &mut self,
+        auth_type: AuthType,
+        username: &str,
+        password: &str,
+    ) -> Result<()> {
+        match auth_type {
+            AuthType::UserPass => {
+                if let Response::ERR(v) = self.send(&Request::USER(username.to_string())).await? {
+                    return Err(ErrResponse::new("USER", v).into());
+                }
+                if let Response::ERR(v) = self.send(&Request::PASS(password.to_string())).await? {
+                    return Err(ErrResponse::new("PASS", v).into());
+                }
+
+                Ok(())
+            }
+            AuthType::Apop => self.apop(username, password).await,
+            AuthType::SaslPlain => sasl::auth_plain(self, username, password).await,
+            AuthType::SaslLogin => sasl::auth_login(self, username, password).await,
+            AuthType::SaslCramMd5 => sasl::auth_cram_md5(self, username, password).await,
+        }
+    }
+
+    /// Retrieve all messages in the maildrop, and mark them as deleted as
+    /// `policy` says.
+    ///
+    /// `seen` is required if the policy has a retention window, messages
+    /// are recorded in it by UIDL, so that a message seen in the window
+    /// will never be deleted.
+    ///
+    /// Returns the content of messages keyed by message number, a message
+    /// which can't be retrieved or deleted has its own error. Deletions
+    /// only take effect after QUIT.
+    pub async fn fetch_all(
+        &mut self,
+        policy: RetrievalPolicy,
+        seen: Option<&dyn SeenStore>,
+    ) -> Result<Vec<(usize, Result<Vec<u8>>)>> {
+        let window = match policy {
+            RetrievalPolicy::DeleteAfterRetrieve { days: Some(days) } => {
+                let seen =
+                    seen.ok_or_else(|| anyhow::anyhow!("retention window requires a seen store"))?;
+                Some((u64::from(days) * 24 * 60 * 60, seen))
+            }
+            _ => None,
+        };
+
+        let ids: Vec<usize> = match self.send(&Request::LIST(None)).await? {
+            Response::LIST(ListResponse::All(v)) => v.into_iter().map(|(id, _)| id).collect(),
+            Response::ERR(v) => return Err(ErrResponse::new("LIST", v).into()),
+            v => return Err(anyhow::anyhow!("unexpected response for LIST: {:?}", v)),
+        };
+        let uids = match window {
+            None => BTreeMap::new(),
+            Some(_) => match self.send(&Request::UIDL(None)).await? {
+                Response::UIDL(UidlResponse::All(v)) => v,
+                Response::ERR(v) => return Err(ErrResponse::new("UIDL", v).into()),
+                v => return Err(anyhow::anyhow!("unexpected response for UIDL: {:?}", v)),
+            },
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut messages = Vec::new();
+        for id in ids {
+            let content = match self.retr_bytes(id).await? {
+                Ok(v) => v,
+                Err(v) => {
+                    messages.push((id, Err(anyhow::anyhow!("RETR {} failed: {}", id, v))));
+                    continue;
+                }
+            };
+
+            let delete = match (policy, window) {
+                (RetrievalPolicy::LeaveOnServer, _) => false,
+                (_, None) => true,
+                (_, Some((window, seen))) => match uids.get(&id) {
+                    // Message without uid can't be tracked, keep it.
+                    None => false,
+                    Some(uid) => match seen.mark_seen(uid).and_then(|_| seen.first_seen(uid)) {
+                        Ok(first) => now.saturating_sub(first.unwrap_or(now)) > window,
+                        Err(err) => {
+                            messages.push((id, Err(anyhow::anyhow!("seen {}: {}", uid, err))));
+                            continue;
+                        }
+                    },
+                },
+            };
+            if delete {
+                if let Response::ERR(v) = self.send(&Request::DELE(id)).await? {
+                    messages.push((id, Err(anyhow::anyhow!("DELE {} failed: {}", id, v))));
+                    continue;
+                }
+            }
+
+            messages.push((id, Ok(content)));
+        }
+
+        Ok(messages)
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        self.read_line_into(&mut line).await?;
+
+        Ok(line)
+    }
+
+    /// Read a response into `buf`, a positive response is read until the
+    /// terminating `.` if `multiline`.
+    ///
+    /// `list` is the command of a CAPA or AUTH list, whose lines are waited
+    /// for at most `Timeouts::read` or `TERMINATOR_WAIT` if not set.
+    async fn read_response_into(
+        &mut self,
+        buf: &mut String,
+        multiline: bool,
+        list: Option<Command>,
+    ) -> Result<()> {
+        buf.clear();
+        self.read_line_into(buf).await?;
+        if buf.starts_with("+OK") && multiline {
+            loop {
+                let start = buf.len();
+                match list {
+                    Some(cmd) => {
+                        let wait = self.timeouts.read.unwrap_or(TERMINATOR_WAIT);
+                        match self.read_line_within(buf, Some(wait)).await {
+                            Err(err) if err.is::<TimeoutError>() => {
+                                return Err(ProtoError::MissingTerminator(cmd).into())
+                            }
+                            v => v?,
+                        }
+                    }
+                    None => self.read_line_into(buf).await?,
+                }
+                if &buf[start..] == ".\r\n" {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_all(&mut self, v: &[u8]) -> Result<()> {
+        self.check_broken()?;
+
+        let stream = &mut self.stream;
+        let res = with_timeout(self.timeouts.write, "write", async move {
+            stream.write_all(v).await.map_err(ClientError::Io)
+        })
+        .await;
+        self.broken = res.is_err();
+        res
+    }
+
+    /// Read a line and append it to `buf`, `buf` is not allowed to exceed
+    /// `max_response_bytes`.
+    async fn read_line_into(&mut self, buf: &mut String) -> Result<()> {
+        self.read_line_within(buf, self.timeouts.read).await
+    }
+
+    /// Same as `read_line_into` but limited by `timeout` instead of
+    /// `Timeouts::read`.
+    async fn read_line_within(
+        &mut self,
+        buf: &mut String,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.check_broken()?;
+
+        let max = self.max_response_bytes;
+        let stream = &mut self.stream;
+        let res = with_timeout(timeout, "read", async move {
+            let n = match max {
+                Some(max) => {
+                    // Read one more byte to tell whether the limit is exceeded.
+                    let limit = max.saturating_sub(buf.len()) as u64 + 1;
+                    stream.take(limit).read_line(buf).await
+                }
+                None => stream.read_line(buf).await,
+            }
+            .map_err(ClientError::Io)?;
+            if n == 0 {
+                return Err(ClientError::ConnectionClosed.into());
+            }
+            check_response_bytes(max, buf.len())
+        })
+        .await;
+        self.broken = res.is_err();
+        res
+    }
+
+    /// Read a line of bytes and append it to `buf`, `buf` is not allowed to
+    /// exceed `max_response_bytes`.
+    async fn read_bytes_into(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        self.check_broken()?;
+
+        let max = self.max_response_bytes;
+        let stream = &mut self.stream;
+        let res = with_timeout(self.timeouts.read, "read", async move {
+            let n = match max {
+                Some(max) => {
+                    let limit = max.saturating_sub(buf.len()) as u64 + 1;
+                    stream.take(limit).read_until(b'\n', buf).await
+                }
+                None => stream.read_until(b'\n', buf).await,
+            }
+            .map_err(ClientError::Io)?;
+            if n == 0 {
+                return Err(ClientError::ConnectionClosed.into());
+            }
+            check_response_bytes(max, buf.len())
+        })
+        .await;
+        self.broken = res.is_err();
+        res
+    }
+
+    fn check_broken(&self) -> Result<()> {
+        if self.broken {
+            return Err(anyhow::anyhow!("connection is broken"));
+        }
+        Ok(())
+    }
+}
+
+fn check_response_bytes(max: Option<usize>, n: usize) -> Result<()> {
+    match max {
+        Some(max) if n > max => Err(anyhow::anyhow!(
+            "response exceeds max_response_bytes {}",
+            max
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Run a network operation in `timeout`, `TimeoutError` of `op` will be
+/// returned if timed out.
+async fn with_timeout<T, E>(
+    timeout: Option<Duration>,
+    op: &'static str,
+    f: impl Future<Output = std::result::Result<T, E>>,
+) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match timeout {
+        None => f.await.map_err(Into::into),
+        Some(after) => match time::timeout(after, f).await {
+            Ok(v) => v.map_err(Into::into),
+            Err(_) => Err(TimeoutError { op, after }.into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockServer;
+    use std::collections::HashMap;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    #[tokio::test]
+    async fn login_sasl_plain() -> Result<()> {
+        let (client, mut server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            let n = server.read(&mut buf).await?;
+            assert_eq!(&buf[..n], b"AUTH PLAIN\r\n");
+            server.write_all(b"+ \r\n").await?;
+            let n = server.read(&mut buf).await?;
+            assert_eq!(&buf[..n], b"AHRpbQB0YW5zdGFhZnRhbnN0YWFm\r\n");
+            server
+                .write_all(b"+OK maildrop locked and ready\r\n")
+                .await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        assert_eq!(client.greeting(), "POP3 server ready");
+        client
+            .login(AuthType::SaslPlain, "tim", "tanstaaftanstaaf")
+            .await?;
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn login_apop() -> Result<()> {
+        let mut server = MockServer::new();
+        server
+            .greeting(Response::GREET(
+                "POP3 server ready <1896.697170952@dbc.mtview.ca.us>".to_string(),
+            ))
+            .expect(Request::APOP {
+                username: "mrose".to_string(),
+                digest: "c4c9334bac560ecc979e58001b3e22fb".to_string(),
+            })
+            .respond(Response::APOP);
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        assert!(client.stream.get_ref().nodelay()?);
+        assert_eq!(
+            client.apop_timestamp(),
+            Some("<1896.697170952@dbc.mtview.ca.us>")
+        );
+        client.login(AuthType::Apop, "mrose", "tanstaaf").await?;
+        drop(client);
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn close() -> Result<()> {
+        let mut server = MockServer::new();
+        server.expect(Request::DELE(1)).respond(Response::DELE);
+        server.expect(Request::QUIT).respond(Response::QUIT(None));
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        client.send(&Request::DELE(1)).await?;
+        client.close().await?;
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn apop_without_timestamp() -> Result<()> {
+        let (client, mut server) = duplex(1024);
+        server.write_all(b"+OK POP3 server ready\r\n").await?;
+
+        let mut client = Client::new(client).await?;
+        assert_eq!(client.apop_timestamp(), None);
+        assert!(client.apop("mrose", "tanstaaf").await.is_err());
+
+        // Nothing has been sent.
+        drop(client);
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await?;
+        assert!(buf.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_response_bytes() -> Result<()> {
+        let (client, mut server) = duplex(64 * 1024);
+
+        // The server task is left blocked on writing after the client gives
+        // up, it's dropped with the runtime.
+        tokio::spawn(async move {
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            let mut buf = vec![0; 1024];
+            let n = server.read(&mut buf).await?;
+            assert_eq!(&buf[..n], b"RETR 1\r\n");
+
+            // 1 MB without the terminator.
+            server.write_all(b"+OK\r\n").await?;
+            let line = [b'a'; 1023];
+            for _ in 0..1024 {
+                server.write_all(&line).await?;
+                server.write_all(b"\n").await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        client.set_max_response_bytes(Some(64 * 1024));
+        let err = client.send(&Request::RETR(1)).await.unwrap_err();
+        assert_eq!(err.to_string(), "response exceeds max_response_bytes 65536");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timeout() -> Result<()> {
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+
+        // Server accepts but never greets.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let srv = tokio::spawn(async move { listener.accept().await });
+        let err = Client::connect_with_timeouts(addr, timeouts)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TimeoutError>(),
+            Some(&TimeoutError {
+                op: "read",
+                after: Duration::from_millis(100)
+            })
+        );
+        drop(srv.await??);
+
+        // Server greets but never replies.
+        let (client, mut server) = duplex(1024);
+        server.write_all(b"+OK POP3 server ready\r\n").await?;
+        let mut client = Client::new_with_timeouts(client, timeouts).await?;
+        let err = client.send(&Request::NOOP).await.unwrap_err();
+        assert!(err.is::<TimeoutError>());
+        assert!(client.is_broken());
+
+        // A late response must not be taken as the response of next request.
+        server.write_all(b"+OK\r\n").await?;
+        assert!(client.send(&Request::NOOP).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_terminator() -> Result<()> {
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+
+        for (req, resp) in [
+            (Request::CAPA, "+OK\r\nTOP\r\nUIDL\r\n"),
+            (Request::AUTH(None), "+OK\r\nPLAIN\r\n"),
+        ] {
+            let (client, mut server) = duplex(1024);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            server.write_all(resp.as_bytes()).await?;
+
+            let mut client = Client::new_with_timeouts(client, timeouts).await?;
+            let err = time::timeout(Duration::from_secs(5), client.send(&req))
+                .await?
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ProtoError>(),
+                Some(&ProtoError::MissingTerminator(req.command()))
+            );
+            assert!(client.is_broken());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retr_to() -> Result<()> {
+        let (client, mut server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            let mut buf = vec![0; 1024];
+            let n = server.read(&mut buf).await?;
+            assert_eq!(&buf[..n], b"RETR 1\r\n");
+            server
+                .write_all(b"+OK\r\nSubject: a\r\n\r\n..hello\r\n.\r\n")
+                .await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        // Limits lines only.
+        client.set_max_response_bytes(Some(16));
+        let mut content = Vec::new();
+        assert_eq!(client.retr_to(1, &mut content).await?, 22);
+        assert_eq!(content, b"Subject: a\r\n\r\n.hello\r\n");
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn retr_progress() -> Result<()> {
+        let (client, mut server) = duplex(1024);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let srv = tokio::spawn(async move {
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            let mut buf = vec![0; 1024];
+            let n = server.read(&mut buf).await?;
+            assert_eq!(&buf[..n], b"RETR 1\r\n");
+            server.write_all(b"+OK\r\nSubject: a\r\n\r\n").await?;
+            // Progress is reported before the body is complete.
+            assert_eq!(rx.recv().await, Some((12, 1)));
+            assert_eq!(rx.recv().await, Some((14, 2)));
+            server.write_all(b"hello\r\n.\r\n").await?;
+            assert_eq!(rx.recv().await, Some((21, 3)));
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        let n = client
+            .retr_to_with_progress(1, &mut tokio::io::sink(), |bytes, lines| {
+                let _ = tx.send((bytes, lines));
+            })
+            .await?;
+        assert_eq!(n, 21);
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn list_each() -> Result<()> {
+        let mut server = MockServer::new();
+        server
+            .expect(Request::LIST(None))
+            .respond(Response::LIST(ListResponse::All(
+                (1..=10_000).map(|i| (i, i * 10)).collect(),
+            )));
+        let mut uids = BTreeMap::new();
+        uids.insert(1, ".a".to_string());
+        server
+            .expect(Request::UIDL(None))
+            .respond(Response::UIDL(UidlResponse::All(uids)));
+        server
+            .expect(Request::LIST(None))
+            .respond(Response::ERR("maildrop locked".to_string()));
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        // Limits lines only.
+        client.set_max_response_bytes(Some(32));
+        let mut size = 0;
+        assert_eq!(client.list_each(|meta| size += meta.size).await?, 10_000);
+        assert_eq!(size, (1..=10_000).map(|i| i * 10).sum::<usize>());
+
+        let mut uids = Vec::new();
+        assert_eq!(client.uidl_each(|id, uid| uids.push((id, uid))).await?, 1);
+        assert_eq!(uids, vec![(1, ".a".to_string())]);
+
+        let err = client.list_each(|_| {}).await.unwrap_err();
+        assert_eq!(err.to_string(), "LIST failed: maildrop locked");
+        drop(client);
+
+        handle.verify().await
+    }
+
+    /// Serve a session by replies in order, and close the connection once
+    /// they are sent.
+    fn serve(replies: Vec<(&'static str, &'static [u8])>) -> tokio::io::DuplexStream {
+        let (client, server) = duplex(1024);
+        tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            for (req, resp) in replies {
+                let mut line = String::new();
+                server.read_line(&mut line).await?;
+                assert_eq!(line, req);
+                server.write_all(resp).await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        client
+    }
+
+    #[tokio::test]
+    async fn retr_with_fallback() -> Result<()> {
+        let mut sessions = vec![
+            serve(vec![("TOP 1 0\r\n", b"+OK\r\nSubject: a\r\n\r\n.\r\n")]),
+            serve(vec![("RETR 1\r\n", b"+OK\r\nSubject: a\r\n\r\nb")]),
+            serve(vec![("RETR 1\r\n", b"+OK\r\nSubject: a\r\n")]),
+        ];
+        let mut reconnect = || {
+            let stream = sessions.pop().expect("no more sessions");
+            Client::new(stream)
+        };
+
+        let mut client = reconnect().await?;
+        match client.retr_with_fallback(1, &mut reconnect).await? {
+            RetrFallback::Partial {
+                partial,
+                error,
+                headers,
+            } => {
+                assert_eq!(partial, b"Subject: a\r\n\r\nb");
+                assert_eq!(error.to_string(), "connection closed by server");
+                assert_eq!(headers.as_deref(), Some(&b"Subject: a\r\n\r\n"[..]));
+            }
+            v => panic!("unexpected result: {:?}", v),
+        }
+        assert!(!client.is_broken());
+
+        let mut sessions = vec![
+            serve(vec![("RETR 1\r\n", b"+OK\r\nSubject: a\r\n.\r\n")]),
+            serve(vec![("RETR 1\r\n", b"+OK\r\nSubject")]),
+        ];
+        let mut reconnect = || {
+            let stream = sessions.pop().expect("no more sessions");
+            Client::new(stream)
+        };
+
+        let mut client = reconnect().await?;
+        match client.retr_with_fallback(1, &mut reconnect).await? {
+            RetrFallback::Complete(v) => assert_eq!(v, b"Subject: a\r\n"),
+            v => panic!("unexpected result: {:?}", v),
+        }
+
+        let mut client =
+            Client::new(serve(vec![("RETR 2\r\n", b"-ERR no such message\r\n")])).await?;
+        let err = client
+            .retr_with_fallback(2, || async { unreachable!() })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "RETR failed: no such message");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_closed() -> Result<()> {
+        let mut client = Client::new(serve(vec![("RETR 1\r\n", b"+OK\r\nSubject: a\r\n")])).await?;
+        let err = client.send(&Request::RETR(1)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::ConnectionClosed)
+        ));
+        assert!(client.is_broken());
+
+        // Malformed replies are not taken as closed connections.
+        let mut client = Client::new(serve(vec![("NOOP\r\n", b"+Hello\r\n")])).await?;
+        let err = client.send(&Request::NOOP).await.unwrap_err();
+        assert!(err.downcast_ref::<ClientError>().is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dele_range() -> Result<()> {
+        let (client, server) = duplex(1024);
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+
+            let mut line = String::new();
+            server.read_line(&mut line).await?;
+            assert_eq!(line, "CAPA\r\n");
+            server.write_all(b"+OK\r\nPIPELINING\r\n.\r\n").await?;
+
+            // All requests are read before any reply is sent.
+            for id in 1..=5 {
+                line.clear();
+                server.read_line(&mut line).await?;
+                assert_eq!(line, format!("DELE {}\r\n", id));
+            }
+            for id in 1..=5 {
+                let reply = match id {
+                    3 => "-ERR message 3 already deleted\r\n".to_string(),
+                    id => format!("+OK message {} deleted\r\n", id),
+                };
+                server.write_all(reply.as_bytes()).await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        client.capabilities().await?;
+        let results = time::timeout(Duration::from_secs(5), client.dele_range(1..=5)).await??;
+        assert_eq!(results.len(), 5);
+        for (id, res) in results {
+            match id {
+                3 => assert_eq!(
+                    res.unwrap_err().to_string(),
+                    "DELE 3 failed: message 3 already deleted"
+                ),
+                _ => assert!(res.is_ok()),
+            }
+        }
+        srv.await??;
+
+        // Sent one by one if CAPA has never been queried.
+        let mut server = MockServer::new();
+        for id in 1..=2 {
+            server.expect(Request::DELE(id)).respond(Response::DELE);
+        }
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        let results = client.dele_range(vec![1, 2]).await?;
+        assert!(results.values().all(|v| v.is_ok()));
+        assert_eq!(results.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+        drop(client);
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn quirks() -> Result<()> {
+        let messages = ["Subject: a\r\n\r\nhello\r\n", "Subject: b\r\n\r\nworld\r\n"];
+        let md5 = |v: &str| format!("{:x}", Md5::digest(v.as_bytes()));
+
+        let mut server = MockServer::new();
+        // UIDL is synthesized from contents, which are retrieved once.
+        server
+            .expect(Request::LIST(None))
+            .respond(Response::LIST(ListResponse::All(vec![(1, 22), (2, 22)])));
+        for (i, v) in messages.iter().enumerate() {
+            server
+                .expect(Request::RETR(i + 1))
+                .respond(Response::RETR(v.to_string()));
+        }
+        server
+            .expect(Request::LIST(Some(2)))
+            .respond(Response::LIST(ListResponse::Single(2, 22)));
+        // TOP is served by RETR.
+        server
+            .expect(Request::RETR(1))
+            .respond(Response::RETR(messages[0].to_string()));
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        client.set_quirks(Quirks {
+            no_uidl: true,
+            no_top: true,
+            ..Quirks::default()
+        });
+        let expect: BTreeMap<usize, String> = vec![(1, md5(messages[0])), (2, md5(messages[1]))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            client.send(&Request::UIDL(None)).await?,
+            Response::UIDL(UidlResponse::All(expect))
+        );
+        assert_eq!(
+            client.send(&Request::UIDL(Some(2))).await?,
+            Response::UIDL(UidlResponse::Single(2, md5(messages[1])))
+        );
+        assert_eq!(
+            client.send(&Request::TOP { id: 1, lines: 0 }).await?,
+            Response::TOP("Subject: a\r\n\r\n".to_string())
+        );
+        drop(client);
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn quirks_8bit() -> Result<()> {
+        let message = b"Subject: a\r\n\r\n\xe4\xff\r\n";
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+
+            let retr = [&b"+OK\r\n"[..], message, b".\r\n"].concat();
+            let replies: &[(&str, &[u8])] = &[
+                ("LIST 1\r\n", b"+OK 1 19\r\n"),
+                ("RETR 1\r\n", &retr),
+                ("RETR 1\r\n", &retr),
+            ];
+            for (req, resp) in replies {
+                let mut line = String::new();
+                server.read_line(&mut line).await?;
+                assert_eq!(&line, req);
+                server.write_all(resp).await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        client.set_quirks(Quirks {
+            no_uidl: true,
+            no_top: true,
+            ..Quirks::default()
+        });
+        assert_eq!(
+            client.send(&Request::UIDL(Some(1))).await?,
+            Response::UIDL(UidlResponse::Single(
+                1,
+                format!("{:x}", Md5::digest(message))
+            ))
+        );
+        assert_eq!(
+            client.send(&Request::TOP { id: 1, lines: 0 }).await?,
+            Response::TOP("Subject: a\r\n\r\n".to_string())
+        );
+        assert!(!client.is_broken());
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn connection_refused() -> Result<()> {
+        let mut server = MockServer::new();
+        server.greeting(Response::ERR("server busy, try later".to_string()));
+        let handle = server.start().await?;
+
+        let err = Client::connect(handle.addr()).await.unwrap_err();
+        match err.downcast_ref::<ClientError>() {
+            Some(ClientError::ConnectionRefused(v)) => assert_eq!(v, "server busy, try later"),
+            v => panic!("unexpected error: {:?}", v),
+        }
+        assert_eq!(
+            err.to_string(),
+            "connection refused: server busy, try later"
+        );
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn sasl_exchange() -> Result<()> {
+        // A mechanism which answers every challenge in uppercase.
+        let upper = |v: &[u8]| v.to_ascii_uppercase();
+
+        let mut client = Client::new(serve(vec![
+            ("AUTH X-UPPER\r\n", b"+ b25l\r\n"),
+            ("T05F\r\n", b"+ dHdv\r\n"),
+            ("VFdP\r\n", b"+OK\r\n"),
+        ]))
+        .await?;
+        let mut challenges = Vec::new();
+        client
+            .sasl_exchange("X-UPPER", |v| {
+                challenges.push(v.to_vec());
+                upper(v)
+            })
+            .await?;
+        assert_eq!(challenges, vec![b"one".to_vec(), b"two".to_vec()]);
+
+        let mut client = Client::new(serve(vec![
+            ("AUTH X-UPPER\r\n", b"+ b25l\r\n"),
+            ("T05F\r\n", b"-ERR [AUTH] invalid credentials\r\n"),
+        ]))
+        .await?;
+        let err = client.sasl_exchange("X-UPPER", upper).await.unwrap_err();
+        let err = err.downcast_ref::<ErrResponse>().expect("ErrResponse");
+        assert_eq!(err.command, "AUTH X-UPPER");
+
+        // Undecodable challenge cancels the exchange.
+        let mut client = Client::new(serve(vec![
+            ("AUTH X-UPPER\r\n", b"+ not base64!\r\n"),
+            ("*\r\n", b"-ERR authentication cancelled\r\n"),
+        ]))
+        .await?;
+        let err = client.sasl_exchange("X-UPPER", upper).await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(ProtoError::InvalidBase64(_))),
+            "{}",
+            err
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping() -> Result<()> {
+        let mut server = MockServer::new();
+        server.expect(Request::NOOP).respond(Response::NOOP);
+        server.expect(Request::STAT).respond(Response::STAT {
+            count: 2,
+            size: 320,
+        });
+        server
+            .expect(Request::NOOP)
+            .respond(Response::ERR("server busy".to_string()));
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        let rtt = client.ping().await?;
+        assert!(rtt < Duration::from_secs(5), "{:?}", rtt);
+        assert_eq!(client.stat().await?, (2, 320));
+
+        let err = client.ping().await.unwrap_err();
+        assert_eq!(err.to_string(), "NOOP failed: server busy");
+        drop(client);
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn supports_pipelining() -> Result<()> {
+        let (client, server) = duplex(1024);
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+
+            let mut line = String::new();
+            server.read_line(&mut line).await?;
+            assert_eq!(line, "CAPA\r\n");
+            server.write_all(b"+OK\r\nTOP\r\nUIDL\r\n.\r\n").await?;
+
+            // Each request is sent after the reply of the previous one.
+            for id in 1..=3 {
+                line.clear();
+                server.read_line(&mut line).await?;
+                assert_eq!(line, format!("DELE {}\r\n", id));
+                assert!(server.buffer().is_empty());
+                server.write_all(b"+OK\r\n").await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        assert!(!client.supports_pipelining());
+        assert!(!client.capabilities().await?.pipelining);
+        assert!(!client.supports_pipelining());
+        let results = time::timeout(Duration::from_secs(5), client.dele_range(1..=3)).await??;
+        assert!(results.values().all(|v| v.is_ok()));
+        srv.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn capabilities() -> Result<()> {
+        let mut server = MockServer::new();
+        server.expect(Request::CAPA).respond(Response::CAPA(vec![
+            "TOP".to_string(),
+            "UIDL".to_string(),
+            "SASL PLAIN CRAM-MD5".to_string(),
+        ]));
+        server
+            .expect(Request::CAPA)
+            .respond(Response::ERR("unknown command".to_string()));
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        let caps = client.capabilities().await?;
+        assert!(caps.top);
+        assert!(caps.uidl);
+        assert!(!caps.user);
+        assert_eq!(caps.sasl, vec!["PLAIN", "CRAM-MD5"]);
+
+        assert_eq!(client.capabilities().await?, Capabilities::default());
+        drop(client);
+
+        handle.verify().await
+    }
+
+    #[tokio::test]
+    async fn fetch_all() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+
+            let replies: &[(&str, &[u8])] = &[
+                (
+                    "LIST\r\n",
+                    b"+OK 3 messages\r\n1 10\r\n2 10\r\n3 4\r\n.\r\n",
+                ),
+                ("RETR 1\r\n", b"+OK\r\nSubject: a\r\n.\r\n"),
+                ("DELE 1\r\n", b"+OK\r\n"),
+                ("RETR 2\r\n", b"-ERR no such message\r\n"),
+                ("RETR 3\r\n", b"+OK\r\n\xe4\xff\r\n.\r\n"),
+                ("DELE 3\r\n", b"+OK\r\n"),
+            ];
+            for (req, resp) in replies {
+                let mut line = String::new();
+                server.read_line(&mut line).await?;
+                assert_eq!(&line, req);
+                server.write_all(resp).await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        let policy = RetrievalPolicy::DeleteAfterRetrieve { days: None };
+        let messages = client.fetch_all(policy, None).await?;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].0, 1);
+        assert_eq!(messages[0].1.as_ref().unwrap(), b"Subject: a\r\n");
+        assert_eq!(messages[1].0, 2);
+        assert!(messages[1].1.is_err());
+        // 8-bit content is kept as is.
+        assert_eq!(messages[2].0, 3);
+        assert_eq!(messages[2].1.as_ref().unwrap(), b"\xe4\xff\r\n");
+
+        srv.await?
+    }
+
+    /// MemorySeen returns the given first seen time of uids, and fails to
+    /// mark uid `broken` as seen.
+    struct MemorySeen(HashMap<&'static str, u64>);
+
+    impl SeenStore for MemorySeen {
+        fn mark_seen(&self, uid: &str) -> Result<()> {
+            match uid {
+                "broken" => Err(anyhow::anyhow!("store is broken")),
+                _ => Ok(()),
+            }
+        }
+
+        fn first_seen(&self, uid: &str) -> Result<Option<u64>> {
+            Ok(self.0.get(uid).copied())
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_all_retention() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+
+            let replies: &[(&str, &[u8])] = &[
+                (
+                    "LIST\r\n",
+                    b"+OK 4 messages\r\n1 10\r\n2 10\r\n3 10\r\n4 10\r\n.\r\n",
+                ),
+                (
+                    "UIDL\r\n",
+                    b"+OK\r\n1 broken\r\n2 old\r\n3 today\r\n4 new\r\n.\r\n",
+                ),
+                ("RETR 1\r\n", b"+OK\r\nx\r\n.\r\n"),
+                ("RETR 2\r\n", b"+OK\r\na\r\n.\r\n"),
+                ("DELE 2\r\n", b"+OK\r\n"),
+                ("RETR 3\r\n", b"+OK\r\nb\r\n.\r\n"),
+                ("RETR 4\r\n", b"+OK\r\nc\r\n.\r\n"),
+            ];
+            for (req, resp) in replies {
+                let mut line = String::new();
+                server.read_line(&mut line).await?;
+                assert_eq!(&line, req);
+                server.write_all(resp).await?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let seen = MemorySeen(
+            vec![("old", now - 8 * 24 * 60 * 60), ("today", now)]
+                .into_iter()
+                .collect(),
+        );
+
+        let mut client = Client::new(client).await?;
+        let policy = RetrievalPolicy::DeleteAfterRetrieve { days: Some(7) };
+        let messages = client.fetch_all(policy, Some(&seen)).await?;
+        assert_eq!(messages.len(), 4);
+        // A failure of the store is reported for the message only.
+        assert!(messages[0].1.is_err());
+        assert!(messages[1..].iter().all(|(_, v)| v.is_ok()));
+
+        assert!(client
+            .fetch_all(policy, None)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("seen store"));
+
+        srv.await?
+    }
+}
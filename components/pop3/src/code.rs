@@ -0,0 +1,122 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{AuthResponse, Response};
+
+/// RespCode is the extended response code described in
+/// [RFC 2449](https://tools.ietf.org/html/rfc2449) and
+/// [RFC 3206](https://tools.ietf.org/html/rfc3206).
+///
+/// ```text
+/// S: -ERR [IN-USE] maildrop already locked
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespCode {
+    /// `IN-USE`: maildrop is locked by another session.
+    InUse,
+    /// `LOGIN-DELAY`: user logins too often.
+    LoginDelay,
+    /// `SYS/TEMP` and `SYS/PERM`: failure caused by the system.
+    Sys(SysCode),
+    /// `AUTH`: failure caused by the credentials.
+    Auth,
+    /// Codes not known, kept as is.
+    Other(String),
+}
+
+/// SysCode tells whether a system failure is transient.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SysCode {
+    Temp,
+    Perm,
+}
+
+impl RespCode {
+    /// Split the leading `[CODE]` from a response text.
+    ///
+    /// Returns the code and the rest human readable text.
+    pub fn parse(text: &str) -> (Option<RespCode>, &str) {
+        let (code, rest) = match text
+            .strip_prefix('[')
+            .and_then(|v| v.find(']').map(|i| (&v[..i], &v[i + 1..])))
+        {
+            Some(v) => v,
+            None => return (None, text),
+        };
+
+        let code = match code.to_ascii_uppercase().as_str() {
+            "IN-USE" => RespCode::InUse,
+            "LOGIN-DELAY" => RespCode::LoginDelay,
+            "SYS/TEMP" => RespCode::Sys(SysCode::Temp),
+            "SYS/PERM" => RespCode::Sys(SysCode::Perm),
+            "AUTH" => RespCode::Auth,
+            _ => RespCode::Other(code.to_string()),
+        };
+
+        (Some(code), rest.trim_start())
+    }
+}
+
+impl Display for RespCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RespCode::InUse => write!(f, "IN-USE"),
+            RespCode::LoginDelay => write!(f, "LOGIN-DELAY"),
+            RespCode::Sys(SysCode::Temp) => write!(f, "SYS/TEMP"),
+            RespCode::Sys(SysCode::Perm) => write!(f, "SYS/PERM"),
+            RespCode::Auth => write!(f, "AUTH"),
+            RespCode::Other(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Response {
+    /// Extended response code carried by the response text.
+    pub fn resp_code(&self) -> Option<RespCode> {
+        match self {
+            Response::ERR(v)
+            | Response::GREET(v)
+            | Response::USER(v)
+            | Response::PASS(v)
+            | Response::AUTH(AuthResponse::Success(v)) => RespCode::parse(v).0,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let cases = vec![
+            (
+                "[IN-USE] maildrop already locked",
+                Some(RespCode::InUse),
+                "maildrop already locked",
+            ),
+            ("[login-delay] wait", Some(RespCode::LoginDelay), "wait"),
+            (
+                "[SYS/TEMP] try later",
+                Some(RespCode::Sys(SysCode::Temp)),
+                "try later",
+            ),
+            ("[SYS/PERM]", Some(RespCode::Sys(SysCode::Perm)), ""),
+            (
+                "[XYZZY] magic",
+                Some(RespCode::Other("XYZZY".to_string())),
+                "magic",
+            ),
+            ("no such message", None, "no such message"),
+            ("[unclosed", None, "[unclosed"),
+        ];
+
+        for (text, code, rest) in cases {
+            assert_eq!(RespCode::parse(text), (code, rest), "{}", text);
+        }
+
+        let resp = Response::ERR("[IN-USE] maildrop already locked".to_string());
+        assert_eq!(resp.resp_code(), Some(RespCode::InUse));
+        assert_eq!(Response::NOOP.resp_code(), None);
+    }
+}
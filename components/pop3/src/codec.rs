@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Request, Response};
+
+/// Pop3Codec decodes requests and encodes responses for a server.
+///
+/// Requests are framed by CRLF, a partial line will be kept until more
+/// bytes arrived.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Pop3Codec;
+
+impl Decoder for Pop3Codec {
+    type Item = Request;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Request>> {
+        let n = match src.windows(2).position(|v| v == b"\r\n") {
+            Some(v) => v + 2,
+            None => return Ok(None),
+        };
+
+        let line = src.split_to(n);
+        let req = Request::from_str(std::str::from_utf8(&line)?)?;
+        Ok(Some(req))
+    }
+}
+
+impl Encoder<Response> for Pop3Codec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&item.to_bytes()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() -> Result<()> {
+        let mut codec = Pop3Codec;
+        let mut buf = BytesMut::from("USER postman\r\nST");
+
+        assert_eq!(
+            codec.decode(&mut buf)?,
+            Some(Request::USER("postman".to_string()))
+        );
+        assert_eq!(codec.decode(&mut buf)?, None);
+        buf.extend_from_slice(b"AT\r");
+        assert_eq!(codec.decode(&mut buf)?, None);
+        buf.extend_from_slice(b"\n");
+        assert_eq!(codec.decode(&mut buf)?, Some(Request::STAT));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::new();
+        codec.encode(Response::NOOP, &mut buf)?;
+        assert_eq!(&buf[..], b"+OK\r\n");
+
+        Ok(())
+    }
+}
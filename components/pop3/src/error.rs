@@ -0,0 +1,161 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use crate::{Command, RespCode, State};
+
+/// ProtoError is the error of POP3 protocol.
+///
+/// Errors are returned wrapped in `anyhow::Error`, use `downcast_ref` to
+/// handle a specific one:
+///
+/// ```
+/// use std::str::FromStr;
+/// use postman_pop3::{ProtoError, Request};
+///
+/// let err = Request::from_str("FOOBAR\r\n").unwrap_err();
+/// assert!(matches!(
+///     err.downcast_ref::<ProtoError>(),
+///     Some(ProtoError::UnknownCommand(_))
+/// ));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtoError {
+    /// Command is not known by this crate.
+    UnknownCommand(String),
+    /// Message number is not a positive integer, or count is not a
+    /// non-negative integer.
+    InvalidInteger(String),
+    /// Message number or count is larger than the limit of parser.
+    IntegerTooLarge { value: String, max: usize },
+    /// Unique-id is not 1 to 70 characters in the range of 0x21 to 0x7E.
+    InvalidUid(String),
+    /// Line is not a scan listing like `1 120`.
+    InvalidScanListing(String),
+    /// Text is not valid base64 with padding.
+    InvalidBase64(String),
+    /// Known capability has malformed arguments, like `LOGIN-DELAY abc`.
+    InvalidCapability(String),
+    /// Command is not allowed in current state of the session.
+    NotAllowed { command: Command, state: State },
+    /// STLS is issued after TLS is active.
+    TlsActive,
+    /// PASS is not issued immediately after a successful USER.
+    PassWithoutUser,
+    /// Request is malformed, like containing control characters. The
+    /// request is not included as it may carry secrets.
+    Malformed(String),
+    /// Multi-line response of the command is never terminated by `.`, which
+    /// is sent by some buggy servers for CAPA and AUTH.
+    MissingTerminator(Command),
+}
+
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtoError::UnknownCommand(v) => write!(f, "unknown command {:?}", v),
+            ProtoError::InvalidInteger(v) => write!(f, "invalid integer {:?}", v),
+            ProtoError::IntegerTooLarge { value, max } => {
+                write!(f, "integer {:?} is larger than {}", value, max)
+            }
+            ProtoError::InvalidUid(v) => write!(f, "invalid unique-id {:?}", v),
+            ProtoError::InvalidScanListing(v) => write!(f, "invalid scan listing {:?}", v),
+            ProtoError::InvalidBase64(v) => write!(f, "invalid base64 {:?}", v),
+            ProtoError::InvalidCapability(v) => write!(f, "invalid capability {:?}", v),
+            ProtoError::NotAllowed { command, state } => {
+                write!(f, "{} is not allowed in {:?} state", command, state)
+            }
+            ProtoError::TlsActive => write!(f, "command not permitted after TLS"),
+            ProtoError::PassWithoutUser => write!(f, "PASS must follow a successful USER"),
+            ProtoError::Malformed(v) => write!(f, "malformed request: {}", v),
+            ProtoError::MissingTerminator(v) => {
+                write!(f, "response of {} is not terminated by \".\"", v)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+/// ErrResponse is a `-ERR` response returned by server, use `resp_code` to
+/// tell whether it's worth retrying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrResponse {
+    /// Command rejected by server, like `PASS` or `AUTH PLAIN`.
+    pub command: String,
+    /// Text of the response, may start with an extended response code.
+    pub text: String,
+}
+
+impl ErrResponse {
+    pub fn new(command: impl Into<String>, text: impl Into<String>) -> ErrResponse {
+        ErrResponse {
+            command: command.into(),
+            text: text.into(),
+        }
+    }
+
+    /// Extended response code carried by the response text.
+    pub fn resp_code(&self) -> Option<RespCode> {
+        RespCode::parse(&self.text).0
+    }
+}
+
+impl Display for ErrResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.command, self.text)
+    }
+}
+
+impl std::error::Error for ErrResponse {}
+
+/// ClientError is a failure of the connection of `Client`.
+///
+/// A connection closed by server could be reconnected, while a malformed
+/// reply is reported as other errors like `ProtoError`, which will not
+/// go away by reconnecting.
+#[derive(Debug)]
+pub enum ClientError {
+    /// Server closed the connection, probably in the middle of a response.
+    ConnectionClosed,
+    /// Server refused the connection by a `-ERR` greeting like
+    /// `-ERR server busy, try later`, carrying the text of the greeting.
+    ConnectionRefused(String),
+    /// Network operation failed.
+    Io(std::io::Error),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::ConnectionClosed => write!(f, "connection closed by server"),
+            ClientError::ConnectionRefused(v) => write!(f, "connection refused: {}", v),
+            ClientError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::ConnectionClosed | ClientError::ConnectionRefused(_) => None,
+            ClientError::Io(err) => Some(err),
+        }
+    }
+}
+
+/// TimeoutError is returned if a network operation of `Client` doesn't
+/// finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// Operation timed out, like `connect`, `read` or `write`.
+    pub op: &'static str,
+    pub after: Duration,
+}
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} timed out after {:?}", self.op, self.after)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
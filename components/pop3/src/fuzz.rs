@@ -0,0 +1,162 @@
+//! Entry points for fuzzers like cargo-fuzz.
+//!
+//! Both parsers must return `Err` for malformed input instead of panicking,
+//! any panic raised from here is a bug:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| postman_pop3::fuzz_request(data));
+//! ```
+use std::str::FromStr;
+
+use crate::{Command, Request, Response};
+
+/// Parse `data` as a request, a parsed request must be serialized into a
+/// line which parses to the same request.
+pub fn fuzz_request(data: &[u8]) {
+    let v = match std::str::from_utf8(data) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let req = match Request::from_str(v) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let line = format!("{}", req);
+    match Request::from_str(&line) {
+        Ok(v) => assert_eq!(v, req, "{:?} is parsed from {:?}", v, line),
+        Err(err) => panic!("{:?} can't be parsed: {}", line, err),
+    }
+}
+
+/// Parse `data` as a response for every form of request of `cmd`.
+///
+/// The wire form of a parsed response must parse again, and serialize to
+/// the same bytes. Responses are not compared directly, because line
+/// endings in bodies of RETR and TOP are normalized to CRLF.
+pub fn fuzz_response(data: &[u8], cmd: Command) {
+    let v = match std::str::from_utf8(data) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    for req in requests(cmd) {
+        let resp = match Response::from_str(v, &req) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        let content = format!("{}", resp);
+        match Response::from_str(&content, &req) {
+            Ok(v) => assert_eq!(
+                format!("{}", v),
+                content,
+                "{:?} is parsed from {:?} for {:?}",
+                v,
+                content,
+                req
+            ),
+            Err(err) => panic!("{:?} for {:?} can't be parsed: {}", content, req, err),
+        }
+    }
+}
+
+/// Requests of `cmd` whose responses are parsed differently.
+fn requests(cmd: Command) -> Vec<Request> {
+    match cmd {
+        Command::USER => vec![Request::USER("postman".to_string())],
+        Command::PASS => vec![Request::PASS("postman".to_string())],
+        Command::STAT => vec![Request::STAT],
+        Command::UIDL => vec![Request::UIDL(None), Request::UIDL(Some(1))],
+        Command::LIST => vec![Request::LIST(None), Request::LIST(Some(1))],
+        Command::RETR => vec![Request::RETR(1)],
+        Command::DELE => vec![Request::DELE(1)],
+        Command::NOOP => vec![Request::NOOP],
+        Command::RSET => vec![Request::RSET],
+        Command::QUIT => vec![Request::QUIT],
+        Command::APOP => vec![Request::APOP {
+            username: "postman".to_string(),
+            digest: "c4c9334bac560ecc979e58001b3e22fb".to_string(),
+        }],
+        Command::TOP => vec![Request::TOP { id: 1, lines: 0 }],
+        Command::AUTH => vec![
+            Request::AUTH(None),
+            Request::AUTH(Some("PLAIN".to_string())),
+        ],
+        Command::CAPA => vec![Request::CAPA],
+        Command::STLS => vec![Request::STLS],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build inputs out of tokens which are likely to hit edge cases, by a
+    /// fixed seed so that failures are reproducible.
+    fn inputs(n: usize) -> Vec<Vec<u8>> {
+        const TOKENS: &[&[u8]] = &[
+            b"+OK",
+            b"-ERR",
+            b"+",
+            b" ",
+            b"\r\n",
+            b"\n",
+            b"\r",
+            b".",
+            b"..",
+            b"0",
+            b"1",
+            b"18446744073709551616",
+            b"-1",
+            b"USER",
+            b"user",
+            b"LIST",
+            b"TOP",
+            b"APOP",
+            b"AUTH",
+            b"x",
+            b"\xff",
+            "\u{e9}".as_bytes(),
+            b"\0",
+            b"\t",
+        ];
+
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        };
+
+        (0..n)
+            .map(|_| {
+                let len = next() % 12;
+                (0..len)
+                    .flat_map(|_| TOKENS[next() % TOKENS.len()].iter().copied())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn request() {
+        for data in inputs(20000) {
+            fuzz_request(&data);
+        }
+        fuzz_request(b"TOP 1 0\r\n");
+    }
+
+    #[test]
+    fn response() {
+        for data in inputs(20000) {
+            for cmd in Command::all() {
+                fuzz_response(&data, *cmd);
+            }
+        }
+        fuzz_response(b"+OK\r\na\nb\r\n..\r\n.\r\n", Command::RETR);
+        fuzz_response(b"+OK\r\n..\r\n..x\r\n.\r\n", Command::CAPA);
+        fuzz_response(b"+OK\r\n..\r\n.\r\n", Command::AUTH);
+    }
+}
@@ -28,6 +28,36 @@
 /// S:    +OK dewey POP3 server signing off (maildrop empty)
 /// C:  <close connection>
 /// S:  <wait for next connection>
+pub use apop::{apop_digest, apop_verify, make_apop_greeting, validate_hostname};
+pub use capa::{Capabilities, Expire};
+pub use client::{Client, Quirks, RetrFallback, RetrievalPolicy, SeenStore, Timeouts};
+pub use code::{RespCode, SysCode};
+#[cfg(feature = "codec")]
+pub use codec::Pop3Codec;
+pub use error::{ClientError, ErrResponse, ProtoError, TimeoutError};
+#[cfg(feature = "fuzz")]
+pub use fuzz::{fuzz_request, fuzz_response};
+pub use maildrop::{dispatch, message_top, Maildrop, MaildropStat};
+#[cfg(feature = "test-util")]
+pub use mock::{Expectation, MockHandle, MockServer};
 pub use proto::*;
+pub use response_ref::ResponseRef;
+pub use session::{validate_script, Session, SessionSnapshot};
 
+mod apop;
+pub mod b64;
+mod capa;
+mod client;
+mod code;
+#[cfg(feature = "codec")]
+mod codec;
+mod error;
+#[cfg(any(test, feature = "fuzz"))]
+mod fuzz;
+mod maildrop;
+#[cfg(any(test, feature = "test-util"))]
+mod mock;
 mod proto;
+mod response_ref;
+pub mod sasl;
+mod session;
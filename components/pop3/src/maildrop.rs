@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use anyhow::Result;
+
+use crate::{ListResponse, MessageMeta, Request, Response, UidlResponse};
+
+/// Maildrop is the storage of messages which serves a TRANSACTION state.
+///
+/// Messages are numbered from `1`, and messages marked as deleted should
+/// not be visible in any following commands. Deletions only take effect
+/// after `commit` which happens while entering the UPDATE state.
+pub trait Maildrop {
+    /// Returns count and total size in octets of messages not deleted.
+    fn stat(&self) -> Result<(usize, usize)>;
+    /// Returns count and total size of messages not deleted, and count and
+    /// total size of messages marked as deleted.
+    ///
+    /// It's for diagnostics only, STAT must not count deleted messages.
+    fn stat_full(&self) -> Result<(usize, usize, usize, usize)>;
+    /// Returns messages not deleted.
+    fn list(&self) -> Result<Vec<MessageMeta>>;
+    /// Returns the whole content of message `id`.
+    fn retr(&mut self, id: usize) -> Result<Vec<u8>>;
+    /// Returns headers and the first `lines` lines of body of message `id`.
+    ///
+    /// Headers end at the first blank line which is always included, so
+    /// `lines = 0` returns headers and the blank line only. `message_top`
+    /// could be used to implement it.
+    fn top(&mut self, id: usize, lines: usize) -> Result<Vec<u8>>;
+    /// Mark message `id` as deleted.
+    fn dele(&mut self, id: usize) -> Result<()>;
+    /// Returns the unique id of messages not deleted.
+    fn uidl(&self) -> Result<BTreeMap<usize, String>>;
+    /// Unmark all messages marked as deleted.
+    fn reset(&mut self) -> Result<()>;
+    /// Remove all messages marked as deleted.
+    fn commit(&mut self) -> Result<()>;
+    /// Whether the maildrop is read-only, `dispatch` refuses DELE and
+    /// never commits it.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// MaildropStat is the full stat of a maildrop including deleted messages,
+/// which is displayed for operators instead of sending to clients.
+///
+/// ```
+/// use postman_pop3::MaildropStat;
+///
+/// let stat = MaildropStat::from((2, 320, 1, 120));
+/// assert_eq!(
+///     stat.to_string(),
+///     "2 messages (320 octets), 1 deleted (120 octets)"
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaildropStat {
+    pub count: usize,
+    pub size: usize,
+    pub deleted_count: usize,
+    pub deleted_size: usize,
+}
+
+impl From<(usize, usize, usize, usize)> for MaildropStat {
+    fn from((count, size, deleted_count, deleted_size): (usize, usize, usize, usize)) -> Self {
+        MaildropStat {
+            count,
+            size,
+            deleted_count,
+            deleted_size,
+        }
+    }
+}
+
+impl Display for MaildropStat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} messages ({} octets), {} deleted ({} octets)",
+            self.count, self.size, self.deleted_count, self.deleted_size
+        )
+    }
+}
+
+/// Handle a TRANSACTION state request with maildrop.
+///
+/// Messages marked as deleted are never listed by LIST and UIDL, and asking
+/// for one of them by id is an error. Listings are sorted by id whatever
+/// order maildrop returns them in. Errors returned by maildrop will be
+/// sent to client as `-ERR`, the returned error means this request can't
+/// be served by a maildrop.
+///
+/// QUIT commits the maildrop and replies how many messages are deleted and
+/// left in the sign-off text.
+///
+/// A read-only maildrop refuses DELE, RSET and QUIT reply `+OK` without
+/// touching it.
+pub fn dispatch(maildrop: &mut dyn Maildrop, req: &Request) -> Result<Response> {
+    let resp = match req {
+        Request::STAT => maildrop
+            .stat()
+            .map(|(count, size)| Response::STAT { count, size }),
+        Request::LIST(None) => maildrop.list().map(|mut v| {
+            v.sort();
+            Response::LIST(ListResponse::All(
+                v.into_iter().map(|v| (v.id, v.size)).collect(),
+            ))
+        }),
+        Request::LIST(Some(id)) => maildrop.list().and_then(|v| {
+            v.into_iter()
+                .find(|v| v.id == *id)
+                .map(|v| Response::LIST(ListResponse::Single(v.id, v.size)))
+                .ok_or_else(|| no_such_message(maildrop, *id))
+        }),
+        Request::UIDL(None) => maildrop
+            .uidl()
+            .map(|v| Response::UIDL(UidlResponse::All(v))),
+        Request::UIDL(Some(id)) => maildrop.uidl().and_then(|mut v| {
+            v.remove(id)
+                .map(|uid| Response::UIDL(UidlResponse::Single(*id, uid)))
+                .ok_or_else(|| no_such_message(maildrop, *id))
+        }),
+        Request::RETR(id) => maildrop.retr(*id).map(|v| Response::RETR(into_string(v))),
+        Request::TOP { id, lines } => maildrop
+            .top(*id, *lines)
+            .map(|v| Response::TOP(into_string(v))),
+        Request::DELE(_) if maildrop.read_only() => Err(anyhow::anyhow!("maildrop is read-only")),
+        Request::DELE(id) => maildrop.list().and_then(|v| {
+            if v.iter().all(|v| v.id != *id) {
+                return Err(no_such_message(maildrop, *id));
+            }
+            maildrop.dele(*id).map(|_| Response::DELE)
+        }),
+        Request::NOOP => Ok(Response::NOOP),
+        Request::RSET if maildrop.read_only() => Ok(Response::RSET(String::new())),
+        Request::RSET => maildrop
+            .reset()
+            .and_then(|_| maildrop.stat())
+            .map(|(count, size)| {
+                Response::RSET(format!("maildrop has {} messages ({} octets)", count, size))
+            }),
+        Request::QUIT if maildrop.read_only() => Ok(Response::QUIT(Some(
+            "POP3 server signing off (read-only maildrop)".to_string(),
+        ))),
+        Request::QUIT => maildrop.stat_full().and_then(|(count, _, deleted, _)| {
+            maildrop.commit()?;
+            Ok(Response::QUIT(Some(sign_off(count, deleted))))
+        }),
+        v => return Err(anyhow::anyhow!("request {:?} is not for maildrop", v)),
+    };
+
+    Ok(resp.unwrap_or_else(|err| Response::ERR(err.to_string())))
+}
+
+/// Build the error for message `id` which is not listed by maildrop.
+///
+/// Messages are numbered in the whole session, so an id in range must have
+/// been marked as deleted. Otherwise the error tells how many messages are
+/// not deleted like `no such message, only 2 messages in maildrop`.
+fn no_such_message(maildrop: &dyn Maildrop, id: usize) -> anyhow::Error {
+    match maildrop.stat_full() {
+        Ok((count, _, deleted, _)) if id >= 1 && id <= count + deleted => {
+            anyhow::anyhow!("message {} already deleted", id)
+        }
+        Ok((count, ..)) => {
+            anyhow::anyhow!("no such message, only {} messages in maildrop", count)
+        }
+        Err(_) => anyhow::anyhow!("no such message"),
+    }
+}
+
+/// Take headers, the first blank line and at most `lines` lines of body
+/// from a message.
+///
+/// A message without blank line has headers only, the whole message is
+/// returned with a blank line appended, so that the separator is always
+/// sent. An empty message stays empty.
+pub fn message_top(content: &[u8], lines: usize) -> Vec<u8> {
+    let mut end = 0;
+    let mut in_body = false;
+    let mut body_lines = 0;
+
+    for line in content.split_inclusive(|v| *v == b'\n') {
+        if in_body {
+            if body_lines == lines {
+                break;
+            }
+            body_lines += 1;
+        } else if line == b"\r\n" || line == b"\n" {
+            in_body = true;
+        }
+        end += line.len();
+    }
+
+    let mut top = content[..end].to_vec();
+    if !in_body && !top.is_empty() {
+        if !top.ends_with(b"\n") {
+            top.extend_from_slice(b"\r\n");
+        }
+        top.extend_from_slice(b"\r\n");
+    }
+
+    top
+}
+
+/// Sign-off text of QUIT after `deleted` messages are removed and `count`
+/// are left, like `POP3 server signing off (maildrop empty)`.
+fn sign_off(count: usize, deleted: usize) -> String {
+    match (count, deleted) {
+        (0, 0) => "POP3 server signing off (maildrop empty)".to_string(),
+        (0, deleted) => format!(
+            "POP3 server signing off ({} messages deleted, maildrop empty)",
+            deleted
+        ),
+        (count, deleted) => format!(
+            "POP3 server signing off ({} messages deleted, {} messages left)",
+            deleted, count
+        ),
+    }
+}
+
+/// Convert message content into string without copying if it's valid UTF-8.
+fn into_string(v: Vec<u8>) -> String {
+    String::from_utf8(v).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// MemoryMaildrop keeps messages in memory with their deleted mark.
+    struct MemoryMaildrop {
+        messages: Vec<(String, bool)>,
+    }
+
+    impl MemoryMaildrop {
+        fn get(&self, id: usize) -> Result<&str> {
+            match id.checked_sub(1).and_then(|i| self.messages.get(i)) {
+                Some((v, false)) => Ok(v),
+                _ => Err(anyhow::anyhow!("no such message")),
+            }
+        }
+    }
+
+    impl Maildrop for MemoryMaildrop {
+        fn stat(&self) -> Result<(usize, usize)> {
+            let v = self.list()?;
+            Ok((v.len(), v.iter().map(|v| v.size).sum()))
+        }
+
+        fn stat_full(&self) -> Result<(usize, usize, usize, usize)> {
+            let (count, size) = self.stat()?;
+            let deleted: Vec<_> = self.messages.iter().filter(|v| v.1).collect();
+
+            Ok((
+                count,
+                size,
+                deleted.len(),
+                deleted.iter().map(|v| v.0.len()).sum(),
+            ))
+        }
+
+        fn list(&self) -> Result<Vec<MessageMeta>> {
+            Ok(self
+                .messages
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, deleted))| !deleted)
+                .map(|(i, (v, _))| MessageMeta::new(i + 1, &format!("uid-{}", i + 1), v.len(), ""))
+                .collect())
+        }
+
+        fn retr(&mut self, id: usize) -> Result<Vec<u8>> {
+            Ok(self.get(id)?.as_bytes().to_vec())
+        }
+
+        fn top(&mut self, id: usize, lines: usize) -> Result<Vec<u8>> {
+            Ok(message_top(self.get(id)?.as_bytes(), lines))
+        }
+
+        fn dele(&mut self, id: usize) -> Result<()> {
+            self.get(id)?;
+            self.messages[id - 1].1 = true;
+            Ok(())
+        }
+
+        fn uidl(&self) -> Result<BTreeMap<usize, String>> {
+            Ok(self.list()?.into_iter().map(|v| (v.id, v.uid)).collect())
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            self.messages.iter_mut().for_each(|v| v.1 = false);
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            self.messages.retain(|v| !v.1);
+            Ok(())
+        }
+    }
+
+    fn send(maildrop: &mut dyn Maildrop, req: &str) -> String {
+        let req: Request = req.parse().expect("parse request");
+        let resp = dispatch(maildrop, &req).expect("dispatch");
+        format!("{}", resp)
+    }
+
+    #[test]
+    fn transaction() {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![
+                ("Subject: a\r\n\r\nhello\r\n".to_string(), false),
+                ("Subject: b\r\n\r\nworld\r\n".to_string(), false),
+            ],
+        };
+        let md = &mut maildrop;
+
+        assert_eq!(send(md, "STAT\r\n"), "+OK 2 42\r\n");
+        assert_eq!(send(md, "DELE 1\r\n"), "+OK\r\n");
+        assert_eq!(send(md, "DELE 1\r\n"), "-ERR message 1 already deleted\r\n");
+        assert_eq!(send(md, "RETR 1\r\n"), "-ERR no such message\r\n");
+        assert_eq!(send(md, "UIDL\r\n"), "+OK 1 mails\r\n2 uid-2\r\n.\r\n");
+        assert_eq!(send(md, "UIDL 1\r\n"), "-ERR message 1 already deleted\r\n");
+        assert_eq!(
+            send(md, "UIDL 3\r\n"),
+            "-ERR no such message, only 1 messages in maildrop\r\n"
+        );
+        assert_eq!(
+            send(md, "RETR 2\r\n"),
+            "+OK\r\nSubject: b\r\n\r\nworld\r\n.\r\n"
+        );
+        assert_eq!(
+            send(md, "RSET\r\n"),
+            "+OK maildrop has 2 messages (42 octets)\r\n"
+        );
+        assert_eq!(send(md, "STAT\r\n"), "+OK 2 42\r\n");
+        assert_eq!(send(md, "DELE 2\r\n"), "+OK\r\n");
+        assert_eq!(
+            send(md, "QUIT\r\n"),
+            "+OK POP3 server signing off (1 messages deleted, 1 messages left)\r\n"
+        );
+        assert_eq!(maildrop.messages.len(), 1);
+
+        let req = Request::USER("postman".to_string());
+        assert!(dispatch(&mut maildrop, &req).is_err());
+    }
+
+    #[test]
+    fn quit_signs_off() {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![("a\r\n".to_string(), false), ("b\r\n".to_string(), false)],
+        };
+        let md = &mut maildrop;
+
+        assert_eq!(send(md, "DELE 1\r\n"), "+OK\r\n");
+        assert_eq!(send(md, "DELE 2\r\n"), "+OK\r\n");
+        assert_eq!(
+            send(md, "QUIT\r\n"),
+            "+OK POP3 server signing off (2 messages deleted, maildrop empty)\r\n"
+        );
+        assert!(maildrop.messages.is_empty());
+    }
+
+    #[test]
+    fn list_skips_deleted() -> Result<()> {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![("a\r\n".to_string(), false), ("b\r\n".to_string(), false)],
+        };
+
+        dispatch(&mut maildrop, &Request::DELE(1))?;
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::LIST(None))?,
+            Response::LIST(ListResponse::All(vec![(2, 3)]))
+        );
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::LIST(Some(1)))?,
+            Response::ERR("message 1 already deleted".to_string())
+        );
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::LIST(Some(2)))?,
+            Response::LIST(ListResponse::Single(2, 3))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_out_of_range() -> Result<()> {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![("a\r\n".to_string(), false), ("b\r\n".to_string(), false)],
+        };
+
+        assert_eq!(
+            send(&mut maildrop, "LIST 3\r\n"),
+            "-ERR no such message, only 2 messages in maildrop\r\n"
+        );
+
+        // Count of messages not deleted.
+        dispatch(&mut maildrop, &Request::DELE(1))?;
+        assert_eq!(
+            send(&mut maildrop, "LIST 3\r\n"),
+            "-ERR no such message, only 1 messages in maildrop\r\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_sorted() -> Result<()> {
+        /// UnsortedMaildrop lists messages in reverse order.
+        struct UnsortedMaildrop(MemoryMaildrop);
+
+        impl Maildrop for UnsortedMaildrop {
+            fn stat(&self) -> Result<(usize, usize)> {
+                self.0.stat()
+            }
+            fn stat_full(&self) -> Result<(usize, usize, usize, usize)> {
+                self.0.stat_full()
+            }
+            fn list(&self) -> Result<Vec<MessageMeta>> {
+                Ok(self.0.list()?.into_iter().rev().collect())
+            }
+            fn retr(&mut self, id: usize) -> Result<Vec<u8>> {
+                self.0.retr(id)
+            }
+            fn top(&mut self, id: usize, lines: usize) -> Result<Vec<u8>> {
+                self.0.top(id, lines)
+            }
+            fn dele(&mut self, id: usize) -> Result<()> {
+                self.0.dele(id)
+            }
+            fn uidl(&self) -> Result<BTreeMap<usize, String>> {
+                self.0.uidl()
+            }
+            fn reset(&mut self) -> Result<()> {
+                self.0.reset()
+            }
+            fn commit(&mut self) -> Result<()> {
+                self.0.commit()
+            }
+        }
+
+        let mut maildrop = UnsortedMaildrop(MemoryMaildrop {
+            messages: vec![
+                ("a\r\n".to_string(), false),
+                ("bb\r\n".to_string(), false),
+                ("ccc\r\n".to_string(), false),
+            ],
+        });
+        assert_eq!(maildrop.list()?[0].id, 3);
+
+        assert_eq!(
+            send(&mut maildrop, "LIST\r\n"),
+            "+OK 3 messages\r\n1 3\r\n2 4\r\n3 5\r\n.\r\n"
+        );
+        dispatch(&mut maildrop, &Request::DELE(2))?;
+        assert_eq!(
+            send(&mut maildrop, "LIST\r\n"),
+            "+OK 2 messages\r\n1 3\r\n3 5\r\n.\r\n"
+        );
+        assert_eq!(
+            send(&mut maildrop, "UIDL\r\n"),
+            "+OK 2 mails\r\n1 uid-1\r\n3 uid-3\r\n.\r\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn uidl_out_of_range() -> Result<()> {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![("a\r\n".to_string(), false), ("b\r\n".to_string(), false)],
+        };
+
+        assert_eq!(
+            send(&mut maildrop, "UIDL 3\r\n"),
+            "-ERR no such message, only 2 messages in maildrop\r\n"
+        );
+
+        // Uid of a deleted message is never returned.
+        dispatch(&mut maildrop, &Request::DELE(1))?;
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::UIDL(Some(1)))?,
+            dispatch(&mut maildrop, &Request::LIST(Some(1)))?
+        );
+        assert_eq!(
+            send(&mut maildrop, "UIDL 1\r\n"),
+            "-ERR message 1 already deleted\r\n"
+        );
+        assert_eq!(
+            send(&mut maildrop, "UIDL 3\r\n"),
+            "-ERR no such message, only 1 messages in maildrop\r\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn dele() -> Result<()> {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![("a\r\n".to_string(), false), ("b\r\n".to_string(), false)],
+        };
+
+        for id in &[0, 3] {
+            assert_eq!(
+                dispatch(&mut maildrop, &Request::DELE(*id))?,
+                Response::ERR("no such message, only 2 messages in maildrop".to_string())
+            );
+        }
+
+        assert_eq!(dispatch(&mut maildrop, &Request::DELE(2))?, Response::DELE);
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::DELE(2))?,
+            Response::ERR("message 2 already deleted".to_string())
+        );
+
+        // Deletions are unmarked by RSET.
+        dispatch(&mut maildrop, &Request::RSET)?;
+        assert_eq!(dispatch(&mut maildrop, &Request::DELE(2))?, Response::DELE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rset() -> Result<()> {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![
+                ("a\r\n".to_string(), false),
+                ("b\r\n".to_string(), false),
+                ("c\r\n".to_string(), false),
+            ],
+        };
+
+        dispatch(&mut maildrop, &Request::DELE(1))?;
+        dispatch(&mut maildrop, &Request::DELE(3))?;
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::STAT)?,
+            Response::STAT { count: 1, size: 3 }
+        );
+        // Deleted messages are only visible in the full stat.
+        assert_eq!(maildrop.stat_full()?, (1, 3, 2, 6));
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::RSET)?,
+            Response::RSET("maildrop has 3 messages (9 octets)".to_string())
+        );
+        assert_eq!(
+            dispatch(&mut maildrop, &Request::STAT)?,
+            Response::STAT { count: 3, size: 9 }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn top() {
+        let mut maildrop = MemoryMaildrop {
+            messages: vec![(
+                "From: a\r\nSubject: b\r\n\r\nhello\r\n\r\nworld\r\n".to_string(),
+                false,
+            )],
+        };
+        let md = &mut maildrop;
+
+        assert_eq!(
+            send(md, "TOP 1 0\r\n"),
+            "+OK\r\nFrom: a\r\nSubject: b\r\n\r\n.\r\n"
+        );
+        assert_eq!(
+            send(md, "TOP 1 2\r\n"),
+            "+OK\r\nFrom: a\r\nSubject: b\r\n\r\nhello\r\n\r\n.\r\n"
+        );
+        assert_eq!(
+            send(md, "TOP 1 10\r\n"),
+            "+OK\r\nFrom: a\r\nSubject: b\r\n\r\nhello\r\n\r\nworld\r\n.\r\n"
+        );
+
+        assert_eq!(message_top(b"a\nb\n", 0), b"a\nb\n\r\n");
+        assert_eq!(message_top(b"a\nb", 0), b"a\nb\r\n\r\n");
+        assert_eq!(message_top(b"a\n\nb\n", 0), b"a\n\n");
+        assert_eq!(message_top(b"a\n\n", 10), b"a\n\n");
+        assert_eq!(message_top(b"\r\nb\r\n", 0), b"\r\n");
+        assert_eq!(message_top(b"", 0), b"");
+    }
+}
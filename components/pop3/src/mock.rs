@@ -0,0 +1,158 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::{Request, Response};
+
+/// MockServer is a scripted POP3 server for tests.
+///
+/// It serves one connection: sends the greeting, then replies the canned
+/// response for every expected request in order. Script it by
+/// `expect(request).respond(response)`, connect the client to the address of
+/// the handle returned by `start`, and call `MockHandle::verify` after the
+/// client is dropped to check the requests it sent.
+#[derive(Debug)]
+pub struct MockServer {
+    greeting: Response,
+    script: Vec<(Request, Response)>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        MockServer::new()
+    }
+}
+
+impl MockServer {
+    /// Create a server which greets with `+OK POP3 server ready`.
+    pub fn new() -> MockServer {
+        MockServer {
+            greeting: Response::GREET("POP3 server ready".to_string()),
+            script: Vec::new(),
+        }
+    }
+
+    /// Set the greeting, should be `Response::GREET` or `Response::ERR`.
+    pub fn greeting(&mut self, resp: Response) -> &mut Self {
+        self.greeting = resp;
+        self
+    }
+
+    /// Expect `req` as the next request.
+    pub fn expect(&mut self, req: Request) -> Expectation<'_> {
+        Expectation { server: self, req }
+    }
+
+    /// Bind an ephemeral port on localhost and serve the script.
+    pub async fn start(self) -> Result<MockHandle> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(self.serve(listener));
+
+        Ok(MockHandle { addr, task })
+    }
+
+    async fn serve(self, listener: TcpListener) -> Result<()> {
+        let (socket, _) = listener.accept().await?;
+        let mut socket = BufReader::new(socket);
+        socket
+            .get_mut()
+            .write_all(&self.greeting.to_bytes()?)
+            .await?;
+
+        let mut line = String::new();
+        for (i, (expect, resp)) in self.script.iter().enumerate() {
+            line.clear();
+            if socket.read_line(&mut line).await? == 0 {
+                return Err(anyhow::anyhow!(
+                    "connection closed, expected request {}: {:?}",
+                    i,
+                    expect
+                ));
+            }
+
+            let req = Request::from_str(&line)?;
+            if &req != expect {
+                return Err(anyhow::anyhow!(
+                    "request {}: expected {:?}, got {:?}",
+                    i,
+                    expect,
+                    req
+                ));
+            }
+            socket.get_mut().write_all(&resp.to_bytes()?).await?;
+        }
+
+        line.clear();
+        if socket.read_line(&mut line).await? > 0 {
+            return Err(anyhow::anyhow!("unexpected request: {:?}", line));
+        }
+
+        Ok(())
+    }
+}
+
+/// Expectation is a request to be answered by `respond`.
+#[derive(Debug)]
+pub struct Expectation<'a> {
+    server: &'a mut MockServer,
+    req: Request,
+}
+
+impl Expectation<'_> {
+    /// Reply `resp` to the expected request.
+    pub fn respond(self, resp: Response) {
+        self.server.script.push((self.req, resp));
+    }
+}
+
+/// MockHandle is a started `MockServer`.
+#[derive(Debug)]
+pub struct MockHandle {
+    addr: SocketAddr,
+    task: JoinHandle<Result<()>>,
+}
+
+impl MockHandle {
+    /// Address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the connection to be closed, and check whether the client
+    /// sent exactly the expected requests.
+    ///
+    /// The client must be dropped or have sent all requests before, or
+    /// this waits forever.
+    pub async fn verify(self) -> Result<()> {
+        self.task.await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Client, ListResponse};
+
+    #[tokio::test]
+    async fn unexpected_request() -> Result<()> {
+        let mut server = MockServer::new();
+        server
+            .expect(Request::LIST(None))
+            .respond(Response::LIST(ListResponse::All(vec![(1, 120)])));
+        let handle = server.start().await?;
+
+        let mut client = Client::connect(handle.addr()).await?;
+        assert!(client.send(&Request::STAT).await.is_err());
+        drop(client);
+
+        let err = handle.verify().await.unwrap_err();
+        assert_eq!(err.to_string(), "request 0: expected LIST(None), got STAT");
+
+        Ok(())
+    }
+}
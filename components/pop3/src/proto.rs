@@ -1,12 +1,30 @@
 use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter, Write};
+use std::num::IntErrorKind;
 use std::str::FromStr;
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "serde")]
 use sled::IVec;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 
-#[derive(Copy, Clone, Debug)]
+use crate::{b64, ProtoError};
+
+/// Capacity of the buffer to write multi-line bodies, which bounds the
+/// memory held for a slow client. Writers yield to other tasks after every
+/// this many bytes.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Max length of a response line including the CRLF.
+pub const MAX_LINE_LENGTH: usize = 512;
+
+/// Max message number or line count accepted in requests by default, larger
+/// values are nonsensical and may be used to exhaust memory downstream.
+pub const MAX_INTEGER: usize = 10_000_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Command {
     /// APOP is used to do digest auth
     ///
@@ -93,11 +111,15 @@ pub enum Command {
     ///  In this example, the shared  secret  is  the  string  `tan-
     ///  staaf'.  Hence, the MD5 algorithm is applied to the string
     ///
-    ///     <1896.697170952@dbc.mtview.ca.us>tanstaaf
+    /// ```text
+    /// <1896.697170952@dbc.mtview.ca.us>tanstaaf
+    /// ```
     ///
     ///  which produces a digest value of
     ///
-    ///     c4c9334bac560ecc979e58001b3e22fb
+    /// ```text
+    /// c4c9334bac560ecc979e58001b3e22fb
+    /// ```
     APOP,
     /// AUTH command indicates an authentication mechanism to the server.
     ///
@@ -527,11 +549,11 @@ pub enum Command {
     /// response with a CRLF pair.  More advanced implementations
     /// may include other information.
     ///
-    ///     NOTE: This memo STRONGLY discourages implementations
-    ///     from supplying additional information in the drop
-    ///     listing.  Other, optional, facilities are discussed
-    ///     later on which permit the client to parse the messages
-    ///     in the maildrop.
+    /// NOTE: This memo STRONGLY discourages implementations
+    /// from supplying additional information in the drop
+    /// listing.  Other, optional, facilities are discussed
+    /// later on which permit the client to parse the messages
+    /// in the maildrop.
     ///
     /// Note that messages marked as deleted are not counted in
     /// either total.
@@ -550,6 +572,41 @@ pub enum Command {
     /// S: +OK 2 320
     /// ```
     STAT,
+    /// STLS will start TLS negotiation, described in
+    /// [RFC 2595](https://tools.ietf.org/html/rfc2595#section-4).
+    ///
+    /// # Restrictions
+    ///
+    /// Only permitted in AUTHORIZATION state, and not permitted after TLS
+    /// is active.
+    ///
+    /// # Discussion
+    ///
+    /// The client begins the TLS negotiation immediately after the CRLF
+    /// at the end of the +OK response from the server. Once TLS has
+    /// been started, the client must discard cached information about
+    /// server capabilities and should re-issue the CAPA command.
+    ///
+    /// # Syntax
+    ///
+    /// ```text
+    /// C: STLS
+    /// S: +OK [msg]
+    /// ```
+    ///
+    /// # Examples
+    ///
+    /// ```text
+    /// C: STLS
+    /// S: +OK Begin TLS negotiation
+    /// <TLS negotiation, further commands are under TLS layer>
+    /// ```
+    ///
+    /// ```text
+    /// C: STLS
+    /// S: -ERR Command not permitted when TLS active
+    /// ```
+    STLS,
     /// TOP will used to send top lines of messages.
     ///
     /// # Restrictions
@@ -722,6 +779,49 @@ pub enum Command {
     USER,
 }
 
+impl Command {
+    /// All commands in a stable order.
+    pub fn all() -> &'static [Command] {
+        &[
+            Command::USER,
+            Command::PASS,
+            Command::STAT,
+            Command::UIDL,
+            Command::LIST,
+            Command::RETR,
+            Command::DELE,
+            Command::NOOP,
+            Command::RSET,
+            Command::QUIT,
+            Command::APOP,
+            Command::TOP,
+            Command::AUTH,
+            Command::CAPA,
+            Command::STLS,
+        ]
+    }
+
+    /// Min and max count of arguments accepted by `Request::from_str`,
+    /// the max is `None` if not limited.
+    ///
+    /// Arguments are separated by spaces, except that the rest of a PASS
+    /// line is taken as the password, which may contain spaces.
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        match self {
+            Command::STAT
+            | Command::NOOP
+            | Command::RSET
+            | Command::QUIT
+            | Command::CAPA
+            | Command::STLS => (0, Some(0)),
+            Command::UIDL | Command::LIST | Command::AUTH => (0, Some(1)),
+            Command::USER | Command::RETR | Command::DELE => (1, Some(1)),
+            Command::PASS => (1, None),
+            Command::APOP | Command::TOP => (2, Some(2)),
+        }
+    }
+}
+
 impl FromStr for Command {
     type Err = anyhow::Error;
 
@@ -741,7 +841,8 @@ impl FromStr for Command {
             "TOP" => Command::TOP,
             "AUTH" => Command::AUTH,
             "CAPA" => Command::CAPA,
-            _ => return Err(anyhow::anyhow!("invalid command: {}", s)),
+            "STLS" => Command::STLS,
+            _ => return Err(ProtoError::UnknownCommand(s.to_string()).into()),
         })
     }
 }
@@ -763,30 +864,34 @@ impl Display for Command {
             Command::TOP => "TOP",
             Command::AUTH => "AUTH",
             Command::CAPA => "CAPA",
+            Command::STLS => "STLS",
         };
 
         write!(f, "{}", v)
     }
 }
 
+/// Command is serialized as its name like `DELE`.
+#[cfg(feature = "serde")]
+impl Serialize for Command {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+/// Command is deserialized from its name case-insensitively, unknown names
+/// are errors.
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Command {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let v = String::deserialize(d)?;
+        Command::from_str(&v.to_ascii_uppercase()).map_err(de::Error::custom)
+    }
+}
+
 impl From<&Request> for Command {
     fn from(v: &Request) -> Self {
-        match v {
-            Request::APOP { .. } => Command::APOP,
-            Request::AUTH(_) => Command::AUTH,
-            Request::CAPA => Command::CAPA,
-            Request::DELE(_) => Command::DELE,
-            Request::LIST(_) => Command::LIST,
-            Request::NOOP => Command::NOOP,
-            Request::PASS(_) => Command::PASS,
-            Request::QUIT => Command::QUIT,
-            Request::RETR(_) => Command::RETR,
-            Request::RSET => Command::RSET,
-            Request::STAT => Command::STAT,
-            Request::TOP { .. } => Command::TOP,
-            Request::UIDL(_) => Command::UIDL,
-            Request::USER(_) => Command::USER,
-        }
+        v.command()
     }
 }
 
@@ -799,10 +904,11 @@ impl From<&Response> for Command {
             Response::LIST(_) => Command::LIST,
             Response::NOOP => Command::NOOP,
             Response::PASS(_) => Command::PASS,
-            Response::QUIT => Command::QUIT,
+            Response::QUIT(_) => Command::QUIT,
             Response::RETR(_) => Command::RETR,
             Response::STAT { .. } => Command::STAT,
-            Response::RSET => Command::RSET,
+            Response::RSET(_) => Command::RSET,
+            Response::STLS(_) => Command::STLS,
             Response::USER(_) => Command::USER,
             // GREET and ERR doesn't have related commend.
             _ => panic!("invalid command for response: {:?}", v),
@@ -810,7 +916,9 @@ impl From<&Response> for Command {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "command", content = "args"))]
 pub enum Request {
     APOP { username: String, digest: String },
     AUTH(Option<String>),
@@ -823,48 +931,126 @@ pub enum Request {
     RETR(usize),
     RSET,
     STAT,
+    STLS,
     TOP { id: usize, lines: usize },
     UIDL(Option<usize>),
     USER(String),
 }
 
-impl Request {
-    pub fn to_string(&self) -> Result<String> {
-        let mut f = String::new();
-
+impl Display for Request {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Request::CAPA | Request::NOOP | Request::QUIT | Request::RSET | Request::STAT => {
-                write!(&mut f, "{}\r\n", Command::from(self))?
-            }
-            Request::DELE(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
-            Request::PASS(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
-            Request::RETR(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
-            Request::USER(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
+            Request::CAPA
+            | Request::NOOP
+            | Request::QUIT
+            | Request::RSET
+            | Request::STAT
+            | Request::STLS => write!(f, "{}\r\n", self.command())?,
+            Request::DELE(v) => write!(f, "{} {}\r\n", self.command(), v)?,
+            Request::PASS(v) => write!(f, "{} {}\r\n", self.command(), v)?,
+            Request::RETR(v) => write!(f, "{} {}\r\n", self.command(), v)?,
+            Request::USER(v) => write!(f, "{} {}\r\n", self.command(), v)?,
             Request::AUTH(v) => match v {
-                None => write!(&mut f, "{}\r\n", Command::from(self))?,
-                Some(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
+                None => write!(f, "{}\r\n", self.command())?,
+                Some(v) => write!(f, "{} {}\r\n", self.command(), v)?,
             },
             Request::LIST(v) => match v {
-                None => write!(&mut f, "{}\r\n", Command::from(self))?,
-                Some(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
+                None => write!(f, "{}\r\n", self.command())?,
+                Some(v) => write!(f, "{} {}\r\n", self.command(), v)?,
             },
             Request::UIDL(v) => match v {
-                None => write!(&mut f, "{}\r\n", Command::from(self))?,
-                Some(v) => write!(&mut f, "{} {}\r\n", Command::from(self), v)?,
+                None => write!(f, "{}\r\n", self.command())?,
+                Some(v) => write!(f, "{} {}\r\n", self.command(), v)?,
             },
-            Request::APOP { username, digest } => write!(
-                &mut f,
-                "{} {} {}\r\n",
-                Command::from(self),
-                username,
-                digest
-            )?,
-            Request::TOP { id, lines } => {
-                write!(&mut f, "{} {} {}\r\n", Command::from(self), id, lines)?
+            Request::APOP { username, digest } => {
+                write!(f, "{} {} {}\r\n", self.command(), username, digest)?
+            }
+            Request::TOP { id, lines } => write!(f, "{} {} {}\r\n", self.command(), id, lines)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Request {
+    /// Returns the command of this request.
+    ///
+    /// Every variant is matched without a wildcard, so it never panics and
+    /// a new variant must be handled here.
+    pub fn command(&self) -> Command {
+        match self {
+            Request::APOP { .. } => Command::APOP,
+            Request::AUTH(_) => Command::AUTH,
+            Request::CAPA => Command::CAPA,
+            Request::DELE(_) => Command::DELE,
+            Request::LIST(_) => Command::LIST,
+            Request::NOOP => Command::NOOP,
+            Request::PASS(_) => Command::PASS,
+            Request::QUIT => Command::QUIT,
+            Request::RETR(_) => Command::RETR,
+            Request::RSET => Command::RSET,
+            Request::STAT => Command::STAT,
+            Request::STLS => Command::STLS,
+            Request::TOP { .. } => Command::TOP,
+            Request::UIDL(_) => Command::UIDL,
+            Request::USER(_) => Command::USER,
+        }
+    }
+
+    /// Build the wire form of this request.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = String::new();
+        self.write_into(&mut buf)?;
+        Ok(buf.into_bytes())
+    }
+
+    /// Append the wire form of this request to `buf`, so that a buffer
+    /// could be reused for many requests. Content of `buf` is kept.
+    pub fn write_into(&self, buf: &mut String) -> Result<()> {
+        write!(buf, "{}", self)?;
+        Ok(())
+    }
+
+    #[deprecated(note = "use `Display` or `to_bytes` instead")]
+    pub fn to_string(&self) -> Result<String> {
+        let mut buf = String::new();
+        self.write_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Parse a request line which must be terminated by CRLF.
+    ///
+    /// `from_str` is lenient and also accepts a bare LF sent by some old
+    /// clients.
+    pub fn from_str_strict(v: &str) -> Result<Request> {
+        if !v.ends_with("\r\n") {
+            return Err(anyhow::anyhow!("request must end with CRLF"));
+        }
+
+        Request::from_str(v)
+    }
+
+    /// Parse as many complete requests as there are in `buf`, which is
+    /// read from a socket and may end with a partial line.
+    ///
+    /// Returns the requests, the unconsumed tail which should be kept for
+    /// the next read, and the error of the first malformed line if any.
+    /// Parsing stops at the malformed line, the tail starts after it so
+    /// that the caller could reply the error and go on.
+    pub fn parse_many(buf: &str) -> (Vec<Request>, &str, Option<anyhow::Error>) {
+        let mut reqs = Vec::new();
+        let mut rest = buf;
+
+        while let Some(end) = rest.find('\n') {
+            let (line, tail) = rest.split_at(end + 1);
+            rest = tail;
+            match Request::from_str(line) {
+                Ok(v) => reqs.push(v),
+                Err(err) => return (reqs, rest, Some(err)),
             }
         }
 
-        Ok(f)
+        (reqs, rest, None)
     }
 }
 
@@ -872,29 +1058,47 @@ impl FromStr for Request {
     type Err = anyhow::Error;
 
     fn from_str(v: &str) -> Result<Self> {
-        let v = v.strip_suffix("\r\n").unwrap();
+        Request::from_str_with_max_integer(v, MAX_INTEGER)
+    }
+}
+
+impl Request {
+    /// Parse a request line whose message numbers and line counts must not
+    /// exceed `max_integer`, `from_str` uses `MAX_INTEGER`.
+    pub fn from_str_with_max_integer(v: &str, max_integer: usize) -> Result<Request> {
+        let v = v
+            .strip_suffix("\r\n")
+            .or_else(|| v.strip_suffix('\n'))
+            .ok_or_else(|| anyhow::anyhow!("request is not terminated"))?;
+        // NUL and other control characters may confuse upstreams or forge
+        // lines in logs.
+        if let Some(b) = v.bytes().find(|b| *b < 0x20 || *b == 0x7f) {
+            return Err(ProtoError::Malformed(format!("control character {:#04x}", b)).into());
+        }
 
         let vs: Vec<&str> = v.split(' ').filter(|s| !s.is_empty()).collect();
-        let cmd = Command::from_str(vs[0])?;
+        let cmd = Command::from_str(vs.first().copied().unwrap_or_default())?;
 
         let req = match cmd {
             Command::USER => {
                 if vs.len() != 2 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::USER(vs[1].to_string())
             }
             Command::PASS => {
-                if vs.len() != 2 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                // Password is the rest of the line, spaces included.
+                match v.trim_start_matches(' ').split_once(' ') {
+                    Some((_, pass)) if !pass.trim_matches(' ').is_empty() => {
+                        Request::PASS(pass.to_string())
+                    }
+                    _ => return Err(anyhow::anyhow!("invalid request for {}", cmd)),
                 }
-
-                Request::PASS(vs[1].to_string())
             }
             Command::STAT => {
                 if vs.len() != 1 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::STAT
@@ -902,77 +1106,85 @@ impl FromStr for Request {
             Command::UIDL => match vs.len() {
                 1 => Request::UIDL(None),
                 2 => {
-                    let msg = usize::from_str(vs[1])?;
+                    let msg = parse_id(vs[1], max_integer)?;
 
                     Request::UIDL(Some(msg))
                 }
                 _ => {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
             },
             Command::LIST => match vs.len() {
                 1 => Request::LIST(None),
                 2 => {
-                    let msg = usize::from_str(vs[1])?;
+                    let msg = parse_id(vs[1], max_integer)?;
 
                     Request::LIST(Some(msg))
                 }
                 _ => {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
             },
             Command::RETR => {
                 if vs.len() != 2 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
-                let msg = usize::from_str(vs[1])?;
+                let msg = parse_id(vs[1], max_integer)?;
 
                 Request::RETR(msg)
             }
             Command::DELE => {
                 if vs.len() != 2 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
-                let msg = usize::from_str(vs[1])?;
+                let msg = parse_id(vs[1], max_integer)?;
 
                 Request::DELE(msg)
             }
             Command::NOOP => {
                 if vs.len() != 1 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::NOOP
             }
             Command::RSET => {
                 if vs.len() != 1 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::RSET
             }
+            Command::STLS => {
+                if vs.len() != 1 {
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
+                }
+
+                Request::STLS
+            }
             Command::QUIT => {
                 if vs.len() != 1 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::QUIT
             }
             Command::TOP => {
                 if vs.len() != 3 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
-                let id = usize::from_str(vs[1])?;
-                let lines = usize::from_str(vs[2])?;
+                let id = parse_id(vs[1], max_integer)?;
+                // Zero lines means headers only.
+                let lines = parse_integer(vs[2], max_integer)?;
 
                 Request::TOP { id, lines }
             }
             Command::APOP => {
                 if vs.len() != 3 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::APOP {
@@ -984,12 +1196,12 @@ impl FromStr for Request {
                 1 => Request::AUTH(None),
                 2 => Request::AUTH(Some(vs[1].to_string())),
                 _ => {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
             },
             Command::CAPA => {
                 if vs.len() != 1 {
-                    return Err(anyhow::anyhow!("invalid request for {}: {}", cmd, v));
+                    return Err(anyhow::anyhow!("invalid request for {}", cmd));
                 }
 
                 Request::CAPA
@@ -1000,7 +1212,9 @@ impl FromStr for Request {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "command", content = "args"))]
 pub enum Response {
     APOP,
     AUTH(AuthResponse),
@@ -1010,10 +1224,15 @@ pub enum Response {
     LIST(ListResponse),
     NOOP,
     PASS(String),
-    QUIT,
+    /// Sign-off text after `+OK`, `None` for a bare `+OK`.
+    QUIT(Option<String>),
     RETR(String),
-    STAT { count: usize, size: usize },
-    RSET,
+    STAT {
+        count: usize,
+        size: usize,
+    },
+    STLS(String),
+    RSET(String),
     TOP(String),
     UIDL(UidlResponse),
     USER(String),
@@ -1023,89 +1242,408 @@ pub enum Response {
 
 /// The first is message id.
 /// The second is message size in bytes.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ListResponse {
     Single(usize, usize),
     All(Vec<(usize, usize)>),
 }
 
+impl ListResponse {
+    /// Count and total size of listed messages, which are the same as the
+    /// reply of STAT if all messages are listed.
+    pub fn to_stat(&self) -> (usize, usize) {
+        match self {
+            ListResponse::Single(_, size) => (1, *size),
+            ListResponse::All(v) => (v.len(), v.iter().map(|(_, size)| size).sum()),
+        }
+    }
+}
+
 /// The first is message id.
 /// The second is message unique id.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum UidlResponse {
     Single(usize, String),
     All(BTreeMap<usize, String>),
 }
 
-#[derive(Debug)]
+/// Check whether the positive response of this request is multi-line.
+pub(crate) fn is_multiline(req: &Request) -> bool {
+    match req {
+        Request::LIST(v) | Request::UIDL(v) => v.is_none(),
+        Request::AUTH(v) => v.is_none(),
+        _ => matches!(req.command(), Command::RETR | Command::TOP | Command::CAPA),
+    }
+}
+
+/// Take the text after the status indicator like `+OK` or `-ERR` of a
+/// status line, the space between them is omitted if there's no text.
+///
+/// Returns `None` if the line is not of `status`.
+fn status_text<'a>(line: &'a str, status: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(status)?;
+    match rest.strip_prefix(' ') {
+        Some(text) => Some(text),
+        None if rest.is_empty() => Some(rest),
+        None => None,
+    }
+}
+
+/// Parse a message number, which starts from 1.
+fn parse_id(v: &str, max: usize) -> std::result::Result<usize, ProtoError> {
+    match parse_integer(v, max)? {
+        0 => Err(ProtoError::InvalidInteger(v.to_string())),
+        id => Ok(id),
+    }
+}
+
+/// Parse a non-negative integer no larger than `max`.
+///
+/// Digits overflowing `usize` are reported as too large instead of
+/// invalid, as the limit of `usize` differs between targets.
+fn parse_integer(v: &str, max: usize) -> std::result::Result<usize, ProtoError> {
+    let too_large = || ProtoError::IntegerTooLarge {
+        value: v.to_string(),
+        max,
+    };
+
+    match usize::from_str(v) {
+        Ok(n) if n <= max => Ok(n),
+        Ok(_) => Err(too_large()),
+        Err(err) if *err.kind() == IntErrorKind::PosOverflow => Err(too_large()),
+        Err(_) => Err(ProtoError::InvalidInteger(v.to_string())),
+    }
+}
+
+/// Max length of a unique-id.
+pub const MAX_UID_LENGTH: usize = 70;
+
+/// Check the unique-id which consists of 1 to 70 characters in the range of
+/// 0x21 to 0x7E.
+pub fn validate_uid(uid: &str) -> std::result::Result<(), ProtoError> {
+    if uid.is_empty()
+        || uid.len() > MAX_UID_LENGTH
+        || !uid.bytes().all(|v| (0x21..=0x7e).contains(&v))
+    {
+        return Err(ProtoError::InvalidUid(uid.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Parse a scan listing like `1 120` sent by LIST into a `MessageMeta`
+/// with id and size only.
+///
+/// RFC 1939 allows nothing after the size, but some servers append more
+/// information separated by a space, which is ignored if `lenient`.
+///
+/// ```
+/// use postman_pop3::parse_scan_listing;
+///
+/// assert_eq!(parse_scan_listing("1 120", false).unwrap().size, 120);
+/// assert!(parse_scan_listing("1 120 extra", false).is_err());
+/// assert_eq!(parse_scan_listing("1 120 extra", true).unwrap().size, 120);
+/// ```
+pub fn parse_scan_listing(
+    line: &str,
+    lenient: bool,
+) -> std::result::Result<MessageMeta, ProtoError> {
+    let err = || ProtoError::InvalidScanListing(line.to_string());
+
+    let mut vs = line.splitn(3, ' ');
+    let id = vs.next().ok_or_else(err)?;
+    let size = vs.next().ok_or_else(err)?;
+    if vs.next().is_some() && !lenient {
+        return Err(err());
+    }
+
+    let id = parse_id(id, usize::MAX).map_err(|_| err())?;
+    let size = match size.bytes().all(|b| b.is_ascii_digit()) {
+        true => usize::from_str(size).map_err(|_| err())?,
+        false => return Err(err()),
+    };
+
+    Ok(MessageMeta::new(id, "", size, ""))
+}
+
+/// Parse a unique-id listing like `1 whqtswO00WBw418f9t5JxYwZ` sent by
+/// UIDL into message number and unique-id.
+pub fn parse_unique_id_listing(line: &str) -> Result<(usize, String)> {
+    let mut vs = line.splitn(2, ' ');
+    let (id, uid) = match (vs.next(), vs.next()) {
+        (Some(id), Some(uid)) => (id, uid),
+        _ => return Err(anyhow::anyhow!("invalid response for UIDL: {}", line)),
+    };
+
+    validate_uid(uid)?;
+    Ok((usize::from_str(id)?, uid.to_string()))
+}
+
+/// Parse lines of a multi-line LIST response after the status line one by
+/// one, so that a huge maildrop is never collected as a whole.
+///
+/// `lines` have no line endings. Iteration stops at the terminator `.`,
+/// or ends with an error if `lines` runs out before that.
+///
+/// ```
+/// use postman_pop3::list_stream;
+///
+/// let mut messages = list_stream("1 120\r\n2 200\r\n.\r\n".split("\r\n"));
+/// assert_eq!(messages.next().unwrap().unwrap().size, 120);
+/// assert_eq!(messages.next().unwrap().unwrap().size, 200);
+/// assert!(messages.next().is_none());
+/// ```
+pub fn list_stream<'a, I>(lines: I) -> impl Iterator<Item = Result<MessageMeta>> + 'a
+where
+    I: IntoIterator<Item = &'a str>,
+    I::IntoIter: 'a,
+{
+    multiline_stream(lines.into_iter()).map(|v| Ok(parse_scan_listing(v?, false)?))
+}
+
+/// Parse lines of a multi-line UIDL response after the status line one by
+/// one into message number and unique-id, like `list_stream`.
+pub fn uidl_stream<'a, I>(lines: I) -> impl Iterator<Item = Result<(usize, String)>> + 'a
+where
+    I: IntoIterator<Item = &'a str>,
+    I::IntoIter: 'a,
+{
+    multiline_stream(lines.into_iter()).map(|v| parse_unique_id_listing(v?))
+}
+
+/// Dot-unstuff lines of a multi-line response until the terminator.
+fn multiline_stream<'a, I>(mut lines: I) -> impl Iterator<Item = Result<&'a str>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut done = false;
+
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        match lines.next() {
+            Some(".") => {
+                done = true;
+                None
+            }
+            Some(v) => Some(Ok(v.strip_prefix('.').unwrap_or(v))),
+            None => {
+                done = true;
+                Some(Err(anyhow::anyhow!(
+                    "multi-line response is not terminated"
+                )))
+            }
+        }
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AuthResponse {
+    /// Supported mechanisms returned by `AUTH` without mechanism.
     All(Vec<String>),
+    /// Server challenge (still base64 encoded) returned as `+ <challenge>`.
+    Challenge(String),
+    /// Authentication succeeded.
+    Success(String),
 }
 
-impl Response {
-    pub fn to_string(&self) -> Result<String> {
-        let mut f = String::new();
-
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Response::APOP | Response::DELE | Response::NOOP | Response::QUIT | Response::RSET => {
-                write!(&mut f, "+OK\r\n")?
-            }
-            Response::GREET(v) | Response::PASS(v) | Response::USER(v) => {
-                write!(&mut f, "+OK {}\r\n", v)?
+            Response::APOP | Response::DELE | Response::NOOP | Response::QUIT(None) => {
+                write!(f, "+OK\r\n")?
             }
+            Response::QUIT(Some(v)) => write!(f, "+OK {}\r\n", v)?,
+            Response::GREET(v)
+            | Response::PASS(v)
+            | Response::RSET(v)
+            | Response::STLS(v)
+            | Response::USER(v) => write!(f, "+OK {}\r\n", v)?,
             Response::RETR(v) | Response::TOP(v) => {
-                write!(&mut f, "+OK\r\n")?;
-                write!(&mut f, "{}", v)?;
-                write!(&mut f, ".\r\n")?
+                write!(f, "+OK\r\n")?;
+                for line in v.split_inclusive('\n') {
+                    write_stuffed_line(f, trim_line_ending(line))?;
+                }
+                write!(f, ".\r\n")?
             }
             Response::AUTH(v) => match v {
                 AuthResponse::All(v) => {
-                    write!(&mut f, "+OK {} auth methods\r\n", v.len())?;
+                    write!(f, "+OK {} auth methods\r\n", v.len())?;
                     for v in v.iter() {
-                        write!(&mut f, "{}\r\n", v)?;
+                        write_stuffed_line(f, v)?;
                     }
-                    write!(&mut f, ".\r\n")?
+                    write!(f, ".\r\n")?
                 }
+                AuthResponse::Challenge(v) => write!(f, "+ {}\r\n", v)?,
+                AuthResponse::Success(v) => write!(f, "+OK {}\r\n", v)?,
             },
             Response::CAPA(v) => {
-                write!(&mut f, "+OK Capability list follows\r\n")?;
+                write!(f, "+OK Capability list follows\r\n")?;
                 for v in v.iter() {
-                    write!(&mut f, "{}\r\n", v)?;
+                    write_stuffed_line(f, v)?;
                 }
-                write!(&mut f, ".\r\n")?
+                write!(f, ".\r\n")?
             }
             Response::LIST(v) => match v {
                 ListResponse::All(messages) => {
-                    write!(&mut f, "+OK {} messages\r\n", messages.len())?;
+                    write!(f, "+OK {} messages\r\n", messages.len())?;
                     for v in messages.iter() {
-                        write!(&mut f, "{} {}\r\n", v.0, v.1)?;
+                        write!(f, "{} {}\r\n", v.0, v.1)?;
                     }
-                    write!(&mut f, ".\r\n")?
+                    write!(f, ".\r\n")?
                 }
-                ListResponse::Single(id, size) => write!(&mut f, "+OK {} {}\r\n", id, size * 8)?,
+                ListResponse::Single(id, size) => write!(f, "+OK {} {}\r\n", id, size)?,
             },
-            Response::STAT { count, size } => write!(&mut f, "+OK {} {}\r\n", count, size)?,
+            Response::STAT { count, size } => write!(f, "+OK {} {}\r\n", count, size)?,
             Response::UIDL(v) => match v {
                 UidlResponse::Single(id, uid) => {
-                    write!(&mut f, "+OK {} {}\r\n", id, uid)?;
+                    write!(f, "+OK {} {}\r\n", id, uid)?;
                 }
                 UidlResponse::All(v) => {
-                    write!(&mut f, "+OK {} mails\r\n", v.len())?;
+                    write!(f, "+OK {} mails\r\n", v.len())?;
                     for (id, uid) in v.iter() {
-                        write!(&mut f, "{} {}\r\n", id, uid)?;
+                        write!(f, "{} {}\r\n", id, uid)?;
                     }
-                    write!(&mut f, ".\r\n")?
+                    write!(f, ".\r\n")?
                 }
             },
 
-            Response::ERR(v) => write!(&mut f, "-ERR {}\r\n", v)?,
+            // Text of ERR may echo input of client, clamp it instead of
+            // failing the whole response.
+            Response::ERR(v) => write!(
+                f,
+                "-ERR {}\r\n",
+                truncate_str(v, MAX_LINE_LENGTH - "-ERR \r\n".len())
+            )?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Response {
+    /// Build the wire form of this response.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = String::new();
+        self.write_into(&mut buf)?;
+        Ok(buf.into_bytes())
+    }
+
+    /// Append the wire form of this response to `buf`, so that a buffer
+    /// could be reused for many responses. Content of `buf` is kept.
+    pub fn write_into(&self, buf: &mut String) -> Result<()> {
+        write!(buf, "{}", self)?;
+        Ok(())
+    }
+
+    #[deprecated(note = "use `Display` or `to_bytes` instead")]
+    pub fn to_string(&self) -> Result<String> {
+        let mut buf = String::new();
+        self.write_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Whether this is a positive response, including continuations of
+    /// AUTH.
+    pub fn is_ok(&self) -> bool {
+        !self.is_err()
+    }
+
+    /// Whether this is a `-ERR` response.
+    pub fn is_err(&self) -> bool {
+        matches!(self, Response::ERR(_))
+    }
+
+    /// Text of a `-ERR` response, `None` if it's positive.
+    pub fn err_message(&self) -> Option<&str> {
+        match self {
+            Response::ERR(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Check that every line of this response fits in `MAX_LINE_LENGTH`.
+    ///
+    /// Bodies of RETR and TOP are not checked, text of ERR is truncated to
+    /// fit instead.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Response::RETR(_) | Response::TOP(_) => Ok(()),
+            v => check_line_lengths(&v.to_bytes()?),
+        }
+    }
+
+    /// Write the response into `w`.
+    ///
+    /// Bodies of RETR and TOP are written line by line with dot-stuffing
+    /// instead of building the whole response in memory. Other responses
+    /// are built first and written by a single `write_all`, so multi-line
+    /// ones like CAPA go out in one write if the socket accepts them all.
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Response::RETR(v) | Response::TOP(v) => {
+                write_retr(v.as_bytes(), w).await?;
+            }
+            v => {
+                // Check the bytes actually sent instead of formatting twice.
+                let buf = v.to_bytes()?;
+                check_line_lengths(&buf)?;
+                w.write_all(&buf).await?
+            }
         }
 
-        Ok(f)
+        Ok(())
+    }
+
+    /// Parse the greeting sent by server after connected, which has no
+    /// related request.
+    ///
+    /// Returns `GREET` with the whole banner text after `+OK`, or `ERR` if
+    /// server refuses the connection.
+    pub fn parse_greeting(v: &str) -> Result<Response> {
+        let line = v
+            .strip_suffix("\r\n")
+            .or_else(|| v.strip_suffix('\n'))
+            .unwrap_or(v);
+
+        let (ok, text) = match (status_text(line, "+OK"), status_text(line, "-ERR")) {
+            (Some(text), _) => (true, text),
+            (_, Some(text)) => (false, text),
+            _ => return Err(anyhow::anyhow!("invalid greeting: {}", v)),
+        };
+
+        if ok {
+            Ok(Response::GREET(text.to_string()))
+        } else {
+            Ok(Response::ERR(text.to_string()))
+        }
     }
 
+    /// Parse the response of `req`.
+    ///
+    /// Text some servers append after the counts of STAT and a single LIST,
+    /// like `+OK 2 320 messages`, is ignored.
     pub fn from_str(content: &str, req: &Request) -> Result<Response> {
-        if !content.starts_with("-ERR") || !content.starts_with("+OK") {
+        Response::parse(content, req, false)
+    }
+
+    /// Parse the response of `req` like `from_str`, but reject any text
+    /// after the counts of STAT and a single LIST.
+    pub fn from_str_strict(content: &str, req: &Request) -> Result<Response> {
+        Response::parse(content, req, true)
+    }
+
+    fn parse(content: &str, req: &Request, strict: bool) -> Result<Response> {
+        // Only AUTH could have a continuation response which starts with `+ `.
+        let is_continuation = matches!(req, Request::AUTH(Some(_))) && content.starts_with('+');
+        if !content.starts_with("-ERR") && !content.starts_with("+OK") && !is_continuation {
             return Err(anyhow::anyhow!(
                 "invalid response for {:?}: {}",
                 req,
@@ -1114,29 +1652,44 @@ impl Response {
         }
 
         if content.starts_with("-ERR") {
-            let v = content.strip_prefix("-ERR ").unwrap();
-            let v = v.strip_suffix("\r\n").unwrap();
+            let line = content.strip_suffix("\r\n").unwrap_or(content);
+            let v = status_text(line, "-ERR")
+                .ok_or_else(|| anyhow::anyhow!("invalid response for {:?}: {}", req, content))?;
 
             return Ok(Response::ERR(v.to_string()));
         }
 
-        let vs: Vec<&str> = content.split("\r\n").filter(|s| !s.is_empty()).collect();
+        let vs: Vec<&str> = content
+            .strip_suffix("\r\n")
+            .unwrap_or(content)
+            .split("\r\n")
+            .collect();
 
-        let cmd = Command::from(req);
+        let cmd = req.command();
         let resp = match cmd {
             Command::USER => {
                 if vs.len() != 1 {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
 
-                Response::USER(vs[0].strip_prefix("+OK ").unwrap().to_string())
+                match status_text(vs[0], "+OK") {
+                    Some(v) => Response::USER(v.to_string()),
+                    None => {
+                        return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
+                    }
+                }
             }
             Command::PASS => {
                 if vs.len() != 1 {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
 
-                Response::PASS(vs[0].strip_prefix("+OK ").unwrap().to_string())
+                match status_text(vs[0], "+OK") {
+                    Some(v) => Response::PASS(v.to_string()),
+                    None => {
+                        return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
+                    }
+                }
             }
             Command::STAT => {
                 if vs.len() != 1 {
@@ -1145,7 +1698,7 @@ impl Response {
 
                 let vs: Vec<&str> = vs[0].split(' ').collect();
 
-                if vs.len() != 3 {
+                if vs.len() < 3 || (strict && vs.len() != 3) {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
 
@@ -1157,22 +1710,10 @@ impl Response {
             Command::UIDL => match req {
                 Request::UIDL(v) => match v {
                     None => {
-                        if vs.len() < 2 {
-                            return Err(anyhow::anyhow!(
-                                "invalid response for {}: {}",
-                                cmd,
-                                content
-                            ));
-                        }
-
                         let mut m = BTreeMap::new();
-                        for v in vs[1..vs.len() - 1].iter() {
-                            let ids: Vec<&str> = v.splitn(2, ' ').collect();
-                            if ids.len() != 2 {
-                                return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, v));
-                            }
-
-                            m.insert(usize::from_str(ids[0])?, ids[1].to_string());
+                        for v in read_multiline(&vs[1..])?.iter() {
+                            let (id, uid) = parse_unique_id_listing(v)?;
+                            m.insert(id, uid);
                         }
 
                         Response::UIDL(UidlResponse::All(m))
@@ -1196,6 +1737,7 @@ impl Response {
                             ));
                         }
 
+                        validate_uid(vs[2])?;
                         Response::UIDL(UidlResponse::Single(
                             usize::from_str(vs[1])?,
                             String::from(vs[2]),
@@ -1209,52 +1751,36 @@ impl Response {
             Command::LIST => match req {
                 Request::LIST(v) => match v {
                     None => {
-                        if vs.len() < 2 {
-                            return Err(anyhow::anyhow!(
-                                "invalid response for {}: {}",
-                                cmd,
-                                content
-                            ));
-                        }
                         let mut messages = Vec::new();
 
-                        for v in vs[1..vs.len() - 1].iter() {
-                            let ids: Vec<&str> = v.splitn(2, ' ').collect();
-                            if ids.len() != 2 {
-                                return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, v));
-                            }
-
-                            messages.push((usize::from_str(ids[0])?, usize::from_str(ids[1])? / 8));
+                        for v in read_multiline(&vs[1..])?.iter() {
+                            let meta = parse_scan_listing(v, false)?;
+                            messages.push((meta.id, meta.size));
                         }
 
                         Response::LIST(ListResponse::All(messages))
                     }
                     Some(_) => {
-                        if vs.len() != 3 {
-                            return Err(anyhow::anyhow!(
-                                "invalid response for {}: {}",
-                                cmd,
-                                content
-                            ));
-                        }
+                        let line = match (vs.len(), status_text(vs[0], "+OK")) {
+                            (1, Some(v)) => v,
+                            _ => {
+                                return Err(anyhow::anyhow!(
+                                    "invalid response for {}: {}",
+                                    cmd,
+                                    content
+                                ));
+                            }
+                        };
 
-                        Response::LIST(ListResponse::Single(
-                            usize::from_str(vs[1])?,
-                            usize::from_str(vs[2])? / 8,
-                        ))
+                        let meta = parse_scan_listing(line, !strict)?;
+                        Response::LIST(ListResponse::Single(meta.id, meta.size))
                     }
                 },
                 _ => {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
             },
-            Command::RETR => {
-                if vs.len() < 2 {
-                    return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
-                }
-
-                Response::RETR(vs[1..vs.len() - 1].join(""))
-            }
+            Command::RETR => Response::RETR(join_lines(&read_multiline(&vs[1..])?)),
             Command::DELE => {
                 if vs.len() != 1 {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
@@ -1274,22 +1800,24 @@ impl Response {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
 
-                Response::RSET
+                Response::RSET(vs[0].trim_start_matches("+OK").trim_start().to_string())
             }
-            Command::QUIT => {
+            Command::STLS => {
                 if vs.len() != 1 {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
 
-                Response::QUIT
+                Response::STLS(vs[0].trim_start_matches("+OK").trim_start().to_string())
             }
-            Command::TOP => {
-                if vs.len() < 2 {
+            Command::QUIT => {
+                if vs.len() != 1 {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
                 }
 
-                Response::TOP(vs[1..vs.len() - 1].join(""))
+                let v = vs[0].trim_start_matches("+OK").trim_start();
+                Response::QUIT(Some(v).filter(|v| !v.is_empty()).map(String::from))
             }
+            Command::TOP => Response::TOP(join_lines(&read_multiline(&vs[1..])?)),
             Command::APOP => {
                 if vs.len() != 1 {
                     return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
@@ -1297,21 +1825,44 @@ impl Response {
 
                 Response::APOP
             }
-            Command::AUTH => {
-                if vs.len() != 1 {
-                    return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
-                }
+            Command::AUTH => match req {
+                Request::AUTH(None) => {
+                    let mut mechanisms = Vec::new();
+                    for v in read_multiline(&vs[1..])?.iter() {
+                        mechanisms.push(v.to_string())
+                    }
 
-                unimplemented!()
-            }
-            Command::CAPA => {
-                if vs.len() < 2 {
-                    return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
+                    Response::AUTH(AuthResponse::All(mechanisms))
                 }
+                _ => {
+                    if vs.len() != 1 {
+                        return Err(anyhow::anyhow!("invalid response for {}: {}", cmd, content));
+                    }
 
+                    match (vs[0].strip_prefix("+OK"), vs[0].strip_prefix('+')) {
+                        (Some(v), _) => {
+                            Response::AUTH(AuthResponse::Success(v.trim_start().to_string()))
+                        }
+                        (_, Some(v)) => {
+                            // Kept encoded, but must be valid base64.
+                            let v = v.trim();
+                            b64::decode(v)?;
+                            Response::AUTH(AuthResponse::Challenge(v.to_string()))
+                        }
+                        _ => {
+                            return Err(anyhow::anyhow!(
+                                "invalid response for {}: {}",
+                                cmd,
+                                content
+                            ));
+                        }
+                    }
+                }
+            },
+            Command::CAPA => {
                 let mut caps = Vec::new();
 
-                for v in vs[1..vs.len() - 1].iter() {
+                for v in read_multiline(&vs[1..])?.iter() {
                     caps.push(v.to_string())
                 }
 
@@ -1323,7 +1874,8 @@ impl Response {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum State {
     AUTHORIZATION,
     TRANSACTION,
@@ -1338,6 +1890,27 @@ pub enum State {
     UPDATE,
 }
 
+/// AuthType is the way to authenticate a POP3 session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AuthType {
+    /// USER and PASS commands.
+    #[cfg_attr(feature = "serde", serde(rename = "user"))]
+    UserPass,
+    /// APOP command.
+    #[cfg_attr(feature = "serde", serde(rename = "apop"))]
+    Apop,
+    /// AUTH command with SASL PLAIN mechanism.
+    #[cfg_attr(feature = "serde", serde(rename = "plain"))]
+    SaslPlain,
+    /// AUTH command with SASL LOGIN mechanism.
+    #[cfg_attr(feature = "serde", serde(rename = "login"))]
+    SaslLogin,
+    /// AUTH command with SASL CRAM-MD5 mechanism.
+    #[cfg_attr(feature = "serde", serde(rename = "cram-md5"))]
+    SaslCramMd5,
+}
+
 /// MessageMeta stores related message metadata.
 ///
 /// `status` is the message's current committed status
@@ -1347,7 +1920,10 @@ pub enum State {
 /// - `next_status` is Some means the message's status has been updated, `status` could
 ///   be replace be `next_status` is user send `QUIT` or dropped if user close the
 ///   connection or send `REST`
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Metas are ordered by `id` first, as `id` is the first field.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MessageMeta {
     pub id: usize,
     pub uid: String,
@@ -1358,6 +1934,17 @@ pub struct MessageMeta {
 }
 
 impl MessageMeta {
+    pub fn new(id: usize, uid: &str, size: usize, path: &str) -> MessageMeta {
+        MessageMeta {
+            id,
+            uid: uid.to_string(),
+            size,
+            path: path.to_string(),
+            status: MessageStatus::default(),
+            next_status: None,
+        }
+    }
+
     pub fn is_fetched(&self) -> bool {
         match self.next_status {
             None => self.status.fetched,
@@ -1372,29 +1959,844 @@ impl MessageMeta {
     }
 
     pub fn set_fetched(&mut self) {
-        self.next_status.unwrap_or_default().fetched = true
+        self.next_status.get_or_insert(self.status).fetched = true
     }
     pub fn set_deleted(&mut self) {
-        self.next_status.unwrap_or_default().deleted = true
+        self.next_status.get_or_insert(self.status).deleted = true
+    }
+    /// Drop all changes made in current session.
+    pub fn reset(&mut self) {
+        self.next_status = None
     }
 }
 
+#[cfg(feature = "serde")]
 impl From<sled::IVec> for MessageMeta {
     fn from(v: IVec) -> Self {
         bincode::deserialize(v.as_ref()).expect("deserialize MessageMeta failed")
     }
 }
 
-impl Into<sled::IVec> for MessageMeta {
-    fn into(self) -> IVec {
-        IVec::from(bincode::serialize(&self).expect("serialize MessageMeta failed"))
+#[cfg(feature = "serde")]
+impl From<MessageMeta> for sled::IVec {
+    fn from(v: MessageMeta) -> Self {
+        IVec::from(bincode::serialize(&v).expect("serialize MessageMeta failed"))
     }
 }
 
-#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MessageStatus {
     // Whether or not this message has been fetched by client.
     pub fetched: bool,
     // Whether or not his message has been deleted by client.
     pub deleted: bool,
 }
+
+/// Calculate the exact size of a message in octets.
+///
+/// POP3 transmits messages with CRLF line endings, so bare LF line endings
+/// and the missing line ending of the last line are counted as CRLF.
+pub fn message_octet_size(raw: &[u8]) -> usize {
+    let mut size = raw.len();
+
+    for (i, v) in raw.iter().enumerate() {
+        if *v == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            size += 1;
+        }
+    }
+    // A CR ending the last line is taken as a truncated CRLF, like
+    // `normalize_line_endings` and `write_body` do.
+    if raw.ends_with(b"\r") {
+        size += 1;
+    } else if !raw.is_empty() && !raw.ends_with(b"\n") {
+        size += 2;
+    }
+
+    size
+}
+
+/// Convert bare LF line endings of a message into CRLF, and end the last
+/// line with CRLF if missing. Existing CRLFs are kept as is.
+///
+/// The result is the message sent on the wire before dot-stuffing, whose
+/// length is `message_octet_size`. Content already normalized is returned
+/// without copying.
+pub fn normalize_line_endings(raw: Vec<u8>) -> Vec<u8> {
+    let size = message_octet_size(&raw);
+    if size == raw.len() {
+        return raw;
+    }
+
+    let mut v = Vec::with_capacity(size);
+    for line in raw.split_inclusive(|v| *v == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        v.extend_from_slice(content);
+        v.extend_from_slice(b"\r\n");
+    }
+
+    v
+}
+
+/// Write a multi-line body read from `r` into `w`, and then the terminator.
+///
+/// Lines starting with `.` will be dot-stuffed, and all lines will be ended
+/// with CRLF. Writing a large body yields periodically, so that a body
+/// always ready to be read and a client always ready to receive can't
+/// starve other tasks.
+///
+/// Returns octets of the body with CRLF line endings, dot-stuffing and the
+/// terminator are not counted.
+pub async fn write_body<R, W>(r: R, w: &mut W) -> Result<usize>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut r = BufReader::new(r);
+    let mut line = Vec::new();
+    let mut unyielded = 0;
+    let mut written = 0;
+
+    loop {
+        line.clear();
+        if r.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+
+        unyielded += line.len();
+        if unyielded >= WRITE_BUFFER_SIZE {
+            unyielded = 0;
+            // The output is `()`, tokio marks it must_use by mistake.
+            let _ = tokio::task::yield_now().await;
+        }
+
+        if line.starts_with(b".") {
+            w.write_all(b".").await?;
+        }
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        w.write_all(content).await?;
+        w.write_all(b"\r\n").await?;
+        written += content.len() + 2;
+    }
+    w.write_all(b".\r\n").await?;
+
+    Ok(written)
+}
+
+/// Write the response of RETR or TOP whose body is read from `r` into `w`,
+/// the body is streamed by `write_body` instead of buffered as a whole.
+///
+/// Returns octets of the body like `write_body`.
+pub async fn write_retr<R, W>(r: R, w: &mut W) -> Result<usize>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut w = BufWriter::with_capacity(WRITE_BUFFER_SIZE, w);
+    w.write_all(b"+OK\r\n").await?;
+    let n = write_body(r, &mut w).await?;
+    w.flush().await?;
+
+    Ok(n)
+}
+
+/// Read lines of a multi-line response after the status line.
+///
+/// Lines are dot-unstuffed, and the terminator `.` must be the last line.
+pub fn read_multiline(lines: &[&str]) -> Result<Vec<String>> {
+    let mut vs = Vec::with_capacity(lines.len());
+
+    for (i, v) in lines.iter().enumerate() {
+        if *v == "." {
+            if i != lines.len() - 1 {
+                return Err(anyhow::anyhow!("unexpected lines after terminator"));
+            }
+            return Ok(vs);
+        }
+
+        vs.push(v.strip_prefix('.').unwrap_or(v).to_string());
+    }
+
+    Err(anyhow::anyhow!("multi-line response is not terminated"))
+}
+
+/// Write a line of a multi-line response, a line starting with `.` is
+/// dot-stuffed so that it won't be taken as the terminator.
+fn write_stuffed_line(f: &mut Formatter<'_>, line: &str) -> std::fmt::Result {
+    if line.starts_with('.') {
+        f.write_char('.')?;
+    }
+    write!(f, "{}\r\n", line)
+}
+
+/// Join lines of a body with CRLF line endings.
+fn join_lines(lines: &[String]) -> String {
+    lines.iter().map(|v| format!("{}\r\n", v)).collect()
+}
+
+/// Check that every line of `buf` fits in `MAX_LINE_LENGTH`.
+fn check_line_lengths(buf: &[u8]) -> Result<()> {
+    for line in buf.split_inclusive(|v| *v == b'\n') {
+        if line.len() > MAX_LINE_LENGTH {
+            return Err(anyhow::anyhow!(
+                "response line exceeds {} octets: {:?}",
+                MAX_LINE_LENGTH,
+                String::from_utf8_lossy(line)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Truncate `v` to at most `max` octets without splitting a character.
+fn truncate_str(v: &str, max: usize) -> &str {
+    if v.len() <= max {
+        return v;
+    }
+    let mut end = max;
+    while !v.is_char_boundary(end) {
+        end -= 1;
+    }
+    &v[..end]
+}
+
+fn trim_line_ending(v: &str) -> &str {
+    let v = v.strip_suffix('\n').unwrap_or(v);
+    v.strip_suffix('\r').unwrap_or(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// CountingWriter counts writes, which are syscalls on a socket.
+    #[derive(Default)]
+    struct CountingWriter {
+        buf: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.buf.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn write_multiline_once() -> Result<()> {
+        let caps = (0..20).map(|i| format!("X-CAP-{}", i)).collect();
+        let resp = Response::CAPA(caps);
+
+        let mut w = CountingWriter::default();
+        resp.write_to(&mut w).await?;
+        assert_eq!(w.writes, 1);
+        assert_eq!(w.buf, resp.to_bytes()?);
+
+        let resp = Response::AUTH(AuthResponse::All(vec!["PLAIN".to_string(); 20]));
+        let mut w = CountingWriter::default();
+        resp.write_to(&mut w).await?;
+        assert_eq!(w.writes, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_retr() -> Result<()> {
+        let resp = Response::RETR("Subject: a\n\n.hello\r\nworld".to_string());
+        let expected = "+OK\r\nSubject: a\r\n\r\n..hello\r\nworld\r\n.\r\n";
+
+        let mut buf = Vec::new();
+        resp.write_to(&mut buf).await?;
+        assert_eq!(String::from_utf8(buf)?, expected);
+        assert_eq!(format!("{}", resp), expected);
+
+        // Streamed from a reader.
+        let mut buf = Vec::new();
+        let n = super::write_retr(&b"Subject: a\n\n.hello\r\nworld"[..], &mut buf).await?;
+        assert_eq!(String::from_utf8(buf)?, expected);
+        assert_eq!(n, "Subject: a\r\n\r\n.hello\r\nworld\r\n".len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_top() -> Result<()> {
+        let cases = vec![
+            // Headers only, the blank line is kept.
+            ("Subject: a\r\n\r\n", "+OK\r\nSubject: a\r\n\r\n.\r\n"),
+            // Body of a single `.` line is dot-stuffed.
+            (
+                "Subject: a\r\n\r\n.\r\n",
+                "+OK\r\nSubject: a\r\n\r\n..\r\n.\r\n",
+            ),
+            // No headers at all.
+            ("\r\n", "+OK\r\n\r\n.\r\n"),
+            ("", "+OK\r\n.\r\n"),
+        ];
+        for (body, expected) in cases {
+            let resp = Response::TOP(body.to_string());
+
+            let mut buf = Vec::new();
+            resp.write_to(&mut buf).await?;
+            assert_eq!(String::from_utf8(buf)?, expected);
+            assert_eq!(format!("{}", resp), expected);
+
+            let req = Request::TOP { id: 1, lines: 0 };
+            assert_eq!(Response::from_str(expected, &req)?, resp, "{:?}", expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_ending() -> Result<()> {
+        assert_eq!(Request::from_str("STAT\r\n")?, Request::STAT);
+        assert_eq!(Request::from_str("STAT\n")?, Request::STAT);
+        assert_eq!(Request::from_str("RETR 1\n")?, Request::RETR(1));
+        assert!(Request::from_str("STAT").is_err());
+
+        assert_eq!(Request::from_str_strict("STAT\r\n")?, Request::STAT);
+        assert!(Request::from_str_strict("STAT\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_hides_arguments() {
+        // Errors are echoed to clients and logged, secrets must not leak.
+        let err = Request::from_str_strict("PASS s3cret\n").unwrap_err();
+        assert_eq!(err.to_string(), "request must end with CRLF");
+        let err = Request::from_str("APOP mrose s3cret extra\r\n").unwrap_err();
+        assert_eq!(err.to_string(), "invalid request for APOP");
+    }
+
+    #[test]
+    fn greeting() -> Result<()> {
+        assert_eq!(
+            Response::parse_greeting("+OK POP3 server ready\r\n")?,
+            Response::GREET("POP3 server ready".to_string())
+        );
+        assert_eq!(
+            Response::parse_greeting(
+                "+OK POP3 server ready <1896.697170952@dbc.mtview.ca.us>\r\n"
+            )?,
+            Response::GREET("POP3 server ready <1896.697170952@dbc.mtview.ca.us>".to_string())
+        );
+        assert_eq!(
+            Response::parse_greeting("+OK\r\n")?,
+            Response::GREET(String::new())
+        );
+        assert_eq!(
+            Response::parse_greeting("-ERR [SYS/TEMP] too many connections\r\n")?,
+            Response::ERR("[SYS/TEMP] too many connections".to_string())
+        );
+        assert!(Response::parse_greeting("+OKAY\r\n").is_err());
+        assert!(Response::parse_greeting("* OK IMAP4rev1\r\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn all_commands() -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for cmd in Command::all() {
+            // Fails to compile if a new command is not handled here, add it
+            // to `Command::all` as well.
+            match cmd {
+                Command::USER
+                | Command::PASS
+                | Command::STAT
+                | Command::UIDL
+                | Command::LIST
+                | Command::RETR
+                | Command::DELE
+                | Command::NOOP
+                | Command::RSET
+                | Command::QUIT
+                | Command::APOP
+                | Command::TOP
+                | Command::AUTH
+                | Command::CAPA
+                | Command::STLS => {}
+            }
+
+            assert!(seen.insert(*cmd), "{} is duplicated", cmd);
+            assert_eq!(Command::from_str(&cmd.to_string())?, *cmd);
+        }
+        assert_eq!(seen.len(), 15);
+
+        let req = Request::TOP { id: 1, lines: 0 };
+        assert_eq!(req.command(), Command::TOP);
+        assert_eq!(Command::from(&req), req.command());
+
+        Ok(())
+    }
+
+    #[test]
+    fn arity() {
+        for cmd in Command::all() {
+            let (min, max) = cmd.arity();
+            for n in 0..5 {
+                let line = format!("{}{}\r\n", cmd, " 1".repeat(n));
+                let allowed = n >= min && max.is_none_or(|max| n <= max);
+                assert_eq!(Request::from_str(&line).is_ok(), allowed, "{:?}", line);
+            }
+        }
+
+        // The rest of PASS is the password.
+        assert_eq!(
+            Request::from_str("PASS a b\r\n").unwrap(),
+            Request::PASS("a b".to_string())
+        );
+        assert!(Request::from_str("PASS  \r\n").is_err());
+    }
+
+    #[test]
+    fn uid() -> Result<()> {
+        validate_uid("whqtswO00WBw418f9t5JxYwZ")?;
+        validate_uid(&"a".repeat(70))?;
+        assert_eq!(
+            validate_uid(&"a".repeat(71)),
+            Err(ProtoError::InvalidUid("a".repeat(71)))
+        );
+        assert!(validate_uid("a b").is_err());
+        assert!(validate_uid("").is_err());
+
+        assert!(Response::from_str("+OK\r\n1 a b\r\n.\r\n", &Request::UIDL(None)).is_err());
+        assert!(Response::from_str(
+            &format!("+OK 1 {}\r\n", "a".repeat(71)),
+            &Request::UIDL(Some(1))
+        )
+        .is_err());
+        assert_eq!(
+            Response::from_str("+OK 1 abc\r\n", &Request::UIDL(Some(1)))?,
+            Response::UIDL(UidlResponse::Single(1, "abc".to_string()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn control_characters() {
+        for v in &[
+            "USER post\0man\r\n",
+            "PASS s3\x1bcret\r\n",
+            "USER a\tb\r\n",
+            "NOOP\x7f\r\n",
+        ] {
+            let err = Request::from_str(v).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<ProtoError>(),
+                    Some(ProtoError::Malformed(_))
+                ),
+                "{:?}",
+                v
+            );
+        }
+
+        assert_eq!(
+            Request::from_str("USER post\0man\r\n")
+                .unwrap_err()
+                .to_string(),
+            "malformed request: control character 0x00"
+        );
+        // Non-ASCII is not a control character.
+        assert!(Request::from_str("USER pöstman\r\n").is_ok());
+    }
+
+    #[test]
+    fn status_without_text() -> Result<()> {
+        let req = Request::USER("postman".to_string());
+        assert_eq!(
+            Response::from_str("+OK\r\n", &req)?,
+            Response::USER(String::new())
+        );
+        assert_eq!(
+            Response::from_str("+OK hi\r\n", &req)?,
+            Response::USER("hi".to_string())
+        );
+        assert!(Response::from_str("+OKAY\r\n", &req).is_err());
+
+        assert_eq!(
+            Response::from_str("-ERR\r\n", &req)?,
+            Response::ERR(String::new())
+        );
+        assert_eq!(
+            Response::from_str("-ERR\r\n", &Request::NOOP)?,
+            Response::ERR(String::new())
+        );
+        assert_eq!(
+            Response::from_str("+OK\r\n", &Request::PASS("s3cret".to_string()))?,
+            Response::PASS(String::new())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stream_listing() -> Result<()> {
+        let content: String = (1..=10_000)
+            .map(|i| format!("{} {}\r\n", i, i * 10))
+            .collect();
+        let content = content + ".\r\n";
+
+        // Only the last message is kept while iterating.
+        let (n, last) = list_stream(content.split("\r\n"))
+            .try_fold((0, None), |(n, _), v| v.map(|v| (n + 1, Some(v))))?;
+        assert_eq!(n, 10_000);
+        assert_eq!(last.map(|v| (v.id, v.size)), Some((10_000, 100_000)));
+
+        let uids: Vec<_> = uidl_stream(vec!["1 a", "2 b", ".", "3 c"]).collect::<Result<_>>()?;
+        assert_eq!(uids, vec![(1, "a".to_string()), (2, "b".to_string())]);
+
+        let mut messages = list_stream(vec!["1 120", "x"]);
+        assert!(messages.next().unwrap().is_ok());
+        assert!(messages.next().unwrap().is_err());
+        assert!(list_stream(vec!["1 120"]).nth(1).unwrap().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_listing() -> Result<()> {
+        for lenient in [false, true].iter() {
+            let meta = parse_scan_listing("1 120", *lenient)?;
+            assert_eq!((meta.id, meta.size), (1, 120));
+        }
+        assert_eq!(
+            parse_scan_listing("1 120 extra", false),
+            Err(ProtoError::InvalidScanListing("1 120 extra".to_string()))
+        );
+        let meta = parse_scan_listing("1 120 extra", true)?;
+        assert_eq!((meta.id, meta.size), (1, 120));
+
+        for v in &["", "1", "0 120", "1 -120", "1 +120", "1  120", "a 120"] {
+            assert!(parse_scan_listing(v, true).is_err(), "{:?}", v);
+        }
+
+        assert_eq!(
+            Response::from_str("+OK 2 200\r\n", &Request::LIST(Some(2)))?,
+            Response::LIST(ListResponse::Single(2, 200))
+        );
+        assert!(Response::from_str("+OK\r\n1 120 extra\r\n.\r\n", &Request::LIST(None)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_text() -> Result<()> {
+        let stat = Response::STAT {
+            count: 2,
+            size: 320,
+        };
+        assert_eq!(
+            Response::from_str("+OK 2 320 extra\r\n", &Request::STAT)?,
+            stat
+        );
+        assert_eq!(
+            Response::from_str("+OK 2 320 messages (320 octets)\r\n", &Request::STAT)?,
+            stat
+        );
+        assert_eq!(
+            Response::from_str_strict("+OK 2 320\r\n", &Request::STAT)?,
+            stat
+        );
+        assert!(Response::from_str_strict("+OK 2 320 extra\r\n", &Request::STAT).is_err());
+        assert!(Response::from_str("+OK 2\r\n", &Request::STAT).is_err());
+        assert!(Response::from_str("+OK 2 x extra\r\n", &Request::STAT).is_err());
+
+        let req = Request::LIST(Some(2));
+        assert_eq!(
+            Response::from_str("+OK 2 200 extra\r\n", &req)?,
+            Response::LIST(ListResponse::Single(2, 200))
+        );
+        assert!(Response::from_str_strict("+OK 2 200 extra\r\n", &req).is_err());
+
+        assert_eq!(ListResponse::Single(2, 200).to_stat(), (1, 200));
+        assert_eq!(
+            ListResponse::All(vec![(1, 120), (3, 200)]).to_stat(),
+            (2, 320)
+        );
+        assert_eq!(ListResponse::All(vec![]).to_stat(), (0, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn multiline() -> Result<()> {
+        assert_eq!(read_multiline(&["a", "..b", "", "."])?, vec!["a", ".b", ""]);
+        assert!(read_multiline(&["a", "b"]).is_err());
+        assert!(read_multiline(&[]).is_err());
+
+        let content = "+OK\r\nSubject: a\r\n\r\n..hello\r\n.\r\n";
+        assert_eq!(
+            Response::from_str(content, &Request::RETR(1))?,
+            Response::RETR("Subject: a\r\n\r\n.hello\r\n".to_string())
+        );
+        assert!(Response::from_str("+OK\r\nSubject: a\r\n", &Request::RETR(1)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn display() -> Result<()> {
+        let req: Request = "USER postman\r\n".parse()?;
+        assert_eq!(req, Request::USER("postman".to_string()));
+        assert_eq!(format!("{}", req), "USER postman\r\n");
+        assert_eq!(req.to_bytes()?, b"USER postman\r\n");
+        assert_eq!(
+            format!(
+                "{}",
+                Response::STAT {
+                    count: 2,
+                    size: 320
+                }
+            ),
+            "+OK 2 320\r\n"
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() -> Result<()> {
+        let resp = Response::STAT {
+            count: 2,
+            size: 320,
+        };
+
+        let v = serde_json::to_string(&resp)?;
+        assert_eq!(v, r#"{"command":"STAT","args":{"count":2,"size":320}}"#);
+        assert_eq!(serde_json::from_str::<Response>(&v)?, resp);
+
+        let req = Request::USER("postman".to_string());
+        let v = serde_json::to_string(&req)?;
+        assert_eq!(serde_json::from_str::<Request>(&v)?, req);
+
+        Ok(())
+    }
+
+    #[test]
+    fn status() {
+        let positive = vec![
+            Response::APOP,
+            Response::AUTH(AuthResponse::All(vec!["PLAIN".to_string()])),
+            Response::AUTH(AuthResponse::Challenge(String::new())),
+            Response::AUTH(AuthResponse::Success(String::new())),
+            Response::CAPA(vec!["TOP".to_string()]),
+            Response::DELE,
+            Response::GREET("POP3 server ready".to_string()),
+            Response::LIST(ListResponse::Single(1, 120)),
+            Response::LIST(ListResponse::All(vec![(1, 120)])),
+            Response::NOOP,
+            Response::PASS(String::new()),
+            Response::QUIT(None),
+            Response::QUIT(Some("signing off".to_string())),
+            Response::RETR("Subject: a\r\n".to_string()),
+            Response::STAT {
+                count: 1,
+                size: 120,
+            },
+            Response::STLS(String::new()),
+            Response::RSET(String::new()),
+            Response::TOP("Subject: a\r\n".to_string()),
+            Response::UIDL(UidlResponse::Single(1, "a".to_string())),
+            Response::UIDL(UidlResponse::All(BTreeMap::new())),
+            Response::USER(String::new()),
+        ];
+        for resp in positive {
+            assert!(resp.is_ok(), "{:?}", resp);
+            assert!(!resp.is_err(), "{:?}", resp);
+            assert_eq!(resp.err_message(), None, "{:?}", resp);
+        }
+
+        let resp = Response::ERR("no such message".to_string());
+        assert!(!resp.is_ok());
+        assert!(resp.is_err());
+        assert_eq!(resp.err_message(), Some("no such message"));
+        assert_eq!(Response::ERR(String::new()).err_message(), Some(""));
+    }
+
+    #[test]
+    fn rset() -> Result<()> {
+        assert_eq!(
+            Response::from_str(
+                "+OK maildrop has 2 messages (320 octets)\r\n",
+                &Request::RSET
+            )?,
+            Response::RSET("maildrop has 2 messages (320 octets)".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_into() -> Result<()> {
+        let mut buf = String::from("previous\r\n");
+        Request::RETR(1).write_into(&mut buf)?;
+        Response::RETR("a\r\n.b\r\n".to_string()).write_into(&mut buf)?;
+        Response::DELE.write_into(&mut buf)?;
+        assert_eq!(buf, "previous\r\nRETR 1\r\n+OK\r\na\r\n..b\r\n.\r\n+OK\r\n");
+
+        // Reused after cleared.
+        buf.clear();
+        Request::NOOP.write_into(&mut buf)?;
+        assert_eq!(buf, "NOOP\r\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn quit() -> Result<()> {
+        let cases = vec![
+            ("+OK\r\n", Response::QUIT(None)),
+            (
+                "+OK dewey POP3 server signing off (maildrop empty)\r\n",
+                Response::QUIT(Some(
+                    "dewey POP3 server signing off (maildrop empty)".to_string(),
+                )),
+            ),
+        ];
+        for (content, expect) in cases {
+            assert_eq!(Response::from_str(content, &Request::QUIT)?, expect);
+            assert_eq!(format!("{}", expect), content);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn message_id() -> Result<()> {
+        let cases = vec![
+            ("RETR 0\r\n", "0"),
+            ("DELE abc\r\n", "abc"),
+            ("LIST -1\r\n", "-1"),
+            ("UIDL 0\r\n", "0"),
+            ("TOP 0 1\r\n", "0"),
+            ("TOP 1 x\r\n", "x"),
+        ];
+        for (line, token) in cases {
+            let err = Request::from_str(line).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ProtoError>(),
+                Some(&ProtoError::InvalidInteger(token.to_string())),
+                "{}",
+                line
+            );
+        }
+
+        assert_eq!(
+            Request::from_str("TOP 1 0\r\n")?,
+            Request::TOP { id: 1, lines: 0 }
+        );
+        assert_eq!(Request::from_str("RETR 1\r\n")?, Request::RETR(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_many() {
+        let (reqs, rest, err) = Request::parse_many("");
+        assert!(reqs.is_empty() && rest.is_empty() && err.is_none());
+
+        let (reqs, rest, err) = Request::parse_many("STAT\r\n");
+        assert_eq!(reqs, vec![Request::STAT]);
+        assert!(rest.is_empty() && err.is_none());
+
+        let (reqs, rest, err) = Request::parse_many("RET");
+        assert!(reqs.is_empty() && err.is_none());
+        assert_eq!(rest, "RET");
+
+        let (reqs, rest, err) = Request::parse_many("RETR 1\r\nSTAT\r\n");
+        assert_eq!(reqs, vec![Request::RETR(1), Request::STAT]);
+        assert!(rest.is_empty() && err.is_none());
+
+        let (reqs, rest, err) = Request::parse_many("RETR 1\r\nDELE 1\r\nQU");
+        assert_eq!(reqs, vec![Request::RETR(1), Request::DELE(1)]);
+        assert_eq!(rest, "QU");
+        assert!(err.is_none());
+
+        // Stops at the malformed line.
+        let (reqs, rest, err) = Request::parse_many("NOOP\r\nRETR x\r\nSTAT\r\nQU");
+        assert_eq!(reqs, vec![Request::NOOP]);
+        assert_eq!(rest, "STAT\r\nQU");
+        assert!(err.is_some());
+    }
+
+    #[test]
+    fn integer_too_large() -> Result<()> {
+        let cases = vec![
+            ("RETR 18446744073709551616\r\n", "18446744073709551616"),
+            ("DELE 10000001\r\n", "10000001"),
+            ("TOP 1 99999999999999999999\r\n", "99999999999999999999"),
+            ("TOP 1 10000001\r\n", "10000001"),
+        ];
+        for (line, token) in cases {
+            let err = Request::from_str(line).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ProtoError>(),
+                Some(&ProtoError::IntegerTooLarge {
+                    value: token.to_string(),
+                    max: MAX_INTEGER
+                }),
+                "{}",
+                line
+            );
+        }
+
+        assert_eq!(
+            Request::from_str("TOP 10000000 10000000\r\n")?,
+            Request::TOP {
+                id: MAX_INTEGER,
+                lines: MAX_INTEGER
+            }
+        );
+        assert!(Request::from_str_with_max_integer("RETR 101\r\n", 100).is_err());
+        assert_eq!(
+            Request::from_str_with_max_integer("RETR 100\r\n", 100)?,
+            Request::RETR(100)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Response::GREET("a".repeat(600)).validate().is_err());
+        assert!(Response::GREET("a".repeat(500)).validate().is_ok());
+        assert!(Response::CAPA(vec!["TOP".to_string(), "X".repeat(600)])
+            .validate()
+            .is_err());
+        assert!(Response::RETR(format!("{}\r\n", "a".repeat(600)))
+            .validate()
+            .is_ok());
+        // Echoed input is truncated instead of failing the session.
+        let err = Response::ERR(format!("unknown command {}", "é".repeat(300)));
+        assert!(err.validate().is_ok());
+        let buf = err.to_bytes().unwrap();
+        assert!(buf.len() <= MAX_LINE_LENGTH);
+        assert!(buf.ends_with(b"\r\n"));
+        assert!(String::from_utf8(buf).is_ok());
+    }
+}
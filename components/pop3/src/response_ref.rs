@@ -0,0 +1,153 @@
+use anyhow::Result;
+
+use crate::proto::is_multiline;
+use crate::{Request, Response};
+
+/// ResponseRef is a response borrowed from the buffer it's read into.
+///
+/// Nothing is decoded or copied, the body of a multi-line response is kept
+/// dot-stuffed so that it could be forwarded as is. Use `to_owned` to get
+/// the parsed `Response`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseRef<'a> {
+    req: &'a Request,
+    raw: &'a str,
+    status: Status,
+    /// Length of the status line including the CRLF.
+    status_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Err,
+    Continuation,
+}
+
+impl<'a> ResponseRef<'a> {
+    /// Check the whole response of `req` in `raw` and borrow it.
+    ///
+    /// `raw` should end with the CRLF of the status line, or the `.CRLF`
+    /// terminator if it's a multi-line response.
+    pub fn parse(raw: &'a str, req: &'a Request) -> Result<ResponseRef<'a>> {
+        let err = || anyhow::anyhow!("invalid response for {:?}: {}", req, raw);
+
+        let status_len = raw.find("\r\n").ok_or_else(err)? + 2;
+        let status = if raw.starts_with("+OK") {
+            Status::Ok
+        } else if raw.starts_with("-ERR") {
+            Status::Err
+        } else if raw.starts_with('+') && matches!(req, Request::AUTH(Some(_))) {
+            Status::Continuation
+        } else {
+            return Err(err());
+        };
+
+        let rest = &raw[status_len..];
+        let complete = if status == Status::Ok && is_multiline(req) {
+            rest == ".\r\n" || rest.ends_with("\r\n.\r\n")
+        } else {
+            rest.is_empty()
+        };
+        if !complete {
+            return Err(err());
+        }
+
+        Ok(ResponseRef {
+            req,
+            raw,
+            status,
+            status_len,
+        })
+    }
+
+    /// Whether this is a `+OK` response.
+    pub fn is_ok(&self) -> bool {
+        self.status == Status::Ok
+    }
+
+    /// Whether this is a `-ERR` response.
+    pub fn is_err(&self) -> bool {
+        self.status == Status::Err
+    }
+
+    /// Whether this is a `+ ` continuation of AUTH.
+    pub fn is_continuation(&self) -> bool {
+        self.status == Status::Continuation
+    }
+
+    /// Text of the status line after the status indicator.
+    pub fn status(&self) -> &'a str {
+        let line = &self.raw[..self.status_len - 2];
+        let indicator = match self.status {
+            Status::Ok => "+OK",
+            Status::Err => "-ERR",
+            Status::Continuation => "+",
+        };
+
+        let text = &line[indicator.len()..];
+        text.strip_prefix(' ').unwrap_or(text)
+    }
+
+    /// Dot-stuffed lines of a multi-line response, without the terminating
+    /// `.CRLF`.
+    pub fn body(&self) -> Option<&'a str> {
+        if self.status != Status::Ok || !is_multiline(self.req) {
+            return None;
+        }
+
+        Some(&self.raw[self.status_len..self.raw.len() - 3])
+    }
+
+    /// The whole response in wire format.
+    pub fn as_str(&self) -> &'a str {
+        self.raw
+    }
+
+    /// Parse into an owned `Response`.
+    pub fn to_owned(&self) -> Result<Response> {
+        Response::from_str(self.raw, self.req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let req = Request::RETR(1);
+        let raw = "+OK 12 octets\r\nSubject: a\r\n\r\n..hello\r\n.\r\n";
+        let resp = ResponseRef::parse(raw, &req)?;
+        assert!(resp.is_ok());
+        assert_eq!(resp.status(), "12 octets");
+        assert_eq!(resp.body(), Some("Subject: a\r\n\r\n..hello\r\n"));
+        assert_eq!(resp.as_str(), raw);
+        assert_eq!(
+            resp.to_owned()?,
+            Response::RETR("Subject: a\r\n\r\n.hello\r\n".to_string())
+        );
+
+        let resp = ResponseRef::parse("+OK\r\n.\r\n", &req)?;
+        assert_eq!(resp.status(), "");
+        assert_eq!(resp.body(), Some(""));
+
+        let resp = ResponseRef::parse("-ERR no such message\r\n", &req)?;
+        assert!(resp.is_err());
+        assert_eq!(resp.status(), "no such message");
+        assert_eq!(resp.body(), None);
+
+        let req = Request::AUTH(Some("PLAIN".to_string()));
+        let resp = ResponseRef::parse("+ \r\n", &req)?;
+        assert!(resp.is_continuation());
+        assert_eq!(resp.status(), "");
+
+        // Incomplete responses.
+        assert!(ResponseRef::parse("+OK\r\nSubject: a\r\n", &Request::RETR(1)).is_err());
+        assert!(ResponseRef::parse("+OK", &Request::NOOP).is_err());
+        assert!(ResponseRef::parse("+OK\r\nextra\r\n", &Request::NOOP).is_err());
+        assert!(ResponseRef::parse("+ \r\n", &Request::NOOP).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,561 @@
+//! SASL mechanisms used by the POP3 AUTH command.
+//!
+//! Refer to [RFC 5034](https://tools.ietf.org/html/rfc5034) for how SASL
+//! works in POP3.
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use md5::Md5;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{b64, AuthResponse, Client, ErrResponse, ProtoError, Request, Response};
+
+/// Encode credentials for the PLAIN mechanism described in
+/// [RFC 4616](https://tools.ietf.org/html/rfc4616).
+///
+/// The message is `[authzid] NUL authcid NUL passwd` encoded by base64.
+pub fn plain_encode(authzid: Option<&str>, authcid: &str, passwd: &str) -> String {
+    let v = format!("{}\0{}\0{}", authzid.unwrap_or_default(), authcid, passwd);
+
+    b64::encode(v)
+}
+
+/// Decode credentials for the PLAIN mechanism into `(authzid, authcid, passwd)`.
+///
+/// An empty authzid will be returned as `None`.
+pub fn plain_decode(v: &str) -> Result<(Option<String>, String, String)> {
+    let v = decode_str(v)?;
+
+    let vs: Vec<&str> = v.split('\0').collect();
+    if vs.len() != 3 || vs[1].is_empty() || vs[2].is_empty() {
+        return Err(anyhow::anyhow!("invalid PLAIN message"));
+    }
+
+    let authzid = match vs[0] {
+        "" => None,
+        v => Some(v.to_string()),
+    };
+
+    Ok((authzid, vs[1].to_string(), vs[2].to_string()))
+}
+
+/// Encode the authorization identity for the EXTERNAL mechanism described
+/// in [RFC 4422](https://tools.ietf.org/html/rfc4422#appendix-A).
+///
+/// An empty authzid, which means the identity derived from the external
+/// credentials, is sent as `=`.
+pub fn external_encode(authzid: Option<&str>) -> String {
+    match authzid {
+        None | Some("") => "=".to_string(),
+        Some(v) => b64::encode(v),
+    }
+}
+
+/// Decode the authorization identity for the EXTERNAL mechanism.
+///
+/// Both `=` and an empty line are decoded as `None`.
+pub fn external_decode(v: &str) -> Result<Option<String>> {
+    match v.trim() {
+        "" | "=" => Ok(None),
+        v => Ok(Some(decode_str(v)?).filter(|v| !v.is_empty())),
+    }
+}
+
+/// Encode the initial client response for the XOAUTH2 mechanism used by
+/// Gmail and Outlook.
+///
+/// The message is `user={user}^Aauth=Bearer {token}^A^A` encoded by base64,
+/// `^A` is the control character `\x01`.
+pub fn xoauth2_encode(user: &str, access_token: &str) -> String {
+    let v = format!("user={}\x01auth=Bearer {}\x01\x01", user, access_token);
+
+    b64::encode(v)
+}
+
+/// Whether `mechanism` sends the password in clear, like PLAIN and LOGIN.
+pub fn is_plaintext(mechanism: &str) -> bool {
+    ["PLAIN", "LOGIN"]
+        .iter()
+        .any(|v| v.eq_ignore_ascii_case(mechanism))
+}
+
+/// Mechanisms out of `supported` which could be used in a session, listed
+/// by a bare AUTH and advertised by CAPA.
+///
+/// Plaintext mechanisms are dropped unless TLS is active or
+/// `allow_plaintext` is set.
+pub fn mechanisms(supported: &[String], tls_active: bool, allow_plaintext: bool) -> Vec<String> {
+    supported
+        .iter()
+        .filter(|v| tls_active || allow_plaintext || !is_plaintext(v))
+        .cloned()
+        .collect()
+}
+
+/// Challenge sent by server to ask for username in the LOGIN mechanism.
+pub const LOGIN_USERNAME_PROMPT: &str = "Username:";
+/// Challenge sent by server to ask for password in the LOGIN mechanism.
+pub const LOGIN_PASSWORD_PROMPT: &str = "Password:";
+
+/// Decode a base64 encoded SASL message into string.
+pub fn decode_str(v: &str) -> Result<String> {
+    let bs = b64::decode(v.trim())?;
+
+    Ok(String::from_utf8(bs)?)
+}
+
+/// Compute the lowercase hex encoded HMAC-MD5 digest of challenge.
+pub fn hmac_md5_hex(secret: &str, challenge: &[u8]) -> String {
+    let mut mac = Hmac::<Md5>::new_varkey(secret.as_bytes()).expect("HMAC accepts key of any size");
+    mac.update(challenge);
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+/// Compute the client response for the CRAM-MD5 mechanism described in
+/// [RFC 2195](https://tools.ietf.org/html/rfc2195).
+///
+/// The response is `username SP digest` encoded by base64, `challenge` is
+/// the decoded challenge sent by server.
+pub fn cram_md5_response(username: &str, secret: &str, challenge: &[u8]) -> String {
+    let v = format!("{} {}", username, hmac_md5_hex(secret, challenge));
+
+    b64::encode(v)
+}
+
+/// Decode client response for the CRAM-MD5 mechanism into `(username, digest)`.
+pub fn cram_md5_decode(v: &str) -> Result<(String, String)> {
+    let v = decode_str(v)?;
+
+    match v.rsplit_once(' ') {
+        Some((username, digest)) if !username.is_empty() && digest.len() == 32 => {
+            Ok((username.to_string(), digest.to_string()))
+        }
+        _ => Err(anyhow::anyhow!("invalid CRAM-MD5 message")),
+    }
+}
+
+/// Verify the digest sent by client with user's secret.
+pub fn cram_md5_verify(digest: &str, secret: &str, challenge: &[u8]) -> bool {
+    hmac_md5_hex(secret, challenge).eq_ignore_ascii_case(digest)
+}
+
+/// Drive the AUTH exchange of `mechanism` until server accepts or rejects it.
+///
+/// `step` is called with every decoded challenge and returns the response
+/// to send. If `step` fails or a challenge can't be decoded, the exchange
+/// is cancelled by `*` and the error is returned.
+pub(crate) async fn exchange<S, F>(
+    client: &mut Client<S>,
+    mechanism: &str,
+    mut step: F,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(&[u8]) -> Result<Vec<u8>>,
+{
+    let mut resp = client
+        .send(&Request::AUTH(Some(mechanism.to_string())))
+        .await;
+    loop {
+        let answer = match resp {
+            Ok(Response::AUTH(AuthResponse::Challenge(v))) => b64::decode(&v)
+                .map_err(anyhow::Error::from)
+                .and_then(|v| step(&v)),
+            // Challenge is validated by the parser, the exchange is still
+            // waiting for a response.
+            Err(err) if matches!(err.downcast_ref(), Some(ProtoError::InvalidBase64(_))) => {
+                Err(err)
+            }
+            Ok(Response::AUTH(AuthResponse::Success(_))) => return Ok(()),
+            Ok(Response::ERR(v)) => {
+                return Err(ErrResponse::new(format!("AUTH {}", mechanism), v).into())
+            }
+            Ok(v) => {
+                return Err(anyhow::anyhow!(
+                    "unexpected response for AUTH {}: {:?}",
+                    mechanism,
+                    v
+                ))
+            }
+            Err(err) => return Err(err),
+        };
+
+        match answer {
+            Ok(v) => resp = client.auth_continue(&b64::encode(v)).await,
+            Err(err) => {
+                // Server's response to the cancellation doesn't matter.
+                client.auth_continue("*").await?;
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Authenticate the client with the PLAIN mechanism.
+pub async fn auth_plain<S>(client: &mut Client<S>, username: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    exchange(client, "PLAIN", |_| {
+        Ok(format!("\0{}\0{}", username, password).into_bytes())
+    })
+    .await
+}
+
+/// Authenticate the client with the EXTERNAL mechanism, the credentials
+/// are established out of band, like a TLS client certificate.
+pub async fn auth_external<S>(client: &mut Client<S>, authzid: Option<&str>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match client
+        .send(&Request::AUTH(Some("EXTERNAL".to_string())))
+        .await?
+    {
+        Response::AUTH(AuthResponse::Challenge(_)) => {}
+        Response::ERR(v) => return Err(anyhow::anyhow!("AUTH EXTERNAL rejected: {}", v)),
+        v => {
+            return Err(anyhow::anyhow!(
+                "unexpected response for AUTH EXTERNAL: {:?}",
+                v
+            ))
+        }
+    }
+
+    match client.auth_continue(&external_encode(authzid)).await? {
+        Response::AUTH(AuthResponse::Success(_)) => Ok(()),
+        Response::ERR(v) => Err(ErrResponse::new("AUTH EXTERNAL", v).into()),
+        v => Err(anyhow::anyhow!(
+            "unexpected response for AUTH EXTERNAL: {:?}",
+            v
+        )),
+    }
+}
+
+/// Authenticate the client with the XOAUTH2 mechanism.
+///
+/// Server sends a base64 encoded JSON error as challenge if the token is
+/// rejected, the decoded error will be returned.
+pub async fn auth_xoauth2<S>(client: &mut Client<S>, user: &str, access_token: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match client
+        .send(&Request::AUTH(Some("XOAUTH2".to_string())))
+        .await?
+    {
+        Response::AUTH(AuthResponse::Challenge(_)) => {}
+        Response::ERR(v) => return Err(anyhow::anyhow!("AUTH XOAUTH2 rejected: {}", v)),
+        v => {
+            return Err(anyhow::anyhow!(
+                "unexpected response for AUTH XOAUTH2: {:?}",
+                v
+            ))
+        }
+    }
+
+    match client
+        .auth_continue(&xoauth2_encode(user, access_token))
+        .await?
+    {
+        Response::AUTH(AuthResponse::Success(_)) => Ok(()),
+        Response::AUTH(AuthResponse::Challenge(v)) => {
+            let err = decode_str(&v).unwrap_or(v);
+            // An empty response is required to finish the exchange.
+            let resp = client.auth_continue("").await?;
+            Err(anyhow::anyhow!("AUTH XOAUTH2 failed: {} ({:?})", err, resp))
+        }
+        Response::ERR(v) => Err(ErrResponse::new("AUTH XOAUTH2", v).into()),
+        v => Err(anyhow::anyhow!(
+            "unexpected response for AUTH XOAUTH2: {:?}",
+            v
+        )),
+    }
+}
+
+/// Authenticate the client with the LOGIN mechanism.
+///
+/// Server will ask for username and password in turn, the exchange will be
+/// cancelled by sending `*` if server sends any unexpected challenge.
+pub async fn auth_login<S>(client: &mut Client<S>, username: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut answers = vec![
+        (LOGIN_PASSWORD_PROMPT, password),
+        (LOGIN_USERNAME_PROMPT, username),
+    ];
+
+    exchange(client, "LOGIN", |challenge| {
+        let prompt = String::from_utf8_lossy(challenge);
+        match answers.pop() {
+            Some((expect, answer)) if prompt.eq_ignore_ascii_case(expect) => {
+                Ok(answer.as_bytes().to_vec())
+            }
+            _ => Err(anyhow::anyhow!(
+                "unexpected challenge for AUTH LOGIN: {:?}",
+                prompt
+            )),
+        }
+    })
+    .await?;
+    if !answers.is_empty() {
+        return Err(anyhow::anyhow!(
+            "AUTH LOGIN succeeded without asking for {}",
+            answers[0].0
+        ));
+    }
+
+    Ok(())
+}
+
+/// Authenticate the client with the CRAM-MD5 mechanism.
+pub async fn auth_cram_md5<S>(client: &mut Client<S>, username: &str, secret: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    exchange(client, "CRAM-MD5", |challenge| {
+        if challenge.is_empty() {
+            return Err(anyhow::anyhow!("empty challenge for AUTH CRAM-MD5"));
+        }
+
+        let v = format!("{} {}", username, hmac_md5_hex(secret, challenge));
+        Ok(v.into_bytes())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    #[test]
+    fn plain() {
+        let v = plain_encode(None, "tim", "tanstaaftanstaaf");
+        assert_eq!(v, "AHRpbQB0YW5zdGFhZnRhbnN0YWFm");
+
+        let (authzid, authcid, passwd) = plain_decode(&v).expect("decode");
+        assert_eq!(authzid, None);
+        assert_eq!(authcid, "tim");
+        assert_eq!(passwd, "tanstaaftanstaaf");
+
+        let v = plain_encode(Some("Ursel"), "Kurt", "xipj3plmq");
+        assert_eq!(v, "VXJzZWwAS3VydAB4aXBqM3BsbXE=");
+        let (authzid, _, _) = plain_decode(&v).expect("decode");
+        assert_eq!(authzid.as_deref(), Some("Ursel"));
+
+        assert!(plain_decode("dGlt").is_err());
+        assert!(plain_decode("not base64!").is_err());
+    }
+
+    #[test]
+    fn external() {
+        assert_eq!(external_encode(None), "=");
+        assert_eq!(external_encode(Some("")), "=");
+        assert_eq!(external_encode(Some("postman")), "cG9zdG1hbg==");
+
+        assert_eq!(external_decode("=").expect("decode"), None);
+        assert_eq!(external_decode("").expect("decode"), None);
+        assert_eq!(
+            external_decode("cG9zdG1hbg==").expect("decode").as_deref(),
+            Some("postman")
+        );
+        assert!(external_decode("not base64!").is_err());
+    }
+
+    #[test]
+    fn xoauth2() {
+        let v = xoauth2_encode(
+            "someuser@example.com",
+            "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg",
+        );
+        assert_eq!(
+            decode_str(&v).expect("decode"),
+            "user=someuser@example.com\x01auth=Bearer ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg\x01\x01"
+        );
+        // Example from https://developers.google.com/gmail/imap/xoauth2-protocol
+        assert_eq!(
+            v,
+            "dXNlcj1zb21ldXNlckBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB5YTI5LnZGOWRmdDRxbVRjMk52YjNSbGNrQmhkSFJoZG1semRHRXVZMjl0Q2cBAQ=="
+        );
+    }
+
+    #[test]
+    fn mechanisms() {
+        let supported: Vec<String> = vec!["PLAIN".into(), "LOGIN".into(), "EXTERNAL".into()];
+
+        assert_eq!(
+            super::mechanisms(&supported, false, false),
+            vec!["EXTERNAL"]
+        );
+        assert_eq!(super::mechanisms(&supported, true, false), supported);
+        assert_eq!(super::mechanisms(&supported, false, true), supported);
+    }
+
+    #[tokio::test]
+    async fn xoauth2_client() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            expect_line(&mut server, "AUTH XOAUTH2\r\n").await?;
+            server.write_all(b"+ \r\n").await?;
+            expect_line(
+                &mut server,
+                &format!("{}\r\n", xoauth2_encode("tim", "expired")),
+            )
+            .await?;
+            server
+                .write_all(format!("+ {}\r\n", b64::encode(r#"{"status":"401"}"#)).as_bytes())
+                .await?;
+            expect_line(&mut server, "\r\n").await?;
+            server.write_all(b"-ERR invalid credentials\r\n").await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        let err = client.auth_xoauth2("tim", "expired").await.unwrap_err();
+        assert!(err.to_string().contains(r#"{"status":"401"}"#), "{}", err);
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn external_client() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            expect_line(&mut server, "AUTH EXTERNAL\r\n").await?;
+            server.write_all(b"+ \r\n").await?;
+            expect_line(&mut server, "=\r\n").await?;
+            server
+                .write_all(b"+OK maildrop locked and ready\r\n")
+                .await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        auth_external(&mut client, None).await?;
+
+        srv.await?
+    }
+
+    #[test]
+    fn cram_md5() {
+        // Test vector from RFC 2195.
+        let challenge = b"<1896.697170952@postoffice.reston.mci.net>";
+
+        assert_eq!(
+            hmac_md5_hex("tanstaaftanstaaf", challenge),
+            "b913a602c7eda7a495b4e6e7334d3890"
+        );
+
+        let v = cram_md5_response("tim", "tanstaaftanstaaf", challenge);
+        assert_eq!(v, "dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw");
+
+        let (username, digest) = cram_md5_decode(&v).expect("decode");
+        assert_eq!(username, "tim");
+        assert!(cram_md5_verify(&digest, "tanstaaftanstaaf", challenge));
+        assert!(!cram_md5_verify(&digest, "tanstaaf", challenge));
+    }
+
+    #[tokio::test]
+    async fn cram_md5_client() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            expect_line(&mut server, "AUTH CRAM-MD5\r\n").await?;
+            server
+                .write_all(b"+ PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2UucmVzdG9uLm1jaS5uZXQ+\r\n")
+                .await?;
+            expect_line(
+                &mut server,
+                "dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw\r\n",
+            )
+            .await?;
+            server
+                .write_all(b"+OK maildrop locked and ready\r\n")
+                .await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        auth_cram_md5(&mut client, "tim", "tanstaaftanstaaf").await?;
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn login() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            expect_line(&mut server, "AUTH LOGIN\r\n").await?;
+            server.write_all(b"+ VXNlcm5hbWU6\r\n").await?;
+            expect_line(&mut server, "dGlt\r\n").await?;
+            server.write_all(b"+ UGFzc3dvcmQ6\r\n").await?;
+            expect_line(&mut server, "dGFuc3RhYWY=\r\n").await?;
+            server
+                .write_all(b"+OK maildrop locked and ready\r\n")
+                .await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        auth_login(&mut client, "tim", "tanstaaf").await?;
+
+        srv.await?
+    }
+
+    #[tokio::test]
+    async fn login_cancel() -> Result<()> {
+        let (client, server) = duplex(1024);
+
+        let srv = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+
+            server.write_all(b"+OK POP3 server ready\r\n").await?;
+            expect_line(&mut server, "AUTH LOGIN\r\n").await?;
+            server.write_all(b"+ UGFzc3dvcmQ6\r\n").await?;
+            expect_line(&mut server, "*\r\n").await?;
+            server
+                .write_all(b"-ERR authentication cancelled\r\n")
+                .await?;
+
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = Client::new(client).await?;
+        assert!(auth_login(&mut client, "tim", "tanstaaf").await.is_err());
+
+        srv.await?
+    }
+
+    async fn expect_line(r: &mut (impl AsyncBufReadExt + Unpin), expect: &str) -> Result<()> {
+        let mut line = String::new();
+        r.read_line(&mut line).await?;
+        assert_eq!(line, expect);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,542 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{AuthResponse, Command, ProtoError, Request, Response, State};
+
+/// Count of commands known by this crate, see `Command::all`.
+const COMMANDS: usize = 15;
+
+/// Session tracks the state of a POP3 session.
+///
+/// Requests should be checked by `apply` before serving, and the responses
+/// should be fed back by `apply_response` so that the session could enter
+/// the TRANSACTION state after authenticated.
+#[derive(Debug, Clone)]
+pub struct Session {
+    state: State,
+    closed: bool,
+    tls_active: bool,
+    /// USER has been accepted by the last response, so PASS is allowed.
+    user_accepted: bool,
+    /// User of the last USER or APOP, becomes `user` once logged in.
+    pending_user: Option<String>,
+    /// Authenticated user.
+    user: Option<String>,
+    /// Message of the last DELE, becomes deleted once succeeded.
+    pending_dele: Option<usize>,
+    /// Messages marked as deleted, they are removed in the UPDATE state.
+    deleted: BTreeSet<usize>,
+    /// Name of the upstream serving the maildrop.
+    upstream: Option<String>,
+    /// When this session was created.
+    started: Instant,
+    /// Requests applied by command, indexed by `Command as usize`.
+    commands: [usize; COMMANDS],
+}
+
+/// SessionSnapshot is a read-only view of a `Session` for debugging, taken
+/// by `Session::snapshot`. Passwords are never kept by the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SessionSnapshot {
+    pub state: State,
+    pub closed: bool,
+    /// Authenticated user, `None` before logged in.
+    pub user: Option<String>,
+    /// Messages marked as deleted in order.
+    pub deleted: Vec<usize>,
+    pub tls_active: bool,
+    pub upstream: Option<String>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+impl Session {
+    /// Create a session in the AUTHORIZATION state.
+    pub fn new() -> Session {
+        Session {
+            state: State::AUTHORIZATION,
+            closed: false,
+            tls_active: false,
+            user_accepted: false,
+            pending_user: None,
+            user: None,
+            pending_dele: None,
+            deleted: BTreeSet::new(),
+            upstream: None,
+            started: Instant::now(),
+            commands: [0; COMMANDS],
+        }
+    }
+
+    /// Current state of this session.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Time since this session was created, which is when the connection
+    /// was accepted for a server.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Count of requests applied, including those refused.
+    pub fn command_count(&self) -> usize {
+        self.commands.iter().sum()
+    }
+
+    /// Count of requests applied with `command`, including those refused.
+    pub fn command_count_of(&self, command: Command) -> usize {
+        self.commands[command as usize]
+    }
+
+    /// Whether this session has been terminated by QUIT.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Whether TLS is active on the connection, by STLS or implicit TLS.
+    pub fn is_tls_active(&self) -> bool {
+        self.tls_active
+    }
+
+    /// Mark TLS as active, should be called if the connection is wrapped
+    /// in TLS before greeting.
+    pub fn set_tls_active(&mut self) {
+        self.tls_active = true;
+    }
+
+    /// Set the authenticated user, for logins like AUTH whose user is not
+    /// seen by the session.
+    pub fn set_user(&mut self, user: impl Into<String>) {
+        self.user = Some(user.into());
+    }
+
+    /// Set the name of the upstream serving the maildrop.
+    pub fn set_upstream(&mut self, name: impl Into<String>) {
+        self.upstream = Some(name.into());
+    }
+
+    /// Messages marked as deleted in order, which will be removed by QUIT
+    /// unless unmarked by RSET.
+    pub fn pending_deletes(&self) -> Vec<usize> {
+        self.deleted.iter().copied().collect()
+    }
+
+    /// Take a snapshot of this session, the session is not changed.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            state: self.state,
+            closed: self.closed,
+            user: self.user.clone(),
+            deleted: self.pending_deletes(),
+            tls_active: self.tls_active,
+            upstream: self.upstream.clone(),
+        }
+    }
+
+    /// Check whether the request is allowed in current state.
+    ///
+    /// PASS is only allowed immediately after a successful USER. QUIT
+    /// terminates the session, and enters the UPDATE state if it's issued in
+    /// the TRANSACTION state.
+    ///
+    /// Requests are counted by command whether allowed or not, arguments
+    /// are never recorded.
+    pub fn apply(&mut self, req: &Request) -> Result<()> {
+        self.commands[req.command() as usize] += 1;
+        Ok(self.try_apply(req)?)
+    }
+
+    fn try_apply(&mut self, req: &Request) -> std::result::Result<(), ProtoError> {
+        let command = req.command();
+        if self.closed || !is_allowed(self.state, command) {
+            return Err(ProtoError::NotAllowed {
+                command,
+                state: self.state,
+            });
+        }
+        // Never negotiate TLS twice or fall back to plaintext.
+        if let (Request::STLS, true) = (req, self.tls_active) {
+            return Err(ProtoError::TlsActive);
+        }
+        if command == Command::PASS && !self.user_accepted {
+            return Err(ProtoError::PassWithoutUser);
+        }
+        // Any other request, including a failed PASS, requires USER again.
+        self.user_accepted = false;
+
+        match req {
+            Request::USER(v) | Request::APOP { username: v, .. } => {
+                self.pending_user = Some(v.clone())
+            }
+            _ => {}
+        }
+        self.pending_dele = match req {
+            Request::DELE(id) => Some(*id),
+            _ => None,
+        };
+
+        if let Request::QUIT = req {
+            self.closed = true;
+            if self.state == State::TRANSACTION {
+                self.state = State::UPDATE;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Enter the TRANSACTION state if the response is a successful login,
+    /// allow PASS if USER succeeded, or mark TLS as active if STLS
+    /// succeeded.
+    ///
+    /// Messages marked as deleted by DELE and unmarked by RSET are tracked
+    /// in the TRANSACTION state.
+    pub fn apply_response(&mut self, resp: &Response) {
+        if self.state == State::TRANSACTION {
+            match resp {
+                Response::DELE => self.deleted.extend(self.pending_dele.take()),
+                Response::RSET(_) => self.deleted.clear(),
+                _ => {}
+            }
+        }
+        if self.state != State::AUTHORIZATION {
+            return;
+        }
+        match resp {
+            Response::STLS(_) => self.tls_active = true,
+            Response::USER(_) => self.user_accepted = true,
+            _ => {}
+        }
+
+        if let Response::PASS(_) | Response::APOP | Response::AUTH(AuthResponse::Success(_)) = resp
+        {
+            self.state = State::TRANSACTION;
+            if let Some(user) = self.pending_user.take() {
+                self.user = Some(user);
+            }
+        }
+    }
+}
+
+/// Check whether a sequence of requests is legal without a connection.
+///
+/// Requests are applied to a fresh `Session` in order, and all logins are
+/// assumed to succeed. Returns the index and error of the first illegal
+/// request.
+///
+/// ```
+/// use postman_pop3::{validate_script, Request};
+///
+/// let script = [Request::USER("postman".to_string()), Request::RETR(1)];
+/// assert_eq!(validate_script(&script).unwrap_err().0, 1);
+/// ```
+pub fn validate_script(cmds: &[Request]) -> std::result::Result<(), (usize, ProtoError)> {
+    let mut session = Session::new();
+
+    for (i, req) in cmds.iter().enumerate() {
+        session.try_apply(req).map_err(|err| (i, err))?;
+
+        let resp = match req {
+            Request::USER(_) => Response::USER(String::new()),
+            Request::PASS(_) => Response::PASS(String::new()),
+            Request::APOP { .. } => Response::APOP,
+            Request::AUTH(Some(_)) => Response::AUTH(AuthResponse::Success(String::new())),
+            _ => continue,
+        };
+        session.apply_response(&resp);
+    }
+
+    Ok(())
+}
+
+/// Check the `# Restrictions` of command.
+fn is_allowed(state: State, cmd: Command) -> bool {
+    match state {
+        State::AUTHORIZATION => matches!(
+            cmd,
+            Command::USER
+                | Command::PASS
+                | Command::APOP
+                | Command::AUTH
+                | Command::CAPA
+                | Command::STLS
+                | Command::QUIT
+        ),
+        State::TRANSACTION => matches!(
+            cmd,
+            Command::STAT
+                | Command::LIST
+                | Command::RETR
+                | Command::DELE
+                | Command::NOOP
+                | Command::RSET
+                | Command::TOP
+                | Command::UIDL
+                | Command::CAPA
+                | Command::QUIT
+        ),
+        State::UPDATE => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn full_session() -> Result<()> {
+        let mut session = Session::new();
+
+        session.apply(&Request::CAPA)?;
+        session.apply(&Request::USER("postman".to_string()))?;
+        session.apply_response(&Response::USER(String::new()));
+        session.apply(&Request::PASS("postman".to_string()))?;
+        session.apply_response(&Response::PASS(String::new()));
+        assert_eq!(session.state(), State::TRANSACTION);
+
+        session.apply(&Request::STAT)?;
+        session.apply(&Request::RETR(1))?;
+        session.apply(&Request::DELE(1))?;
+        assert!(session
+            .apply(&Request::USER("postman".to_string()))
+            .is_err());
+
+        session.apply(&Request::QUIT)?;
+        assert_eq!(session.state(), State::UPDATE);
+        assert!(session.is_closed());
+        assert!(session.apply(&Request::NOOP).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_count() -> Result<()> {
+        let mut session = Session::new();
+        assert_eq!(session.command_count(), 0);
+
+        session.apply(&Request::USER("postman".to_string()))?;
+        session.apply_response(&Response::USER(String::new()));
+        session.apply(&Request::PASS("postman".to_string()))?;
+        session.apply_response(&Response::PASS(String::new()));
+        session.apply(&Request::RETR(1))?;
+        session.apply(&Request::RETR(2))?;
+        // Refused requests are counted as well.
+        assert!(session
+            .apply(&Request::PASS("postman".to_string()))
+            .is_err());
+
+        assert_eq!(session.command_count(), 5);
+        assert_eq!(session.command_count_of(Command::RETR), 2);
+        assert_eq!(session.command_count_of(Command::PASS), 2);
+        assert_eq!(session.command_count_of(Command::DELE), 0);
+        assert!(session.elapsed() < Duration::from_secs(60));
+        assert_eq!(Command::all().len(), COMMANDS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot() -> Result<()> {
+        let mut session = Session::new();
+        session.set_tls_active();
+        session.apply(&Request::USER("postman".to_string()))?;
+        session.apply_response(&Response::USER(String::new()));
+        assert_eq!(session.snapshot().user, None);
+
+        session.apply(&Request::PASS("secret".to_string()))?;
+        session.apply_response(&Response::PASS(String::new()));
+        session.set_upstream("example");
+        for (req, resp) in [
+            (Request::DELE(2), Response::DELE),
+            (
+                Request::DELE(9),
+                Response::ERR("no such message".to_string()),
+            ),
+            (Request::RSET, Response::RSET(String::new())),
+            (Request::DELE(3), Response::DELE),
+            (Request::DELE(1), Response::DELE),
+            (Request::STAT, Response::STAT { count: 3, size: 30 }),
+        ] {
+            session.apply(&req)?;
+            session.apply_response(&resp);
+        }
+
+        let snapshot = session.snapshot();
+        assert_eq!(
+            snapshot,
+            SessionSnapshot {
+                state: State::TRANSACTION,
+                closed: false,
+                user: Some("postman".to_string()),
+                deleted: vec![1, 3],
+                tls_active: true,
+                upstream: Some("example".to_string()),
+            }
+        );
+        // Taking a snapshot doesn't change the session.
+        assert_eq!(session.snapshot(), snapshot);
+        assert!(!format!("{:?}", snapshot).contains("secret"));
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::to_string(&snapshot)?,
+            r#"{"state":"TRANSACTION","closed":false,"user":"postman","deleted":[1,3],"tls_active":true,"upstream":"example"}"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pending_deletes() -> Result<()> {
+        let mut session = Session::new();
+        for (req, resp) in [
+            (
+                Request::USER("postman".to_string()),
+                Response::USER(String::new()),
+            ),
+            (
+                Request::PASS("postman".to_string()),
+                Response::PASS(String::new()),
+            ),
+            (Request::DELE(5), Response::DELE),
+            (Request::DELE(2), Response::DELE),
+        ] {
+            session.apply(&req)?;
+            session.apply_response(&resp);
+        }
+        assert_eq!(session.pending_deletes(), vec![2, 5]);
+
+        session.apply(&Request::RSET)?;
+        session.apply_response(&Response::RSET(String::new()));
+        assert!(session.pending_deletes().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn retr_before_login() -> Result<()> {
+        let mut session = Session::new();
+
+        assert!(session.apply(&Request::RETR(1)).is_err());
+        session.apply(&Request::USER("postman".to_string()))?;
+        session.apply_response(&Response::USER(String::new()));
+        session.apply(&Request::PASS("wrong".to_string()))?;
+        session.apply_response(&Response::ERR("invalid password".to_string()));
+        assert!(session.apply(&Request::RETR(1)).is_err());
+
+        session.apply(&Request::QUIT)?;
+        assert_eq!(session.state(), State::AUTHORIZATION);
+        assert!(session.is_closed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn pass_before_user() {
+        let mut session = Session::new();
+
+        let err = session
+            .apply(&Request::PASS("postman".to_string()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "PASS must follow a successful USER");
+
+        // USER is rejected.
+        session
+            .apply(&Request::USER("postman".to_string()))
+            .expect("apply USER");
+        session.apply_response(&Response::ERR("no such user".to_string()));
+        assert!(session
+            .apply(&Request::PASS("postman".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn pass_after_failed_pass() -> Result<()> {
+        let mut session = Session::new();
+        let user = Request::USER("postman".to_string());
+        let pass = Request::PASS("postman".to_string());
+
+        session.apply(&user)?;
+        session.apply_response(&Response::USER(String::new()));
+        session.apply(&pass)?;
+        session.apply_response(&Response::ERR("[AUTH] invalid password".to_string()));
+
+        // Failed PASS drops back to requiring USER.
+        assert!(session.apply(&pass).is_err());
+        session.apply(&user)?;
+        session.apply_response(&Response::USER(String::new()));
+        session.apply(&pass)?;
+        session.apply_response(&Response::PASS(String::new()));
+        assert_eq!(session.state(), State::TRANSACTION);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stls_twice() -> Result<()> {
+        let mut session = Session::new();
+
+        session.apply(&Request::STLS)?;
+        session.apply_response(&Response::STLS("Begin TLS negotiation".to_string()));
+        assert!(session.is_tls_active());
+
+        let err = session.apply(&Request::STLS).unwrap_err();
+        assert_eq!(err.to_string(), "command not permitted after TLS");
+
+        // Implicit TLS forbids STLS as well.
+        let mut session = Session::new();
+        session.set_tls_active();
+        assert!(session.apply(&Request::STLS).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn script() {
+        let user = || Request::USER("postman".to_string());
+        let pass = || Request::PASS("postman".to_string());
+
+        assert_eq!(
+            validate_script(&[user(), Request::RETR(1)]),
+            Err((
+                1,
+                ProtoError::NotAllowed {
+                    command: Command::RETR,
+                    state: State::AUTHORIZATION
+                }
+            ))
+        );
+        assert_eq!(
+            validate_script(&[user(), pass(), Request::RETR(1), Request::QUIT]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_script(&[Request::AUTH(None), Request::STAT]).map_err(|v| v.0),
+            Err(1)
+        );
+        assert_eq!(
+            validate_script(&[Request::AUTH(Some("PLAIN".to_string())), Request::STAT]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_script(&[user(), pass(), Request::QUIT, Request::NOOP]),
+            Err((
+                3,
+                ProtoError::NotAllowed {
+                    command: Command::NOOP,
+                    state: State::UPDATE
+                }
+            ))
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+
+use async_trait::async_trait;
+use postman_pop3::apop_verify;
+
+/// AuthResult is the verdict of an `Authenticator` on credentials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    /// Credentials are valid.
+    Accept,
+    /// Credentials are invalid.
+    Reject,
+    /// Credentials can't be verified for now, like the identity source is
+    /// unreachable. Client could try again later.
+    TemporaryFailure(String),
+}
+
+/// Authenticator verifies credentials sent by downstream clients in the
+/// AUTHORIZATION state, against an identity source like a file, LDAP or
+/// the upstream itself.
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+    /// Verify the password of user, sent by USER/PASS, AUTH PLAIN or AUTH
+    /// LOGIN.
+    async fn verify_userpass(&self, user: &str, pass: &str) -> AuthResult;
+    /// Verify the APOP digest of user, `timestamp` is the one sent in the
+    /// greeting.
+    async fn verify_apop(&self, user: &str, timestamp: &str, digest: &str) -> AuthResult;
+}
+
+/// SharedSecret authenticates the only user of a downstream by the secret
+/// shared with it, which are the username and password of downstream.
+#[derive(Clone)]
+pub struct SharedSecret {
+    user: String,
+    secret: String,
+}
+
+impl SharedSecret {
+    pub fn new(user: impl Into<String>, secret: impl Into<String>) -> SharedSecret {
+        SharedSecret {
+            user: user.into(),
+            secret: secret.into(),
+        }
+    }
+}
+
+impl Debug for SharedSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedSecret")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Authenticator for SharedSecret {
+    async fn verify_userpass(&self, user: &str, pass: &str) -> AuthResult {
+        verdict(user == self.user && pass == self.secret)
+    }
+
+    async fn verify_apop(&self, user: &str, timestamp: &str, digest: &str) -> AuthResult {
+        verdict(user == self.user && apop_verify(timestamp, &self.secret, digest))
+    }
+}
+
+/// MemoryAuthenticator authenticates users by secrets kept in memory,
+/// mostly used in tests.
+#[derive(Clone, Default)]
+pub struct MemoryAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl MemoryAuthenticator {
+    pub fn new() -> MemoryAuthenticator {
+        MemoryAuthenticator::default()
+    }
+
+    /// Add user with its secret, which replaces the old one if existed.
+    pub fn with_user(mut self, user: impl Into<String>, secret: impl Into<String>) -> Self {
+        self.users.insert(user.into(), secret.into());
+        self
+    }
+}
+
+impl Debug for MemoryAuthenticator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryAuthenticator")
+            .field("users", &self.users.keys())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Authenticator for MemoryAuthenticator {
+    async fn verify_userpass(&self, user: &str, pass: &str) -> AuthResult {
+        verdict(self.users.get(user).map(String::as_str) == Some(pass))
+    }
+
+    async fn verify_apop(&self, user: &str, timestamp: &str, digest: &str) -> AuthResult {
+        verdict(matches!(
+            self.users.get(user),
+            Some(secret) if apop_verify(timestamp, secret, digest)
+        ))
+    }
+}
+
+/// AcceptAny authenticates any user, by a password shared by all users if
+/// set or by any password otherwise. It's for demos and tests only, see
+/// `Downstream::anonymous`.
+#[derive(Clone, Default)]
+pub struct AcceptAny {
+    password: Option<String>,
+}
+
+impl AcceptAny {
+    pub fn new(password: Option<String>) -> AcceptAny {
+        AcceptAny { password }
+    }
+}
+
+impl Debug for AcceptAny {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcceptAny").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Authenticator for AcceptAny {
+    async fn verify_userpass(&self, _user: &str, pass: &str) -> AuthResult {
+        verdict(self.password.as_deref().is_none_or(|v| v == pass))
+    }
+
+    async fn verify_apop(&self, _user: &str, timestamp: &str, digest: &str) -> AuthResult {
+        verdict(
+            self.password
+                .as_deref()
+                .is_none_or(|v| apop_verify(timestamp, v, digest)),
+        )
+    }
+}
+
+fn verdict(accepted: bool) -> AuthResult {
+    if accepted {
+        AuthResult::Accept
+    } else {
+        AuthResult::Reject
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use postman_pop3::apop_digest;
+
+    #[tokio::test]
+    async fn shared_secret() {
+        let auth = SharedSecret::new("postman", "s3cret");
+        let ts = "<1896.697170952@dbc.mtview.ca.us>";
+
+        assert_eq!(
+            auth.verify_userpass("postman", "s3cret").await,
+            AuthResult::Accept
+        );
+        assert_eq!(
+            auth.verify_userpass("other", "s3cret").await,
+            AuthResult::Reject
+        );
+
+        let digest = apop_digest(ts, "s3cret");
+        assert_eq!(
+            auth.verify_apop("postman", ts, &digest).await,
+            AuthResult::Accept
+        );
+        assert_eq!(
+            auth.verify_apop("other", ts, &digest).await,
+            AuthResult::Reject
+        );
+        assert!(!format!("{:?}", auth).contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn memory() {
+        let auth = MemoryAuthenticator::new().with_user("postman", "s3cret");
+        let ts = "<1896.697170952@dbc.mtview.ca.us>";
+
+        assert_eq!(
+            auth.verify_userpass("postman", "s3cret").await,
+            AuthResult::Accept
+        );
+        assert_eq!(
+            auth.verify_userpass("postman", "other").await,
+            AuthResult::Reject
+        );
+        assert_eq!(
+            auth.verify_userpass("other", "s3cret").await,
+            AuthResult::Reject
+        );
+
+        let digest = apop_digest(ts, "s3cret");
+        assert_eq!(
+            auth.verify_apop("postman", ts, &digest).await,
+            AuthResult::Accept
+        );
+        assert_eq!(
+            auth.verify_apop("other", ts, &digest).await,
+            AuthResult::Reject
+        );
+        assert!(!format!("{:?}", auth).contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn accept_any() {
+        let auth = AcceptAny::new(Some("demo".to_string()));
+        assert_eq!(
+            auth.verify_userpass("anyone", "demo").await,
+            AuthResult::Accept
+        );
+        assert_eq!(
+            auth.verify_userpass("anyone", "other").await,
+            AuthResult::Reject
+        );
+
+        let auth = AcceptAny::new(None);
+        assert_eq!(auth.verify_userpass("anyone", "").await, AuthResult::Accept);
+        assert_eq!(
+            auth.verify_apop("anyone", "<1@example.com>", "x").await,
+            AuthResult::Accept
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::{debug, warn};
+use md5::{Digest, Md5};
+
+/// MessageCache keeps messages retrieved from upstreams on disk.
+///
+/// Entries are keyed by `(name, uid)` where `name` is the upstream's name,
+/// each of them is a file of the message prefixed by its MD5 digest so that
+/// a corrupted entry could be detected and dropped. Least recently used
+/// entries will be evicted once the total size exceeds `max_bytes`.
+#[derive(Debug)]
+pub struct MessageCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Held while writing entries, so that eviction sees complete files.
+    lock: Mutex<()>,
+}
+
+impl MessageCache {
+    /// Create a cache stored under `dir`, the dir will be created while
+    /// caching the first message.
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> MessageCache {
+        MessageCache {
+            dir: dir.into(),
+            max_bytes,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Get a cached message.
+    ///
+    /// Returns `None` if it's not cached or can't be read, corrupted entries
+    /// will be removed.
+    pub fn get(&self, name: &str, uid: &str) -> Option<Vec<u8>> {
+        let path = self.dir.join(hex(name)).join(hex(uid));
+        let data = match fs::read(&path) {
+            Ok(v) => v,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!("read cache {}: {}", path.display(), err);
+                return None;
+            }
+        };
+
+        match decode(&data) {
+            Some(v) => {
+                // Mark as recently used, it's fine to be evicted earlier if
+                // failed.
+                let _ = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                Some(v)
+            }
+            None => {
+                warn!("remove corrupted cache {}", path.display());
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Cache a message as is, and evict entries if the cache is full.
+    pub fn put(&self, name: &str, uid: &str, message: &[u8]) -> Result<()> {
+        let _guard = self.lock.lock().expect("lock message cache");
+
+        let dir = self.dir.join(hex(name));
+        fs::create_dir_all(&dir)?;
+
+        // Write to a temporary file first, readers never see a partial
+        // entry.
+        let path = dir.join(hex(uid));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, encode(message))?;
+        fs::rename(&tmp, &path)?;
+
+        self.evict()
+    }
+
+    /// Remove cached messages of upstream `name` whose uid is not in `uids`.
+    ///
+    /// Returns how many entries have been removed.
+    pub fn purge_missing<'a>(
+        &self,
+        name: &str,
+        uids: impl IntoIterator<Item = &'a str>,
+    ) -> Result<usize> {
+        let _guard = self.lock.lock().expect("lock message cache");
+
+        let uids: HashSet<String> = uids.into_iter().map(hex).collect();
+        let entries = match fs::read_dir(self.dir.join(hex(name))) {
+            Ok(v) => v,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut n = 0;
+        for entry in entries {
+            let entry = entry?;
+            if !uids.contains(entry.file_name().to_string_lossy().as_ref()) {
+                fs::remove_file(entry.path())?;
+                n += 1;
+            }
+        }
+
+        Ok(n)
+    }
+
+    /// Remove least recently used entries until the total size fits in
+    /// `max_bytes`.
+    fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for dir in fs::read_dir(&self.dir)? {
+            for entry in fs::read_dir(dir?.path())? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+
+                total += meta.len();
+                entries.push((meta.modified()?, meta.len(), entry.path()));
+            }
+        }
+        entries.sort();
+
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+
+            debug!("evict cache {}", path.display());
+            fs::remove_file(&path)?;
+            total -= size;
+        }
+
+        Ok(())
+    }
+}
+
+/// Encode name or uid as lowercase hex, so that it's safe to be a file
+/// name.
+fn hex(v: &str) -> String {
+    v.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Build entry as `digest LF message`.
+fn encode(message: &[u8]) -> Vec<u8> {
+    let mut data = format!("{:x}\n", Md5::digest(message)).into_bytes();
+    data.extend_from_slice(message);
+    data
+}
+
+/// Take the message from entry, returns `None` if the digest mismatched.
+fn decode(data: &[u8]) -> Option<Vec<u8>> {
+    let i = data.iter().position(|&b| b == b'\n')?;
+    let (digest, message) = (&data[..i], &data[i + 1..]);
+
+    if digest != format!("{:x}", Md5::digest(message)).as_bytes() {
+        return None;
+    }
+    Some(message.to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn cache() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-cache-{}", std::process::id()));
+        let cache = MessageCache::new(&dir, 1024);
+
+        assert_eq!(cache.get("qq", "a/1"), None);
+        cache.put("qq", "a/1", b"Subject: a\r\n\r\nhello\r\n")?;
+        cache.put("qq", "b", b"Subject: b\r\n\r\nworld\r\n")?;
+        // 8-bit bodies are kept as is.
+        cache.put("gmail", "a/1", b"Subject: c\r\n\r\n\xe4\xff\r\n")?;
+        assert_eq!(
+            cache.get("qq", "a/1").as_deref(),
+            Some(&b"Subject: a\r\n\r\nhello\r\n"[..])
+        );
+        assert_eq!(
+            cache.get("gmail", "a/1").as_deref(),
+            Some(&b"Subject: c\r\n\r\n\xe4\xff\r\n"[..])
+        );
+
+        // Corrupted entry is dropped.
+        let path = dir.join(hex("qq")).join(hex("b"));
+        let mut data = fs::read(&path)?;
+        *data.last_mut().unwrap() = b'!';
+        fs::write(&path, data)?;
+        assert_eq!(cache.get("qq", "b"), None);
+        assert!(!path.exists());
+
+        assert_eq!(cache.purge_missing("qq", vec!["b"])?, 1);
+        assert_eq!(cache.get("qq", "a/1"), None);
+        assert!(cache.get("gmail", "a/1").is_some());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn evict() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-evict-{}", std::process::id()));
+        // Every entry takes 33 bytes of digest and 32 bytes of message.
+        let cache = MessageCache::new(&dir, 150);
+        let message = [b'x'; 32];
+
+        cache.put("qq", "1", &message)?;
+        thread::sleep(Duration::from_millis(10));
+        cache.put("qq", "2", &message)?;
+        thread::sleep(Duration::from_millis(10));
+        // Used recently, so 2 is the least recently used one.
+        assert!(cache.get("qq", "1").is_some());
+        thread::sleep(Duration::from_millis(10));
+        cache.put("qq", "3", &message)?;
+
+        assert!(cache.get("qq", "1").is_some());
+        assert_eq!(cache.get("qq", "2"), None);
+        assert!(cache.get("qq", "3").is_some());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,29 +1,1544 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::net::Ipv6Addr;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use postman_pop3::{
+    make_apop_greeting, validate_hostname, AuthType, Capabilities, Command, Expire, Quirks,
+    Timeouts, MAX_LINE_LENGTH,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
-    database_dir: String,
-    data_dir: String,
+    pub database_dir: PathBuf,
+    pub data_dir: PathBuf,
+    /// Max seconds to wait for in-flight sessions while shutting down,
+    /// sessions still running after that will be dropped.
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// Seconds between NOOPs sent on idle upstream connections, should be
+    /// well under the autologout timer of upstreams.
+    #[serde(default = "default_upstream_keepalive")]
+    pub upstream_keepalive: u64,
+    /// Max bytes of messages cached for upstreams with `cache` enabled,
+    /// the cache is stored under `data_dir/.cache`.
+    #[serde(default = "default_cache_max_bytes")]
+    pub cache_max_bytes: u64,
+    /// Fully-qualified hostname in the greeting timestamp used by APOP,
+    /// detected from the system if missing.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Max seconds to keep messages in `data_dir`, older messages are
+    /// removed by a sweep even if no client has deleted them. Messages are
+    /// kept forever if missing.
+    #[serde(default)]
+    pub max_message_age: Option<u64>,
+    /// Seconds between sweeps of `data_dir` if `max_message_age` is set.
+    #[serde(default = "default_sweep_interval")]
+    pub sweep_interval: u64,
+    /// Minimum days messages are kept in `data_dir`, advertised as `EXPIRE`
+    /// in CAPA. Sweeps never remove messages younger than that even if
+    /// `max_message_age` is shorter. `0` allows clients to expect messages
+    /// to be deleted once retrieved.
+    #[serde(default)]
+    pub expire_days: Option<u32>,
+    /// Delay the greeting and replies to IPs which have failed logins this
+    /// many times in `tarpit_window`, IPs are never delayed if missing.
+    #[serde(default)]
+    pub tarpit_after_failures: Option<u32>,
+    /// Milliseconds to delay the greeting and every reply to an IP in the
+    /// tarpit.
+    #[serde(default = "default_tarpit_delay")]
+    pub tarpit_delay: u64,
+    /// Seconds to remember failed logins of an IP since the last one.
+    #[serde(default = "default_tarpit_window")]
+    pub tarpit_window: u64,
 
-    downstreams: Vec<Downstream>,
-    upstreams: Vec<Upstream>,
+    #[serde(rename = "downstream")]
+    pub downstreams: Vec<Downstream>,
+    #[serde(rename = "upstream", default)]
+    pub upstreams: Vec<Upstream>,
+    /// Rules to route users to upstreams, users not routed will be served
+    /// from `data_dir`.
+    #[serde(rename = "route", default)]
+    pub routes: Vec<Route>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database_dir: PathBuf::from("data/db"),
+            data_dir: PathBuf::from("data/mails"),
+            drain_timeout: default_drain_timeout(),
+            upstream_keepalive: default_upstream_keepalive(),
+            cache_max_bytes: default_cache_max_bytes(),
+            hostname: None,
+            max_message_age: None,
+            sweep_interval: default_sweep_interval(),
+            expire_days: None,
+            tarpit_after_failures: None,
+            tarpit_delay: default_tarpit_delay(),
+            tarpit_window: default_tarpit_window(),
+            downstreams: Vec::new(),
+            upstreams: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// ConfigBuilder builds a validated `Config` programmatically.
+///
+/// ```no_run
+/// use postman::config::ConfigBuilder;
+///
+/// let cfg = ConfigBuilder::new()
+///     .database_dir("/var/lib/postman/db")
+///     .data_dir("/var/lib/postman/mails")
+///     .build();
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Create a builder starts from `Config::default()`.
+    pub fn new() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    pub fn database_dir(mut self, v: impl Into<PathBuf>) -> Self {
+        self.config.database_dir = v.into();
+        self
+    }
+
+    pub fn data_dir(mut self, v: impl Into<PathBuf>) -> Self {
+        self.config.data_dir = v.into();
+        self
+    }
+
+    pub fn add_downstream(mut self, v: Downstream) -> Self {
+        self.config.downstreams.push(v);
+        self
+    }
+
+    pub fn add_upstream(mut self, v: Upstream) -> Self {
+        self.config.upstreams.push(v);
+        self
+    }
+
+    pub fn add_route(mut self, user: impl Into<String>, upstream: impl Into<String>) -> Self {
+        self.config.routes.push(Route {
+            user: user.into(),
+            upstream: upstream.into(),
+        });
+        self
+    }
+
+    /// Validate and return the config, all problems found will be returned.
+    pub fn build(self) -> Result<Config, Vec<ConfigError>> {
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_upstream_keepalive() -> u64 {
+    60
+}
+
+fn default_cache_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_sweep_interval() -> u64 {
+    3600
+}
+
+fn default_tarpit_delay() -> u64 {
+    3000
+}
+
+fn default_tarpit_window() -> u64 {
+    3600
+}
+
+fn default_allow_plaintext_auth() -> bool {
+    true
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_io_timeout() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Downstream {
-    protocol: String,
-    addr: String,
-    auth_type: String,
-    username: String,
-    password: String,
+    pub protocol: Protocol,
+    pub addr: String,
+    pub auth_type: AuthType,
+    pub username: String,
+    pub password: String,
+    /// Minimum seconds between logins of the same user, advertised as
+    /// `LOGIN-DELAY` in CAPA.
+    #[serde(default)]
+    pub login_delay: Option<u32>,
+    /// Expect a PROXY protocol v1 header before the greeting, enable it
+    /// only when behind a load balancer which sends the header.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Max PASS, APOP and AUTH attempts of a connection rejected by the
+    /// authenticator, the connection will be closed after that. Errors
+    /// like a locked maildrop are not counted.
+    #[serde(default)]
+    pub max_auth_attempts: Option<u32>,
+    /// Reject requests terminated by a bare LF instead of CRLF.
+    #[serde(default)]
+    pub strict_line_ending: bool,
+    /// Serve maildrops read-only for forensic or archival access, DELE is
+    /// refused and nothing is ever removed.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Commands refused by `-ERR command disabled` in any state, like DELE
+    /// for archival or APOP to force SASL. Capabilities of them are not
+    /// advertised in CAPA.
+    #[serde(default)]
+    pub disabled_commands: Vec<Command>,
+    /// Max connections served at once, connections over it are greeted by
+    /// `-ERR [SYS/TEMP]` and closed. Not limited if missing.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Max seconds a session may last, the session is closed by `-ERR`
+    /// without committing deletions after that, even if it's busy. Not
+    /// limited if missing.
+    #[serde(default)]
+    pub max_session_duration: Option<u64>,
+    /// Allow AUTH PLAIN and LOGIN, which send the password in clear,
+    /// without TLS. They are neither advertised nor accepted over
+    /// plaintext connections if disabled.
+    #[serde(default = "default_allow_plaintext_auth")]
+    pub allow_plaintext_auth: bool,
+    /// Set `TCP_NODELAY` on accepted connections so that small responses
+    /// are not delayed by Nagle's algorithm, disable it to favor bulk
+    /// transfers.
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Commands unknown by postman to be forwarded to the upstream as is,
+    /// keyed by verb like `XTND`, the value tells whether a positive reply
+    /// is multi-line. Other unknown commands are still rejected.
+    #[serde(default)]
+    pub passthrough: BTreeMap<String, bool>,
+    /// Text of the greeting before the APOP timestamp, defaults to
+    /// `POP3 server ready`.
+    #[serde(default)]
+    pub greeting: Option<String>,
+    /// `IMPLEMENTATION` advertised in CAPA, defaults to `postman` without
+    /// the version.
+    #[serde(default)]
+    pub implementation: Option<String>,
+    /// Accept any user and serve them all a demo maildrop read-only, for
+    /// public test servers. Upstream routes are ignored. Disabled if
+    /// missing.
+    #[serde(default)]
+    pub anonymous: Option<Anonymous>,
+    /// Serve implicit TLS with the certificate, connections will be TLS
+    /// from the first byte.
+    #[serde(default)]
+    pub tls: Option<DownstreamTls>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Anonymous is the demo mode of a downstream, see `Downstream::anonymous`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Anonymous {
+    /// Dir under data_dir served to every user.
+    pub maildrop: String,
+    /// Password required from every user, any password is accepted if
+    /// missing.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Certificate and private key in PEM of a TLS downstream.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownstreamTls {
+    /// Certificate chain, the leaf certificate comes first.
+    pub cert: PathBuf,
+    /// PKCS#8 or RSA private key.
+    pub key: PathBuf,
+    /// CA certificates to verify client certificates, clients presenting a
+    /// verified certificate could login by AUTH EXTERNAL as the common name
+    /// of the certificate.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Upstream {
-    name: String,
-    protocol: String,
-    addr: String,
-    auth_type: String,
-    username: String,
-    password: String,
-}
\ No newline at end of file
+    pub name: String,
+    pub protocol: Protocol,
+    /// Address or a list of addresses to be tried in order.
+    pub addr: Addrs,
+    #[serde(default)]
+    pub tls: bool,
+    /// Server name sent in TLS SNI and used to verify the certificate, the
+    /// host of addr will be used if not set.
+    #[serde(default)]
+    pub tls_sni: Option<String>,
+    /// CA certificates in PEM to verify the upstream, instead of the
+    /// built-in web PKI roots, for upstreams with a private CA.
+    #[serde(default)]
+    pub tls_ca: Option<PathBuf>,
+    /// Client certificate chain in PEM presented to the upstream for mutual
+    /// TLS, requires `tls_key`.
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    /// PKCS#8 or RSA private key of `tls_cert`.
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    pub auth_type: AuthType,
+    pub username: String,
+    pub password: String,
+    /// Max bytes of a buffered response from upstream, larger responses
+    /// will be aborted. RETR is relayed line by line, only a single line of
+    /// it is limited.
+    #[serde(default)]
+    pub max_response_bytes: Option<usize>,
+    /// Max seconds to connect, including the TLS handshake and greeting.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Max seconds to wait for a line of response.
+    #[serde(default = "default_io_timeout")]
+    pub read_timeout: u64,
+    /// Max seconds to send a request.
+    #[serde(default = "default_io_timeout")]
+    pub write_timeout: u64,
+    /// Cache retrieved messages on disk, so that retrieving them again will
+    /// not fetch from upstream.
+    #[serde(default)]
+    pub cache: bool,
+    /// Set `TCP_NODELAY` on connections to this upstream, like
+    /// `Downstream::tcp_nodelay`.
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Retry logins rejected by transient errors like `[IN-USE]`.
+    #[serde(default)]
+    pub retry: Retry,
+    /// Commands this upstream doesn't support, which are worked around.
+    #[serde(default)]
+    pub quirks: Quirks,
+}
+
+/// Retry is the policy to retry transient errors of an upstream, errors
+/// with `[IN-USE]` or `[SYS/TEMP]` response code are transient.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Max attempts including the first one, `1` disables retry.
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled after each
+    /// retry.
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: default_retry_attempts(),
+            backoff: default_retry_backoff(),
+        }
+    }
+}
+
+fn default_retry_attempts() -> u32 {
+    1
+}
+
+fn default_retry_backoff() -> u64 {
+    1000
+}
+
+/// Addrs is a single address or a list of addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Addrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Addrs {
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
+        match self {
+            Addrs::One(v) => std::slice::from_ref(v).iter(),
+            Addrs::Many(v) => v.iter(),
+        }
+    }
+
+    fn iter_mut(&mut self) -> std::slice::IterMut<'_, String> {
+        match self {
+            Addrs::One(v) => std::slice::from_mut(v).iter_mut(),
+            Addrs::Many(v) => v.iter_mut(),
+        }
+    }
+}
+
+/// Route maps users to an upstream.
+///
+/// `user` could be:
+///
+/// - an exact username like `alice@example.com`
+/// - a suffix like `*@example.com`
+/// - `*` which matches all users not matched by other routes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub user: String,
+    pub upstream: String,
+}
+
+impl Route {
+    fn matches(&self, username: &str) -> bool {
+        match self.user.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => username.ends_with(suffix),
+            None => self.user == username,
+        }
+    }
+}
+
+/// Protocol that postman speaks with downstreams or upstreams.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    Pop3,
+}
+
+impl Protocol {
+    const VARIANTS: &'static [&'static str] = &["pop3"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Pop3 => "pop3",
+        }
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Protocol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Protocol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let v = String::deserialize(deserializer)?;
+
+        match v.as_str() {
+            "pop3" => Ok(Protocol::Pop3),
+            _ => Err(de::Error::custom(format!(
+                "unsupported protocol {:?}, expected one of {:?}",
+                v,
+                Protocol::VARIANTS
+            ))),
+        }
+    }
+}
+
+/// Placeholder for secrets in debug output.
+const REDACTED: &str = "***";
+
+impl Debug for Downstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Downstream")
+            .field("protocol", &self.protocol)
+            .field("addr", &self.addr)
+            .field("auth_type", &self.auth_type)
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .field("login_delay", &self.login_delay)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("max_auth_attempts", &self.max_auth_attempts)
+            .field("strict_line_ending", &self.strict_line_ending)
+            .field("read_only", &self.read_only)
+            .field("disabled_commands", &self.disabled_commands)
+            .field("max_connections", &self.max_connections)
+            .field("max_session_duration", &self.max_session_duration)
+            .field("allow_plaintext_auth", &self.allow_plaintext_auth)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("passthrough", &self.passthrough)
+            .field("greeting", &self.greeting)
+            .field("implementation", &self.implementation)
+            .field("anonymous", &self.anonymous)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl Debug for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Upstream")
+            .field("name", &self.name)
+            .field("protocol", &self.protocol)
+            .field("addr", &self.addr)
+            .field("tls", &self.tls)
+            .field("tls_sni", &self.tls_sni)
+            .field("tls_ca", &self.tls_ca)
+            .field("tls_cert", &self.tls_cert)
+            .field("tls_key", &self.tls_key)
+            .field("auth_type", &self.auth_type)
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .field("max_response_bytes", &self.max_response_bytes)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("read_timeout", &self.read_timeout)
+            .field("write_timeout", &self.write_timeout)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("retry", &self.retry)
+            .field("quirks", &self.quirks)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+impl Debug for Anonymous {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Anonymous")
+            .field("maildrop", &self.maildrop)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl Downstream {
+    /// Split addr into host and port, port will be filled by default if missing.
+    pub fn host_port(&self) -> Result<(String, u16), ConfigError> {
+        parse_host_port(&self.addr, self.tls.is_some())
+    }
+
+    /// Whether the listener should set `IPV6_V6ONLY`, `None` if addr is
+    /// not an IPv6 address. Only the unspecified `[::]` is bound as dual
+    /// stack to accept IPv4 as well.
+    pub fn only_v6(&self) -> Result<Option<bool>, ConfigError> {
+        let (host, _) = self.host_port()?;
+
+        Ok(host.parse::<Ipv6Addr>().ok().map(|v| !v.is_unspecified()))
+    }
+
+    /// Capabilities served to clients of this downstream.
+    pub fn capabilities(&self) -> Capabilities {
+        let mut sasl = vec![String::from("PLAIN"), String::from("LOGIN")];
+        if matches!(&self.tls, Some(v) if v.client_ca.is_some()) {
+            sasl.push(String::from("EXTERNAL"));
+        }
+
+        let disabled = |cmd| self.disabled_commands.contains(&cmd);
+        if disabled(Command::AUTH) {
+            sasl.clear();
+        }
+
+        Capabilities {
+            top: !disabled(Command::TOP),
+            user: !disabled(Command::USER) && !disabled(Command::PASS),
+            sasl,
+            login_delay: self.login_delay,
+            uidl: !disabled(Command::UIDL),
+            implementation: Some(
+                self.implementation
+                    .clone()
+                    .unwrap_or_else(|| "postman".to_string()),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// Text of the greeting before the APOP timestamp.
+    pub fn greeting(&self) -> &str {
+        self.greeting.as_deref().unwrap_or("POP3 server ready")
+    }
+}
+
+impl Upstream {
+    /// Split all addrs into host and port in order, port will be filled by
+    /// default if missing.
+    pub fn host_ports(&self) -> Result<Vec<(String, u16)>, ConfigError> {
+        self.addr
+            .iter()
+            .map(|v| parse_host_port(v, self.tls))
+            .collect()
+    }
+
+    /// Timeouts of the connection to this upstream.
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(self.connect_timeout)),
+            read: Some(Duration::from_secs(self.read_timeout)),
+            write: Some(Duration::from_secs(self.write_timeout)),
+        }
+    }
+
+    /// Server name for TLS while connecting to `host`.
+    pub fn sni<'a>(&'a self, host: &'a str) -> &'a str {
+        self.tls_sni.as_deref().unwrap_or(host)
+    }
+}
+
+impl Config {
+    /// Load config from a file, the format is decided by its extension:
+    /// `.toml`, `.json` with feature `json`, or `.yaml` and `.yml` with
+    /// feature `yaml`. A trailing `.example` is skipped, so that
+    /// `config.toml.example` is read as toml.
+    ///
+    /// Relative `database_dir`, `data_dir` and TLS files of downstreams will
+    /// be resolved against the directory which contains the config file.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+
+        let content = read_to_string(path).map_err(|err| ConfigError::Io {
+            path: path.to_path_buf(),
+            source: err,
+        })?;
+        let mut cfg = parse(path, &content)?;
+
+        let base = path
+            .canonicalize()
+            .map_err(|err| ConfigError::Io {
+                path: path.to_path_buf(),
+                source: err,
+            })?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        cfg.database_dir = base.join(&cfg.database_dir);
+        cfg.data_dir = base.join(&cfg.data_dir);
+        for tls in cfg.downstreams.iter_mut().filter_map(|v| v.tls.as_mut()) {
+            tls.cert = base.join(&tls.cert);
+            tls.key = base.join(&tls.key);
+            if let Some(v) = tls.client_ca.as_mut() {
+                *v = base.join(&v);
+            }
+        }
+        for v in cfg.upstreams.iter_mut() {
+            for path in vec![&mut v.tls_ca, &mut v.tls_cert, &mut v.tls_key]
+                .into_iter()
+                .flatten()
+            {
+                *path = base.join(&path);
+            }
+        }
+
+        Ok(cfg)
+    }
+
+    /// Expand `${VAR}` in `username`, `password` and `addr` from environment.
+    ///
+    /// `$$` will be expanded to a literal `$`.
+    pub fn expand_env(&mut self) -> Result<(), ConfigError> {
+        for (idx, v) in self.downstreams.iter_mut().enumerate() {
+            let field = |name: &str| format!("downstream[{}].{}", idx, name);
+
+            v.addr = expand_env(field("addr"), &v.addr)?;
+            v.username = expand_env(field("username"), &v.username)?;
+            v.password = expand_env(field("password"), &v.password)?;
+        }
+        for (idx, v) in self.upstreams.iter_mut().enumerate() {
+            let field = |name: &str| format!("upstream[{}].{}", idx, name);
+
+            for addr in v.addr.iter_mut() {
+                *addr = expand_env(field("addr"), addr)?;
+            }
+            v.username = expand_env(field("username"), &v.username)?;
+            v.password = expand_env(field("password"), &v.password)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the upstream which serves `username`.
+    ///
+    /// Routes are checked in order, and the catch-all `*` route is used only
+    /// when no other routes matched.
+    pub fn resolve_upstream(&self, username: &str) -> Option<&Upstream> {
+        let route = self
+            .routes
+            .iter()
+            .filter(|v| v.user != "*")
+            .find(|v| v.matches(username))
+            .or_else(|| self.routes.iter().find(|v| v.user == "*"))?;
+
+        self.upstreams.iter().find(|v| v.name == route.upstream)
+    }
+
+    /// Hostname to be used in the greeting, the configured one or the
+    /// system hostname, falls back to `localhost` if it can't be detected
+    /// or is not valid.
+    pub fn hostname(&self) -> String {
+        if let Some(v) = &self.hostname {
+            return v.clone();
+        }
+
+        ["/proc/sys/kernel/hostname", "/etc/hostname"]
+            .iter()
+            .filter_map(|path| read_to_string(path).ok())
+            .map(|v| v.trim().to_string())
+            .find(|v| validate_hostname(v).is_ok())
+            .unwrap_or_else(|| "localhost".to_string())
+    }
+
+    /// Retention advertised as `EXPIRE`: `expire_days` if set, otherwise
+    /// the whole days of `max_message_age`, or `NEVER` if messages are kept
+    /// forever.
+    pub fn expire(&self) -> Expire {
+        match (self.expire_days, self.max_message_age) {
+            (Some(days), _) => Expire::Days(days),
+            (None, Some(age)) => Expire::Days((age / SECONDS_PER_DAY).min(u32::MAX as u64) as u32),
+            (None, None) => Expire::Never,
+        }
+    }
+
+    /// Age of messages to be removed by sweeps, never shorter than
+    /// `expire_days`. `None` if messages are kept forever.
+    pub fn sweep_age(&self) -> Option<Duration> {
+        let min = self.expire_days.unwrap_or_default() as u64 * SECONDS_PER_DAY;
+
+        self.max_message_age
+            .map(|age| Duration::from_secs(age.max(min)))
+    }
+
+    /// Validate config and collect all problems found.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errs = Vec::new();
+
+        if self.upstream_keepalive == 0 {
+            errs.push(ConfigError::InvalidValue {
+                field: "upstream_keepalive".to_string(),
+                value: self.upstream_keepalive.to_string(),
+            });
+        }
+        if self.max_message_age.is_some() && self.sweep_interval == 0 {
+            errs.push(ConfigError::InvalidValue {
+                field: "sweep_interval".to_string(),
+                value: self.sweep_interval.to_string(),
+            });
+        }
+        if self.tarpit_after_failures == Some(0) {
+            errs.push(ConfigError::InvalidValue {
+                field: "tarpit_after_failures".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        if let Some(v) = &self.hostname {
+            if validate_hostname(v).is_err() {
+                errs.push(ConfigError::InvalidValue {
+                    field: "hostname".to_string(),
+                    value: v.clone(),
+                });
+            }
+        }
+
+        // Invalid hostname has been reported above, check greetings with a
+        // valid one instead.
+        let hostname = Some(self.hostname())
+            .filter(|v| validate_hostname(v).is_ok())
+            .unwrap_or_else(|| "localhost".to_string());
+        for (idx, v) in self.downstreams.iter().enumerate() {
+            let field = |name: &str| format!("downstream[{}].{}", idx, name);
+
+            if v.host_port().is_err() {
+                errs.push(ConfigError::InvalidAddr {
+                    field: field("addr"),
+                    value: v.addr.clone(),
+                });
+            }
+            if v.max_connections == Some(0) {
+                errs.push(ConfigError::InvalidValue {
+                    field: field("max_connections"),
+                    value: "0".to_string(),
+                });
+            }
+            if v.max_session_duration == Some(0) {
+                errs.push(ConfigError::InvalidValue {
+                    field: field("max_session_duration"),
+                    value: "0".to_string(),
+                });
+            }
+            if let Some(greeting) = &v.greeting {
+                if make_apop_greeting(greeting, &hostname).is_err() {
+                    errs.push(ConfigError::InvalidValue {
+                        field: field("greeting"),
+                        value: greeting.clone(),
+                    });
+                }
+            }
+            if let Some(anonymous) = &v.anonymous {
+                // Served as a dir under data_dir like a user.
+                let mut components = Path::new(&anonymous.maildrop).components();
+                if !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                ) || anonymous.maildrop.starts_with('.')
+                {
+                    errs.push(ConfigError::InvalidValue {
+                        field: field("anonymous.maildrop"),
+                        value: anonymous.maildrop.clone(),
+                    });
+                }
+            }
+            if let Some(implementation) = &v.implementation {
+                // Sent as `IMPLEMENTATION implementation CRLF` in CAPA.
+                if implementation.bytes().any(|b| b < 0x20 || b == 0x7f)
+                    || implementation.len() + 17 > MAX_LINE_LENGTH
+                {
+                    errs.push(ConfigError::InvalidValue {
+                        field: field("implementation"),
+                        value: implementation.clone(),
+                    });
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        for (idx, v) in self.upstreams.iter().enumerate() {
+            let field = |name: &str| format!("upstream[{}].{}", idx, name);
+
+            if !names.insert(v.name.as_str()) {
+                errs.push(ConfigError::DuplicateUpstream {
+                    field: field("name"),
+                    value: v.name.clone(),
+                });
+            }
+            if v.addr.iter().next().is_none() {
+                errs.push(ConfigError::InvalidAddr {
+                    field: field("addr"),
+                    value: String::new(),
+                });
+            }
+            for addr in v.addr.iter() {
+                if parse_host_port(addr, v.tls).is_err() {
+                    errs.push(ConfigError::InvalidAddr {
+                        field: field("addr"),
+                        value: addr.clone(),
+                    });
+                }
+            }
+            if let Some(sni) = &v.tls_sni {
+                if webpki::DNSNameRef::try_from_ascii_str(sni).is_err() {
+                    errs.push(ConfigError::InvalidValue {
+                        field: field("tls_sni"),
+                        value: sni.clone(),
+                    });
+                }
+            }
+            // A client certificate is useless without its key.
+            match (&v.tls_cert, &v.tls_key) {
+                (Some(_), None) => errs.push(ConfigError::InvalidValue {
+                    field: field("tls_key"),
+                    value: String::new(),
+                }),
+                (None, Some(path)) => errs.push(ConfigError::InvalidValue {
+                    field: field("tls_cert"),
+                    value: path.display().to_string(),
+                }),
+                _ => {}
+            }
+        }
+
+        for (idx, v) in self.routes.iter().enumerate() {
+            if !names.contains(v.upstream.as_str()) {
+                errs.push(ConfigError::UnknownUpstream {
+                    field: format!("route[{}].upstream", idx),
+                    value: v.upstream.clone(),
+                });
+            }
+        }
+
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+}
+
+fn expand_env(field: String, value: &str) -> Result<String, ConfigError> {
+    let mut s = String::with_capacity(value.len());
+
+    let mut rest = value;
+    while let Some(idx) = rest.find('$') {
+        s.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        if let Some(v) = rest.strip_prefix('$') {
+            s.push('$');
+            rest = v;
+        } else if let Some(v) = rest.strip_prefix('{') {
+            let (name, v) = v.split_once('}').ok_or_else(|| ConfigError::InvalidEnv {
+                field: field.clone(),
+                value: value.to_string(),
+            })?;
+            let env = std::env::var(name).map_err(|_| ConfigError::UnsetEnv {
+                field: field.clone(),
+                value: name.to_string(),
+            })?;
+
+            s.push_str(&env);
+            rest = v;
+        } else {
+            s.push('$');
+        }
+    }
+    s.push_str(rest);
+
+    Ok(s)
+}
+
+/// Default port for POP3.
+const POP3_PORT: u16 = 110;
+/// Default port for POP3 over TLS.
+const POP3S_PORT: u16 = 995;
+
+/// Parse config content in the format decided by the extension of path.
+fn parse(path: &Path, content: &str) -> Result<Config, ConfigError> {
+    let name = path
+        .file_name()
+        .and_then(|v| v.to_str())
+        .unwrap_or_default();
+    let name = name.strip_suffix(".example").unwrap_or(name);
+
+    let parse_err = |err: Box<dyn std::error::Error + Send + Sync>| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source: err,
+    };
+
+    match Path::new(name).extension().and_then(|v| v.to_str()) {
+        Some("toml") => toml::from_str(content).map_err(|err| parse_err(err.into())),
+        #[cfg(feature = "json")]
+        Some("json") => serde_json::from_str(content).map_err(|err| parse_err(err.into())),
+        #[cfg(not(feature = "json"))]
+        Some("json") => Err(ConfigError::FormatDisabled {
+            path: path.to_path_buf(),
+            format: "json",
+        }),
+        #[cfg(feature = "yaml")]
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(content).map_err(|err| parse_err(err.into()))
+        }
+        #[cfg(not(feature = "yaml"))]
+        Some("yaml") | Some("yml") => Err(ConfigError::FormatDisabled {
+            path: path.to_path_buf(),
+            format: "yaml",
+        }),
+        _ => Err(ConfigError::UnknownFormat {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+/// Parse addr like `host`, `host:port`, `[ipv6]:port` into host and port.
+///
+/// Host will not be resolved here, so that hostnames which need DNS can
+/// be returned as is.
+fn parse_host_port(addr: &str, tls: bool) -> Result<(String, u16), ConfigError> {
+    let err = || ConfigError::InvalidAddr {
+        field: "addr".to_string(),
+        value: addr.to_string(),
+    };
+
+    let (host, port) = if let Some(v) = addr.strip_prefix('[') {
+        // IPv6 addr like `[::1]:110`.
+        let (host, rest) = v.split_once(']').ok_or_else(err)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(err)?)),
+        }
+    } else if addr.matches(':').count() > 1 {
+        // Bare IPv6 addr without port.
+        (addr, None)
+    } else {
+        match addr.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(err());
+    }
+    let port = match port {
+        None if tls => POP3S_PORT,
+        None => POP3_PORT,
+        Some(v) => v.parse::<u16>().map_err(|_| err())?,
+    };
+
+    Ok((host.to_string(), port))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Config file can't be read.
+    Io { path: PathBuf, source: io::Error },
+    /// Config file is not valid in its format or doesn't match `Config`.
+    Parse {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Extension of config file is not a known format.
+    UnknownFormat { path: PathBuf },
+    /// Config file is in a format not enabled by features.
+    FormatDisabled { path: PathBuf, format: &'static str },
+    /// Address can't be parsed.
+    InvalidAddr { field: String, value: String },
+    /// Value is out of the allowed range.
+    InvalidValue { field: String, value: String },
+    /// Upstream name has been used by another upstream.
+    DuplicateUpstream { field: String, value: String },
+    /// Upstream referenced by route doesn't exist.
+    UnknownUpstream { field: String, value: String },
+    /// Environment variable is referenced but not set.
+    UnsetEnv { field: String, value: String },
+    /// Environment variable reference is not closed.
+    InvalidEnv { field: String, value: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "read config {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "parse config {}: {}", path.display(), source)
+            }
+            ConfigError::UnknownFormat { path } => write!(
+                f,
+                "unknown format of config {}, expect .toml, .json, .yaml or .yml",
+                path.display()
+            ),
+            ConfigError::FormatDisabled { path, format } => write!(
+                f,
+                "read config {}: format {} is not enabled, build with feature {}",
+                path.display(),
+                format,
+                format
+            ),
+            ConfigError::InvalidAddr { field, value } => {
+                write!(f, "{}: invalid address {:?}", field, value)
+            }
+            ConfigError::InvalidValue { field, value } => {
+                write!(f, "{}: invalid value {:?}", field, value)
+            }
+            ConfigError::DuplicateUpstream { field, value } => {
+                write!(f, "{}: duplicate upstream name {:?}", field, value)
+            }
+            ConfigError::UnknownUpstream { field, value } => {
+                write!(f, "{}: unknown upstream {:?}", field, value)
+            }
+            ConfigError::UnsetEnv { field, value } => {
+                write!(f, "{}: environment variable {} is not set", field, value)
+            }
+            ConfigError::InvalidEnv { field, value } => {
+                write!(f, "{}: unclosed environment variable in {:?}", field, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_path() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml.example");
+
+        let cfg = Config::from_path(&path).expect("load config");
+
+        assert_eq!(cfg.downstreams.len(), 1);
+        assert_eq!(cfg.downstreams[0].protocol, Protocol::Pop3);
+        assert_eq!(cfg.upstreams.len(), 1);
+        assert_eq!(cfg.upstreams[0].name, "example");
+        assert!(cfg.database_dir.is_absolute());
+        assert!(cfg.data_dir.is_absolute());
+    }
+
+    #[test]
+    fn formats() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("postman-formats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let path = dir.join("config.ini");
+        std::fs::write(&path, "")?;
+        assert!(matches!(
+            Config::from_path(&path),
+            Err(ConfigError::UnknownFormat { .. })
+        ));
+
+        let json = r#"{
+            "database_dir": "db",
+            "data_dir": "mails",
+            "downstream": [{
+                "protocol": "pop3",
+                "addr": "127.0.0.1:110",
+                "auth_type": "user",
+                "username": "postman",
+                "password": "postman"
+            }]
+        }"#;
+        let path = dir.join("config.json");
+        std::fs::write(&path, json)?;
+        match Config::from_path(&path) {
+            #[cfg(feature = "json")]
+            Ok(cfg) => assert_eq!(cfg.downstreams[0].addr, "127.0.0.1:110"),
+            #[cfg(not(feature = "json"))]
+            Err(ConfigError::FormatDisabled { format: "json", .. }) => {}
+            v => panic!("unexpected result: {:?}", v),
+        }
+
+        let yaml = r#"
+database_dir: db
+data_dir: mails
+downstream:
+  - protocol: pop3
+    addr: "127.0.0.1:110"
+    auth_type: user
+    username: postman
+    password: postman
+"#;
+        let path = dir.join("config.yml");
+        std::fs::write(&path, yaml)?;
+        match Config::from_path(&path) {
+            #[cfg(feature = "yaml")]
+            Ok(cfg) => assert_eq!(cfg.downstreams[0].addr, "127.0.0.1:110"),
+            #[cfg(not(feature = "yaml"))]
+            Err(ConfigError::FormatDisabled { format: "yaml", .. }) => {}
+            v => panic!("unexpected result: {:?}", v),
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml.example");
+        let mut cfg = Config::from_path(&path).expect("load config");
+        assert!(cfg.validate().is_ok());
+
+        cfg.upstream_keepalive = 0;
+        cfg.tarpit_after_failures = Some(0);
+        cfg.hostname = Some("my host".to_string());
+        cfg.downstreams[0].addr = "localhost:".to_string();
+        cfg.downstreams[0].max_session_duration = Some(0);
+        cfg.downstreams[0].greeting = Some("x".repeat(500));
+        cfg.downstreams[0].implementation = Some("postman\r\n+OK".to_string());
+        cfg.downstreams[0].anonymous = Some(Anonymous {
+            maildrop: "../demo".to_string(),
+            password: None,
+        });
+        cfg.upstreams.push(cfg.upstreams[0].clone());
+        cfg.upstreams[1].tls_sni = Some("127.0.0.1".to_string());
+        cfg.upstreams[1].tls_cert = Some(PathBuf::from("cert.pem"));
+        cfg.routes.push(Route {
+            user: "*".to_string(),
+            upstream: "unknown".to_string(),
+        });
+
+        let errs = cfg.validate().unwrap_err();
+        assert_eq!(errs.len(), 12);
+        assert_eq!(
+            errs.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            vec![
+                r#"upstream_keepalive: invalid value "0""#,
+                r#"tarpit_after_failures: invalid value "0""#,
+                r#"hostname: invalid value "my host""#,
+                r#"downstream[0].addr: invalid address "localhost:""#,
+                r#"downstream[0].max_session_duration: invalid value "0""#,
+                format!(
+                    r#"downstream[0].greeting: invalid value "{}""#,
+                    "x".repeat(500)
+                )
+                .as_str(),
+                r#"downstream[0].anonymous.maildrop: invalid value "../demo""#,
+                r#"downstream[0].implementation: invalid value "postman\r\n+OK""#,
+                r#"upstream[1].name: duplicate upstream name "example""#,
+                r#"upstream[1].tls_sni: invalid value "127.0.0.1""#,
+                r#"upstream[1].tls_key: invalid value """#,
+                r#"route[1].upstream: unknown upstream "unknown""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn expire() {
+        let mut cfg = Config::default();
+        let expire = |cfg: &Config| format!("EXPIRE {}", cfg.expire());
+
+        assert_eq!(expire(&cfg), "EXPIRE NEVER");
+        assert_eq!(cfg.sweep_age(), None);
+
+        cfg.max_message_age = Some(30 * SECONDS_PER_DAY + 1);
+        assert_eq!(expire(&cfg), "EXPIRE 30");
+        assert_eq!(
+            cfg.sweep_age(),
+            Some(Duration::from_secs(30 * SECONDS_PER_DAY + 1))
+        );
+
+        // Sweeps honor the advertised guarantee.
+        cfg.expire_days = Some(60);
+        assert_eq!(expire(&cfg), "EXPIRE 60");
+        assert_eq!(
+            cfg.sweep_age(),
+            Some(Duration::from_secs(60 * SECONDS_PER_DAY))
+        );
+
+        cfg.max_message_age = None;
+        cfg.expire_days = Some(0);
+        assert_eq!(expire(&cfg), "EXPIRE 0");
+        assert_eq!(cfg.sweep_age(), None);
+    }
+
+    #[test]
+    fn resolve_upstream() {
+        let mut cfg: Config = toml::from_str(
+            r#"
+database_dir = "db"
+data_dir = "mails"
+
+[[downstream]]
+protocol = "pop3"
+addr = "127.0.0.1:110"
+auth_type = "user"
+username = "postman"
+password = "postman"
+
+[[upstream]]
+name = "default"
+protocol = "pop3"
+addr = "pop.example.com"
+auth_type = "user"
+username = "user"
+password = "pass"
+
+[[upstream]]
+name = "qq"
+protocol = "pop3"
+addr = "pop.qq.com"
+auth_type = "user"
+username = "user"
+password = "pass"
+
+[[route]]
+user = "*"
+upstream = "default"
+
+[[route]]
+user = "*@qq.com"
+upstream = "qq"
+"#,
+        )
+        .expect("parse config");
+
+        let name = |cfg: &Config, user| cfg.resolve_upstream(user).map(|v| v.name.clone());
+        assert_eq!(name(&cfg, "alice@qq.com"), Some("qq".to_string()));
+        assert_eq!(name(&cfg, "alice@gmail.com"), Some("default".to_string()));
+
+        cfg.routes.remove(0);
+        assert_eq!(name(&cfg, "alice@gmail.com"), None);
+    }
+
+    #[test]
+    fn builder() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+name = "example"
+protocol = "pop3"
+addr = "pop.example.com"
+auth_type = "user"
+username = "user"
+password = "pass"
+"#,
+        )
+        .expect("parse upstream");
+
+        let cfg = ConfigBuilder::new()
+            .database_dir("db")
+            .data_dir("mails")
+            .add_upstream(upstream.clone())
+            .add_route("*", "example")
+            .build()
+            .expect("build config");
+        assert_eq!(cfg.database_dir, PathBuf::from("db"));
+        assert_eq!(cfg.drain_timeout, 30);
+        assert_eq!(
+            cfg.resolve_upstream("alice").map(|v| v.name.as_str()),
+            Some("example")
+        );
+
+        let errs = ConfigBuilder::new()
+            .add_upstream(upstream.clone())
+            .add_upstream(upstream)
+            .add_route("*", "unknown")
+            .build()
+            .unwrap_err();
+        assert_eq!(errs.len(), 2);
+    }
+
+    #[test]
+    fn host_port() {
+        let cases = vec![
+            ("example.com", false, Some(("example.com", 110))),
+            ("example.com", true, Some(("example.com", 995))),
+            ("example.com:1110", true, Some(("example.com", 1110))),
+            ("127.0.0.1:110", false, Some(("127.0.0.1", 110))),
+            ("[::1]:995", false, Some(("::1", 995))),
+            ("[::]:110", true, Some(("::", 110))),
+            ("[::1]", true, Some(("::1", 995))),
+            ("::1", false, Some(("::1", 110))),
+            ("example.com:", false, None),
+            (":110", false, None),
+            ("example.com:pop3", false, None),
+            ("[::1", false, None),
+            ("", false, None),
+        ];
+
+        for (addr, tls, expect) in cases {
+            let actual = parse_host_port(addr, tls).ok();
+            let expect = expect.map(|(h, p)| (h.to_string(), p));
+
+            assert_eq!(actual, expect, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn only_v6() {
+        let mut cfg: Downstream = toml::from_str(
+            r#"
+protocol = "pop3"
+addr = "[::1]:995"
+auth_type = "user"
+username = "postman"
+password = "postman"
+"#,
+        )
+        .expect("parse downstream");
+
+        let cases = vec![
+            ("[::1]:995", Some(Some(true))),
+            ("[::]:110", Some(Some(false))),
+            ("0.0.0.0:110", Some(None)),
+            ("localhost:110", Some(None)),
+            ("[::1", None),
+        ];
+        for (addr, expect) in cases {
+            cfg.addr = addr.to_string();
+            assert_eq!(cfg.only_v6().ok(), expect, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn auth_type() {
+        let cfg: Upstream = toml::from_str(
+            r#"
+name = "example"
+protocol = "pop3"
+addr = "pop.example.com"
+auth_type = "cram-md5"
+username = "user"
+password = "pass"
+"#,
+        )
+        .expect("parse upstream");
+        assert_eq!(cfg.auth_type, AuthType::SaslCramMd5);
+        assert_eq!(cfg.addr, Addrs::One("pop.example.com".to_string()));
+
+        let err = toml::from_str::<Upstream>(
+            r#"
+name = "example"
+protocol = "pop3"
+addr = "pop.example.com"
+auth_type = "PLAIN"
+username = "user"
+password = "pass"
+"#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected one of `user`, `apop`, `plain`, `login`, `cram-md5`"));
+    }
+
+    #[test]
+    fn disabled_commands() {
+        let cfg: Downstream = toml::from_str(
+            r#"
+protocol = "pop3"
+addr = "127.0.0.1"
+auth_type = "user"
+username = "postman"
+password = "postman"
+disabled_commands = ["dele", "TOP"]
+"#,
+        )
+        .expect("parse downstream");
+        assert_eq!(cfg.disabled_commands, vec![Command::DELE, Command::TOP]);
+        let capabilities = cfg.capabilities();
+        assert!(!capabilities.top);
+        assert!(capabilities.uidl);
+
+        let err = toml::from_str::<Downstream>(
+            r#"
+protocol = "pop3"
+addr = "127.0.0.1"
+auth_type = "user"
+username = "postman"
+password = "postman"
+disabled_commands = ["XTND"]
+"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains(r#"unknown command "XTND""#));
+    }
+
+    #[test]
+    fn addrs() {
+        let cfg: Upstream = toml::from_str(
+            r#"
+name = "example"
+protocol = "pop3"
+addr = ["pop1.example.com", "pop2.example.com:1110"]
+auth_type = "user"
+username = "user"
+password = "pass"
+"#,
+        )
+        .expect("parse upstream");
+
+        assert_eq!(
+            cfg.host_ports().expect("parse addrs"),
+            vec![
+                ("pop1.example.com".to_string(), 110),
+                ("pop2.example.com".to_string(), 1110)
+            ]
+        );
+        assert_eq!(cfg.sni("pop1.example.com"), "pop1.example.com");
+
+        let cfg = Upstream {
+            tls_sni: Some("pop.example.com".to_string()),
+            ..cfg
+        };
+        assert_eq!(cfg.sni("192.0.2.1"), "pop.example.com");
+    }
+
+    #[test]
+    fn protocol() {
+        let err = toml::from_str::<Upstream>(
+            r#"
+name = "example"
+protocol = "imap"
+addr = "imap.example.com"
+auth_type = "user"
+username = "user"
+password = "pass"
+"#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(r#"unsupported protocol "imap", expected one of ["pop3"]"#));
+    }
+
+    #[test]
+    fn debug_redacts_password() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml.example");
+        let mut cfg = Config::from_path(&path).expect("load config");
+        cfg.downstreams[0].password = "downstream-secret".to_string();
+        cfg.upstreams[0].password = "upstream-secret".to_string();
+
+        let v = format!("{:?}", cfg);
+        assert!(!v.contains("downstream-secret"));
+        assert!(!v.contains("upstream-secret"));
+        assert!(v.contains(r#"password: "***""#));
+        assert!(v.contains(&cfg.upstreams[0].username));
+
+        let v = toml::to_string(&cfg).expect("serialize config");
+        assert!(v.contains("downstream-secret"));
+        assert!(v.contains("upstream-secret"));
+    }
+
+    #[test]
+    fn expand_env() {
+        std::env::set_var("POSTMAN_TEST_EXPAND_USER", "alice");
+        std::env::set_var("POSTMAN_TEST_EXPAND_PASS", "s3cret");
+        std::env::remove_var("POSTMAN_TEST_EXPAND_UNSET");
+
+        let cases = vec![
+            ("plain", Some("plain")),
+            ("${POSTMAN_TEST_EXPAND_USER}", Some("alice")),
+            (
+                "${POSTMAN_TEST_EXPAND_USER}:${POSTMAN_TEST_EXPAND_PASS}",
+                Some("alice:s3cret"),
+            ),
+            ("pa$$word", Some("pa$word")),
+            ("$$${POSTMAN_TEST_EXPAND_PASS}", Some("$s3cret")),
+            ("cost $5", Some("cost $5")),
+            ("${POSTMAN_TEST_EXPAND_UNSET}", None),
+            ("${POSTMAN_TEST_EXPAND_USER", None),
+        ];
+        for (value, expect) in cases {
+            let actual = super::expand_env("password".to_string(), value).ok();
+
+            assert_eq!(actual.as_deref(), expect, "{}", value);
+        }
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml.example");
+        let mut cfg = Config::from_path(&path).expect("load config");
+        cfg.upstreams[0].password = "${POSTMAN_TEST_EXPAND_PASS}".to_string();
+        cfg.upstreams[0].name = "${POSTMAN_TEST_EXPAND_USER}".to_string();
+        cfg.expand_env().expect("expand env");
+        assert_eq!(cfg.upstreams[0].password, "s3cret");
+        assert_eq!(cfg.upstreams[0].name, "${POSTMAN_TEST_EXPAND_USER}");
+
+        cfg.downstreams[0].username = "${POSTMAN_TEST_EXPAND_UNSET}".to_string();
+        assert_eq!(
+            cfg.expand_env().unwrap_err().to_string(),
+            "downstream[0].username: environment variable POSTMAN_TEST_EXPAND_UNSET is not set"
+        );
+    }
+
+    #[test]
+    fn from_path_not_exist() {
+        let err = Config::from_path("not_exist.toml").unwrap_err();
+
+        assert!(matches!(err, ConfigError::Io { .. }));
+    }
+}
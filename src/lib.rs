@@ -0,0 +1,19 @@
+pub mod auth;
+pub mod cache;
+pub mod config;
+mod counting;
+pub mod lock;
+pub mod login;
+pub mod maildrop;
+pub mod metrics;
+pub mod proxy;
+mod reload;
+pub mod rewrite;
+mod server;
+mod shutdown;
+pub mod tarpit;
+mod tls;
+pub mod uidl;
+pub mod upstream;
+
+pub use server::Server;
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::warn;
+
+/// A session which has been idle for less than this is considered alive,
+/// it's the minimum autologout timer of RFC 1939.
+const LIVE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// MaildropLocks grants exclusive access to maildrops keyed by user, as
+/// POP3 requires a maildrop to be opened by one session at a time.
+///
+/// A lock is held by `MaildropLock` and released once it's dropped, so
+/// that a session ending by any means, including a panic or a timeout,
+/// never leaves the maildrop locked.
+#[derive(Debug, Clone, Default)]
+pub struct MaildropLocks {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    locks: HashMap<String, Entry>,
+    /// Id of the next lock, so that a lock released by force never
+    /// releases the one acquired after it.
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    /// When the holder was active for the last time.
+    active: Arc<Mutex<Instant>>,
+}
+
+impl MaildropLocks {
+    pub fn new() -> MaildropLocks {
+        MaildropLocks::default()
+    }
+
+    /// Lock the maildrop of user, returns `None` if it has been locked by
+    /// another session.
+    pub fn try_lock(&self, user: &str) -> Option<MaildropLock> {
+        let mut state = self.state.lock().expect("lock maildrop locks");
+        if state.locks.contains_key(user) {
+            return None;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let active = Arc::new(Mutex::new(Instant::now()));
+        state.locks.insert(
+            user.to_string(),
+            Entry {
+                id,
+                active: active.clone(),
+            },
+        );
+
+        Some(MaildropLock {
+            state: self.state.clone(),
+            user: user.to_string(),
+            id,
+            active,
+        })
+    }
+
+    /// Release the lock of user left by a stuck session, so that the user
+    /// could login again. Returns whether there was a lock.
+    ///
+    /// Locks whose holder has been active in the last 10 minutes are held
+    /// by live sessions, they are refused.
+    pub fn force_unlock(&self, user: &str) -> Result<bool> {
+        self.force_unlock_at(user, Instant::now())
+    }
+
+    fn force_unlock_at(&self, user: &str, now: Instant) -> Result<bool> {
+        let mut state = self.state.lock().expect("lock maildrop locks");
+        let idle = match state.locks.get(user) {
+            Some(v) => now.saturating_duration_since(*v.active.lock().expect("lock active")),
+            None => return Ok(false),
+        };
+        if idle < LIVE_WINDOW {
+            return Err(anyhow::anyhow!(
+                "maildrop of {} is held by a live session, idle for {:?}",
+                user,
+                idle
+            ));
+        }
+
+        state.locks.remove(user);
+        warn!("force unlocked maildrop of {}, idle for {:?}", user, idle);
+        Ok(true)
+    }
+}
+
+/// MaildropLock is an acquired lock of `MaildropLocks`.
+#[derive(Debug)]
+pub struct MaildropLock {
+    state: Arc<Mutex<State>>,
+    user: String,
+    id: u64,
+    active: Arc<Mutex<Instant>>,
+}
+
+impl MaildropLock {
+    /// Mark the holder as active, so that the lock is not released by
+    /// `MaildropLocks::force_unlock`.
+    pub fn touch(&self) {
+        *self.active.lock().expect("lock active") = Instant::now();
+    }
+}
+
+impl Drop for MaildropLock {
+    fn drop(&mut self) {
+        // Never panic here, it may be dropped while unwinding.
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        // It may have been released by force and acquired by another.
+        if matches!(state.locks.get(&self.user), Some(v) if v.id == self.id) {
+            state.locks.remove(&self.user);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock() {
+        let locks = MaildropLocks::new();
+
+        let lock = locks.try_lock("postman");
+        assert!(lock.is_some());
+        assert!(locks.try_lock("postman").is_none());
+        assert!(locks.try_lock("other").is_some());
+
+        drop(lock);
+        assert!(locks.try_lock("postman").is_some());
+    }
+
+    #[test]
+    fn force_unlock() {
+        let locks = MaildropLocks::new();
+        let stale = locks.try_lock("postman").expect("lock");
+        let now = Instant::now();
+
+        // Held by a live session.
+        assert!(locks.force_unlock_at("postman", now).is_err());
+        assert!(locks.try_lock("postman").is_none());
+
+        assert!(locks
+            .force_unlock_at("postman", now + LIVE_WINDOW)
+            .expect("force unlock"));
+        assert!(!locks.force_unlock("postman").expect("force unlock"));
+        let lock = locks.try_lock("postman").expect("lock again");
+
+        // The stale lock never releases the new one.
+        drop(stale);
+        assert!(locks.try_lock("postman").is_none());
+        drop(lock);
+        assert!(locks.try_lock("postman").is_some());
+    }
+}
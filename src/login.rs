@@ -0,0 +1,67 @@
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+/// LoginStore records the last login time of users.
+///
+/// The value is the unix timestamp of the last successful login, used to
+/// enforce `LOGIN-DELAY` described in RFC 2449.
+#[derive(Debug, Clone)]
+pub struct LoginStore {
+    tree: sled::Tree,
+}
+
+impl LoginStore {
+    pub fn open(db: &sled::Db) -> Result<LoginStore> {
+        Ok(LoginStore {
+            tree: db.open_tree("login")?,
+        })
+    }
+
+    /// Record a successful login of user at now.
+    pub fn record(&self, user: &str) -> Result<()> {
+        self.tree.insert(user, &now()?.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Returns how many seconds user needs to wait before the next login,
+    /// `None` means user could login now.
+    pub fn remaining(&self, user: &str, delay: u32) -> Result<Option<u64>> {
+        let last = match self.tree.get(user)? {
+            None => return Ok(None),
+            Some(v) => u64::from_be_bytes(v.as_ref().try_into()?),
+        };
+
+        let next = last + u64::from(delay);
+        let now = now()?;
+        if now >= next {
+            Ok(None)
+        } else {
+            Ok(Some(next - now))
+        }
+    }
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remaining() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let store = LoginStore::open(&db)?;
+
+        assert_eq!(store.remaining("postman", 900)?, None);
+        store.record("postman")?;
+        assert!(store.remaining("postman", 900)?.unwrap() > 0);
+        assert_eq!(store.remaining("postman", 0)?, None);
+        assert_eq!(store.remaining("others", 900)?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use md5::{Digest, Md5};
+use postman_pop3::{
+    message_octet_size, message_top, normalize_line_endings, validate_uid, Maildrop, MessageMeta,
+};
+
+/// Dirs of opened maildrops with how many times each one is opened, which
+/// are skipped by `FileMaildrop::sweep`.
+static OPENED: Mutex<BTreeMap<PathBuf, usize>> = Mutex::new(BTreeMap::new());
+
+/// OpenGuard marks a maildrop dir as opened until dropped.
+#[derive(Debug)]
+struct OpenGuard(PathBuf);
+
+impl OpenGuard {
+    fn new(dir: &Path) -> OpenGuard {
+        let mut opened = OPENED.lock().expect("lock opened maildrops");
+        *opened.entry(dir.to_path_buf()).or_default() += 1;
+
+        OpenGuard(dir.to_path_buf())
+    }
+}
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        let mut opened = OPENED.lock().expect("lock opened maildrops");
+        if let Some(n) = opened.get_mut(&self.0) {
+            *n -= 1;
+            if *n == 0 {
+                opened.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// FileMaildrop serves `.eml` files in a directory as a maildrop.
+///
+/// Files are sorted by name and numbered from `1` while opening, the
+/// numbering keeps stable in the whole session. Files are removed only
+/// while committing or sweeping.
+#[derive(Debug)]
+pub struct FileMaildrop {
+    messages: Vec<MessageMeta>,
+    /// See `Maildrop::read_only`.
+    read_only: bool,
+    /// Keeps the maildrop from being swept while it's open.
+    _guard: OpenGuard,
+}
+
+impl FileMaildrop {
+    /// Open the maildrop at `dir`, an absent dir is an empty maildrop.
+    pub fn open(dir: impl AsRef<Path>) -> Result<FileMaildrop> {
+        // Marked before reading, so that a sweep never removes files of a
+        // maildrop being opened.
+        let guard = OpenGuard::new(dir.as_ref());
+
+        let mut paths = Vec::new();
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry?.path();
+                    if path.is_file() && path.extension() == Some("eml".as_ref()) {
+                        paths.push(path);
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        paths.sort();
+
+        let mut messages = Vec::with_capacity(paths.len());
+        let mut uids = HashSet::with_capacity(paths.len());
+        for (i, path) in paths.iter().enumerate() {
+            let content = fs::read(path)?;
+            // Derived from both the file name and content, so that identical
+            // messages in different files have their own unique-ids, and a
+            // rewritten file is a new message to clients.
+            let mut hasher = Md5::new();
+            hasher.update(
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .as_bytes(),
+            );
+            hasher.update(b"\0");
+            hasher.update(&content);
+            let uid = format!("{:x}", hasher.finalize());
+            validate_uid(&uid)?;
+            // Unique-ids must be unique in a maildrop, which could only be
+            // broken by a collision of digests.
+            if !uids.insert(uid.clone()) {
+                return Err(anyhow::anyhow!(
+                    "duplicate unique-id {} of {}",
+                    uid,
+                    path.display()
+                ));
+            }
+
+            messages.push(MessageMeta::new(
+                i + 1,
+                &uid,
+                message_octet_size(&content),
+                &path.to_string_lossy(),
+            ));
+        }
+
+        Ok(FileMaildrop {
+            messages,
+            read_only: false,
+            _guard: guard,
+        })
+    }
+
+    /// Serve the maildrop read-only, files are never removed by the
+    /// session.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Remove messages of the maildrop at `dir` whose file was modified
+    /// more than `max_age` ago, returns how many have been removed.
+    ///
+    /// Sweeping stops once the maildrop is opened by any session, the rest
+    /// of it will be swept next time.
+    pub fn sweep(dir: impl AsRef<Path>, max_age: Duration) -> Result<usize> {
+        let dir = dir.as_ref();
+        let entries = match fs::read_dir(dir) {
+            Ok(v) => v,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+
+        let now = SystemTime::now();
+        let mut n = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() || path.extension() != Some("eml".as_ref()) {
+                continue;
+            }
+
+            let modified = fs::metadata(&path)?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() <= max_age {
+                continue;
+            }
+
+            // Checked for every file as sessions may open the maildrop
+            // during the sweep, the lock is held only while removing.
+            let opened = OPENED.lock().expect("lock opened maildrops");
+            if opened.contains_key(dir) {
+                break;
+            }
+            fs::remove_file(&path)?;
+            n += 1;
+        }
+
+        Ok(n)
+    }
+
+    fn get(&self, id: usize) -> Result<&MessageMeta> {
+        match id.checked_sub(1).and_then(|i| self.messages.get(i)) {
+            Some(v) if !v.is_deleted() => Ok(v),
+            _ => Err(anyhow::anyhow!("no such message")),
+        }
+    }
+
+    /// Read message `id` with CRLF line endings, as messages may be stored
+    /// with LF line endings.
+    fn read(&self, id: usize) -> Result<Vec<u8>> {
+        let msg = self.get(id)?;
+
+        match fs::read(&msg.path) {
+            Ok(v) => Ok(normalize_line_endings(v)),
+            // The file could be removed by others during this session.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(anyhow::anyhow!("no such message"))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Open the file of message `id` to stream it like RETR, line endings
+    /// are left to the writer like `write_retr`. The message is marked as
+    /// fetched.
+    pub fn open_message(&mut self, id: usize) -> Result<fs::File> {
+        let msg = self.get(id)?;
+
+        let file = match fs::File::open(&msg.path) {
+            Ok(v) => v,
+            // The file could be removed by others during this session.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow::anyhow!("no such message"))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.messages[id - 1].set_fetched();
+
+        Ok(file)
+    }
+}
+
+impl Maildrop for FileMaildrop {
+    fn stat(&self) -> Result<(usize, usize)> {
+        let v = self.list()?;
+
+        Ok((v.len(), v.iter().map(|v| v.size).sum()))
+    }
+
+    fn stat_full(&self) -> Result<(usize, usize, usize, usize)> {
+        let (count, size) = self.stat()?;
+        let deleted: Vec<&MessageMeta> = self.messages.iter().filter(|v| v.is_deleted()).collect();
+
+        Ok((
+            count,
+            size,
+            deleted.len(),
+            deleted.iter().map(|v| v.size).sum(),
+        ))
+    }
+
+    fn list(&self) -> Result<Vec<MessageMeta>> {
+        Ok(self
+            .messages
+            .iter()
+            .filter(|v| !v.is_deleted())
+            .cloned()
+            .collect())
+    }
+
+    fn retr(&mut self, id: usize) -> Result<Vec<u8>> {
+        let content = self.read(id)?;
+        self.messages[id - 1].set_fetched();
+
+        Ok(content)
+    }
+
+    fn top(&mut self, id: usize, lines: usize) -> Result<Vec<u8>> {
+        let content = self.read(id)?;
+
+        Ok(message_top(&content, lines))
+    }
+
+    fn dele(&mut self, id: usize) -> Result<()> {
+        self.read(id)?;
+        self.messages[id - 1].set_deleted();
+
+        Ok(())
+    }
+
+    fn uidl(&self) -> Result<BTreeMap<usize, String>> {
+        Ok(self.list()?.into_iter().map(|v| (v.id, v.uid)).collect())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.messages.iter_mut().for_each(MessageMeta::reset);
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        for msg in self.messages.iter().filter(|v| v.is_deleted()) {
+            match fs::remove_file(&msg.path) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.messages.retain(|v| !v.is_deleted());
+
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use postman_pop3::{dispatch, Request, Response};
+    use std::env;
+
+    #[test]
+    fn file_maildrop() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-maildrop-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("1.eml"), "Subject: a\r\n\r\nhello\r\nworld\r\n")?;
+        fs::write(dir.join("2.eml"), "Subject: b\r\n\r\nworld\r\n")?;
+        fs::write(dir.join("3.eml"), "Subject: c\r\n\r\n!\r\n")?;
+        fs::write(dir.join("ignored.txt"), "not a message")?;
+
+        let mut md = FileMaildrop::open(&dir)?;
+        assert_eq!(md.stat()?, (3, 66));
+        assert_eq!(md.uidl()?.len(), 3);
+        assert_eq!(md.top(1, 0)?, b"Subject: a\r\n\r\n");
+        assert_eq!(md.top(1, 1)?, b"Subject: a\r\n\r\nhello\r\n");
+
+        md.dele(1)?;
+        assert_eq!(md.stat()?, (2, 38));
+        assert_eq!(md.stat_full()?, (2, 38, 1, 28));
+        assert!(md.retr(1).is_err());
+        md.reset()?;
+        assert_eq!(md.stat()?, (3, 66));
+
+        // Removed by others during the session.
+        fs::remove_file(dir.join("3.eml"))?;
+        assert!(md.retr(3).is_err());
+        assert!(md.open_message(3).is_err());
+        let mut content = String::new();
+        io::Read::read_to_string(&mut md.open_message(2)?, &mut content)?;
+        assert_eq!(content, "Subject: b\r\n\r\nworld\r\n");
+
+        md.dele(2)?;
+        md.commit()?;
+        assert!(dir.join("1.eml").exists());
+        assert!(!dir.join("2.eml").exists());
+        assert_eq!(FileMaildrop::open(&dir)?.stat()?.0, 1);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_only() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-read-only-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let mut md = FileMaildrop::open(&dir)?;
+        md.set_read_only(true);
+        assert_eq!(
+            dispatch(&mut md, &Request::DELE(1))?,
+            Response::ERR("maildrop is read-only".to_string())
+        );
+        assert_eq!(
+            dispatch(&mut md, &Request::RSET)?,
+            Response::RSET(String::new())
+        );
+        assert!(matches!(
+            dispatch(&mut md, &Request::RETR(1))?,
+            Response::RETR(_)
+        ));
+        assert_eq!(
+            dispatch(&mut md, &Request::QUIT)?,
+            Response::QUIT(Some(
+                "POP3 server signing off (read-only maildrop)".to_string()
+            ))
+        );
+        assert_eq!(FileMaildrop::open(&dir)?.stat()?.0, 1);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sweep() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-sweep-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("1.eml"), "Subject: old\r\n\r\n")?;
+        fs::write(dir.join("2.eml"), "Subject: new\r\n\r\n")?;
+        fs::File::options()
+            .write(true)
+            .open(dir.join("1.eml"))?
+            .set_modified(SystemTime::now() - Duration::from_secs(7200))?;
+
+        // Skipped while opened by a session.
+        let md = FileMaildrop::open(&dir)?;
+        assert_eq!(FileMaildrop::sweep(&dir, Duration::from_secs(3600))?, 0);
+        drop(md);
+
+        assert_eq!(FileMaildrop::sweep(&dir, Duration::from_secs(3600))?, 1);
+        assert!(!dir.join("1.eml").exists());
+        assert!(dir.join("2.eml").exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn identical_messages() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-identical-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+        fs::write(dir.join("2.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let uids = FileMaildrop::open(&dir)?.uidl()?;
+        assert_eq!(uids.len(), 2);
+        assert_ne!(uids[&1], uids[&2]);
+        // Stable across sessions.
+        assert_eq!(FileMaildrop::open(&dir)?.uidl()?, uids);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn octet_size() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-octet-size-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let raw = "Subject: a\n\nhello\n";
+        fs::write(dir.join("1.eml"), raw)?;
+
+        let md = FileMaildrop::open(&dir)?;
+        assert_eq!(md.stat()?, (1, raw.len() + 3));
+        assert_eq!(md.list()?[0].size, raw.len() + 3);
+
+        assert_eq!(message_octet_size(b"a\r\nb\n"), 6);
+        assert_eq!(message_octet_size(b"a\r\nb"), 6);
+        assert_eq!(message_octet_size(b"a\r"), 3);
+        assert_eq!(message_octet_size(b""), 0);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn crlf() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-crlf-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("1.eml"), "Subject: a\n\n.hello\nworld")?;
+        fs::write(dir.join("2.eml"), "Subject: b\r\n\r\nhello\r\n")?;
+        fs::write(dir.join("3.eml"), "a\r")?;
+
+        let mut md = FileMaildrop::open(&dir)?;
+        let list = md.list()?;
+
+        let content = md.retr(1)?;
+        assert_eq!(content, b"Subject: a\r\n\r\n.hello\r\nworld\r\n");
+        assert_eq!(content.len(), list[0].size);
+        assert_eq!(md.top(1, 0)?, b"Subject: a\r\n\r\n");
+        assert_eq!(
+            format!("{}", dispatch(&mut md, &Request::RETR(1))?),
+            "+OK\r\nSubject: a\r\n\r\n..hello\r\nworld\r\n.\r\n"
+        );
+
+        // CRLFs are never converted again.
+        let content = md.retr(2)?;
+        assert_eq!(content, b"Subject: b\r\n\r\nhello\r\n");
+        assert_eq!(content.len(), list[1].size);
+
+        // A CR ending the last line is a truncated CRLF.
+        let content = md.retr(3)?;
+        assert_eq!(content, b"a\r\n");
+        assert_eq!(content.len(), list[2].size);
+        assert_eq!(
+            format!("{}", dispatch(&mut md, &Request::RETR(3))?),
+            "+OK\r\na\r\n.\r\n"
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
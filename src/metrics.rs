@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use postman_pop3::Command;
+
+/// Metrics receives events of the server, so that operators could export
+/// them to any metrics system.
+///
+/// All methods do nothing by default, implementors only need to override
+/// events they care about. Methods are called in the session tasks, they
+/// should be cheap and never block.
+pub trait Metrics: Debug + Send + Sync {
+    /// A request has been parsed from client.
+    fn on_command(&self, _cmd: Command) {}
+    /// Content of a message has been sent to client by RETR or TOP.
+    fn on_bytes_retrieved(&self, _n: usize) {}
+    /// Client failed to authenticate.
+    fn on_auth_failure(&self) {}
+    /// Connecting to or talking with the upstream failed.
+    fn on_upstream_error(&self, _upstream: &str) {}
+    /// A connection to downstream `addr` has been rejected since it has
+    /// reached `max_connections`.
+    fn on_connection_rejected(&self, _addr: &str) {}
+    /// A connection has been closed, called once per connection however it
+    /// ends.
+    fn on_session_end(&self, _log: &SessionLog) {}
+}
+
+/// SessionLog is the audit record of a connection from accepted to closed.
+///
+/// Secrets sent by PASS, APOP or AUTH are never recorded.
+#[derive(Debug, Clone)]
+pub struct SessionLog {
+    /// Address of client, taken from the PROXY header if enabled.
+    pub peer: SocketAddr,
+    /// User named by the last USER, APOP or AUTH, empty if none.
+    pub user: String,
+    /// Whether the user has been authenticated.
+    pub authenticated: bool,
+    pub started: SystemTime,
+    pub duration: Duration,
+    /// Requests parsed from client by command.
+    pub commands: HashMap<Command, usize>,
+    /// Messages sent by RETR.
+    pub retrieved: usize,
+    /// Bytes of messages sent by RETR and TOP.
+    pub bytes_retrieved: usize,
+    /// Messages marked as deleted and not reset by RSET, they are deleted
+    /// only if `outcome` is `Quit` after authenticated.
+    pub deleted: usize,
+    /// Octets received from client as they are on the wire, including
+    /// CRLFs. Counted after TLS decryption.
+    pub bytes_received: u64,
+    /// Octets sent to client as they are on the wire, including CRLFs and
+    /// dot-stuffing. Counted before TLS encryption.
+    pub bytes_sent: u64,
+    pub outcome: SessionOutcome,
+}
+
+impl SessionLog {
+    /// Create a log of a connection accepted just now.
+    pub fn new(peer: SocketAddr) -> SessionLog {
+        SessionLog {
+            peer,
+            user: String::new(),
+            authenticated: false,
+            started: SystemTime::now(),
+            duration: Duration::default(),
+            commands: HashMap::new(),
+            retrieved: 0,
+            bytes_retrieved: 0,
+            deleted: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+            outcome: SessionOutcome::Closed,
+        }
+    }
+}
+
+/// SessionOutcome tells why a connection has been closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionOutcome {
+    /// Client sent QUIT.
+    Quit,
+    /// Client closed the connection without QUIT.
+    Closed,
+    /// Server is shutting down.
+    Shutdown,
+    /// Closed after too many failed auth attempts.
+    TooManyAuthFailures,
+    /// Closed after lasting longer than `max_session_duration`.
+    TimeLimitExceeded,
+    /// Failed by an IO error, a timeout or an upstream error.
+    Error(String),
+}
+
+/// NoopMetrics drops all events.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
@@ -0,0 +1,81 @@
+//! PROXY protocol v1 described in
+//! [The PROXY protocol](https://www.haproxy.org/download/2.3/doc/proxy-protocol.txt).
+//!
+//! ```text
+//! PROXY TCP4 192.168.0.1 192.168.0.11 56324 110\r\n
+//! ```
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::Result;
+
+/// Max length of a v1 header including the CRLF.
+pub const MAX_HEADER_LENGTH: usize = 107;
+
+/// Parse a PROXY v1 header line into the source address.
+///
+/// Returns `None` for `PROXY UNKNOWN`, which means the real address should
+/// be taken from the connection.
+pub fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let err = || anyhow::anyhow!("invalid PROXY header: {:?}", line);
+
+    if line.len() > MAX_HEADER_LENGTH {
+        return Err(err());
+    }
+    let line = line.strip_suffix("\r\n").ok_or_else(err)?;
+
+    let vs: Vec<&str> = line.split(' ').collect();
+    match vs.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto, src, dst, src_port, dst_port] => {
+            let src = IpAddr::from_str(src).map_err(|_| err())?;
+            let dst = IpAddr::from_str(dst).map_err(|_| err())?;
+            let ok = match *proto {
+                "TCP4" => src.is_ipv4() && dst.is_ipv4(),
+                "TCP6" => src.is_ipv6() && dst.is_ipv6(),
+                _ => false,
+            };
+            if !ok {
+                return Err(err());
+            }
+
+            let port = u16::from_str(src_port).map_err(|_| err())?;
+            u16::from_str(dst_port).map_err(|_| err())?;
+
+            Ok(Some(SocketAddr::new(src, port)))
+        }
+        _ => Err(err()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn v1() {
+        let cases = vec![
+            (
+                "PROXY TCP4 192.168.0.1 192.168.0.11 56324 110\r\n",
+                Some(Some("192.168.0.1:56324")),
+            ),
+            (
+                "PROXY TCP6 ::1 ::1 56324 110\r\n",
+                Some(Some("[::1]:56324")),
+            ),
+            ("PROXY UNKNOWN\r\n", Some(None)),
+            ("PROXY UNKNOWN ::1 ::1 56324 110\r\n", Some(None)),
+            ("PROXY TCP4 ::1 ::1 56324 110\r\n", None),
+            ("PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n", None),
+            ("PROXY TCP4 192.168.0.1 192.168.0.11 56324 110", None),
+            ("PROXY TCP4 192.168.0.1 192.168.0.11 99999 110\r\n", None),
+            ("USER postman\r\n", None),
+        ];
+
+        for (line, expect) in cases {
+            let expect = expect.map(|v| v.map(|v| SocketAddr::from_str(v).unwrap()));
+
+            assert_eq!(parse_v1(line).ok(), expect, "{}", line);
+        }
+    }
+}
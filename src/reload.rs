@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info};
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::upstream::UpstreamPool;
+
+/// Load, expand and validate config at path.
+pub(crate) fn load(path: &Path) -> Result<Config> {
+    let mut cfg = Config::from_path(path)?;
+    cfg.expand_env()?;
+    if let Err(errs) = cfg.validate() {
+        let errs: Vec<String> = errs.iter().map(|v| v.to_string()).collect();
+        return Err(anyhow::anyhow!("invalid config: {}", errs.join("; ")));
+    }
+
+    Ok(cfg)
+}
+
+/// Reload config at path and send it to sessions.
+///
+/// Idle upstream connections are dropped so that new upstream settings
+/// could take effect. An invalid config will be ignored, the last good
+/// one is kept.
+pub(crate) fn reload(path: &Path, tx: &watch::Sender<Arc<Config>>, pool: &UpstreamPool) {
+    match load(path) {
+        Ok(cfg) => {
+            pool.clear();
+            let _ = tx.send(Arc::new(cfg));
+            info!("config {} reloaded", path.display());
+        }
+        Err(err) => error!("reload config {}: {:#}", path.display(), err),
+    }
+}
+
+/// Watch config at path and reload it on change.
+///
+/// The parent dir is watched, so that editors which replace the file could
+/// also be handled. Watching stops after the returned watcher is dropped.
+pub(crate) fn watch(
+    path: &Path,
+    tx: watch::Sender<Arc<Config>>,
+    pool: Arc<UpstreamPool>,
+) -> Result<RecommendedWatcher> {
+    let path = path.canonicalize()?;
+    let dir = path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"));
+
+    let (events_tx, events_rx) = mpsc::channel();
+    let mut w = watcher(events_tx, Duration::from_secs(1))?;
+    w.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        // Channel is closed after the watcher is dropped.
+        while let Ok(event) = events_rx.recv() {
+            match event {
+                DebouncedEvent::Create(v)
+                | DebouncedEvent::Write(v)
+                | DebouncedEvent::Rename(_, v)
+                    if v == path =>
+                {
+                    reload(&path, &tx, &pool)
+                }
+                _ => {}
+            }
+        }
+    });
+
+    Ok(w)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[tokio::test]
+    async fn reload_keeps_last_good() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("config.toml");
+
+        let content = r#"
+database_dir = "db"
+data_dir = "mails"
+
+[[downstream]]
+protocol = "pop3"
+addr = "127.0.0.1:110"
+auth_type = "user"
+username = "postman"
+password = "postman"
+"#;
+        fs::write(&path, content)?;
+        let (tx, rx) = watch::channel(Arc::new(load(&path)?));
+        let pool = UpstreamPool::new(Duration::from_secs(60));
+
+        fs::write(&path, format!("drain_timeout = 5\n{}", content))?;
+        reload(&path, &tx, &pool);
+        assert_eq!(rx.borrow().drain_timeout, 5);
+
+        // Invalid config is ignored.
+        fs::write(&path, format!("upstream_keepalive = 0\n{}", content))?;
+        reload(&path, &tx, &pool);
+        assert_eq!(rx.borrow().drain_timeout, 5);
+        assert_eq!(rx.borrow().upstream_keepalive, 60);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// HeaderRewriter rewrites headers of messages proxied from upstreams, like
+/// stripping `Received:` for privacy. Bodies are never touched.
+///
+/// Sizes in STAT and LIST are computed by rewriting headers fetched by
+/// `TOP <id> 0` once per message in a session, so `rewrite` must return
+/// the same result for the same headers, or clients will see sizes
+/// different from what RETR sends.
+pub trait HeaderRewriter: Debug + Send + Sync {
+    /// Rewrite header lines up to but not including the blank line, every
+    /// line ends with its line ending.
+    fn rewrite(&self, headers: &str) -> String;
+}
+
+/// StripHeaders removes headers by name case-insensitively, together with
+/// their folded lines.
+#[derive(Debug, Clone, Default)]
+pub struct StripHeaders {
+    names: Vec<String>,
+}
+
+impl StripHeaders {
+    pub fn new<I, S>(names: I) -> StripHeaders
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        StripHeaders {
+            names: names.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl HeaderRewriter for StripHeaders {
+    fn rewrite(&self, headers: &str) -> String {
+        let mut v = String::with_capacity(headers.len());
+        let mut stripping = false;
+        for line in headers.split_inclusive('\n') {
+            // Folded lines belong to the header above.
+            if !line.starts_with([' ', '\t']) {
+                let name = line.split(':').next().unwrap_or_default().trim_end();
+                stripping = self.names.iter().any(|v| v.eq_ignore_ascii_case(name));
+            }
+            if !stripping {
+                v.push_str(line);
+            }
+        }
+
+        v
+    }
+}
+
+/// Split message content into header lines and the rest starting from the
+/// blank line, a message without blank line has headers only.
+fn split_headers(content: &str) -> (&str, &str) {
+    let mut end = 0;
+    for line in content.split_inclusive('\n') {
+        if line == "\r\n" || line == "\n" {
+            break;
+        }
+        end += line.len();
+    }
+
+    content.split_at(end)
+}
+
+/// Rewrite headers of message content, the rest is kept as is.
+pub(crate) fn rewrite_message(rewriter: &dyn HeaderRewriter, content: &str) -> String {
+    let (headers, rest) = split_headers(content);
+    let mut v = rewriter.rewrite(headers);
+    v.push_str(rest);
+
+    v
+}
+
+/// Read headers of a message from `r` up to and including the blank line,
+/// and return them rewritten, so that the rest of `r` could be streamed as
+/// is.
+pub(crate) async fn rewrite_head<R>(rewriter: &dyn HeaderRewriter, r: &mut R) -> Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut headers = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if r.read_until(b'\n', &mut line).await? == 0 || line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        headers.extend_from_slice(&line);
+    }
+
+    let mut v = rewriter.rewrite(&String::from_utf8_lossy(&headers));
+    v.push_str(&String::from_utf8_lossy(&line));
+    Ok(v)
+}
+
+/// Size of a message after rewritten, `top` is the message fetched by
+/// `TOP <id> 0`.
+pub(crate) fn rewritten_size(rewriter: &dyn HeaderRewriter, size: usize, top: &str) -> usize {
+    let (headers, _) = split_headers(top);
+
+    (size + rewriter.rewrite(headers).len()).saturating_sub(headers.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strip_headers() {
+        let strip = StripHeaders::new(vec!["received"]);
+        let content =
+            "Received: from a\r\n\tby b\r\nSubject: x\r\nRECEIVED: c\r\n\r\nReceived: body\r\n";
+
+        let v = rewrite_message(&strip, content);
+        assert_eq!(v, "Subject: x\r\n\r\nReceived: body\r\n");
+        assert_eq!(
+            rewritten_size(
+                &strip,
+                content.len(),
+                "Received: from a\r\n\tby b\r\nSubject: x\r\nRECEIVED: c\r\n\r\n"
+            ),
+            v.len()
+        );
+
+        // Headers only.
+        assert_eq!(rewrite_message(&strip, "Received: a\r\n"), "");
+    }
+
+    #[tokio::test]
+    async fn rewrite_stream() -> Result<()> {
+        let strip = StripHeaders::new(vec!["received"]);
+        let content = "Received: a\r\nSubject: x\r\n\r\nReceived: body\r\n";
+
+        let mut r = content.as_bytes();
+        assert_eq!(rewrite_head(&strip, &mut r).await?, "Subject: x\r\n\r\n");
+        // The body is left in the reader.
+        assert_eq!(r, b"Received: body\r\n");
+
+        let mut r = &b"Received: a\r\n"[..];
+        assert_eq!(rewrite_head(&strip, &mut r).await?, "");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,3360 @@
+/// # Example POP3 Session
+///
+/// S: <wait for connection on TCP port 110>
+/// C: <open connection>
+/// S:    +OK POP3 server ready <1896.697170952@dbc.mtview.ca.us>
+/// C:    APOP mrose c4c9334bac560ecc979e58001b3e22fb
+/// S:    +OK mrose's maildrop has 2 messages (320 octets)
+/// C:    STAT
+/// S:    +OK 2 320
+/// C:    LIST
+/// S:    +OK 2 messages (320 octets)
+/// S:    1 120
+/// S:    2 200
+/// S:    .
+/// C:    RETR 1
+/// S:    +OK 120 octets
+/// S:    <the POP3 server sends message 1>
+/// S:    .
+/// C:    DELE 1
+/// S:    +OK message 1 deleted
+/// C:    RETR 2
+/// S:    +OK 200 octets
+/// S:    <the POP3 server sends message 2>
+/// S:    .
+/// C:    DELE 2
+/// S:    +OK message 2 deleted
+/// C:    QUIT
+/// S:    +OK dewey POP3 server signing off (maildrop empty)
+/// C:  <close connection>
+/// S:  <wait for next connection>
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::future::{self, Future};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use tokio_rustls::rustls::Session as _;
+use tokio_rustls::rustls::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth::{AcceptAny, AuthResult, Authenticator, SharedSecret};
+use crate::cache::MessageCache;
+use crate::config::{Config, DownstreamTls};
+use crate::counting::{ByteCounts, CountingStream};
+use crate::lock::{MaildropLock, MaildropLocks};
+use crate::login::LoginStore;
+use crate::maildrop::FileMaildrop;
+use crate::metrics::{Metrics, SessionLog, SessionOutcome};
+use crate::proxy;
+use crate::reload;
+use crate::rewrite::{self, HeaderRewriter};
+use crate::shutdown::Shutdown;
+use crate::tarpit::Tarpit;
+use crate::tls;
+use crate::uidl::UidlStore;
+use crate::upstream::{Stream, UpstreamClient, UpstreamPool};
+use postman_pop3::*;
+
+const MAX_CONNECTIONS: usize = 1024;
+
+/// Max time to wait for the TLS handshake of a downstream connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Dir under data_dir to cache messages of upstreams.
+const CACHE_DIR: &str = ".cache";
+
+/// Bytes of a message relayed from upstream which are buffered before the
+/// downstream takes them.
+const RELAY_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+struct Listener {
+    config: watch::Receiver<Arc<Config>>,
+    metrics: Arc<dyn Metrics>,
+    capabilities: Capabilities,
+    authenticator: Arc<dyn Authenticator>,
+    header_rewriter: Option<Arc<dyn HeaderRewriter>>,
+    hostname: String,
+    greeting: String,
+    proxy_protocol: bool,
+    max_auth_attempts: Option<u32>,
+    max_session_duration: Option<Duration>,
+    strict_line_ending: bool,
+    allow_plaintext_auth: bool,
+    read_only: bool,
+    /// Maildrop served to every user, see `Downstream::anonymous`.
+    anonymous: Option<String>,
+    tcp_nodelay: bool,
+    disabled_commands: Arc<Vec<Command>>,
+    passthrough: Arc<BTreeMap<String, bool>>,
+    uidl: UidlStore,
+    cache: Arc<MessageCache>,
+    logins: LoginStore,
+    locks: MaildropLocks,
+    tarpit: Option<Tarpit>,
+    pool: Arc<UpstreamPool>,
+    /// Wrap accepted connections in TLS before greeting.
+    tls: Option<Tls>,
+
+    listener: TcpListener,
+    /// Address of the downstream in config.
+    addr: String,
+    limit_connections: Arc<Semaphore>,
+    /// Connections of this downstream, see `Downstream::max_connections`.
+    max_connections: Option<Arc<Semaphore>>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+#[derive(Debug)]
+struct Handler {
+    context: Context,
+    session: Session,
+    /// Read a PROXY header to get the real peer before greeting.
+    proxy_protocol: bool,
+    /// Failed auth attempts allowed before closing the connection.
+    max_auth_attempts: Option<u32>,
+    /// Failed auth attempts since the last success.
+    auth_failures: u32,
+    /// Max time a session may last, however busy it is.
+    max_session_duration: Option<Duration>,
+    /// Delay replies to peers which failed logins too many times.
+    tarpit: Option<Tarpit>,
+    /// Reject requests terminated by a bare LF.
+    strict_line_ending: bool,
+    /// Commands refused in any state, see `Downstream::disabled_commands`.
+    disabled_commands: Arc<Vec<Command>>,
+    /// Unknown commands forwarded to the upstream, see
+    /// `Downstream::passthrough`.
+    passthrough: Arc<BTreeMap<String, bool>>,
+    /// Handshake TLS on the connection before greeting.
+    tls: Option<Tls>,
+    /// Audit record sent to metrics when the connection is closed.
+    log: SessionLog,
+    /// Octets received from and sent to client.
+    bytes: ByteCounts,
+
+    connection: TcpStream,
+    limit_connections: Arc<Semaphore>,
+    /// Released once the handler is dropped.
+    _connection_permit: Option<OwnedSemaphorePermit>,
+    shutdown: Shutdown,
+
+    /// Not used directly, dropped when the handler is done so that `run`
+    /// knows all sessions have finished.
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+/// Server serves POP3 to downstream clients.
+#[derive(Debug)]
+pub struct Server {
+    config: Arc<Config>,
+    metrics: Arc<dyn Metrics>,
+    /// Verify credentials of all downstreams, `SharedSecret` of each
+    /// downstream's username and password if not set.
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// Rewrite headers of messages proxied from upstreams.
+    header_rewriter: Option<Arc<dyn HeaderRewriter>>,
+    /// Shared by all downstreams, a user is locked whichever it logins from.
+    locks: MaildropLocks,
+    /// Path of config to be watched and reloaded on change.
+    reload: Option<PathBuf>,
+    /// Set by `shutdown`, watched by `serve`.
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl Server {
+    /// Create a server with config, events of all sessions will be sent to
+    /// `metrics`, use `NoopMetrics` if not interested.
+    pub fn new(config: Arc<Config>, metrics: Arc<dyn Metrics>) -> Server {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        Server {
+            config,
+            metrics,
+            authenticator: None,
+            header_rewriter: None,
+            locks: MaildropLocks::new(),
+            reload: None,
+            shutdown_tx,
+            shutdown_rx,
+        }
+    }
+
+    /// Verify credentials of downstream clients by `authenticator`.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Server {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Rewrite headers of messages proxied from upstreams by `rewriter`,
+    /// sizes in STAT and LIST are adjusted to the rewritten messages.
+    ///
+    /// Messages served from data_dir are not rewritten.
+    pub fn with_header_rewriter(mut self, rewriter: Arc<dyn HeaderRewriter>) -> Server {
+        self.header_rewriter = Some(rewriter);
+        self
+    }
+
+    /// Create a server with config loaded from path, and reload it while
+    /// the file changes.
+    ///
+    /// Upstreams and routes of the reloaded config apply to sessions which
+    /// login after that, existing sessions are kept. Invalid config will be
+    /// logged and ignored.
+    pub fn with_config_reload(path: impl AsRef<Path>, metrics: Arc<dyn Metrics>) -> Result<Server> {
+        let path = path.as_ref();
+
+        Ok(Server {
+            reload: Some(path.to_path_buf()),
+            ..Server::new(Arc::new(reload::load(path)?), metrics)
+        })
+    }
+
+    /// Shut down the server like the `shutdown` future of `serve` has
+    /// completed, which could be called while serving. Serving started
+    /// after that returns at once.
+    pub fn shutdown(&self) {
+        // Never fails, the receiver is owned by the server as well.
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    /// Maildrop locks held by sessions, for admins to release the ones left
+    /// by stuck sessions.
+    pub fn maildrop_locks(&self) -> &MaildropLocks {
+        &self.locks
+    }
+
+    /// Bind listeners for all downstreams in config, in the same order.
+    pub async fn bind(&self) -> Result<Vec<TcpListener>> {
+        let mut listeners = Vec::with_capacity(self.config.downstreams.len());
+        for downstream in self.config.downstreams.iter() {
+            let (host, port) = downstream.host_port()?;
+            let listener = match downstream.only_v6()? {
+                Some(only_v6) => bind_v6(&host, port, only_v6),
+                None => TcpListener::bind((host.as_str(), port))
+                    .await
+                    .map_err(Into::into),
+            }
+            .map_err(|err| anyhow::anyhow!("bind {}: {}", downstream.addr, err))?;
+            listeners.push(listener);
+        }
+
+        Ok(listeners)
+    }
+
+    /// Bind all downstreams and serve them until `shutdown` completes, any
+    /// future works like a signal or a channel. See `serve` for draining.
+    pub async fn run(&self, shutdown: impl Future) -> Result<()> {
+        let listeners = self.bind().await?;
+        self.serve(listeners, shutdown).await
+    }
+
+    /// Serve POP3 on listeners until `shutdown` completes or
+    /// `Server::shutdown` is called, listeners are paired with downstreams
+    /// of config in order.
+    ///
+    /// After `shutdown` completes, listeners stop accepting new connections
+    /// and sessions are signalled. Sessions will finish their current
+    /// request and close, sessions waiting for the next request are dropped
+    /// without committing deletions. `serve` waits at most `drain_timeout`
+    /// seconds for sessions before returning.
+    pub async fn serve(&self, listeners: Vec<TcpListener>, shutdown: impl Future) -> Result<()> {
+        if listeners.len() != self.config.downstreams.len() {
+            return Err(anyhow::anyhow!(
+                "got {} listeners for {} downstreams",
+                listeners.len(),
+                self.config.downstreams.len()
+            ));
+        }
+
+        let config = self.config.clone();
+        let pool = UpstreamPool::new(Duration::from_secs(config.upstream_keepalive));
+
+        let (config_tx, config_rx) = watch::channel(config.clone());
+        // Watching stops after the watcher is dropped while returning.
+        let _watcher = match &self.reload {
+            Some(path) => Some(reload::watch(path, config_tx, pool.clone())?),
+            None => None,
+        };
+
+        run(self, config, config_rx, pool, listeners, shutdown).await
+    }
+}
+
+async fn run(
+    server: &Server,
+    config: Arc<Config>,
+    config_rx: watch::Receiver<Arc<Config>>,
+    pool: Arc<UpstreamPool>,
+    listeners: Vec<TcpListener>,
+    shutdown: impl Future,
+) -> Result<()> {
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+    // Every accept loop holds a sender, `recv` returns after all of them
+    // have exited.
+    let (accept_done_tx, mut accept_done_rx) = mpsc::channel::<()>(1);
+
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
+    let hostname = config.hostname();
+    validate_hostname(&hostname)?;
+    let db = sled::open(&config.database_dir)?;
+    let uidl = UidlStore::open(&db)?;
+    let logins = LoginStore::open(&db)?;
+    let locks = server.locks.clone();
+    // Shared by all downstreams as well.
+    let tarpit = config.tarpit_after_failures.map(|after| {
+        Tarpit::new(
+            after,
+            Duration::from_millis(config.tarpit_delay),
+            Duration::from_secs(config.tarpit_window),
+        )
+    });
+    let cache = Arc::new(MessageCache::new(
+        config.data_dir.join(CACHE_DIR),
+        config.cache_max_bytes,
+    ));
+    let limit_connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
+    // Config is read every time, so that a reload can enable, disable or
+    // change the sweep.
+    let sweep_config = config_rx.clone();
+    let mut sweep_shutdown = Shutdown::new(notify_shutdown.subscribe());
+    tokio::spawn(async move {
+        loop {
+            let config = sweep_config.borrow().clone();
+            if let Some(max_age) = config.sweep_age() {
+                let data_dir = config.data_dir.clone();
+                match task::spawn_blocking(move || sweep(&data_dir, max_age)).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(n)) => info!("swept {} messages older than {:?}", n, max_age),
+                    Ok(Err(err)) => warn!("sweep {}: {}", config.data_dir.display(), err),
+                    Err(err) => warn!("sweep {}: {}", config.data_dir.display(), err),
+                }
+            }
+
+            tokio::select! {
+                _ = time::sleep(Duration::from_secs(config.sweep_interval.max(1))) => {}
+                _ = sweep_shutdown.recv() => return,
+            }
+        }
+    });
+
+    // Load all certificates before serving so that errors are reported at
+    // startup.
+    let mut acceptors = Vec::with_capacity(listeners.len());
+    for downstream in config.downstreams.iter() {
+        acceptors.push(match &downstream.tls {
+            Some(tls) => Some(
+                tls_acceptor(tls)
+                    .map_err(|err| anyhow::anyhow!("tls of {}: {}", downstream.addr, err))?,
+            ),
+            None => None,
+        });
+    }
+
+    for ((downstream, listener), tls) in config.downstreams.iter().zip(listeners).zip(acceptors) {
+        let mut capabilities = downstream.capabilities();
+        capabilities.expire = Some(config.expire());
+        let mut server = Listener {
+            capabilities,
+            // Anonymous downstreams never verify users by the real path.
+            authenticator: match (&downstream.anonymous, &server.authenticator) {
+                (Some(v), _) => Arc::new(AcceptAny::new(v.password.clone())),
+                (None, Some(v)) => v.clone(),
+                (None, None) => Arc::new(SharedSecret::new(
+                    downstream.username.clone(),
+                    downstream.password.clone(),
+                )),
+            },
+            anonymous: downstream.anonymous.as_ref().map(|v| v.maildrop.clone()),
+            header_rewriter: server.header_rewriter.clone(),
+            hostname: hostname.clone(),
+            greeting: downstream.greeting().to_string(),
+            proxy_protocol: downstream.proxy_protocol,
+            max_auth_attempts: downstream.max_auth_attempts,
+            max_session_duration: downstream.max_session_duration.map(Duration::from_secs),
+            strict_line_ending: downstream.strict_line_ending,
+            allow_plaintext_auth: downstream.allow_plaintext_auth,
+            read_only: downstream.read_only || downstream.anonymous.is_some(),
+            tcp_nodelay: downstream.tcp_nodelay,
+            disabled_commands: Arc::new(downstream.disabled_commands.clone()),
+            passthrough: Arc::new(downstream.passthrough.clone()),
+            pool: pool.clone(),
+            config: config_rx.clone(),
+            metrics: server.metrics.clone(),
+            uidl: uidl.clone(),
+            cache: cache.clone(),
+            logins: logins.clone(),
+            locks: locks.clone(),
+            tarpit: tarpit.clone(),
+            tls,
+            listener,
+            addr: downstream.addr.clone(),
+            limit_connections: limit_connections.clone(),
+            max_connections: downstream
+                .max_connections
+                .map(|v| Arc::new(Semaphore::new(v))),
+            notify_shutdown: notify_shutdown.clone(),
+            shutdown_complete_tx: shutdown_complete_tx.clone(),
+        };
+
+        let addr = downstream.addr.clone();
+        let mut shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let accept_done = accept_done_tx.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                res = server.run() => {
+                    if let Err(err) = res {
+                        error!("accept on {}: {}", addr, err);
+                    }
+                },
+                _ = shutdown.recv() => {}
+            }
+
+            // Listener is dropped before notifying.
+            drop(server);
+            drop(accept_done);
+        });
+    }
+    drop(accept_done_tx);
+
+    tokio::select! {
+        _ = accept_done_rx.recv() => {
+            error!("all listeners are stopped");
+        },
+        _ = shutdown => {
+            info!("shutting down");
+        }
+        _ = shutdown_requested(server.shutdown_rx.clone()) => {
+            info!("shutting down");
+        }
+    }
+
+    // Stop all accept loops and sessions, the channel is kept open by
+    // listeners so it must be sent explicitly.
+    let _ = notify_shutdown.send(());
+    drop(notify_shutdown);
+    accept_done_rx.recv().await;
+    drop(shutdown_complete_tx);
+
+    if time::timeout(drain_timeout, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        warn!("sessions are not finished in {:?}, dropped", drain_timeout);
+    }
+
+    Ok(())
+}
+
+/// Wait until `Server::shutdown` is called.
+async fn shutdown_requested(mut rx: watch::Receiver<bool>) {
+    while !*rx.borrow() {
+        // The sender is owned by the server, which outlives serving.
+        if rx.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Bind an IPv6 address with `IPV6_V6ONLY` set as `only_v6`, which is
+/// decided by the system if bound by `TcpListener::bind`.
+fn bind_v6(host: &str, port: u16, only_v6: bool) -> Result<TcpListener> {
+    let addr = SocketAddr::new(IpAddr::from_str(host)?, port);
+
+    let socket = Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_only_v6(only_v6)?;
+    // Same as std, allow rebinding while old connections are in TIME_WAIT.
+    if cfg!(unix) {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// Sweep maildrops of all users under data_dir, returns how many messages
+/// have been removed. A maildrop failed to sweep is logged and skipped.
+fn sweep(data_dir: &Path, max_age: Duration) -> Result<usize> {
+    let entries = match fs::read_dir(data_dir) {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut n = 0;
+    for entry in entries {
+        let entry = entry?;
+        // Hidden dirs like the cache are not maildrops.
+        if !entry.file_type()?.is_dir() || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let dir = entry.path();
+        match FileMaildrop::sweep(&dir, max_age) {
+            Ok(v) => n += v,
+            Err(err) => warn!("sweep {}: {}", dir.display(), err),
+        }
+    }
+
+    Ok(n)
+}
+
+impl Listener {
+    async fn run(&mut self) -> Result<()> {
+        info!(
+            "postman pop3 server is running on {}",
+            self.listener.local_addr()?
+        );
+
+        loop {
+            self.limit_connections.acquire().await.forget();
+
+            let (mut socket, peer) = self.accept().await?;
+            if let Err(err) = socket.set_nodelay(self.tcp_nodelay) {
+                warn!("set TCP_NODELAY of {}: {}", peer, err);
+            }
+
+            let permit = match &self.max_connections {
+                Some(limit) => match limit.clone().try_acquire_owned() {
+                    Ok(v) => Some(v),
+                    Err(_) => {
+                        warn!("reject {} on {}: too many connections", peer, self.addr);
+                        self.metrics.on_connection_rejected(&self.addr);
+                        // No handler is created to give the permit back.
+                        self.limit_connections.add_permits(1);
+                        // TLS clients can't read a plaintext reply, just close.
+                        if self.tls.is_none() {
+                            tokio::spawn(async move {
+                                let resp = Response::ERR("[SYS/TEMP] too many connections".into());
+                                let _ = resp.write_to(&mut socket).await;
+                            });
+                        }
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let mut handler = Handler {
+                connection: socket,
+                session: Session::new(),
+                proxy_protocol: self.proxy_protocol,
+                max_auth_attempts: self.max_auth_attempts,
+                max_session_duration: self.max_session_duration,
+                auth_failures: 0,
+                tarpit: self.tarpit.clone(),
+                strict_line_ending: self.strict_line_ending,
+                disabled_commands: self.disabled_commands.clone(),
+                passthrough: self.passthrough.clone(),
+                tls: self.tls.clone(),
+                log: SessionLog::new(peer),
+                bytes: ByteCounts::default(),
+                context: Context {
+                    peer,
+                    config: self.config.clone(),
+                    metrics: self.metrics.clone(),
+                    capabilities: self.capabilities.clone(),
+                    allow_plaintext_auth: self.allow_plaintext_auth,
+                    read_only: self.read_only,
+                    anonymous: self.anonymous.clone(),
+                    authenticator: self.authenticator.clone(),
+                    header_rewriter: self.header_rewriter.clone(),
+                    hostname: self.hostname.clone(),
+                    greeting: self.greeting.clone(),
+                    timestamp: String::new(),
+                    external: None,
+                    uidl: self.uidl.clone(),
+                    cache: self.cache.clone(),
+                    logins: self.logins.clone(),
+                    locks: self.locks.clone(),
+                    lock: None,
+                    pool: self.pool.clone(),
+                    user: String::new(),
+                    maildrop: None,
+                    rejected: false,
+                },
+
+                // The connection state needs a handle to the max connections
+                // semaphore. When the handler is done processing the
+                // connection, a permit is added back to the semaphore.
+                limit_connections: self.limit_connections.clone(),
+                _connection_permit: permit,
+
+                // Receive shutdown notifications.
+                shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
+
+                _shutdown_complete: self.shutdown_complete_tx.clone(),
+            };
+
+            let fut = async move {
+                let outcome = match handler.run().await {
+                    Ok(v) => v,
+                    Err(err) => {
+                        error!("{}", err);
+                        SessionOutcome::Error(err.to_string())
+                    }
+                };
+                handler.finish(outcome);
+            };
+            #[cfg(feature = "tracing")]
+            let fut =
+                tracing::Instrument::instrument(fut, tracing::info_span!("session", peer = %peer));
+            tokio::spawn(fut);
+        }
+    }
+
+    async fn accept(&mut self) -> Result<(TcpStream, SocketAddr)> {
+        let mut backoff = 1;
+
+        // Try to accept a few times
+        loop {
+            // Perform the accept operation. If a socket is successfully
+            // accepted, return it. Otherwise, save the error.
+            match self.listener.accept().await {
+                Ok(v) => return Ok(v),
+                Err(err) => {
+                    if backoff > 64 {
+                        // Accept has failed too many times. Return the error.
+                        return Err(err.into());
+                    }
+                }
+            }
+
+            // Pause execution until the back off period elapses.
+            time::sleep(Duration::from_secs(backoff)).await;
+
+            // Double the back off
+            backoff *= 2;
+        }
+    }
+}
+
+impl Handler {
+    /// Serve the connection, returns why it ends.
+    async fn run(&mut self) -> Result<SessionOutcome> {
+        let deadline = self.max_session_duration.map(|v| Instant::now() + v);
+        let time_limit_exceeded = Response::ERR("session time limit exceeded".to_string());
+
+        if self.proxy_protocol {
+            // The header is always the first line in plaintext even for TLS
+            // downstreams, malformed header closes the connection.
+            let header = read_proxy_header(&mut self.connection).await?;
+            if let Some(peer) = proxy::parse_v1(&header)? {
+                self.context.peer = peer;
+            }
+        }
+
+        let stream: Box<dyn Stream + '_> = match &self.tls {
+            Some(Tls(acceptor)) => {
+                // Plaintext clients may wait for the greeting forever.
+                let stream =
+                    time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(&mut self.connection))
+                        .await
+                        .map_err(|_| anyhow::anyhow!("tls handshake timed out"))??;
+                self.context.external = stream
+                    .get_ref()
+                    .1
+                    .get_peer_certificates()
+                    .and_then(|v| v.first().and_then(|v| common_name(&v.0)));
+                self.session.set_tls_active();
+                Box::new(stream)
+            }
+            None => Box::new(&mut self.connection),
+        };
+        let (r, mut w) = io::split(CountingStream::new(stream, self.bytes.clone()));
+        let mut r = BufReader::new(r);
+
+        if let Some(tarpit) = &self.tarpit {
+            tarpit.wait(self.context.peer.ip()).await;
+        }
+        let (greet, timestamp) =
+            make_apop_greeting(&self.context.greeting, &self.context.hostname)?;
+        self.context.timestamp = timestamp;
+        let greet = Response::GREET(greet);
+        info!("S: {:?}", &greet);
+        w.write_all(&greet.to_bytes()?).await?;
+
+        while !self.shutdown.is_shutdown() {
+            let s = tokio::select! {
+                res = read_line(&mut r) => res?,
+                _ = sleep_until(deadline) => {
+                    info!("S: {:?}", &time_limit_exceeded);
+                    time_limit_exceeded.write_to(&mut w).await?;
+                    return Ok(SessionOutcome::TimeLimitExceeded);
+                }
+                _ = self.shutdown.recv() => return Ok(SessionOutcome::Shutdown),
+            };
+            // Peer has closed the connection.
+            if s.is_empty() {
+                return Ok(SessionOutcome::Closed);
+            }
+            // A busy client may always have a request ready to read.
+            if matches!(deadline, Some(v) if Instant::now() >= v) {
+                info!("S: {:?}", &time_limit_exceeded);
+                time_limit_exceeded.write_to(&mut w).await?;
+                return Ok(SessionOutcome::TimeLimitExceeded);
+            }
+            if let Some(tarpit) = &self.tarpit {
+                tarpit.wait(self.context.peer.ip()).await;
+            }
+
+            // Malformed requests are errors of the client, reply and keep
+            // the connection.
+            let req = if self.strict_line_ending {
+                Request::from_str_strict(&s)
+            } else {
+                Request::from_str(&s)
+            };
+            let req = match req {
+                Ok(v) => v,
+                Err(err) => {
+                    if let Some(reply) = self
+                        .context
+                        .passthrough(&self.passthrough, &s, &err)
+                        .await?
+                    {
+                        w.write_all(reply.as_bytes()).await?;
+                        continue;
+                    }
+                    let resp = Response::ERR(err.to_string());
+                    info!("S: {:?}", &resp);
+                    resp.write_to(&mut w).await?;
+                    continue;
+                }
+            };
+            let cmd = req.command();
+            info!("C: {}", redact(&req));
+            #[cfg(feature = "tracing")]
+            tracing::debug!(command = %cmd, request = %redact(&req));
+            self.context.metrics.on_command(cmd);
+            if let Some(lock) = &self.context.lock {
+                lock.touch();
+            }
+            *self.log.commands.entry(cmd).or_default() += 1;
+            if self.disabled_commands.contains(&cmd) {
+                let resp = Response::ERR("command disabled".to_string());
+                info!("S: {:?}", &resp);
+                resp.write_to(&mut w).await?;
+                continue;
+            }
+            if let Err(err) = self.session.apply(&req) {
+                let resp = Response::ERR(err.to_string());
+                info!("S: {:?}", &resp);
+                resp.write_to(&mut w).await?;
+                continue;
+            }
+
+            let resp = match req {
+                Request::USER(v) => {
+                    self.context.user = v;
+                    Response::USER("".to_string())
+                }
+                Request::PASS(pass) => {
+                    let result = self
+                        .context
+                        .authenticator
+                        .verify_userpass(&self.context.user, &pass)
+                        .await;
+                    match self.context.login(result).await {
+                        Ok(_) => Response::PASS(String::new()),
+                        Err(err) => Response::ERR(err.to_string()),
+                    }
+                }
+                Request::AUTH(v) => match v {
+                    None => Response::AUTH(AuthResponse::All(sasl::mechanisms(
+                        &self.context.capabilities.sasl,
+                        self.session.is_tls_active(),
+                        self.context.allow_plaintext_auth,
+                    ))),
+                    Some(mechanism)
+                        if sasl::is_plaintext(&mechanism)
+                            && !self.session.is_tls_active()
+                            && !self.context.allow_plaintext_auth =>
+                    {
+                        Response::ERR(format!("[AUTH] {} requires TLS", mechanism))
+                    }
+                    Some(mechanism) if mechanism.eq_ignore_ascii_case("PLAIN") => {
+                        match sasl_step(&mut r, &mut w, "").await? {
+                            None => Response::ERR("authentication cancelled".to_string()),
+                            Some(v) => match sasl::plain_decode(&v) {
+                                // Acting as another user is not supported,
+                                // like EXTERNAL.
+                                Ok((Some(authzid), user, _)) if authzid != user => {
+                                    Response::ERR(format!(
+                                        "[AUTH] {} is not allowed to login as {}",
+                                        user, authzid
+                                    ))
+                                }
+                                Ok((_, user, pass)) => {
+                                    let result = self
+                                        .context
+                                        .authenticator
+                                        .verify_userpass(&user, &pass)
+                                        .await;
+                                    self.context.user = user;
+                                    match self.context.login(result).await {
+                                        Ok(_) => {
+                                            Response::AUTH(AuthResponse::Success(String::new()))
+                                        }
+                                        Err(err) => Response::ERR(err.to_string()),
+                                    }
+                                }
+                                Err(err) => Response::ERR(format!("invalid credentials: {}", err)),
+                            },
+                        }
+                    }
+                    Some(mechanism) if mechanism.eq_ignore_ascii_case("LOGIN") => {
+                        let mut answers = Vec::new();
+                        for prompt in
+                            [sasl::LOGIN_USERNAME_PROMPT, sasl::LOGIN_PASSWORD_PROMPT].iter()
+                        {
+                            match sasl_step(&mut r, &mut w, &b64::encode(prompt)).await? {
+                                None => break,
+                                Some(v) => answers.push(sasl::decode_str(&v)),
+                            }
+                        }
+
+                        match answers.as_slice() {
+                            [Ok(user), Ok(pass)] => {
+                                let result =
+                                    self.context.authenticator.verify_userpass(user, pass).await;
+                                self.context.user = user.to_string();
+                                match self.context.login(result).await {
+                                    Ok(_) => Response::AUTH(AuthResponse::Success(String::new())),
+                                    Err(err) => Response::ERR(err.to_string()),
+                                }
+                            }
+                            [_, _] => Response::ERR("invalid credentials".to_string()),
+                            _ => Response::ERR("authentication cancelled".to_string()),
+                        }
+                    }
+                    Some(mechanism) if mechanism.eq_ignore_ascii_case("EXTERNAL") => {
+                        match sasl_step(&mut r, &mut w, "").await? {
+                            None => Response::ERR("authentication cancelled".to_string()),
+                            Some(v) => {
+                                match (sasl::external_decode(&v), self.context.external.clone()) {
+                                    (Err(err), _) => {
+                                        Response::ERR(format!("invalid credentials: {}", err))
+                                    }
+                                    (Ok(_), None) => {
+                                        Response::ERR("[AUTH] no client certificate".to_string())
+                                    }
+                                    (Ok(Some(authzid)), Some(identity)) if authzid != identity => {
+                                        Response::ERR(format!(
+                                            "[AUTH] {} is not allowed to login as {}",
+                                            identity, authzid
+                                        ))
+                                    }
+                                    (Ok(_), Some(identity)) => {
+                                        self.context.user = identity;
+                                        match self.context.open_maildrop().await {
+                                            Ok(_) => {
+                                                Response::AUTH(AuthResponse::Success(String::new()))
+                                            }
+                                            Err(err) => Response::ERR(err.to_string()),
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Some(mechanism) => {
+                        Response::ERR(format!("unsupported mechanism {}", mechanism))
+                    }
+                },
+                Request::CAPA => {
+                    let mut capabilities = self.context.capabilities.advertised(&self.session);
+                    capabilities.sasl = sasl::mechanisms(
+                        &capabilities.sasl,
+                        self.session.is_tls_active(),
+                        self.context.allow_plaintext_auth,
+                    );
+                    capabilities.to_response()
+                }
+                // Not advertised, only implicit TLS is served.
+                Request::STLS => Response::ERR("STLS is not supported".to_string()),
+                Request::QUIT => self.context.quit(self.session.state()).await?,
+                Request::APOP { username, digest } => {
+                    let result = self
+                        .context
+                        .authenticator
+                        .verify_apop(&username, &self.context.timestamp, &digest)
+                        .await;
+                    self.context.user = username;
+                    match self.context.login(result).await {
+                        Ok(_) => Response::APOP,
+                        Err(err) => Response::ERR(err.to_string()),
+                    }
+                }
+                // Messages are streamed instead of built as a response.
+                Request::RETR(id) => match self.context.retr(id, &mut w).await? {
+                    Ok(size) => {
+                        info!("S: RETR({} octets)", size);
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(command = %cmd, size);
+                        self.context.metrics.on_bytes_retrieved(size);
+                        self.log.bytes_retrieved += size;
+                        self.log.retrieved += 1;
+                        continue;
+                    }
+                    Err(resp) => resp,
+                },
+                req => self.context.transaction(&req).await?,
+            };
+
+            let mut resp = resp;
+            let mut too_many_attempts = false;
+            let mut logged_in = false;
+            // Errors like a locked maildrop or an unreachable upstream are
+            // not failed attempts.
+            let rejected = mem::take(&mut self.context.rejected);
+            match &resp {
+                Response::ERR(_) if rejected => {
+                    self.context.metrics.on_auth_failure();
+                    if let Some(tarpit) = &self.tarpit {
+                        tarpit.record_failure(self.context.peer.ip());
+                    }
+
+                    self.auth_failures += 1;
+                    if matches!(self.max_auth_attempts, Some(v) if self.auth_failures > v) {
+                        resp = Response::ERR("too many attempts".to_string());
+                        too_many_attempts = true;
+                    }
+                }
+                Response::PASS(_) | Response::APOP | Response::AUTH(AuthResponse::Success(_)) => {
+                    self.auth_failures = 0;
+                    self.log.authenticated = true;
+                    logged_in = true;
+                }
+                Response::TOP(v) => {
+                    self.context.metrics.on_bytes_retrieved(v.len());
+                    self.log.bytes_retrieved += v.len();
+                }
+                Response::DELE => self.log.deleted += 1,
+                Response::RSET(_) => self.log.deleted = 0,
+                _ => {}
+            }
+
+            self.session.apply_response(&resp);
+            if logged_in {
+                self.session.set_user(self.context.user.as_str());
+                if let Some(Mailbox::Upstream { name, .. }) = &self.context.maildrop {
+                    self.session.set_upstream(name.as_str());
+                }
+            }
+            // The response owns its content, nothing of the maildrop or
+            // upstream is borrowed while waiting for a slow client.
+            info!("S: {}", summarize(&resp));
+            #[cfg(feature = "tracing")]
+            tracing::debug!(command = %cmd, response = %summarize(&resp));
+            resp.write_to(&mut w).await?;
+
+            if too_many_attempts {
+                return Ok(SessionOutcome::TooManyAuthFailures);
+            }
+            if self.session.is_closed() {
+                return Ok(SessionOutcome::Quit);
+            }
+        }
+
+        Ok(SessionOutcome::Shutdown)
+    }
+
+    /// Complete the session log with `outcome` and send it to metrics.
+    fn finish(&mut self, outcome: SessionOutcome) {
+        let log = &mut self.log;
+        log.peer = self.context.peer;
+        log.user = self.context.user.clone();
+        log.duration = log.started.elapsed().unwrap_or_default();
+        log.bytes_received = self.bytes.read();
+        log.bytes_sent = self.bytes.written();
+        log.outcome = outcome;
+
+        info!(
+            "session of {:?} from {} ended: {:?}, {} retrieved, {} deleted",
+            log.user, log.peer, log.outcome, log.retrieved, log.deleted
+        );
+        debug!(
+            "session of {} ended in {:?}",
+            log.peer,
+            self.session.snapshot()
+        );
+        self.context.metrics.on_session_end(log);
+    }
+}
+
+/// Context keeps the user and maildrop of a POP3 session.
+#[derive(Debug)]
+struct Context {
+    /// Address of client, taken from the PROXY header if enabled.
+    peer: SocketAddr,
+    /// Config may be reloaded, take the latest one while using.
+    config: watch::Receiver<Arc<Config>>,
+    metrics: Arc<dyn Metrics>,
+    capabilities: Capabilities,
+    /// `Downstream::allow_plaintext_auth`.
+    allow_plaintext_auth: bool,
+    /// `Downstream::read_only`.
+    read_only: bool,
+    /// Maildrop served to every user instead of their own, see
+    /// `Downstream::anonymous`.
+    anonymous: Option<String>,
+    /// Verify credentials sent in the AUTHORIZATION state.
+    authenticator: Arc<dyn Authenticator>,
+    /// Rewrite headers of messages proxied from upstreams.
+    header_rewriter: Option<Arc<dyn HeaderRewriter>>,
+    /// Hostname in the greeting timestamp.
+    hostname: String,
+    /// Text of the greeting before the timestamp.
+    greeting: String,
+    /// Timestamp sent in the greeting of this connection.
+    timestamp: String,
+    uidl: UidlStore,
+    cache: Arc<MessageCache>,
+    logins: LoginStore,
+    locks: MaildropLocks,
+    /// Lock of the maildrop of user, held until QUIT or the session ends.
+    lock: Option<MaildropLock>,
+    pool: Arc<UpstreamPool>,
+    /// Common name of the verified client certificate, used by AUTH
+    /// EXTERNAL.
+    external: Option<String>,
+    /// User who owns the maildrop, empty before USER.
+    user: String,
+    /// Maildrop of user, opened after authenticated.
+    maildrop: Option<Mailbox>,
+    /// Whether the authenticator has rejected the last login, only such
+    /// failures count as failed attempts.
+    rejected: bool,
+}
+
+/// Mailbox is where the maildrop of user lives.
+#[derive(Debug)]
+enum Mailbox {
+    /// Maildrop stored under data_dir.
+    File(FileMaildrop),
+    /// Maildrop proxied from an upstream, retrieved messages are cached if
+    /// enabled. Headers are rewritten after cached, so that the cache is
+    /// kept as upstream sent.
+    Upstream {
+        name: String,
+        client: Box<UpstreamClient>,
+        cache: Option<Arc<MessageCache>>,
+        header_rewriter: Option<Arc<dyn HeaderRewriter>>,
+        /// Sizes of messages after headers rewritten by message number,
+        /// which are stable in a session.
+        sizes: BTreeMap<usize, usize>,
+        /// Unique-ids by message number known in this session, taken from
+        /// UIDL responses and RETR through the cache.
+        uids: BTreeMap<usize, String>,
+    },
+}
+
+impl Mailbox {
+    async fn send(&mut self, req: &Request) -> Result<Response> {
+        match (self, req) {
+            (Mailbox::File(maildrop), req) => dispatch(maildrop, req),
+            (
+                Mailbox::Upstream {
+                    client,
+                    header_rewriter,
+                    sizes,
+                    uids,
+                    ..
+                },
+                req,
+            ) => {
+                let resp = client.send(req).await?;
+                match &resp {
+                    Response::UIDL(UidlResponse::All(m)) => {
+                        uids.extend(m.iter().map(|(id, uid)| (*id, uid.clone())))
+                    }
+                    Response::UIDL(UidlResponse::Single(id, uid)) => {
+                        uids.insert(*id, uid.clone());
+                    }
+                    _ => {}
+                }
+                match header_rewriter {
+                    Some(rewriter) => rewrite_headers(client, rewriter.as_ref(), sizes, resp).await,
+                    None => Ok(resp),
+                }
+            }
+        }
+    }
+
+    /// Write the response of RETR of message `id` into `w`, the message is
+    /// streamed from the file or upstream instead of buffered, except that
+    /// it's kept in memory to be cached if the cache is enabled.
+    ///
+    /// Returns octets of the message sent, or the `-ERR` response which is
+    /// not written yet.
+    async fn retr_to<W>(
+        &mut self,
+        id: usize,
+        w: &mut W,
+    ) -> Result<std::result::Result<usize, Response>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let res = match self {
+            Mailbox::File(maildrop) => match maildrop.open_message(id) {
+                Ok(file) => write_retr(tokio::fs::File::from_std(file), w).await,
+                Err(err) => return Ok(Err(Response::ERR(err.to_string()))),
+            },
+            Mailbox::Upstream {
+                name,
+                client,
+                cache,
+                header_rewriter,
+                uids,
+                ..
+            } => {
+                let rewriter = header_rewriter.as_deref();
+                match cache {
+                    Some(cache) => retr_cached(client, cache, name, uids, id, rewriter, w).await,
+                    None => relay_retr(client, id, rewriter, w).await,
+                }
+            }
+        };
+
+        match res {
+            Ok(n) => Ok(Ok(n)),
+            Err(err) => match err.downcast_ref::<ErrResponse>() {
+                Some(v) => Ok(Err(Response::ERR(v.text.clone()))),
+                None => Err(err),
+            },
+        }
+    }
+}
+
+/// Rewrite headers of messages in resp, and adjust sizes to the rewritten
+/// messages by rewriting headers fetched by `TOP <id> 0`. Rewritten sizes
+/// are kept in `sizes`, so headers of a message are fetched only once.
+async fn rewrite_headers(
+    client: &mut UpstreamClient,
+    rewriter: &dyn HeaderRewriter,
+    sizes: &mut BTreeMap<usize, usize>,
+    resp: Response,
+) -> Result<Response> {
+    Ok(match resp {
+        Response::TOP(v) => Response::TOP(rewrite::rewrite_message(rewriter, &v)),
+        Response::LIST(ListResponse::Single(id, size)) => Response::LIST(ListResponse::Single(
+            id,
+            rewritten_size(client, rewriter, sizes, id, size).await?,
+        )),
+        Response::LIST(ListResponse::All(v)) => {
+            let mut messages = Vec::with_capacity(v.len());
+            for (id, size) in v {
+                messages.push((id, rewritten_size(client, rewriter, sizes, id, size).await?));
+            }
+            Response::LIST(ListResponse::All(messages))
+        }
+        Response::STAT { count, .. } => match client.send(&Request::LIST(None)).await? {
+            Response::LIST(ListResponse::All(v)) => {
+                let mut size = 0;
+                for (id, v) in v {
+                    size += rewritten_size(client, rewriter, sizes, id, v).await?;
+                }
+                Response::STAT { count, size }
+            }
+            v => return Err(anyhow::anyhow!("unexpected response for LIST: {:?}", v)),
+        },
+        resp => resp,
+    })
+}
+
+/// Size of message `id` after its headers rewritten, kept as is if its
+/// headers can't be fetched.
+async fn rewritten_size(
+    client: &mut UpstreamClient,
+    rewriter: &dyn HeaderRewriter,
+    sizes: &mut BTreeMap<usize, usize>,
+    id: usize,
+    size: usize,
+) -> Result<usize> {
+    if let Some(v) = sizes.get(&id) {
+        return Ok(*v);
+    }
+
+    let v = match client.send(&Request::TOP { id, lines: 0 }).await? {
+        Response::TOP(v) => rewrite::rewritten_size(rewriter, size, &v),
+        _ => return Ok(size),
+    };
+    sizes.insert(id, v);
+    Ok(v)
+}
+
+/// Relay message `id` from upstream into `w` as the response of RETR while
+/// it's being read, so that a huge message is never buffered. Nothing is
+/// written if upstream refuses RETR.
+async fn relay_retr<W>(
+    client: &mut UpstreamClient,
+    id: usize,
+    rewriter: Option<&dyn HeaderRewriter>,
+    w: &mut W,
+) -> Result<usize>
+where
+    W: AsyncWrite + Unpin,
+{
+    let (mut tx, rx) = io::duplex(RELAY_BUFFER_SIZE);
+    // The pipe is closed once the body is read, as `tx` is dropped.
+    let upstream = async move { client.retr_to(id, &mut tx).await };
+    let downstream = async move {
+        let mut rx = BufReader::new(rx);
+        // Wait for the body before sending anything. If upstream refuses
+        // RETR, `try_join` returns its error before the pipe is closed.
+        future::poll_fn(|cx| Pin::new(&mut rx).poll_fill_buf(cx).map_ok(|_| ())).await?;
+        write_message(rx, rewriter, w).await
+    };
+
+    let (_, n) = tokio::try_join!(upstream, downstream)?;
+    Ok(n)
+}
+
+/// Retrieve message `id` from upstream `name` into `w` like `relay_retr`,
+/// the cached one will be used if exists. A message not cached yet is read
+/// as a whole to be cached before written.
+async fn retr_cached<W>(
+    client: &mut UpstreamClient,
+    cache: &MessageCache,
+    name: &str,
+    uids: &mut BTreeMap<usize, String>,
+    id: usize,
+    rewriter: Option<&dyn HeaderRewriter>,
+    w: &mut W,
+) -> Result<usize>
+where
+    W: AsyncWrite + Unpin,
+{
+    // Message numbers only make sense in this session, take the uid first.
+    let uid = match uids.get(&id) {
+        Some(v) => v.clone(),
+        None => match client.send(&Request::UIDL(Some(id))).await? {
+            Response::UIDL(UidlResponse::Single(_, uid)) => {
+                uids.insert(id, uid.clone());
+                uid
+            }
+            _ => return relay_retr(client, id, rewriter, w).await,
+        },
+    };
+    let message = match cache.get(name, &uid) {
+        Some(v) => {
+            debug!("serve message {} of upstream {} from cache", uid, name);
+            v
+        }
+        None => {
+            let mut v = Vec::new();
+            client.retr_to(id, &mut v).await?;
+            if let Err(err) = cache.put(name, &uid, &v) {
+                warn!("cache message {} of upstream {}: {}", uid, name, err);
+            }
+            v
+        }
+    };
+
+    write_message(&message[..], rewriter, w).await
+}
+
+/// Write a message read from `r` into `w` as the response of RETR, headers
+/// are rewritten by `rewriter` if set and the rest is streamed as is.
+async fn write_message<R, W>(
+    mut r: R,
+    rewriter: Option<&dyn HeaderRewriter>,
+    w: &mut W,
+) -> Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match rewriter {
+        Some(rewriter) => {
+            let head = rewrite::rewrite_head(rewriter, &mut r).await?;
+            write_retr(head.as_bytes().chain(r), w).await
+        }
+        None => write_retr(r, w).await,
+    }
+}
+
+impl Context {
+    /// Open the maildrop of user if the authenticator accepted it.
+    async fn login(&mut self, result: AuthResult) -> Result<()> {
+        match result {
+            AuthResult::Accept => self.open_maildrop().await,
+            AuthResult::Reject => {
+                self.rejected = true;
+                Err(anyhow::anyhow!("[AUTH] invalid credentials"))
+            }
+            AuthResult::TemporaryFailure(v) => {
+                warn!("authenticate {}: {}", self.user, v);
+                Err(anyhow::anyhow!("[SYS/TEMP] authentication unavailable"))
+            }
+        }
+    }
+
+    /// Open the maildrop of current user, from the routed upstream or
+    /// under data_dir.
+    async fn open_maildrop(&mut self) -> Result<()> {
+        if let Some(delay) = self.capabilities.login_delay {
+            if let Some(v) = self.logins.remaining(&self.user, delay)? {
+                return Err(anyhow::anyhow!(
+                    "[LOGIN-DELAY] wait {} seconds before next login",
+                    v
+                ));
+            }
+        }
+
+        let lock = self
+            .locks
+            .try_lock(&self.user)
+            .ok_or_else(|| anyhow::anyhow!("[IN-USE] maildrop already locked"))?;
+
+        let config = self.config.borrow().clone();
+        let upstream = match &self.anonymous {
+            Some(_) => None,
+            None => config.resolve_upstream(&self.user),
+        };
+        let mailbox = match upstream {
+            Some(upstream) => Mailbox::Upstream {
+                name: upstream.name.clone(),
+                cache: if upstream.cache {
+                    Some(self.cache.clone())
+                } else {
+                    None
+                },
+                header_rewriter: self.header_rewriter.clone(),
+                sizes: BTreeMap::new(),
+                uids: BTreeMap::new(),
+                client: Box::new(self.pool.get(upstream).await.inspect_err(|err| {
+                    warn!("upstream {}: {}", upstream.name, err);
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(upstream = %upstream.name, error = %err);
+                    self.metrics.on_upstream_error(&upstream.name)
+                })?),
+            },
+            None => {
+                // User will be used as the dir name, reject anything could
+                // escape from data_dir. Hidden dirs like the cache are kept
+                // by postman.
+                let mut components = Path::new(&self.user).components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(_)), None) if !self.user.starts_with('.') => {}
+                    _ => return Err(anyhow::anyhow!("invalid user {:?}", self.user)),
+                }
+
+                let name = self.anonymous.as_deref().unwrap_or(&self.user);
+                let mut maildrop = FileMaildrop::open(config.data_dir.join(name))?;
+                maildrop.set_read_only(self.read_only);
+                Mailbox::File(maildrop)
+            }
+        };
+
+        self.maildrop = Some(mailbox);
+        self.lock = Some(lock);
+        self.logins.record(&self.user)?;
+        info!("user {} logged in from {}", self.user, self.peer);
+        Ok(())
+    }
+
+    /// Forward a command unknown by postman to the upstream if its verb is
+    /// listed in `passthrough`, returns the raw reply.
+    ///
+    /// Returns `None` if the command should be rejected as usual.
+    async fn passthrough(
+        &mut self,
+        passthrough: &BTreeMap<String, bool>,
+        line: &str,
+        err: &anyhow::Error,
+    ) -> Result<Option<String>> {
+        let verb = match err.downcast_ref::<ProtoError>() {
+            Some(ProtoError::UnknownCommand(v)) => v,
+            _ => return Ok(None),
+        };
+        let multiline = match passthrough
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(verb))
+        {
+            Some((_, v)) => *v,
+            None => return Ok(None),
+        };
+        let (name, client) = match &mut self.maildrop {
+            Some(Mailbox::Upstream { name, client, .. }) => (name, client),
+            _ => return Ok(None),
+        };
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        info!("C: {}", line);
+        let mut reply = String::new();
+        if let Err(err) = client.send_line(line, multiline, &mut reply).await {
+            warn!("upstream {}: {}", name, err);
+            #[cfg(feature = "tracing")]
+            tracing::warn!(upstream = %name, error = %err);
+            self.metrics.on_upstream_error(name);
+            return Err(err);
+        }
+        info!("S: {:?}", reply.lines().next().unwrap_or_default());
+
+        Ok(Some(reply))
+    }
+
+    /// Close the maildrop, deletions are committed only if session has
+    /// entered the UPDATE state.
+    async fn quit(&mut self, state: State) -> Result<Response> {
+        let resp = match self.maildrop.take() {
+            Some(mut mailbox) if state == State::UPDATE => {
+                if let Mailbox::File(maildrop) = &mailbox {
+                    if let Ok(v) = maildrop.stat_full() {
+                        info!(
+                            "commit maildrop of {}: {}",
+                            self.user,
+                            MaildropStat::from(v)
+                        );
+                    }
+                }
+                mailbox.send(&Request::QUIT).await
+            }
+            // Never put back to the pool, the maildrop of a POP3 session is
+            // fixed at login and messages arrived since then would be hidden
+            // from the next session. Deletions are reset before QUIT as the
+            // session didn't enter the UPDATE state.
+            Some(Mailbox::Upstream {
+                name, mut client, ..
+            }) => {
+                let res = match client.send(&Request::RSET).await {
+                    Ok(_) => client.close().await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = res {
+                    debug!("close connection to upstream {}: {}", name, err);
+                }
+                Ok(Response::QUIT(None))
+            }
+            _ => Ok(Response::QUIT(None)),
+        };
+
+        // Released before replying, so that the client could login again
+        // right after QUIT.
+        self.lock = None;
+        resp
+    }
+
+    /// Serve requests which require an opened maildrop.
+    async fn transaction(&mut self, req: &Request) -> Result<Response> {
+        let mailbox = match self.maildrop.as_mut() {
+            Some(v) => v,
+            None => return Ok(Response::ERR("not authenticated".to_string())),
+        };
+        // File maildrops are refused by `dispatch`, upstreams are kept from
+        // seeing DELE at all so that QUIT never removes anything.
+        if self.read_only {
+            if let Mailbox::Upstream { .. } = mailbox {
+                match req {
+                    Request::DELE(_) => {
+                        return Ok(Response::ERR("maildrop is read-only".to_string()))
+                    }
+                    Request::RSET => return Ok(Response::RSET(String::new())),
+                    _ => {}
+                }
+            }
+        }
+
+        let resp = match mailbox.send(req).await {
+            Ok(v) => v,
+            Err(err) => {
+                upstream_error(self.metrics.as_ref(), mailbox, &err);
+                return Err(err);
+            }
+        };
+        // Seen messages are tracked by upstream, which could be shared by
+        // users.
+        if let (
+            Request::UIDL(None),
+            Response::UIDL(UidlResponse::All(m)),
+            Mailbox::Upstream { name, cache, .. },
+        ) = (req, &resp, mailbox)
+        {
+            self.uidl
+                .purge_missing(name, m.values().map(String::as_str))?;
+            if let Some(cache) = cache {
+                cache.purge_missing(name, m.values().map(String::as_str))?;
+            }
+        }
+
+        Ok(resp)
+    }
+
+    /// Serve RETR by writing message `id` into `w`, see `Mailbox::retr_to`.
+    async fn retr<W>(
+        &mut self,
+        id: usize,
+        w: &mut W,
+    ) -> Result<std::result::Result<usize, Response>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mailbox = match self.maildrop.as_mut() {
+            Some(v) => v,
+            None => return Ok(Err(Response::ERR("not authenticated".to_string()))),
+        };
+
+        let res = match mailbox.retr_to(id, w).await {
+            Ok(v) => v,
+            Err(err) => {
+                upstream_error(self.metrics.as_ref(), mailbox, &err);
+                return Err(err);
+            }
+        };
+        // Only messages whose uid is known in this session are recorded,
+        // asking the upstream costs another round trip per message. The
+        // message has been sent, a failure of the store is only logged.
+        if let (Ok(_), Mailbox::Upstream { name, uids, .. }) = (&res, mailbox) {
+            if let Some(uid) = uids.get(&id) {
+                if let Err(err) = self.uidl.mark_seen(name, uid) {
+                    warn!("mark message {} of upstream {} seen: {}", uid, name, err);
+                }
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+/// Report an error of the mailbox if it's proxied from an upstream.
+fn upstream_error(metrics: &dyn Metrics, mailbox: &Mailbox, err: &anyhow::Error) {
+    if let Mailbox::Upstream { name, .. } = mailbox {
+        warn!("upstream {}: {}", name, err);
+        #[cfg(feature = "tracing")]
+        tracing::warn!(upstream = %name, error = %err);
+        metrics.on_upstream_error(name);
+    }
+}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        // Add a permit back to the semaphore so that the listener can accept
+        // a new connection.
+        self.limit_connections.add_permits(1);
+    }
+}
+
+/// Tls accepts TLS connections of a downstream.
+#[derive(Clone)]
+struct Tls(TlsAcceptor);
+
+impl Debug for Tls {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Tls")
+    }
+}
+
+/// Load certificate and key of downstream into a TLS acceptor.
+fn tls_acceptor(tls: &DownstreamTls) -> Result<Tls> {
+    let certs = tls::load_certs(&tls.cert)?;
+    let key = tls::load_private_key(&tls.key)?;
+
+    let verifier = match &tls.client_ca {
+        // Clients without certificate could still login by others.
+        Some(path) => AllowAnyAnonymousOrAuthenticatedClient::new(tls::load_roots(path)?),
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config.set_single_cert(certs, key)?;
+
+    Ok(Tls(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Take the common name from subject of a DER encoded certificate.
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+
+    cn.as_str().ok().map(String::from)
+}
+
+/// Format request for logging, secrets of PASS and APOP are hidden.
+fn redact(req: &Request) -> String {
+    match req {
+        Request::PASS(_) => "PASS ***".to_string(),
+        Request::APOP { username, .. } => format!("APOP {} ***", username),
+        req => format!("{}", req).trim_end().to_string(),
+    }
+}
+
+/// Describe `resp` for logs, bodies of RETR and TOP are replaced by their
+/// sizes.
+fn summarize(resp: &Response) -> String {
+    match resp {
+        Response::RETR(v) => format!("RETR({} octets)", v.len()),
+        Response::TOP(v) => format!("TOP({} octets)", v.len()),
+        resp => format!("{:?}", resp),
+    }
+}
+
+/// Send a SASL challenge and read client's response.
+///
+/// Returns `None` if client cancelled the exchange with `*`.
+async fn sasl_step(
+    r: &mut (impl AsyncBufReadExt + Unpin),
+    w: &mut (impl AsyncWriteExt + Unpin),
+    challenge: &str,
+) -> Result<Option<String>> {
+    let resp = Response::AUTH(AuthResponse::Challenge(challenge.to_string()));
+    w.write_all(&resp.to_bytes()?).await?;
+
+    let s = read_line(r).await?;
+    match s.trim_end() {
+        "*" => Ok(None),
+        v => Ok(Some(v.to_string())),
+    }
+}
+
+/// Sleep until deadline, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(v) => time::sleep_until(v).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn read_line(mut src: impl AsyncBufReadExt + Unpin) -> Result<String> {
+    let mut data: Vec<u8> = Vec::with_capacity(1024);
+
+    loop {
+        let n = src.read_until(b'\n', &mut data).await?;
+        // No data read, just return current buf instead.
+        if n == 0 && data.is_empty() {
+            return Ok(String::from_utf8_lossy(data.as_ref()).to_string());
+        }
+        // Reach EOF or a line is read, return current buf directly.
+        if n == 0 || data.ends_with(b"\n") {
+            break;
+        }
+    }
+
+    Ok(String::from_utf8_lossy(data.as_ref()).to_string())
+}
+
+/// Read the PROXY header from the raw connection byte by byte, so nothing
+/// after it is consumed before the TLS handshake.
+async fn read_proxy_header(src: &mut TcpStream) -> Result<String> {
+    let mut data: Vec<u8> = Vec::with_capacity(proxy::MAX_HEADER_LENGTH);
+
+    while !data.ends_with(b"\n") && data.len() <= proxy::MAX_HEADER_LENGTH {
+        let mut b = [0; 1];
+        if src.read(&mut b).await? == 0 {
+            break;
+        }
+        data.push(b[0]);
+    }
+
+    Ok(String::from_utf8_lossy(data.as_ref()).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::MemoryAuthenticator;
+    use crate::metrics::NoopMetrics;
+    use crate::rewrite::StripHeaders;
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+    use tokio::signal;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tokio::time::Instant;
+
+    /// Spawn an upstream which replies every request line by `reply`,
+    /// returns its address.
+    async fn mock_upstream<F, R>(reply: F) -> Result<SocketAddr>
+    where
+        F: Fn(&str) -> R + Send + Sync + 'static,
+        R: AsRef<[u8]> + Send,
+    {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = upstream.local_addr()?;
+        let reply = Arc::new(reply);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = upstream.accept().await {
+                let reply = reply.clone();
+                tokio::spawn(async move {
+                    let mut socket = BufReader::new(socket);
+                    socket.get_mut().write_all(b"+OK ready\r\n").await?;
+
+                    let mut line = String::new();
+                    while socket.read_line(&mut line).await? > 0 {
+                        socket.get_mut().write_all(reply(&line).as_ref()).await?;
+                        line.clear();
+                    }
+                    Ok::<_, anyhow::Error>(())
+                });
+            }
+        });
+
+        Ok(addr)
+    }
+
+    /// Config of upstream `example` at `addr` which all users are routed
+    /// to, `extra` is appended to the upstream.
+    fn upstream_toml(addr: SocketAddr, extra: &str) -> String {
+        format!(
+            r#"
+[[upstream]]
+name = "example"
+protocol = "pop3"
+addr = "{}"
+auth_type = "user"
+username = "user"
+password = "pass"
+{}
+
+[[route]]
+user = "*"
+upstream = "example"
+"#,
+            addr, extra
+        )
+    }
+
+    // Runs a server until ctrl-c, use `cargo test -- --ignored debug_run` to debug.
+    #[tokio::test]
+    #[ignore]
+    async fn debug_run() -> Result<()> {
+        let mut log_builder = env_logger::Builder::new();
+        log_builder.filter_level(log::LevelFilter::Debug);
+        log_builder.filter_module("sled", log::LevelFilter::Error);
+        log_builder.parse_default_env();
+        log_builder.init();
+
+        let cfg = Arc::new(Config::from_path("config.toml.example")?);
+
+        Server::new(cfg, Arc::new(NoopMetrics))
+            .run(signal::ctrl_c())
+            .await
+    }
+
+    /// Address of a serving server, the sender stops it and the handle
+    /// returns once it has stopped.
+    type Serving = (SocketAddr, oneshot::Sender<()>, JoinHandle<Result<()>>);
+
+    /// Config with db and mails under dir, `downstream` is appended to its
+    /// only downstream to set options or add tables after it.
+    fn config(dir: &Path, downstream: &str) -> Result<Config> {
+        Ok(toml::from_str(&format!(
+            r#"
+database_dir = {:?}
+data_dir = {:?}
+
+[[downstream]]
+protocol = "pop3"
+addr = "127.0.0.1:0"
+auth_type = "user"
+username = "postman"
+password = "postman"
+max_auth_attempts = 3
+{}
+"#,
+            dir.join("db"),
+            dir.join("mails"),
+            downstream,
+        ))?)
+    }
+
+    /// Serve server until the sender is dropped or sent, the address is of
+    /// its first downstream.
+    async fn spawn(server: Server) -> Result<Serving> {
+        let (tx, rx) = oneshot::channel::<()>();
+        let listeners = server.bind().await?;
+        let addr = listeners[0].local_addr()?;
+        let server = tokio::spawn(async move { server.serve(listeners, rx).await });
+
+        Ok((addr, tx, server))
+    }
+
+    /// Serve a server with db and mails under dir until the sender is
+    /// dropped or sent, see `config` for `downstream`.
+    async fn serve(dir: &Path, downstream: &str) -> Result<Serving> {
+        serve_with_metrics(dir, downstream, Arc::new(NoopMetrics)).await
+    }
+
+    async fn serve_with_metrics(
+        dir: &Path,
+        downstream: &str,
+        metrics: Arc<dyn Metrics>,
+    ) -> Result<Serving> {
+        spawn(Server::new(Arc::new(config(dir, downstream)?), metrics)).await
+    }
+
+    #[tokio::test]
+    async fn commit_on_quit() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-server-{}", std::process::id()));
+        let mails = dir.join("mails").join("postman");
+        fs::create_dir_all(&mails)?;
+        fs::write(mails.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+        fs::write(mails.join("2.eml"), "Subject: b\r\n\r\nworld\r\n")?;
+
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        // Connection dropped without QUIT must not delete anything.
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        assert_eq!(client.send(&Request::DELE(1)).await?, Response::DELE);
+        drop(client);
+
+        // QUIT in AUTHORIZATION state must not delete anything.
+        let mut client = Client::connect(addr).await?;
+        assert_eq!(client.send(&Request::QUIT).await?, Response::QUIT(None));
+        assert!(mails.join("1.eml").exists());
+
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        assert_eq!(
+            client.send(&Request::STAT).await?,
+            Response::STAT { count: 2, size: 42 }
+        );
+        assert_eq!(client.send(&Request::DELE(1)).await?, Response::DELE);
+        // LIST skips deleted messages as STAT does.
+        let stat = match client.send(&Request::LIST(None)).await? {
+            Response::LIST(v) => v.to_stat(),
+            v => panic!("unexpected response: {:?}", v),
+        };
+        assert_eq!(
+            client.send(&Request::STAT).await?,
+            Response::STAT {
+                count: stat.0,
+                size: stat.1
+            }
+        );
+        assert_eq!(stat.0, 1);
+        assert_eq!(
+            client.send(&Request::QUIT).await?,
+            Response::QUIT(Some(
+                "POP3 server signing off (1 messages deleted, 1 messages left)".to_string()
+            ))
+        );
+        assert!(!mails.join("1.eml").exists());
+        assert!(mails.join("2.eml").exists());
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn maildrop_in_use() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-in-use-{}", std::process::id()));
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        let login = || async move {
+            let mut client = Client::connect(addr).await?;
+            client
+                .login(AuthType::UserPass, "postman", "postman")
+                .await
+                .map(|_| client)
+        };
+
+        // Exactly one of simultaneous logins holds the maildrop.
+        let (a, b) = tokio::join!(login(), login());
+        let (client, err) = match (a, b) {
+            (Ok(client), Err(err)) | (Err(err), Ok(client)) => (client, err),
+            (a, b) => panic!("unexpected logins: {:?}, {:?}", a.is_ok(), b.is_ok()),
+        };
+        assert!(err.to_string().contains("[IN-USE] maildrop already locked"));
+
+        // Released by QUIT.
+        client.close().await?;
+        let client = login().await?;
+
+        // Released once the connection is dropped without QUIT.
+        drop(client);
+        let mut retries = 0;
+        let client = loop {
+            match login().await {
+                Ok(v) => break v,
+                Err(_) if retries < 50 => retries += 1,
+                Err(err) => return Err(err),
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        };
+        client.close().await?;
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn downstream_user() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-user-{}", std::process::id()));
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        // Only the user of downstream could login by its password.
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        let greeting = read_line(&mut conn).await?;
+        let timestamp = &greeting[greeting.find('<').unwrap()..].trim_end();
+        let digest = apop_digest(timestamp, "postman");
+        conn.get_mut()
+            .write_all(
+                format!("USER other\r\nPASS postman\r\nAPOP other {}\r\n", digest).as_bytes(),
+            )
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        for _ in 0..2 {
+            assert_eq!(
+                read_line(&mut conn).await?,
+                "-ERR [AUTH] invalid credentials\r\n"
+            );
+        }
+        conn.get_mut()
+            .write_all(format!("APOP postman {}\r\n", digest).as_bytes())
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_command() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-unknown-{}", std::process::id()));
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        conn.get_mut().write_all(b"FOOBAR\r\n").await?;
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR unknown command \"FOOBAR\"\r\n"
+        );
+        // Long verb echoed in the reply is truncated to fit in a line.
+        conn.get_mut()
+            .write_all(format!("{}\r\n", "X".repeat(600)).as_bytes())
+            .await?;
+        let line = read_line(&mut conn).await?;
+        assert!(line.starts_with("-ERR unknown command"), "{}", line);
+        assert_eq!(line.len(), MAX_LINE_LENGTH);
+
+        // Session survives the unknown command.
+        conn.get_mut().write_all(b"QUIT\r\n").await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plaintext_auth() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-plaintext-{}", std::process::id()));
+
+        let (addr, tx, server) = serve(&dir, "allow_plaintext_auth = false").await?;
+
+        let mut client = Client::connect(addr).await?;
+        assert_eq!(
+            client.send(&Request::AUTH(None)).await?,
+            Response::AUTH(AuthResponse::All(Vec::new()))
+        );
+        let capa = client.send(&Request::CAPA).await?;
+        assert!(Capabilities::parse(&capa)?.sasl.is_empty());
+        assert_eq!(
+            client
+                .send(&Request::AUTH(Some("PLAIN".to_string())))
+                .await?,
+            Response::ERR("[AUTH] PLAIN requires TLS".to_string())
+        );
+        drop(client);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    // Compares round-trip latency of STAT with and without TCP_NODELAY, use
+    // `RUST_LOG=info cargo test -- --ignored stat_latency --nocapture` to see
+    // the result.
+    #[tokio::test]
+    #[ignore]
+    async fn stat_latency() -> Result<()> {
+        const ROUNDS: u32 = 200;
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        for nodelay in [true, false] {
+            let dir = env::temp_dir().join(format!(
+                "postman-latency-{}-{}",
+                nodelay,
+                std::process::id()
+            ));
+
+            let (addr, tx, server) = serve(&dir, &format!("tcp_nodelay = {}", nodelay)).await?;
+
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(nodelay)?;
+            let mut client = Client::new(stream).await?;
+            client
+                .login(AuthType::UserPass, "postman", "postman")
+                .await?;
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                client.send(&Request::STAT).await?;
+            }
+            info!(
+                "TCP_NODELAY {}: STAT takes {:?} on average",
+                nodelay,
+                start.elapsed() / ROUNDS
+            );
+            drop(client);
+
+            let _ = tx.send(());
+            server.await??;
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        Ok(())
+    }
+
+    // Checks that RETR of a 50 MB message is streamed instead of buffered,
+    // use `RUST_LOG=info cargo test --release -- --ignored retr_peak_rss
+    // --nocapture` to see how much the peak RSS grows.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore]
+    async fn retr_peak_rss() -> Result<()> {
+        const SIZE: usize = 50 * 1024 * 1024;
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let dir = env::temp_dir().join(format!("postman-rss-{}", std::process::id()));
+        let mails = dir.join("mails").join("postman");
+        fs::create_dir_all(&mails)?;
+        let line = "x".repeat(998) + "\r\n";
+        let mut file = std::io::BufWriter::new(fs::File::create(mails.join("1.eml"))?);
+        for _ in 0..SIZE / line.len() {
+            std::io::Write::write_all(&mut file, line.as_bytes())?;
+        }
+        drop(file);
+
+        let (addr, tx, server) = serve(&dir, "").await?;
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+
+        let before = peak_rss()?;
+        let n = client.retr_to(1, &mut io::sink()).await?;
+        let grown = peak_rss()? - before;
+        info!(
+            "RETR of {} octets grows peak RSS by {} KiB",
+            n,
+            grown / 1024
+        );
+        assert!(grown < SIZE / 2, "peak RSS grows by {} octets", grown);
+        client.close().await?;
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Peak resident set size of this process in octets.
+    #[cfg(target_os = "linux")]
+    fn peak_rss() -> Result<usize> {
+        let status = fs::read_to_string("/proc/self/status")?;
+        let kb = status
+            .lines()
+            .find_map(|v| v.strip_prefix("VmHWM:"))
+            .ok_or_else(|| anyhow::anyhow!("VmHWM not found"))?;
+
+        Ok(kb.trim().trim_end_matches("kB").trim().parse::<usize>()? * 1024)
+    }
+
+    #[tokio::test]
+    async fn custom_greeting() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-greeting-{}", std::process::id()));
+
+        let mut cfg = config(
+            &dir,
+            r#"
+greeting = "Example Mail ready"
+implementation = "Example"
+"#,
+        )?;
+        cfg.hostname = Some("pop.example.com".to_string());
+        cfg.expire_days = Some(0);
+        assert!(cfg.validate().is_ok());
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        let greet = read_line(&mut conn).await?;
+        assert!(greet.starts_with("+OK Example Mail ready <"), "{}", greet);
+        assert!(greet.ends_with("@pop.example.com>\r\n"), "{}", greet);
+
+        conn.get_mut().write_all(b"CAPA\r\n").await?;
+        let mut capa = Vec::new();
+        loop {
+            let line = read_line(&mut conn).await?;
+            if line == ".\r\n" {
+                break;
+            }
+            capa.push(line);
+        }
+        assert!(capa.contains(&"IMPLEMENTATION Example\r\n".to_string()));
+        assert!(capa.contains(&"EXPIRE 0\r\n".to_string()));
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn passthrough() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-passthrough-{}", std::process::id()));
+
+        // Upstream knows XTND only.
+        let upstream_addr = mock_upstream(|line| match line {
+            "XTND XLST Subject\r\n" => "+OK\r\n1 Subject: a\r\n.\r\n".to_string(),
+            _ => "+OK done\r\n".to_string(),
+        })
+        .await?;
+
+        let (addr, tx, server) = serve(
+            &dir,
+            &format!(
+                "[downstream.passthrough]\nxtnd = true\n{}",
+                upstream_toml(upstream_addr, "")
+            ),
+        )
+        .await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        // Not forwarded before login.
+        conn.get_mut().write_all(b"XTND XLST Subject\r\n").await?;
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR unknown command \"XTND\"\r\n"
+        );
+
+        conn.get_mut()
+            .write_all(b"USER postman\r\nPASS postman\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        conn.get_mut().write_all(b"XTND XLST Subject\r\n").await?;
+        assert_eq!(read_line(&mut conn).await?, "+OK\r\n");
+        assert_eq!(read_line(&mut conn).await?, "1 Subject: a\r\n");
+        assert_eq!(read_line(&mut conn).await?, ".\r\n");
+
+        // Commands not listed are still rejected.
+        conn.get_mut().write_all(b"XFOO\r\n").await?;
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR unknown command \"XFOO\"\r\n"
+        );
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn header_rewriter() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-rewriter-{}", std::process::id()));
+        let headers = "Received: from secret.example.com\r\nSubject: a\r\n\r\n";
+        let message = format!("{}hello\r\n", headers);
+
+        let size = message.len();
+        let tops = Arc::new(AtomicUsize::new(0));
+        let upstream_tops = tops.clone();
+        let upstream_addr = mock_upstream(move |line| match line {
+            "LIST 1\r\n" => format!("+OK 1 {}\r\n", size),
+            "TOP 1 0\r\n" => {
+                upstream_tops.fetch_add(1, Ordering::SeqCst);
+                format!("+OK\r\n{}.\r\n", headers)
+            }
+            "RETR 1\r\n" => format!("+OK\r\n{}.\r\n", message),
+            "UIDL 1\r\n" => "+OK 1 a\r\n".to_string(),
+            _ => "+OK\r\n".to_string(),
+        })
+        .await?;
+
+        let cfg = config(&dir, &upstream_toml(upstream_addr, ""))?;
+        let server = Server::new(Arc::new(cfg), Arc::new(NoopMetrics))
+            .with_header_rewriter(Arc::new(StripHeaders::new(vec!["Received"])));
+        let (addr, tx, server) = spawn(server).await?;
+
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        let expect = "Subject: a\r\n\r\nhello\r\n";
+        for _ in 0..2 {
+            assert_eq!(
+                client.send(&Request::LIST(Some(1))).await?,
+                Response::LIST(ListResponse::Single(1, expect.len()))
+            );
+        }
+        // Headers are fetched once for sizes in a session.
+        assert_eq!(tops.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            client.send(&Request::RETR(1)).await?,
+            Response::RETR(expect.to_string())
+        );
+        client.close().await?;
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn relay_large_message() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-relay-{}", std::process::id()));
+        // 1 MB of short lines, much larger than max_response_bytes.
+        let line = "x".repeat(98) + "\r\n";
+        let message = "Subject: a\r\n\r\n".to_string() + &line.repeat(10_000);
+        let body = message.clone();
+        let uidls = Arc::new(AtomicUsize::new(0));
+        let upstream_uidls = uidls.clone();
+        let upstream_addr = mock_upstream(move |line| match line {
+            "RETR 1\r\n" => format!("+OK\r\n{}.\r\n", body),
+            "RETR 2\r\n" => "-ERR no such message\r\n".to_string(),
+            "UIDL 1\r\n" => {
+                upstream_uidls.fetch_add(1, Ordering::SeqCst);
+                "+OK 1 a\r\n".to_string()
+            }
+            _ => "+OK\r\n".to_string(),
+        })
+        .await?;
+
+        let cfg = config(
+            &dir,
+            &upstream_toml(upstream_addr, "max_response_bytes = 4096"),
+        )?;
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        client.send(&Request::UIDL(Some(1))).await?;
+        let mut v = Vec::new();
+        assert_eq!(client.retr_to(1, &mut v).await?, message.len());
+        assert_eq!(v, message.as_bytes());
+        // Refused RETR is replied as usual and the session goes on.
+        assert_eq!(
+            client.send(&Request::RETR(2)).await?,
+            Response::ERR("no such message".to_string())
+        );
+        client.close().await?;
+
+        let _ = tx.send(());
+        server.await??;
+        // The uid listed by the client is reused to mark it seen.
+        assert_eq!(uidls.load(Ordering::SeqCst), 1);
+        // Seen by the upstream instead of the user.
+        let uidl = UidlStore::open(&sled::open(dir.join("db"))?)?;
+        assert!(uidl.is_seen("example", "a")?);
+        assert!(!uidl.is_seen("postman", "a")?);
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upstream_cache() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-upstream-cache-{}", std::process::id()));
+        let message = "Subject: a\r\n\r\n.hello\r\n";
+        let retrs = Arc::new(AtomicUsize::new(0));
+        let upstream_retrs = retrs.clone();
+        let upstream_addr = mock_upstream(move |line| match line {
+            "RETR 1\r\n" => {
+                upstream_retrs.fetch_add(1, Ordering::SeqCst);
+                format!("+OK\r\n{}.\r\n", message.replace("\n.", "\n.."))
+            }
+            "UIDL 1\r\n" => "+OK 1 a\r\n".to_string(),
+            _ => "+OK\r\n".to_string(),
+        })
+        .await?;
+
+        let cfg = config(&dir, &upstream_toml(upstream_addr, "cache = true"))?;
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+
+        for _ in 0..2 {
+            let mut client = Client::connect(addr).await?;
+            client
+                .login(AuthType::UserPass, "postman", "postman")
+                .await?;
+            assert_eq!(
+                client.send(&Request::RETR(1)).await?,
+                Response::RETR(message.to_string())
+            );
+            client.close().await?;
+        }
+        // The second one is served from cache.
+        assert_eq!(retrs.load(Ordering::SeqCst), 1);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upstream_cache_8bit() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-cache-8bit-{}", std::process::id()));
+        let message = b"Subject: a\r\nContent-Transfer-Encoding: 8bit\r\n\r\n\xe4\xf6\xff\r\n";
+        let upstream_addr = mock_upstream(move |line| match line {
+            "RETR 1\r\n" => [&b"+OK\r\n"[..], message, b".\r\n"].concat(),
+            "UIDL 1\r\n" => b"+OK 1 a\r\n".to_vec(),
+            _ => b"+OK\r\n".to_vec(),
+        })
+        .await?;
+
+        let cfg = config(&dir, &upstream_toml(upstream_addr, "cache = true"))?;
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+
+        // Missed at first and then hit, both are sent as is.
+        for _ in 0..2 {
+            let mut client = Client::connect(addr).await?;
+            client
+                .login(AuthType::UserPass, "postman", "postman")
+                .await?;
+            let mut body = Vec::new();
+            client.retr_to(1, &mut body).await?;
+            assert_eq!(body, &message[..]);
+            client.close().await?;
+        }
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upstream_login_per_session() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-upstream-login-{}", std::process::id()));
+        // Every login sees one more message.
+        let logins = Arc::new(AtomicUsize::new(0));
+        let upstream_logins = logins.clone();
+        let upstream_addr = mock_upstream(move |line| match line {
+            "USER user\r\n" => {
+                upstream_logins.fetch_add(1, Ordering::SeqCst);
+                "+OK\r\n".to_string()
+            }
+            "STAT\r\n" => format!("+OK {} 0\r\n", upstream_logins.load(Ordering::SeqCst)),
+            _ => "+OK\r\n".to_string(),
+        })
+        .await?;
+
+        let cfg = config(&dir, &upstream_toml(upstream_addr, ""))?;
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+
+        for (i, quit) in [true, false, true].iter().enumerate() {
+            let mut client = Client::connect(addr).await?;
+            // The lock is released once the last session is gone.
+            while let Err(err) = client.login(AuthType::UserPass, "postman", "postman").await {
+                assert!(err.to_string().contains("[IN-USE]"), "{}", err);
+                time::sleep(Duration::from_millis(20)).await;
+                client = Client::connect(addr).await?;
+            }
+            assert_eq!(
+                client.send(&Request::STAT).await?,
+                Response::STAT {
+                    count: i + 1,
+                    size: 0
+                }
+            );
+            if *quit {
+                client.close().await?;
+            }
+        }
+        assert_eq!(logins.load(Ordering::SeqCst), 3);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_reader() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-slow-{}", std::process::id()));
+        let mails = dir.join("mails").join("postman");
+        fs::create_dir_all(&mails)?;
+        // Much larger than the socket buffers.
+        let body = "x".repeat(1000) + "\r\n";
+        fs::write(mails.join("1.eml"), body.repeat(16 * 1024))?;
+
+        let authenticator = MemoryAuthenticator::new()
+            .with_user("postman", "postman")
+            .with_user("other", "postman");
+        let server = Server::new(Arc::new(config(&dir, "")?), Arc::new(NoopMetrics))
+            .with_authenticator(Arc::new(authenticator));
+        let (addr, tx, server) = spawn(server).await?;
+
+        // Never reads the message.
+        let mut slow = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut slow).await?.starts_with("+OK"));
+        slow.get_mut()
+            .write_all(b"USER postman\r\nPASS postman\r\n")
+            .await?;
+        assert!(read_line(&mut slow).await?.starts_with("+OK"));
+        assert!(read_line(&mut slow).await?.starts_with("+OK"));
+        slow.get_mut().write_all(b"RETR 1\r\n").await?;
+
+        // The maildrop is locked by the slow one, use another user.
+        let other = async {
+            let mut client = Client::connect(addr).await?;
+            client.login(AuthType::UserPass, "other", "postman").await?;
+            assert_eq!(
+                client.send(&Request::STAT).await?,
+                Response::STAT { count: 0, size: 0 }
+            );
+            client.send(&Request::QUIT).await
+        };
+        time::timeout(Duration::from_secs(5), other).await??;
+
+        drop(slow);
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dual_stack() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-dual-stack-{}", std::process::id()));
+        let mut cfg = config(&dir, "")?;
+        cfg.downstreams[0].addr = "[::]:0".to_string();
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+        let port = addr.port();
+
+        for host in &["127.0.0.1", "::1"] {
+            let mut conn =
+                BufReader::new(TcpStream::connect((IpAddr::from_str(host)?, port)).await?);
+            assert!(read_line(&mut conn).await?.starts_with("+OK"), "{}", host);
+        }
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-shutdown-{}", std::process::id()));
+        let mails = dir.join("mails").join("postman");
+        fs::create_dir_all(&mails)?;
+        fs::write(mails.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        assert_eq!(client.send(&Request::DELE(1)).await?, Response::DELE);
+
+        // Idle session is dropped without committing deletions.
+        let _ = tx.send(());
+        server.await??;
+        assert!(client.send(&Request::NOOP).await.is_err());
+        assert!(mails.join("1.eml").exists());
+        assert!(Client::connect(addr).await.is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_handle() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-shutdown-handle-{}", std::process::id()));
+        let server = Arc::new(Server::new(
+            Arc::new(config(&dir, "")?),
+            Arc::new(NoopMetrics),
+        ));
+        let listeners = server.bind().await?;
+        let addr = listeners[0].local_addr()?;
+        let serving = server.clone();
+        let handle =
+            tokio::spawn(async move { serving.serve(listeners, future::pending::<()>()).await });
+
+        let mut client = Client::connect(addr).await?;
+        client.send(&Request::NOOP).await?;
+
+        server.shutdown();
+        handle.await??;
+        assert!(client.send(&Request::NOOP).await.is_err());
+        assert!(Client::connect(addr).await.is_err());
+        // Serving after shutdown returns at once.
+        server
+            .serve(server.bind().await?, future::pending::<()>())
+            .await?;
+
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingMetrics {
+        commands: AtomicUsize,
+        bytes: AtomicUsize,
+        auth_failures: AtomicUsize,
+        rejected: AtomicUsize,
+        sessions: std::sync::Mutex<Vec<SessionLog>>,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn on_command(&self, _: Command) {
+            self.commands.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_bytes_retrieved(&self, n: usize) {
+            self.bytes.fetch_add(n, Ordering::SeqCst);
+        }
+
+        fn on_auth_failure(&self) {
+            self.auth_failures.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_connection_rejected(&self, _: &str) {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_session_end(&self, log: &SessionLog) {
+            self.sessions.lock().unwrap().push(log.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-metrics-{}", std::process::id()));
+        let mails = dir.join("mails").join("postman");
+        fs::create_dir_all(&mails)?;
+        fs::write(mails.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let metrics = Arc::new(CountingMetrics::default());
+        let (addr, tx, server) = serve_with_metrics(&dir, "", metrics.clone()).await?;
+
+        // Dropped without QUIT, it's closed long before the shutdown.
+        let mut client = Client::connect(addr).await?;
+        client.send(&Request::NOOP).await?;
+        drop(client);
+
+        let mut client = Client::connect(addr).await?;
+        assert!(client
+            .login(AuthType::UserPass, "../postman", "postman")
+            .await
+            .is_err());
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        client.send(&Request::RETR(1)).await?;
+        client.send(&Request::DELE(1)).await?;
+        client.send(&Request::QUIT).await?;
+
+        let _ = tx.send(());
+        server.await??;
+        assert_eq!(metrics.commands.load(Ordering::SeqCst), 8);
+        assert_eq!(metrics.bytes.load(Ordering::SeqCst), 21);
+        assert_eq!(metrics.auth_failures.load(Ordering::SeqCst), 1);
+
+        let mut sessions = metrics.sessions.lock().unwrap().clone();
+        sessions.sort_by_key(|v| v.started);
+        match sessions.as_slice() {
+            [closed, quit] => {
+                assert_eq!(quit.user, "postman");
+                assert!(quit.authenticated);
+                assert_eq!(quit.commands[&Command::USER], 2);
+                assert_eq!(quit.commands[&Command::PASS], 2);
+                assert_eq!(quit.retrieved, 1);
+                assert_eq!(quit.bytes_retrieved, 21);
+                assert_eq!(quit.deleted, 1);
+                assert_eq!(quit.outcome, SessionOutcome::Quit);
+
+                assert_eq!(closed.user, "");
+                assert!(!closed.authenticated);
+                assert_eq!(closed.outcome, SessionOutcome::Closed);
+            }
+            v => panic!("unexpected sessions: {:?}", v),
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bytes_counted() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-bytes-{}", std::process::id()));
+        let mails = dir.join("mails").join("postman");
+        let content = "Subject: a\r\n\r\n.leading dot\r\n";
+        fs::create_dir_all(&mails)?;
+        fs::write(mails.join("1.eml"), content)?;
+
+        let metrics = Arc::new(CountingMetrics::default());
+        let (addr, tx, server) = serve_with_metrics(&dir, "", metrics.clone()).await?;
+
+        let requests = "USER postman\r\nPASS postman\r\nRETR 1\r\nQUIT\r\n";
+        let mut conn = TcpStream::connect(addr).await?;
+        conn.write_all(requests.as_bytes()).await?;
+        let mut replies = Vec::new();
+        conn.read_to_end(&mut replies).await?;
+        // The message is dot-stuffed on the wire.
+        let retr = Response::RETR(content.to_string()).to_bytes()?;
+        assert!(replies.windows(retr.len()).any(|v| v == retr.as_slice()));
+        assert!(retr.len() > content.len());
+
+        let _ = tx.send(());
+        server.await??;
+        let sessions = metrics.sessions.lock().unwrap().clone();
+        match sessions.as_slice() {
+            [log] => {
+                assert_eq!(log.bytes_received, requests.len() as u64);
+                assert_eq!(log.bytes_sent, replies.len() as u64);
+            }
+            v => panic!("unexpected sessions: {:?}", v),
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn redact_secrets() {
+        assert_eq!(redact(&Request::PASS("s3cret".to_string())), "PASS ***");
+        assert_eq!(
+            redact(&Request::APOP {
+                username: "mrose".to_string(),
+                digest: "c4c9334bac560ecc979e58001b3e22fb".to_string(),
+            }),
+            "APOP mrose ***"
+        );
+        assert_eq!(redact(&Request::RETR(1)), "RETR 1");
+        assert_eq!(
+            summarize(&Response::RETR("Subject: s3cret\r\n".to_string())),
+            "RETR(17 octets)"
+        );
+    }
+
+    #[tokio::test]
+    async fn sasl_plain_authzid() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-plain-authzid-{}", std::process::id()));
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        conn.get_mut().write_all(b"AUTH PLAIN\r\n").await?;
+        assert_eq!(read_line(&mut conn).await?, "+ \r\n");
+        let v = b64::encode("other\0postman\0postman");
+        conn.get_mut()
+            .write_all(format!("{}\r\n", v).as_bytes())
+            .await?;
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR [AUTH] postman is not allowed to login as other\r\n"
+        );
+
+        // The same authzid as authcid is fine.
+        conn.get_mut().write_all(b"AUTH PLAIN\r\n").await?;
+        assert_eq!(read_line(&mut conn).await?, "+ \r\n");
+        let v = b64::encode("postman\0postman\0postman");
+        conn.get_mut()
+            .write_all(format!("{}\r\n", v).as_bytes())
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        conn.get_mut().write_all(b"QUIT\r\n").await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_auth_attempts() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-attempts-{}", std::process::id()));
+        let (addr, tx, server) = serve(&dir, "").await?;
+
+        let mut client = Client::connect(addr).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        // Valid credentials failed to login are not counted.
+        for _ in 0..4 {
+            conn.get_mut()
+                .write_all(b"USER postman\r\nPASS postman\r\n")
+                .await?;
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+            assert_eq!(
+                read_line(&mut conn).await?,
+                "-ERR [IN-USE] maildrop already locked\r\n"
+            );
+        }
+
+        for i in 0..4 {
+            conn.get_mut().write_all(b"USER ../postman\r\n").await?;
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+            conn.get_mut().write_all(b"PASS postman\r\n").await?;
+
+            let line = read_line(&mut conn).await?;
+            if i < 3 {
+                assert_eq!(line, "-ERR [AUTH] invalid credentials\r\n");
+            } else {
+                assert_eq!(line, "-ERR too many attempts\r\n");
+            }
+        }
+        // Connection has been closed.
+        assert_eq!(read_line(&mut conn).await?, "");
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_connections() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-max-conns-{}", std::process::id()));
+
+        let metrics = Arc::new(CountingMetrics::default());
+        let (addr, tx, server) =
+            serve_with_metrics(&dir, "max_connections = 2", metrics.clone()).await?;
+
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+            conns.push(conn);
+        }
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR [SYS/TEMP] too many connections\r\n"
+        );
+        assert_eq!(read_line(&mut conn).await?, "");
+        assert_eq!(metrics.rejected.load(Ordering::SeqCst), 1);
+
+        // Connections are accepted again once a session ends.
+        let mut conn = conns.pop().unwrap();
+        conn.get_mut().write_all(b"QUIT\r\n").await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(read_line(&mut conn).await?, "");
+        let mut greeted = false;
+        for _ in 0..100 {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+            if read_line(&mut conn).await?.starts_with("+OK") {
+                greeted = true;
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(greeted);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tarpit() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-tarpit-{}", std::process::id()));
+
+        let mut cfg = config(&dir, "")?;
+        cfg.tarpit_after_failures = Some(2);
+        cfg.tarpit_delay = 500;
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+        let delay = Duration::from_millis(500);
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        for _ in 0..2 {
+            let start = Instant::now();
+            conn.get_mut().write_all(b"USER ../postman\r\n").await?;
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+            conn.get_mut().write_all(b"PASS postman\r\n").await?;
+            assert!(read_line(&mut conn).await?.starts_with("-ERR"));
+            assert!(start.elapsed() < delay);
+        }
+
+        // Replies are delayed once the threshold is reached.
+        let start = Instant::now();
+        conn.get_mut().write_all(b"NOOP\r\n").await?;
+        assert!(read_line(&mut conn).await?.starts_with("-ERR"));
+        assert!(start.elapsed() >= delay);
+
+        // And so is the greeting of a new connection.
+        let start = Instant::now();
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(start.elapsed() >= delay);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn authenticator() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-authenticator-{}", std::process::id()));
+
+        let authenticator = MemoryAuthenticator::new()
+            .with_user("postman", "s3cret")
+            .with_user("../postman", "s3cret");
+        let server = Server::new(Arc::new(config(&dir, "")?), Arc::new(NoopMetrics))
+            .with_authenticator(Arc::new(authenticator));
+        let (addr, tx, server) = spawn(server).await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        // Accepted users still can't escape from data_dir.
+        conn.get_mut()
+            .write_all(b"USER ../postman\r\nPASS s3cret\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR invalid user \"../postman\"\r\n"
+        );
+        // Password of downstream is not accepted any more.
+        conn.get_mut()
+            .write_all(b"USER postman\r\nPASS postman\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR [AUTH] invalid credentials\r\n"
+        );
+        conn.get_mut()
+            .write_all(b"USER postman\r\nPASS s3cret\r\nQUIT\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        let greeting = read_line(&mut conn).await?;
+        let timestamp = &greeting[greeting.find('<').unwrap()..].trim_end();
+        conn.get_mut()
+            .write_all(format!("APOP postman {}\r\n", apop_digest(timestamp, "s3cret")).as_bytes())
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn anonymous() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-anonymous-{}", std::process::id()));
+        let demo = dir.join("mails").join("demo");
+        fs::create_dir_all(&demo)?;
+        fs::write(demo.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let (addr, tx, server) = serve(
+            &dir,
+            r#"
+[downstream.anonymous]
+maildrop = "demo"
+password = "demo"
+"#,
+        )
+        .await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        // The password of downstream is not accepted.
+        conn.get_mut()
+            .write_all(b"USER somebody\r\nPASS postman\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR [AUTH] invalid credentials\r\n"
+        );
+        conn.get_mut()
+            .write_all(b"USER somebody\r\nPASS demo\r\nSTAT\r\nDELE 1\r\nQUIT\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK 1 "));
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR maildrop is read-only\r\n"
+        );
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(demo.join("1.eml").exists());
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_only() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-read-only-{}", std::process::id()));
+        let maildrop = dir.join("mails").join("postman");
+        fs::create_dir_all(&maildrop)?;
+        fs::write(maildrop.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+        fs::write(maildrop.join("2.eml"), "Subject: b\r\n\r\nworld\r\n")?;
+
+        let (addr, tx, server) = serve(&dir, "read_only = true").await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        conn.get_mut()
+            .write_all(b"USER postman\r\nPASS postman\r\nDELE 1\r\nRSET\r\nSTAT\r\nQUIT\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(
+            read_line(&mut conn).await?,
+            "-ERR maildrop is read-only\r\n"
+        );
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK 2 "));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(FileMaildrop::open(&maildrop)?.stat()?.0, 2);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_data_dir() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-sweep-data-{}", std::process::id()));
+        for name in &["a", "b", CACHE_DIR] {
+            fs::create_dir_all(dir.join(name))?;
+            let path = dir.join(name).join("1.eml");
+            fs::write(&path, "Subject: old\r\n\r\n")?;
+            fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(std::time::SystemTime::now() - Duration::from_secs(7200))?;
+        }
+
+        assert_eq!(sweep(&dir, Duration::from_secs(3600))?, 2);
+        assert!(dir.join(CACHE_DIR).join("1.eml").exists());
+        // An absent data_dir has nothing to sweep.
+        assert_eq!(sweep(&dir.join("absent"), Duration::from_secs(3600))?, 0);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disabled_commands() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-disabled-{}", std::process::id()));
+        let maildrop = dir.join("mails").join("postman");
+        fs::create_dir_all(&maildrop)?;
+        fs::write(maildrop.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let (addr, tx, server) = serve(&dir, r#"disabled_commands = ["DELE"]"#).await?;
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        // Refused even before authenticated.
+        conn.get_mut().write_all(b"DELE 1\r\n").await?;
+        assert_eq!(read_line(&mut conn).await?, "-ERR command disabled\r\n");
+
+        conn.get_mut()
+            .write_all(b"USER postman\r\nPASS postman\r\nDELE 1\r\nRETR 1\r\n")
+            .await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(read_line(&mut conn).await?, "-ERR command disabled\r\n");
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(read_line(&mut conn).await?, "Subject: a\r\n");
+        assert_eq!(read_line(&mut conn).await?, "\r\n");
+        assert_eq!(read_line(&mut conn).await?, "hello\r\n");
+        assert_eq!(read_line(&mut conn).await?, ".\r\n");
+        conn.get_mut().write_all(b"QUIT\r\n").await?;
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        assert_eq!(FileMaildrop::open(&maildrop)?.stat()?.0, 1);
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_session_duration() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-session-duration-{}", std::process::id()));
+        let maildrop = dir.join("mails").join("postman");
+        fs::create_dir_all(&maildrop)?;
+        fs::write(maildrop.join("1.eml"), "Subject: a\r\n\r\nhello\r\n")?;
+
+        let (addr, tx, server) = serve(&dir, "max_session_duration = 1").await?;
+
+        let start = Instant::now();
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        conn.get_mut()
+            .write_all(b"USER postman\r\nPASS postman\r\nDELE 1\r\n")
+            .await?;
+        for _ in 0..3 {
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+        }
+
+        // NOOPs keep the session busy but never extend it.
+        let line = loop {
+            conn.get_mut().write_all(b"NOOP\r\n").await?;
+            let line = read_line(&mut conn).await?;
+            if !line.starts_with("+OK") {
+                break line;
+            }
+            assert!(start.elapsed() < Duration::from_secs(3));
+            time::sleep(Duration::from_millis(100)).await;
+        };
+        assert_eq!(line, "-ERR session time limit exceeded\r\n");
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(read_line(&mut conn).await?, "");
+        assert!(maildrop.join("1.eml").exists());
+
+        let _ = tx.send(());
+        server.await??;
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiple_downstreams() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-downstreams-{}", std::process::id()));
+        let cfg: Config = toml::from_str(&format!(
+            r#"
+database_dir = {:?}
+data_dir = {:?}
+
+[[downstream]]
+protocol = "pop3"
+addr = "127.0.0.1:0"
+auth_type = "user"
+username = "postman"
+password = "postman"
+
+[[downstream]]
+protocol = "pop3"
+addr = "127.0.0.1:0"
+auth_type = "user"
+username = "postman"
+password = "postman"
+max_auth_attempts = 0
+"#,
+            dir.join("db"),
+            dir.join("mails"),
+        ))?;
+
+        let server = Server::new(Arc::new(cfg), Arc::new(NoopMetrics));
+        let listeners = server.bind().await?;
+        let addrs = listeners
+            .iter()
+            .map(|v| v.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_ne!(addrs[0], addrs[1]);
+
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move { server.serve(listeners, rx).await });
+
+        // Each listener serves with settings of its own downstream.
+        for (addr, expect) in addrs.iter().zip(&["-ERR [AUTH] invalid", "-ERR too many"]) {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+            conn.get_mut()
+                .write_all(b"USER ../postman\r\nPASS postman\r\n")
+                .await?;
+            assert!(read_line(&mut conn).await?.starts_with("+OK"));
+            let line = read_line(&mut conn).await?;
+            assert!(line.starts_with(expect), "{}", line);
+        }
+
+        let _ = tx.send(());
+        server.await??;
+        for addr in addrs {
+            assert!(TcpStream::connect(addr).await.is_err());
+        }
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_downstream() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        fs::write(dir.join("cert.pem"), cert.serialize_pem()?)?;
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+
+        let cfg = |key: &str| {
+            config(
+                &dir,
+                &format!(
+                    r#"
+allow_plaintext_auth = false
+
+[downstream.tls]
+cert = {:?}
+key = {:?}
+"#,
+                    dir.join("cert.pem"),
+                    dir.join(key),
+                ),
+            )
+        };
+
+        // Bad key fails at startup instead of per connection.
+        let server = Server::new(Arc::new(cfg("missing.pem")?), Arc::new(NoopMetrics));
+        let listeners = server.bind().await?;
+        assert!(server.serve(listeners, async {}).await.is_err());
+
+        let (addr, tx, server) = spawn(Server::new(
+            Arc::new(cfg("key.pem")?),
+            Arc::new(NoopMetrics),
+        ))
+        .await?;
+
+        let mut config = tokio_rustls::rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&tokio_rustls::rustls::Certificate(cert.serialize_der()?))?;
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(
+                webpki::DNSNameRef::try_from_ascii_str("localhost")?,
+                TcpStream::connect(addr).await?,
+            )
+            .await?;
+        let mut client = Client::new(stream).await?;
+        // Plaintext mechanisms are fine over TLS.
+        assert_eq!(
+            client.send(&Request::AUTH(None)).await?,
+            Response::AUTH(AuthResponse::All(vec![
+                "PLAIN".to_string(),
+                "LOGIN".to_string()
+            ]))
+        );
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        assert!(matches!(
+            client.send(&Request::QUIT).await?,
+            Response::QUIT(Some(_))
+        ));
+
+        // Plaintext client is rejected instead of hanging.
+        let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+        conn.get_mut().write_all(b"USER postman\r\n").await?;
+        let line = time::timeout(Duration::from_secs(5), read_line(&mut conn)).await?;
+        assert!(!matches!(line, Ok(v) if v.starts_with("+OK")));
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_downstream_proxy_protocol() -> Result<()> {
+        let dir = env::temp_dir().join(format!("postman-tls-proxy-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        fs::write(dir.join("cert.pem"), cert.serialize_pem()?)?;
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+
+        let (addr, tx, server) = serve(
+            &dir,
+            &format!(
+                r#"
+proxy_protocol = true
+
+[downstream.tls]
+cert = {:?}
+key = {:?}
+"#,
+                dir.join("cert.pem"),
+                dir.join("key.pem"),
+            ),
+        )
+        .await?;
+
+        let mut config = tokio_rustls::rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&tokio_rustls::rustls::Certificate(cert.serialize_der()?))?;
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        // The balancer sends the header before the TLS handshake.
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 995\r\n")
+            .await?;
+        let stream = connector
+            .connect(webpki::DNSNameRef::try_from_ascii_str("localhost")?, stream)
+            .await?;
+        let mut client = Client::new(stream).await?;
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+        assert!(matches!(
+            client.send(&Request::QUIT).await?,
+            Response::QUIT(Some(_))
+        ));
+
+        // Malformed header closes the connection before the handshake.
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"HELLO\r\n").await?;
+        let res = time::timeout(
+            Duration::from_secs(5),
+            connector.connect(webpki::DNSNameRef::try_from_ascii_str("localhost")?, stream),
+        )
+        .await?;
+        assert!(res.is_err());
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_external() -> Result<()> {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+        use tokio_rustls::rustls;
+
+        let dir = env::temp_dir().join(format!("postman-external-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        fs::write(dir.join("cert.pem"), cert.serialize_pem()?)?;
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+
+        // Empty subject alt names are rejected by webpki.
+        let mut params = CertificateParams::new(vec!["ca".to_string()]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params)?;
+        fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+        let mut params = CertificateParams::new(vec!["postman".to_string()]);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "postman");
+        let client_cert = Certificate::from_params(params)?;
+
+        let cfg = config(
+            &dir,
+            &format!(
+                r#"
+[downstream.tls]
+cert = {:?}
+key = {:?}
+client_ca = {:?}
+"#,
+                dir.join("cert.pem"),
+                dir.join("key.pem"),
+                dir.join("ca.pem"),
+            ),
+        )?;
+        let (addr, tx, server) = spawn(Server::new(Arc::new(cfg), Arc::new(NoopMetrics))).await?;
+
+        let connect = |client_cert: Option<&Certificate>| {
+            let mut config = rustls::ClientConfig::new();
+            let root = rustls::Certificate(cert.serialize_der().expect("serialize"));
+            config.root_store.add(&root).expect("add root");
+            if let Some(v) = client_cert {
+                let chain = vec![rustls::Certificate(
+                    v.serialize_der_with_signer(&ca).expect("sign"),
+                )];
+                let key = rustls::PrivateKey(v.serialize_private_key_der());
+                config
+                    .set_single_client_cert(chain, key)
+                    .expect("client cert");
+            }
+
+            async move {
+                let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+                    .connect(
+                        webpki::DNSNameRef::try_from_ascii_str("localhost")?,
+                        TcpStream::connect(addr).await?,
+                    )
+                    .await?;
+                Client::new(stream).await
+            }
+        };
+
+        let mut client = connect(Some(&client_cert)).await?;
+        sasl::auth_external(&mut client, None).await?;
+        assert_eq!(
+            client.send(&Request::STAT).await?,
+            Response::STAT { count: 0, size: 0 }
+        );
+        client.close().await?;
+
+        let mut client = connect(Some(&client_cert)).await?;
+        assert!(sasl::auth_external(&mut client, Some("other"))
+            .await
+            .is_err());
+
+        // Clients without certificate could connect but not use EXTERNAL.
+        let mut client = connect(None).await?;
+        assert!(sasl::auth_external(&mut client, None).await.is_err());
+        client
+            .login(AuthType::UserPass, "postman", "postman")
+            .await?;
+
+        let _ = tx.send(());
+        server.await??;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::time;
+
+/// Tarpit delays replies to IPs which have failed logins too many times
+/// recently, to slow down credential stuffing bots.
+///
+/// Failures are counted in memory per IP, and forgotten once `window` has
+/// passed since the last one, so that an IP is never penalized forever.
+#[derive(Debug, Clone)]
+pub struct Tarpit {
+    after: u32,
+    delay: Duration,
+    window: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    failures: HashMap<IpAddr, Failures>,
+    /// When expired entries were removed for the last time.
+    swept: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Tarpit {
+    /// Create a tarpit which delays replies by `delay` to IPs failed
+    /// `after` times in `window`.
+    pub fn new(after: u32, delay: Duration, window: Duration) -> Tarpit {
+        Tarpit {
+            after,
+            delay,
+            window,
+            state: Arc::new(Mutex::new(State {
+                failures: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Record a failed login from ip.
+    pub fn record_failure(&self, ip: IpAddr) {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    /// Delay before replying to ip, `None` if it's not in the tarpit.
+    pub fn delay(&self, ip: IpAddr) -> Option<Duration> {
+        self.delay_at(ip, Instant::now())
+    }
+
+    /// Sleep before replying to ip if it's in the tarpit.
+    pub async fn wait(&self, ip: IpAddr) {
+        if let Some(delay) = self.delay(ip) {
+            time::sleep(delay).await;
+        }
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) {
+        let mut state = self.state.lock().expect("lock tarpit");
+
+        // Sweep at most once per window, so that a flood of failures from
+        // different IPs doesn't scan the map every time.
+        if now.duration_since(state.swept) >= self.window {
+            let window = self.window;
+            state
+                .failures
+                .retain(|_, v| now.duration_since(v.last) < window);
+            state.swept = now;
+        }
+
+        let v = state.failures.entry(ip).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        if now.duration_since(v.last) >= self.window {
+            v.count = 0;
+        }
+        v.count = v.count.saturating_add(1);
+        v.last = now;
+    }
+
+    fn delay_at(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let state = self.state.lock().expect("lock tarpit");
+
+        match state.failures.get(&ip) {
+            Some(v) if v.count >= self.after && now.duration_since(v.last) < self.window => {
+                Some(self.delay)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tarpit() {
+        let delay = Duration::from_secs(3);
+        let window = Duration::from_secs(60);
+        let tarpit = Tarpit::new(2, delay, window);
+        let bad: IpAddr = "192.0.2.1".parse().unwrap();
+        let good: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        tarpit.record_failure_at(bad, now);
+        assert_eq!(tarpit.delay_at(bad, now), None);
+        tarpit.record_failure_at(bad, now);
+        assert_eq!(tarpit.delay_at(bad, now), Some(delay));
+        assert_eq!(tarpit.delay_at(good, now), None);
+
+        // Failures are forgotten after the window.
+        let later = now + window;
+        assert_eq!(tarpit.delay_at(bad, later), None);
+        tarpit.record_failure_at(bad, later);
+        assert_eq!(tarpit.delay_at(bad, later), None);
+
+        // Expired entries are swept.
+        tarpit.record_failure_at(good, now);
+        tarpit.record_failure_at(bad, later + window);
+        let state = tarpit.state.lock().unwrap();
+        assert_eq!(state.failures.len(), 1);
+        assert!(state.failures.contains_key(&bad));
+    }
+}
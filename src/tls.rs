@@ -0,0 +1,50 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::Result;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore};
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| anyhow::anyhow!("open {}: {}", path.display(), err))
+}
+
+/// Load a certificate chain in PEM, the leaf certificate comes first.
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut open(path)?)
+        .map_err(|_| anyhow::anyhow!("invalid certificate {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("no certificate in {}", path.display()));
+    }
+
+    Ok(certs)
+}
+
+/// Load the first PKCS#8 or RSA private key in PEM.
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let invalid_key = |_| anyhow::anyhow!("invalid private key {}", path.display());
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(invalid_key)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(invalid_key)?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no private key in {}", path.display()))
+}
+
+/// Load a bundle of CA certificates in PEM.
+pub(crate) fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots
+        .add_pem_file(&mut open(path)?)
+        .map_err(|_| anyhow::anyhow!("invalid certificate {}", path.display()))?;
+    if added == 0 {
+        return Err(anyhow::anyhow!("no certificate in {}", path.display()));
+    }
+
+    Ok(roots)
+}
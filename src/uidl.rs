@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use postman_pop3::SeenStore;
+
+/// UidlStore records which messages of a maildrop have been seen.
+///
+/// Entries are keyed by `(name, uid)` where `name` is the maildrop's name,
+/// which is the upstream's name for proxied sessions so that users sharing
+/// an upstream share its records. The value is the unix timestamp when the
+/// message was seen at the first time.
+#[derive(Debug, Clone)]
+pub struct UidlStore {
+    tree: sled::Tree,
+}
+
+impl UidlStore {
+    /// Open the store in the database located at `database_dir`.
+    pub fn open(db: &sled::Db) -> Result<UidlStore> {
+        Ok(UidlStore {
+            tree: db.open_tree("uidl")?,
+        })
+    }
+
+    /// Mark a message as seen, the first seen time will be kept.
+    pub fn mark_seen(&self, name: &str, uid: &str) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        // Ignore the result: the key has been seen if compare failed.
+        let _ = self.tree.compare_and_swap(
+            key(name, uid),
+            None as Option<&[u8]>,
+            Some(&now.to_be_bytes()),
+        )?;
+        Ok(())
+    }
+
+    /// Check whether a message has been seen.
+    pub fn is_seen(&self, name: &str, uid: &str) -> Result<bool> {
+        Ok(self.tree.contains_key(key(name, uid))?)
+    }
+
+    /// Returns the unix timestamp when a message was seen at the first time.
+    pub fn first_seen(&self, name: &str, uid: &str) -> Result<Option<u64>> {
+        match self.tree.get(key(name, uid))? {
+            None => Ok(None),
+            Some(v) => Ok(Some(u64::from_be_bytes(v.as_ref().try_into()?))),
+        }
+    }
+
+    /// Returns the view of maildrop `name` to be used as a `SeenStore`.
+    pub fn maildrop<'a>(&'a self, name: &'a str) -> MaildropSeen<'a> {
+        MaildropSeen { store: self, name }
+    }
+
+    /// Remove seen records of the maildrop whose uid is not in `uids`.
+    ///
+    /// Returns how many records have been removed.
+    pub fn purge_missing<'a>(
+        &self,
+        name: &str,
+        uids: impl IntoIterator<Item = &'a str>,
+    ) -> Result<usize> {
+        let uids: HashSet<&str> = uids.into_iter().collect();
+        let prefix = key(name, "");
+
+        let mut n = 0;
+        for v in self.tree.scan_prefix(&prefix) {
+            let (k, _) = v?;
+            let uid = String::from_utf8_lossy(&k[prefix.len()..]);
+
+            if !uids.contains(uid.as_ref()) {
+                self.tree.remove(&k)?;
+                n += 1;
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// MaildropSeen is the seen records of one maildrop in `UidlStore`.
+#[derive(Debug, Clone, Copy)]
+pub struct MaildropSeen<'a> {
+    store: &'a UidlStore,
+    name: &'a str,
+}
+
+impl SeenStore for MaildropSeen<'_> {
+    fn mark_seen(&self, uid: &str) -> Result<()> {
+        self.store.mark_seen(self.name, uid)
+    }
+
+    fn first_seen(&self, uid: &str) -> Result<Option<u64>> {
+        self.store.first_seen(self.name, uid)
+    }
+}
+
+/// Build key as `name NUL uid`, NUL is not allowed in both of them.
+fn key(name: &str, uid: &str) -> Vec<u8> {
+    let mut k = Vec::with_capacity(name.len() + uid.len() + 1);
+    k.extend_from_slice(name.as_bytes());
+    k.push(0);
+    k.extend_from_slice(uid.as_bytes());
+    k
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seen() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let store = UidlStore::open(&db)?;
+
+        store.mark_seen("qq", "whqtswO00WBw418f9t5JxYwZ")?;
+        store.mark_seen("qq", "QhdPYR:00WBw1Ph7x7")?;
+        store.mark_seen("gmail", "whqtswO00WBw418f9t5JxYwZ")?;
+        assert!(store.is_seen("qq", "whqtswO00WBw418f9t5JxYwZ")?);
+        assert!(!store.is_seen("qq", "unknown")?);
+
+        let first = store.first_seen("qq", "QhdPYR:00WBw1Ph7x7")?;
+        assert!(first.is_some());
+        store.maildrop("qq").mark_seen("QhdPYR:00WBw1Ph7x7")?;
+        assert_eq!(
+            store.maildrop("qq").first_seen("QhdPYR:00WBw1Ph7x7")?,
+            first
+        );
+        assert_eq!(store.first_seen("qq", "unknown")?, None);
+
+        assert_eq!(store.purge_missing("qq", vec!["QhdPYR:00WBw1Ph7x7"])?, 1);
+        assert!(!store.is_seen("qq", "whqtswO00WBw418f9t5JxYwZ")?);
+        assert!(store.is_seen("qq", "QhdPYR:00WBw1Ph7x7")?);
+        assert!(store.is_seen("gmail", "whqtswO00WBw418f9t5JxYwZ")?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use log::{debug, warn};
+use postman_pop3::{
+    Client, ClientError, ErrResponse, Request, RespCode, Response, SysCode, TimeoutError,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, Instant};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+
+use crate::config::Upstream;
+use crate::tls;
+
+/// Stream is a plain TCP or TLS connection.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+/// Client connected to an upstream.
+pub type UpstreamClient = Client<Box<dyn Stream>>;
+
+/// UpstreamPool keeps logged in connections to upstreams for reuse.
+///
+/// Connections are keyed by upstream name, a connection taken from pool is
+/// owned by one session until it's put back. Idle connections are kept
+/// alive by NOOP, so that they will not be dropped by the autologout timer
+/// of upstreams.
+///
+/// A connection which has served a downstream session should not be put
+/// back, the maildrop is fixed at login so the next session would miss
+/// messages arrived since then.
+#[derive(Debug, Default)]
+pub struct UpstreamPool {
+    idle: Mutex<HashMap<String, Vec<UpstreamClient>>>,
+}
+
+impl UpstreamPool {
+    /// Create a pool which sends NOOP on idle connections every `keepalive`.
+    ///
+    /// The keepalive task exits after the pool is dropped.
+    pub fn new(keepalive: Duration) -> Arc<UpstreamPool> {
+        let pool = Arc::new(UpstreamPool::default());
+
+        let weak = Arc::downgrade(&pool);
+        tokio::spawn(async move {
+            let mut interval = time::interval(keepalive);
+            // The first tick completes immediately.
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                match weak.upgrade() {
+                    Some(pool) => pool.keepalive().await,
+                    None => return,
+                }
+            }
+        });
+
+        pool
+    }
+
+    /// Take an idle connection to upstream, or connect and login a new one.
+    ///
+    /// Idle connections are checked by NOOP before reused. Logins failed by
+    /// transient errors are retried as the retry policy of upstream, the
+    /// last error is returned if all attempts failed.
+    pub async fn get(&self, upstream: &Upstream) -> Result<UpstreamClient> {
+        loop {
+            let idle = self
+                .idle
+                .lock()
+                .expect("lock upstream pool")
+                .get_mut(&upstream.name)
+                .and_then(Vec::pop);
+            let mut client = match idle {
+                Some(v) => v,
+                None => break,
+            };
+
+            match client.send(&Request::NOOP).await {
+                Ok(Response::NOOP) => {
+                    debug!("reuse connection to upstream {}", upstream.name);
+                    return Ok(client);
+                }
+                v => debug!(
+                    "drop idle connection to upstream {}: {:?}",
+                    upstream.name, v
+                ),
+            }
+        }
+
+        let mut backoff = Duration::from_millis(upstream.retry.backoff);
+        let mut attempt = 1;
+        loop {
+            match login(upstream).await {
+                Err(err) if attempt < upstream.retry.attempts && is_transient(&err) => {
+                    warn!(
+                        "upstream {}: {}, retry in {:?}",
+                        upstream.name, err, backoff
+                    );
+                    time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Put a connection back to pool.
+    ///
+    /// Deletions marked by the last session will be reset, connection will
+    /// be dropped if it's not usable anymore.
+    pub async fn put(&self, name: &str, mut client: UpstreamClient) {
+        if client.is_broken() {
+            debug!("drop broken connection to upstream {}", name);
+            return;
+        }
+        match client.send(&Request::RSET).await {
+            Ok(Response::RSET(_)) => {}
+            _ => return,
+        }
+
+        self.idle
+            .lock()
+            .expect("lock upstream pool")
+            .entry(name.to_string())
+            .or_default()
+            .push(client);
+    }
+
+    /// Drop all idle connections.
+    pub fn clear(&self) {
+        self.idle.lock().expect("lock upstream pool").clear();
+    }
+
+    /// Send NOOP on all idle connections, connections which don't reply
+    /// `+OK` will be dropped.
+    async fn keepalive(&self) {
+        let idle = mem::take(&mut *self.idle.lock().expect("lock upstream pool"));
+
+        for (name, clients) in idle {
+            for mut client in clients {
+                match client.send(&Request::NOOP).await {
+                    Ok(Response::NOOP) => {}
+                    v => {
+                        debug!("drop idle connection to upstream {}: {:?}", name, v);
+                        continue;
+                    }
+                }
+
+                self.idle
+                    .lock()
+                    .expect("lock upstream pool")
+                    .entry(name.clone())
+                    .or_default()
+                    .push(client);
+            }
+        }
+    }
+}
+
+/// HealthStatus is the result of `check_health`.
+#[derive(Debug)]
+pub enum HealthStatus {
+    /// Upstream greeted, and accepted the login if checked. `latency` is
+    /// the time taken by the whole check.
+    Healthy { latency: Duration },
+    /// Upstream is reachable but rejected the configured credentials.
+    AuthFailed,
+    /// Upstream can't be connected, or failed in the middle of the check.
+    Unreachable(anyhow::Error),
+}
+
+/// Check whether upstream is able to serve: connect and read the greeting,
+/// then login with the configured credentials and STAT if `with_login`.
+/// The connection is closed by QUIT after that.
+///
+/// Connections are never taken from or put into a pool, so the result is
+/// about a fresh connection.
+pub async fn check_health(upstream: &Upstream, with_login: bool) -> HealthStatus {
+    let start = Instant::now();
+
+    let mut client = match connect(upstream).await {
+        Ok(v) => v,
+        Err(err) => return HealthStatus::Unreachable(err),
+    };
+    client.set_max_response_bytes(upstream.max_response_bytes);
+    client.set_quirks(upstream.quirks);
+
+    if with_login {
+        if let Err(err) = client
+            .login(upstream.auth_type, &upstream.username, &upstream.password)
+            .await
+        {
+            if err.downcast_ref::<ErrResponse>().is_some() {
+                warn!("health check of upstream {}: {}", upstream.name, err);
+                return HealthStatus::AuthFailed;
+            }
+            return HealthStatus::Unreachable(err);
+        }
+
+        match client.send(&Request::STAT).await {
+            Ok(Response::STAT { .. }) => {}
+            Ok(Response::ERR(v)) => {
+                return HealthStatus::Unreachable(ErrResponse::new("STAT", v).into())
+            }
+            Ok(v) => {
+                return HealthStatus::Unreachable(anyhow::anyhow!(
+                    "unexpected response for STAT: {:?}",
+                    v
+                ))
+            }
+            Err(err) => return HealthStatus::Unreachable(err),
+        }
+    }
+
+    if let Err(err) = client.close().await {
+        return HealthStatus::Unreachable(err);
+    }
+
+    HealthStatus::Healthy {
+        latency: start.elapsed(),
+    }
+}
+
+/// Connect to upstream and login.
+async fn login(upstream: &Upstream) -> Result<UpstreamClient> {
+    let mut client = connect(upstream).await?;
+    client.set_max_response_bytes(upstream.max_response_bytes);
+    client.set_quirks(upstream.quirks);
+    client
+        .login(upstream.auth_type, &upstream.username, &upstream.password)
+        .await?;
+
+    debug!("connected to upstream {}", upstream.name);
+    Ok(client)
+}
+
+/// Whether upstream rejected by an error which may go away later, like the
+/// maildrop is locked by another session, the connection is closed or
+/// timed out, or upstream is too busy to greet.
+fn is_transient(err: &anyhow::Error) -> bool {
+    if err.is::<TimeoutError>()
+        || matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::ConnectionClosed) | Some(ClientError::ConnectionRefused(_))
+        )
+    {
+        return true;
+    }
+
+    matches!(
+        err.downcast_ref::<ErrResponse>()
+            .and_then(ErrResponse::resp_code),
+        Some(RespCode::InUse) | Some(RespCode::Sys(SysCode::Temp))
+    )
+}
+
+/// Connect to addrs of upstream in order, the last error will be returned
+/// if all of them failed.
+async fn connect(upstream: &Upstream) -> Result<UpstreamClient> {
+    let mut last_err = anyhow::anyhow!("upstream {}: no addr configured", upstream.name);
+
+    let timeout = Duration::from_secs(upstream.connect_timeout);
+    for (host, port) in upstream.host_ports()? {
+        let err = match time::timeout(timeout, connect_addr(upstream, &host, port)).await {
+            Ok(Ok(client)) => return Ok(client),
+            Ok(Err(err)) => err,
+            Err(_) => TimeoutError {
+                op: "connect",
+                after: timeout,
+            }
+            .into(),
+        };
+
+        warn!(
+            "connect to upstream {} at {}:{}: {}",
+            upstream.name, host, port, err
+        );
+        last_err = err.context(format!("connect to {}:{}", host, port));
+    }
+
+    Err(last_err)
+}
+
+/// Connect to upstream at host and port, and read the greeting.
+async fn connect_addr(upstream: &Upstream, host: &str, port: u16) -> Result<UpstreamClient> {
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(upstream.tcp_nodelay)?;
+    if !upstream.tls {
+        return Client::new_with_timeouts(Box::new(stream) as Box<dyn Stream>, upstream.timeouts())
+            .await;
+    }
+
+    let sni = upstream.sni(host);
+    let name = webpki::DNSNameRef::try_from_ascii_str(sni)
+        .map_err(|_| anyhow::anyhow!("invalid tls server name {:?}, set tls_sni", sni))?;
+
+    let stream = TlsConnector::from(Arc::new(tls_config(upstream)?))
+        .connect(name, stream)
+        .await?;
+
+    Client::new_with_timeouts(Box::new(stream) as Box<dyn Stream>, upstream.timeouts()).await
+}
+
+/// Build the TLS config to connect to upstream, certificates are verified
+/// by `tls_ca` if set, or the web PKI roots otherwise.
+fn tls_config(upstream: &Upstream) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    match &upstream.tls_ca {
+        Some(path) => config.root_store = tls::load_roots(path)?,
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    if let (Some(cert), Some(key)) = (&upstream.tls_cert, &upstream.tls_key) {
+        config
+            .set_single_client_cert(tls::load_certs(cert)?, tls::load_private_key(key)?)
+            .map_err(|err| anyhow::anyhow!("client certificate {}: {}", cert.display(), err))?;
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Retry;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Serve a mock upstream which replies `noop` to NOOP and `+OK` to
+    /// others.
+    async fn mock(noop: &'static [u8]) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            let mut socket = BufReader::new(socket);
+            socket.get_mut().write_all(b"+OK ready\r\n").await?;
+
+            let mut line = String::new();
+            while socket.read_line(&mut line).await? > 0 {
+                let resp: &[u8] = match line.as_str() {
+                    "NOOP\r\n" => noop,
+                    "RSET\r\n" => b"+OK maildrop has 0 messages (0 octets)\r\n",
+                    _ => b"+OK done\r\n",
+                };
+                socket.get_mut().write_all(resp).await?;
+                line.clear();
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        Ok(addr)
+    }
+
+    fn upstream(addrs: &[SocketAddr]) -> Result<Upstream> {
+        let addrs: Vec<String> = addrs
+            .iter()
+            .map(|v| format!("{:?}", v.to_string()))
+            .collect();
+
+        Ok(toml::from_str(&format!(
+            r#"
+name = "example"
+protocol = "pop3"
+addr = [{}]
+auth_type = "user"
+username = "user"
+password = "pass"
+"#,
+            addrs.join(", ")
+        ))?)
+    }
+
+    #[tokio::test]
+    async fn failover() -> Result<()> {
+        // Take a free port and close it so that connecting to it fails.
+        let bogus = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let addr = mock(b"+OK\r\n").await?;
+
+        let pool = UpstreamPool::new(Duration::from_secs(60));
+        let mut client = pool.get(&upstream(&[bogus, addr])?).await?;
+        assert_eq!(client.send(&Request::NOOP).await?, Response::NOOP);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuse() -> Result<()> {
+        // Mock serves only one connection.
+        let alive = upstream(&[mock(b"+OK\r\n").await?])?;
+
+        let pool = UpstreamPool::new(Duration::from_secs(60));
+        let client = pool.get(&alive).await?;
+        pool.put("example", client).await;
+        let mut client = pool.get(&alive).await?;
+        assert_eq!(client.send(&Request::NOOP).await?, Response::NOOP);
+
+        Ok(())
+    }
+
+    /// Serve a mock upstream which replies PASS of the nth connection by
+    /// `pass[n]`, an empty reply closes the connection instead. Returns
+    /// how many connections have been accepted.
+    async fn mock_login(pass: Vec<&'static [u8]>) -> Result<(SocketAddr, Arc<AtomicUsize>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            for resp in pass {
+                let (socket, _) = listener.accept().await?;
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut socket = BufReader::new(socket);
+                socket.get_mut().write_all(b"+OK ready\r\n").await?;
+
+                let mut line = String::new();
+                while socket.read_line(&mut line).await? > 0 {
+                    let reply = if line.starts_with("PASS") {
+                        resp
+                    } else if line.starts_with("STAT") {
+                        &b"+OK 0 0\r\n"[..]
+                    } else {
+                        b"+OK done\r\n"
+                    };
+                    socket.get_mut().write_all(reply).await?;
+                    if reply.is_empty() || reply.starts_with(b"-ERR") {
+                        break;
+                    }
+                    line.clear();
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        Ok((addr, accepted))
+    }
+
+    #[tokio::test]
+    async fn retry() -> Result<()> {
+        let pool = UpstreamPool::new(Duration::from_secs(60));
+        let retry = Retry {
+            attempts: 3,
+            backoff: 10,
+        };
+
+        let (addr, accepted) = mock_login(vec![
+            b"-ERR [IN-USE] maildrop locked\r\n",
+            b"-ERR [SYS/TEMP] try later\r\n",
+            b"+OK maildrop ready\r\n",
+        ])
+        .await?;
+        let cfg = Upstream {
+            retry,
+            ..upstream(&[addr])?
+        };
+        pool.get(&cfg).await?;
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        // Connections closed by upstream are reconnected.
+        let (addr, accepted) = mock_login(vec![b"", b"+OK maildrop ready\r\n"]).await?;
+        let cfg = Upstream {
+            retry,
+            ..upstream(&[addr])?
+        };
+        pool.get(&cfg).await?;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // The last error is returned after all attempts failed.
+        let (addr, accepted) = mock_login(vec![b"-ERR [IN-USE] maildrop locked\r\n"; 3]).await?;
+        let cfg = Upstream {
+            retry,
+            ..upstream(&[addr])?
+        };
+        let err = pool.get(&cfg).await.unwrap_err();
+        assert_eq!(err.to_string(), "PASS failed: [IN-USE] maildrop locked");
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        // Permanent errors are never retried.
+        let (addr, accepted) = mock_login(vec![
+            b"-ERR [AUTH] invalid password\r\n",
+            b"-ERR [SYS/PERM] mailbox disabled\r\n",
+        ])
+        .await?;
+        let cfg = Upstream {
+            retry,
+            ..upstream(&[addr])?
+        };
+        assert!(pool.get(&cfg).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert!(pool.get(&cfg).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health() -> Result<()> {
+        let (addr, accepted) = mock_login(vec![
+            b"+OK maildrop ready\r\n",
+            b"-ERR [AUTH] invalid password\r\n",
+            b"+OK maildrop ready\r\n",
+        ])
+        .await?;
+        let cfg = upstream(&[addr])?;
+
+        assert!(matches!(
+            check_health(&cfg, true).await,
+            HealthStatus::Healthy { .. }
+        ));
+        assert!(matches!(
+            check_health(&cfg, true).await,
+            HealthStatus::AuthFailed
+        ));
+        // Greeting only, the reply to PASS is never used.
+        assert!(matches!(
+            check_health(&cfg, false).await,
+            HealthStatus::Healthy { .. }
+        ));
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        let bogus = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        assert!(matches!(
+            check_health(&upstream(&[bogus])?, false).await,
+            HealthStatus::Unreachable(_)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keepalive() -> Result<()> {
+        let alive = upstream(&[mock(b"+OK\r\n").await?])?;
+        let dead = upstream(&[mock(b"-ERR autologout\r\n").await?])?;
+
+        let pool = UpstreamPool::new(Duration::from_millis(50));
+        let client = pool.get(&alive).await?;
+        pool.put("alive", client).await;
+        let client = pool.get(&dead).await?;
+        pool.put("dead", client).await;
+
+        // Idle connections are taken out while sending NOOP, wait for a
+        // moment they are all put back.
+        let mut settled = false;
+        for _ in 0..100 {
+            time::sleep(Duration::from_millis(10)).await;
+            let idle = pool.idle.lock().expect("lock upstream pool");
+            if idle.get("alive").map(Vec::len) == Some(1) && idle.get("dead").is_none() {
+                settled = true;
+                break;
+            }
+        }
+        assert!(settled);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_ca() -> Result<()> {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+        use std::fs;
+        use tokio_rustls::rustls::{self, AllowAnyAuthenticatedClient, ServerConfig};
+        use tokio_rustls::TlsAcceptor;
+
+        let dir = std::env::temp_dir().join(format!("postman-private-ca-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        let mut params = CertificateParams::new(vec!["ca".to_string()]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params)?;
+        fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+        fs::write(dir.join("bad.pem"), "not a certificate")?;
+        let server_cert =
+            Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))?;
+        let client_cert =
+            Certificate::from_params(CertificateParams::new(vec!["postman".to_string()]))?;
+        fs::write(
+            dir.join("client.pem"),
+            client_cert.serialize_pem_with_signer(&ca)?,
+        )?;
+        fs::write(
+            dir.join("client.key"),
+            client_cert.serialize_private_key_pem(),
+        )?;
+
+        // Serve clients with a certificate issued by the CA until QUIT.
+        let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(tls::load_roots(
+            &dir.join("ca.pem"),
+        )?));
+        config.set_single_cert(
+            vec![rustls::Certificate(
+                server_cert.serialize_der_with_signer(&ca)?,
+            )],
+            rustls::PrivateKey(server_cert.serialize_private_key_der()),
+        )?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await?;
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(acceptor.accept(socket).await?);
+                    stream.write_all(b"+OK ready\r\n").await?;
+                    stream.flush().await?;
+
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await?;
+                    assert_eq!(line, "QUIT\r\n");
+                    stream.write_all(b"+OK bye\r\n").await?;
+                    stream.flush().await?;
+                    Ok::<_, anyhow::Error>(())
+                });
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let cfg = Upstream {
+            tls: true,
+            tls_sni: Some("localhost".to_string()),
+            tls_ca: Some(dir.join("ca.pem")),
+            tls_cert: Some(dir.join("client.pem")),
+            tls_key: Some(dir.join("client.key")),
+            ..upstream(&[addr])?
+        };
+        let status = check_health(&cfg, false).await;
+        assert!(
+            matches!(status, HealthStatus::Healthy { .. }),
+            "{:?}",
+            status
+        );
+
+        // The web PKI roots don't trust the private CA.
+        let untrusted = Upstream {
+            tls_ca: None,
+            ..cfg.clone()
+        };
+        assert!(matches!(
+            check_health(&untrusted, false).await,
+            HealthStatus::Unreachable(_)
+        ));
+
+        let bad = Upstream {
+            tls_ca: Some(dir.join("bad.pem")),
+            ..cfg.clone()
+        };
+        match check_health(&bad, false).await {
+            HealthStatus::Unreachable(err) => {
+                let err = format!("{:#}", err);
+                assert!(err.contains("no certificate in"), "{}", err);
+            }
+            v => panic!("unexpected status: {:?}", v),
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
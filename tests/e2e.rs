@@ -0,0 +1,140 @@
+//! End-to-end tests which drive the real `Server` by the real `Client`, so
+//! that both sides agree on framing, states and commit semantics.
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use postman::config::Config;
+use postman::metrics::NoopMetrics;
+use postman::Server;
+use postman_pop3::{AuthType, Client, ListResponse, Request, Response};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+const MESSAGES: &[(&str, &str)] = &[
+    ("1.eml", "Subject: a\r\n\r\nhello\r\n"),
+    ("2.eml", "Subject: b\r\n\r\n.leading dot\r\nworld\r\n"),
+    ("3.eml", "Subject: c\r\n\r\n!\r\n"),
+];
+
+async fn login(addr: &str) -> Result<Client<TcpStream>> {
+    let mut client = Client::connect(addr).await?;
+    client
+        .login(AuthType::UserPass, "postman", "postman")
+        .await?;
+
+    Ok(client)
+}
+
+async fn stat(client: &mut Client<TcpStream>) -> Result<(usize, usize)> {
+    match client.send(&Request::STAT).await? {
+        Response::STAT { count, size } => Ok((count, size)),
+        v => Err(anyhow::anyhow!("unexpected response for STAT: {:?}", v)),
+    }
+}
+
+fn size_of(names: &[&str]) -> usize {
+    MESSAGES
+        .iter()
+        .filter(|(name, _)| names.contains(name))
+        .map(|(_, content)| content.len())
+        .sum()
+}
+
+fn exists(dir: &Path, names: &[&str]) -> Vec<bool> {
+    names.iter().map(|v| dir.join(v).exists()).collect()
+}
+
+#[tokio::test]
+async fn session() -> Result<()> {
+    let dir = env::temp_dir().join(format!("postman-e2e-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let maildrop = dir.join("mails").join("postman");
+    fs::create_dir_all(&maildrop)?;
+    for (name, content) in MESSAGES {
+        fs::write(maildrop.join(name), content)?;
+    }
+
+    let cfg: Config = toml::from_str(&format!(
+        r#"
+database_dir = {:?}
+data_dir = {:?}
+drain_timeout = 5
+
+[[downstream]]
+protocol = "pop3"
+addr = "127.0.0.1:0"
+auth_type = "user"
+username = "postman"
+password = "postman"
+"#,
+        dir.join("db"),
+        dir.join("mails"),
+    ))?;
+    let server = Server::new(Arc::new(cfg), Arc::new(NoopMetrics));
+    let listeners = server.bind().await?;
+    let addr = listeners[0].local_addr()?.to_string();
+    let (tx, rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move { server.serve(listeners, rx).await });
+
+    // Deletions are committed by QUIT.
+    let mut client = login(&addr).await?;
+    assert_eq!(
+        stat(&mut client).await?,
+        (3, size_of(&["1.eml", "2.eml", "3.eml"]))
+    );
+    match client.send(&Request::LIST(None)).await? {
+        Response::LIST(ListResponse::All(v)) => {
+            let expect: Vec<(usize, usize)> = MESSAGES
+                .iter()
+                .enumerate()
+                .map(|(i, (_, content))| (i + 1, content.len()))
+                .collect();
+            assert_eq!(v, expect);
+        }
+        v => panic!("unexpected response for LIST: {:?}", v),
+    }
+    match client.send(&Request::RETR(2)).await? {
+        Response::RETR(v) => assert_eq!(v, MESSAGES[1].1),
+        v => panic!("unexpected response for RETR: {:?}", v),
+    }
+    assert_eq!(client.send(&Request::DELE(2)).await?, Response::DELE);
+    assert_eq!(stat(&mut client).await?, (2, size_of(&["1.eml", "3.eml"])));
+    assert!(matches!(
+        client.send(&Request::RETR(2)).await?,
+        Response::ERR(_)
+    ));
+    client.close().await?;
+    assert_eq!(
+        exists(&maildrop, &["1.eml", "2.eml", "3.eml"]),
+        vec![true, false, true]
+    );
+
+    // Deletions are discarded by RSET.
+    let mut client = login(&addr).await?;
+    assert_eq!(stat(&mut client).await?, (2, size_of(&["1.eml", "3.eml"])));
+    assert_eq!(client.send(&Request::DELE(1)).await?, Response::DELE);
+    assert!(matches!(
+        client.send(&Request::RSET).await?,
+        Response::RSET(_)
+    ));
+    assert_eq!(stat(&mut client).await?, (2, size_of(&["1.eml", "3.eml"])));
+    client.close().await?;
+    assert_eq!(exists(&maildrop, &["1.eml", "3.eml"]), vec![true, true]);
+
+    // Deletions of a session which never sends QUIT are not committed.
+    let mut client = login(&addr).await?;
+    assert_eq!(client.send(&Request::DELE(1)).await?, Response::DELE);
+    assert_eq!(client.send(&Request::DELE(2)).await?, Response::DELE);
+    drop(client);
+
+    // Sessions have finished once the server returns.
+    let _ = tx.send(());
+    server.await??;
+    assert_eq!(exists(&maildrop, &["1.eml", "3.eml"]), vec![true, true]);
+
+    let _ = fs::remove_dir_all(&dir);
+    Ok(())
+}